@@ -0,0 +1,341 @@
+//! The daemon's control API (--listen): a small JSON-over-HTTP interface to
+//! list jobs, start one, stop one and read its stats. Each connection
+//! carries one request and is closed after the response.
+//!
+//! - `GET /jobs`: every job, as in the status file
+//! - `POST /jobs`: start a job from a JSON object of `record` options
+//! - `GET /jobs/NAME`: one job's state, restarts, last error and stats
+//! - `POST /jobs/NAME/stop`: stop a job, finalizing its current file
+//!
+//! A job started here can't set hooks, commands or paths, and its URL has
+//! to be a network one: anyone who can reach the API could otherwise run
+//! commands, read local files and write anywhere. Those come from the jobs
+//! file's shared options. With --api-token, every
+//! request has to carry it as `Authorization: Bearer TOKEN`.
+
+use crate::daemon::{Daemon, StartError};
+use crate::error::StreamError as Error;
+use hyper::StatusCode;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+/// Requests are small JSON objects; anything bigger is refused
+const MAX_REQUEST: usize = 64 * 1024;
+/// A client gets this long to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// URL schemes a job started through the API can record. Anything else,
+/// like `file:` or ffmpeg's `concat:`, could read the daemon's own files.
+const ALLOWED_SCHEMES: &[&str] = &["http", "https", "rtsp", "rtsps", "srt", "udp", "rtp"];
+
+/// Options a job started through the API can't set, as their ids: hooks
+/// and other commands, and the paths it would read or write
+const FORBIDDEN_OPTIONS: &[&str] = &[
+    "on_start",
+    "on_segment",
+    "on_segment_exec",
+    "on_exit",
+    "on_exit_error",
+    "on_error",
+    "on_marker",
+    "on_low_disk",
+    "webhook",
+    "ffmpeg",
+    "ffmpeg_stdin",
+    "ffmpeg_path",
+    "ffmpeg_args",
+    "ffmpeg_output_args",
+    "output",
+    "pipe",
+    "direct",
+    "snapshot_url",
+    "filename_template",
+    "state_file",
+    "trigger_file",
+    "stats_json",
+    "log_file",
+    "ca_cert",
+    "client_cert",
+    "client_key",
+];
+
+struct Request {
+    method: String,
+    path: String,
+    /// The Authorization header
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Bind `addr` and serve the API for `daemon` in the background. Without a
+/// `token`, only a loopback address is allowed.
+pub async fn spawn(
+    addr: SocketAddr,
+    token: Option<String>,
+    daemon: Arc<Daemon>,
+) -> Result<(), Error> {
+    if token.is_none() && !addr.ip().is_loopback() {
+        return Err(Error::config(format!(
+            "--listen {addr} isn't a loopback address; set --api-token to serve the API on it"
+        )));
+    }
+    let token: Option<Arc<str>> = token.map(Into::into);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| Error::config(format!("Can't listen on {addr}: {e}")))?;
+    info!("Control API listening on http://{addr}");
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Control API connection failed: {e}");
+                    continue;
+                }
+            };
+            let daemon = daemon.clone();
+            let token = token.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &daemon, token.as_deref()).await {
+                    debug!(%peer, "Control API request failed: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(mut stream: TcpStream, daemon: &Daemon, token: Option<&str>) -> Result<(), Error> {
+    let (status, body) =
+        match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(Some(request))) if !authorized(&request, token) => {
+                error(StatusCode::UNAUTHORIZED, "missing or wrong API token")
+            }
+            Ok(Ok(Some(request))) => route(daemon, request),
+            Ok(Ok(None)) => error(StatusCode::PAYLOAD_TOO_LARGE, "request too large"),
+            Ok(Err(e)) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+            Err(_) => error(StatusCode::REQUEST_TIMEOUT, "request timed out"),
+        };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read one request, or None if it's over MAX_REQUEST
+async fn read_request(stream: &mut TcpStream) -> Result<Option<Request>, Error> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("connection closed mid-request".into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(head_len) = parsed.parse(&buf)? else {
+            continue;
+        };
+        let content_length = parsed
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+            .map(|h| {
+                std::str::from_utf8(h.value)
+                    .ok()?
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+            })
+            .unwrap_or(Some(0))
+            .ok_or("bad Content-Length")?;
+        if head_len + content_length > MAX_REQUEST {
+            return Ok(None);
+        }
+        let method = parsed.method.unwrap_or_default().to_string();
+        let path = parsed.path.unwrap_or_default().to_string();
+        let authorization = parsed
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("authorization"))
+            .map(|h| String::from_utf8_lossy(h.value).trim().to_string());
+        while buf.len() < head_len + content_length {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err("connection closed mid-request".into());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body = buf[head_len..head_len + content_length].to_vec();
+        return Ok(Some(Request {
+            method,
+            path,
+            authorization,
+            body,
+        }));
+    }
+}
+
+/// The request carries the --api-token, if there is one. The comparison
+/// takes as long wherever the first difference is.
+fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let Some(given) = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn route(daemon: &Daemon, request: Request) -> (StatusCode, Vec<u8>) {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), parts.as_slice()) {
+        ("GET", ["jobs"]) => {
+            let jobs = daemon.jobs();
+            let reports: Vec<_> = jobs.iter().map(|job| job.report()).collect();
+            json(StatusCode::OK, &reports)
+        }
+        ("POST", ["jobs"]) => start(daemon, &request.body),
+        ("GET", ["jobs", name]) => match daemon.find(name) {
+            Some(job) => json(StatusCode::OK, &job.report()),
+            None => error(StatusCode::NOT_FOUND, &format!("no job named {name}")),
+        },
+        ("POST", ["jobs", name, "stop"]) => match daemon.find(name) {
+            Some(job) => {
+                info!(job = %job.name, "Stopping job");
+                job.stop();
+                json(StatusCode::ACCEPTED, &job.report())
+            }
+            None => error(StatusCode::NOT_FOUND, &format!("no job named {name}")),
+        },
+        (_, ["jobs"] | ["jobs", _] | ["jobs", _, "stop"]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => error(StatusCode::NOT_FOUND, &format!("no route {path}")),
+    }
+}
+
+/// `POST /jobs`: the body is a job's table as JSON, e.g.
+/// `{"name": "news", "url": "https://...", "segment-secs": 600}`
+fn start(daemon: &Daemon, body: &[u8]) -> (StatusCode, Vec<u8>) {
+    let table: toml::Table = match serde_json::from_slice(body) {
+        Ok(table) => table,
+        Err(e) => {
+            let msg = format!("body must be a JSON object of options: {e}");
+            return error(StatusCode::BAD_REQUEST, &msg);
+        }
+    };
+    if let Err(msg) = check_table(&table) {
+        return error(StatusCode::FORBIDDEN, &msg);
+    }
+    match daemon.start_table(table) {
+        Ok(job) => json(StatusCode::CREATED, &job.report()),
+        Err(StartError::Invalid(msg)) => error(StatusCode::BAD_REQUEST, &msg),
+        Err(StartError::Conflict(name)) => error(
+            StatusCode::CONFLICT,
+            &format!("job {name} is already running"),
+        ),
+        Err(StartError::ShuttingDown) => error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the daemon is shutting down",
+        ),
+    }
+}
+
+/// Refuse the options only the jobs file may set, and a URL that isn't
+/// one of the network schemes
+fn check_table(table: &toml::Table) -> Result<(), String> {
+    if let Some(key) = table
+        .keys()
+        .find(|key| FORBIDDEN_OPTIONS.contains(&key.replace('-', "_").as_str()))
+    {
+        return Err(format!("`{key}` can only be set in the jobs file"));
+    }
+    if let Some(url) = table.get("url") {
+        let allowed = url
+            .as_str()
+            .and_then(|url| url::Url::parse(url).ok())
+            .is_some_and(|url| ALLOWED_SCHEMES.contains(&url.scheme()));
+        if !allowed {
+            return Err(format!(
+                "url must use one of the schemes {}",
+                ALLOWED_SCHEMES.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> (StatusCode, Vec<u8>) {
+    let mut body = serde_json::to_vec_pretty(value).unwrap_or_default();
+    body.push(b'\n');
+    (status, body)
+}
+
+fn error(status: StatusCode, msg: &str) -> (StatusCode, Vec<u8>) {
+    json(status, &serde_json::json!({ "error": msg }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(json: &str) -> toml::Table {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn network_urls_only() {
+        assert!(check_table(&table(r#"{"url": "https://example.com/live.m3u8"}"#)).is_ok());
+        assert!(check_table(&table(r#"{"url": "srt://example.com:9000"}"#)).is_ok());
+        for url in [
+            "concat:/srv/rec/a.ts|/etc/passwd",
+            "file:///etc/passwd",
+            "C:/recordings/a.m3u8",
+            "/etc/passwd",
+        ] {
+            let json = serde_json::json!({ "url": url }).to_string();
+            assert!(check_table(&table(&json)).is_err(), "{url}");
+        }
+        assert!(check_table(&table(r#"{"url": 5}"#)).is_err());
+    }
+
+    #[test]
+    fn forbidden_options() {
+        for json in [
+            r#"{"url": "https://example.com/live.m3u8", "direct": true}"#,
+            r#"{"url": "https://example.com/live.m3u8", "ffmpeg-stdin": true}"#,
+            r#"{"url": "https://example.com/live.m3u8", "snapshot_url": "http://cam/jpg"}"#,
+            r#"{"url": "https://example.com/live.m3u8", "on-segment": "rm -rf /"}"#,
+        ] {
+            assert!(check_table(&table(json)).is_err(), "{json}");
+        }
+    }
+}
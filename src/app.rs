@@ -0,0 +1,52 @@
+//! The command line: parses it, sets up logging and runs the subcommand
+
+use crate::cli::{self, Cli, Command};
+use crate::error::StreamError;
+use crate::{convert, daemon, logging, probe, record, retention};
+use std::process::ExitCode;
+
+/// The `stream-utils` command, run by the binary
+pub async fn main() -> ExitCode {
+    let cli = Cli::parse_with_default();
+    let log = match cli.command.log_args().map(logging::init).transpose() {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return ExitCode::from(&e);
+        }
+    };
+    let result = run(cli.command).await;
+    // Through the logger, so the reason a recording stopped ends up in
+    // --log-file and the JSON output with everything else
+    let code = match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if log.is_some() => {
+            tracing::error!(target: logging::FATAL_TARGET, "{e}");
+            ExitCode::from(&e)
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(&e)
+        }
+    };
+    drop(log);
+    code
+}
+
+async fn run(command: Command) -> Result<(), StreamError> {
+    match command {
+        Command::Record(args) => record::run(*args).await,
+        Command::Probe(args) => probe::run(*args).await,
+        Command::Convert(args) => convert::run(args),
+        Command::Clean(args) => retention::run(args),
+        Command::Daemon(args) => daemon::run(args).await,
+        Command::Completions { shell } => {
+            cli::print_completions(shell);
+            Ok(())
+        }
+        Command::Manpage => {
+            cli::print_manpage()?;
+            Ok(())
+        }
+    }
+}
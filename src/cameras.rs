@@ -0,0 +1,159 @@
+//! Several streams recorded by one process: extra URLs on the command line,
+//! an `--input-list` file of URLs, or a `--cameras` TOML file with a
+//! `[[camera]]` table for each. Every stream records into its own
+//! subdirectory of the output directory.
+
+use crate::error::StreamError;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Camera {
+    /// Subdirectory to record into; from the URL by default
+    #[serde(default)]
+    pub name: String,
+    pub url: String,
+    /// Replace --username/--password for this camera
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Replace --on-segment for this camera
+    pub on_segment: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraFile {
+    #[serde(default)]
+    camera: Vec<Camera>,
+}
+
+impl Camera {
+    pub fn from_url(url: &str) -> Self {
+        Self {
+            name: String::new(),
+            url: url.to_string(),
+            username: None,
+            password: None,
+            on_segment: None,
+        }
+    }
+}
+
+/// Read a --cameras file
+pub fn load(path: &Path) -> Result<Vec<Camera>, StreamError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| StreamError::config(format!("cameras file {}: {e}", path.display())))?;
+    let file: CameraFile = toml::from_str(&text)
+        .map_err(|e| StreamError::config(format!("cameras file {}: {e}", path.display())))?;
+    if file.camera.is_empty() {
+        return Err(StreamError::config(format!(
+            "cameras file {}: no [[camera]] entries",
+            path.display()
+        )));
+    }
+    name_cameras(file.camera)
+}
+
+/// Streams for the URLs given on the command line and those in an
+/// --input-list file
+pub fn from_urls(urls: &[String], list: Option<&Path>) -> Result<Vec<Camera>, StreamError> {
+    let mut cameras: Vec<Camera> = urls.iter().map(|url| Camera::from_url(url)).collect();
+    if let Some(path) = list {
+        cameras.extend(read_list(path)?);
+    }
+    name_cameras(cameras)
+}
+
+/// Read an --input-list file: a URL per line, optionally followed by the
+/// name of its subdirectory. Blank lines and `#` comments are skipped.
+fn read_list(path: &Path) -> Result<Vec<Camera>, StreamError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| StreamError::config(format!("input list {}: {e}", path.display())))?;
+    let mut cameras = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let mut camera = Camera::from_url(fields.next().unwrap_or_default());
+        camera.name = fields.next().unwrap_or_default().to_string();
+        if fields.next().is_some() {
+            return Err(StreamError::config(format!(
+                "input list {} line {}: expected a URL and at most a name",
+                path.display(),
+                i + 1
+            )));
+        }
+        cameras.push(camera);
+    }
+    if cameras.is_empty() {
+        return Err(StreamError::config(format!(
+            "input list {}: no URLs",
+            path.display()
+        )));
+    }
+    Ok(cameras)
+}
+
+/// The subdirectory for a stream without a name: the URL's host, or the
+/// file name of a local playlist
+fn default_name(url: &str) -> String {
+    if let Some(host) = url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .filter(|host| !host.is_empty())
+    {
+        return host;
+    }
+    let path = url.strip_prefix("file://").unwrap_or(url);
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "stream".to_string())
+}
+
+/// Fill in missing names from the URLs' hosts, numbering repeats
+/// (`cam`, `cam-2`), and check that each name is a single path component
+pub fn name_cameras(mut cameras: Vec<Camera>) -> Result<Vec<Camera>, StreamError> {
+    let mut taken: Vec<String> = cameras
+        .iter()
+        .filter(|c| !c.name.is_empty())
+        .map(|c| c.name.clone())
+        .collect();
+    for camera in &mut cameras {
+        if camera.name.is_empty() {
+            let host = default_name(&camera.url);
+            let mut name = host.clone();
+            let mut n = 2;
+            while taken.contains(&name) {
+                name = format!("{host}-{n}");
+                n += 1;
+            }
+            taken.push(name.clone());
+            camera.name = name;
+        }
+        let mut components = Path::new(&camera.name).components();
+        let single = matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        );
+        if !single {
+            return Err(StreamError::config(format!(
+                "stream name {:?} must be a plain directory name",
+                camera.name
+            )));
+        }
+    }
+    for (i, camera) in cameras.iter().enumerate() {
+        if cameras[..i].iter().any(|other| other.name == camera.name) {
+            return Err(StreamError::config(format!(
+                "two streams are named {:?}",
+                camera.name
+            )));
+        }
+    }
+    Ok(cameras)
+}
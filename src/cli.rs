@@ -1,88 +1,1139 @@
-use clap::Parser;
+use crate::config;
+use crate::output::FilenameTemplate;
+use crate::playlist::VariantArgs;
+use clap::{Args as _, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use hyper::header::{HeaderName, HeaderValue};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+/// What to do with an audio rendition (EXT-X-MEDIA) that's separate from
+/// the video variant
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioMode {
+    /// Record video only
+    Ignore,
+    /// Record the audio playlist too, into an `audio` subdirectory
+    Store,
+    /// Have ffmpeg mux audio and video into the same files
+    Mux,
+}
+
+/// Digest written to a manifest for each completed file
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// SHA-256, into SHA256SUMS
+    Sha256,
+}
+
+/// What to do when the output filesystem drops below --min-free-mb
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LowDiskAction {
+    /// Finish the current file and stop the recording
+    Stop,
+    /// Delete the oldest recordings in the output directory to make room
+    Prune,
+    /// Only log a warning (and run --on-low-disk)
+    Warn,
+}
 
 #[derive(Parser, Debug)]
-#[clap(
+#[command(
     name = "m3u8-dl",
-    about = "Download m3u8 streams to chunked video files"
+    version,
+    about = "Download m3u8 streams to chunked video files",
+    after_help = "Running `m3u8-dl <URL> [OPTIONS]` is shorthand for `m3u8-dl record <URL> [OPTIONS]`.",
+    arg_required_else_help = true
 )]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parse the command line, treating `m3u8-dl [OPTIONS] <URL>` as
+    /// `m3u8-dl record [OPTIONS] <URL>` so existing invocations keep working
+    pub fn parse_with_default() -> Self {
+        Self::try_parse_with_default(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Fallible `parse_with_default`, also used to re-read --config on SIGHUP
+    pub fn try_parse_with_default(
+        args: impl IntoIterator<Item = OsString>,
+    ) -> Result<Self, clap::Error> {
+        let mut argv: Vec<OsString> = args.into_iter().collect();
+        if let Some(first) = argv.get(1).and_then(|a| a.to_str()) {
+            let cmd = Self::command();
+            let is_subcommand = first == "help"
+                || cmd.get_subcommands().any(|sub| {
+                    sub.get_name() == first || sub.get_all_aliases().any(|a| a == first)
+                });
+            let is_top_level_flag = matches!(first, "-h" | "--help" | "-V" | "--version");
+            if !is_subcommand && !is_top_level_flag {
+                argv.insert(1, "record".into());
+            }
+        }
+
+        let mut cmd = Self::command_with_env();
+        if argv.get(1).is_some_and(|a| a == "record") {
+            if let Some(path) = config::find_path(&argv[2..]) {
+                let profile = config::find_profile(&argv[2..]);
+                cmd = config::apply(cmd, &path, profile.as_deref())?;
+            }
+        }
+        let matches = cmd.try_get_matches_from(argv)?;
+        Self::from_arg_matches(&matches)
+    }
+
+    /// The clap command with a `STREAM_UTILS_*` environment variable behind
+    /// every option, so secrets can stay out of the process list and
+    /// containers can be configured through env alone. `record` options
+    /// (and the logging/network options every command shares) map to
+    /// `STREAM_UTILS_<OPTION>`; options only another command has get the
+    /// command name too, e.g. `STREAM_UTILS_CLEAN_KEEP_HOURS`. An option
+    /// that names its own variable keeps it, and another option whose
+    /// variable would be the same name goes without.
+    pub fn command_with_env() -> clap::Command {
+        let shared: HashSet<String> = [
+            LogArgs::augment_args(clap::Command::new("log")),
+            NetworkArgs::augment_args(clap::Command::new("net")),
+            VariantArgs::augment_args(clap::Command::new("variant")),
+        ]
+        .iter()
+        .flat_map(|cmd| cmd.get_arguments().map(|a| a.get_id().to_string()))
+        .collect();
+
+        Self::command().mut_subcommands(|sub| {
+            if matches!(sub.get_name(), "completions" | "manpage") {
+                return sub;
+            }
+            let command = sub.get_name().to_uppercase();
+            let is_record = command == "RECORD";
+            let claimed: HashSet<String> = sub
+                .get_arguments()
+                .filter_map(|arg| arg.get_env())
+                .map(|var| var.to_string_lossy().into_owned())
+                .collect();
+            sub.mut_args(|arg| {
+                let id = arg.get_id().to_string();
+                if id == "help" || id == "version" {
+                    return arg;
+                }
+                let name = id.to_uppercase().replace('-', "_");
+                let var = if let Some(var) = arg.get_env() {
+                    var.to_string_lossy().into_owned()
+                } else {
+                    let var = if is_record || shared.contains(&id) {
+                        format!("STREAM_UTILS_{name}")
+                    } else {
+                        format!("STREAM_UTILS_{command}_{name}")
+                    };
+                    // e.g. --ffmpeg, whose name --ffmpeg-path has taken
+                    if claimed.contains(&var) {
+                        return arg;
+                    }
+                    var
+                };
+                // Never echo values (e.g. a password) back in --help
+                let arg = arg.env(var).hide_env_values(true);
+                // Accept 1/yes/on as well as true for flags set through env
+                if matches!(arg.get_action(), clap::ArgAction::SetTrue) {
+                    arg.value_parser(clap::builder::BoolishValueParser::new())
+                } else {
+                    arg
+                }
+            })
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Record a stream to rotating files (the default when only a URL is given)
+    Record(Box<Args>),
+
+    /// Inspect a stream (variants, audio, format, encryption, live/VOD) without recording it
+    #[command(visible_alias = "list-variants")]
+    Probe(Box<ProbeArgs>),
+
+    /// Merge a recording session's rotated files into a single MP4/MKV with chapters
+    #[command(visible_alias = "concat")]
+    Convert(ConvertArgs),
+
+    /// Delete old recordings from a directory by age, count or total size
+    Clean(CleanArgs),
+
+    /// Supervise the recordings in a jobs file, restarting each one that fails
+    Daemon(DaemonArgs),
+
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        shell: clap_complete::Shell,
+    },
+
+    /// Print the man page (roff) to stdout
+    Manpage,
+}
+
+impl Command {
+    /// The logging options, for the commands that log
+    pub fn log_args(&self) -> Option<&LogArgs> {
+        match self {
+            Command::Record(args) => Some(&args.log),
+            Command::Probe(args) => Some(&args.log),
+            Command::Convert(args) => Some(&args.log),
+            Command::Clean(args) => Some(&args.log),
+            Command::Daemon(args) => Some(&args.log),
+            Command::Completions { .. } | Command::Manpage => None,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
 pub struct Args {
-    /// M3U8 URL to download
+    /// M3U8 URL to download, or a local playlist file (required unless --cameras)
+    // Checked when recording starts rather than with required_unless_present,
+    // which a URL from --config couldn't satisfy. The indexes are explicit
+    // since --config re-adds the options it sets.
+    #[arg(index = 1, default_value = "")]
     pub url: String,
 
-    /// Output directory
-    #[arg(short, long, default_value = ".")]
+    /// More URLs to record at the same time, each into its own subdirectory
+    #[arg(index = 2, value_name = "MORE_URLS")]
+    pub more_urls: Vec<String>,
+
+    /// Record the cameras listed in this TOML file at the same time, each
+    /// into its own subdirectory ([[camera]] tables with name, url, username,
+    /// password and on-segment)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["url", "more_urls"])]
+    pub cameras: Option<PathBuf>,
+
+    /// Also record the URLs in this file at the same time, one per line, each optionally followed by a name for its
+    /// subdirectory
+    #[arg(long, value_name = "FILE", conflicts_with = "cameras")]
+    pub input_list: Option<PathBuf>,
+
+    /// Read option defaults from this TOML file (keys are option names, e.g.
+    /// segment-secs = 600). SIGHUP re-reads its hook settings while recording
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Use the options of this [profile.NAME] table in the --config file, over its top-level ones
+    #[arg(long, value_name = "NAME", requires = "config")]
+    pub profile: Option<String>,
+
+    /// Output directory, or - to write the stream to stdout (same as --pipe -)
+    #[arg(short, long, default_value = ".", help_heading = "Output")]
     pub output: PathBuf,
 
+    /// Write the stream to this FIFO (made if missing), or stdout for -, instead of rotating files
+    #[arg(long, value_name = "PATH", help_heading = "Output")]
+    pub pipe: Option<PathBuf>,
+
     /// Segment duration in seconds (rotate file after this duration)
-    #[arg(short, long, default_value = "3600")]
+    #[arg(short, long, default_value = "3600", help_heading = "Output")]
     pub segment_secs: u64,
 
-    /// Fake an error on exit
-    #[clap(long, action)]
-    pub fake_exit_err: bool,
+    /// Also rotate once a file reaches this size, e.g. 4000M or 1G (plain numbers are bytes), whichever comes first
+    #[arg(long, value_name = "SIZE", value_parser = parse_segment_size, help_heading = "Output")]
+    pub segment_bytes: Option<u64>,
 
-    /// Show progress dots
-    #[clap(long, action)]
-    pub progress: bool,
+    /// File extension, ts by default
+    #[arg(long, default_value = "ts", help_heading = "Output")]
+    pub file_extension: String,
 
-    /// Show verbose logs
-    #[clap(long, action)]
-    pub verbose: bool,
+    /// Name files with this template instead: strftime specifiers for the start time, plus {index}, {seq} (media sequence number of the first segment), {stream} and {ext}. May include subdirectories
+    #[arg(long, value_name = "TEMPLATE", help_heading = "Output")]
+    pub filename_template: Option<FilenameTemplate>,
 
-    /// Total timeout in seconds for a fetch operation (across all retries)
-    #[arg(long, default_value = "15")]
-    pub timeout: u64,
+    /// Name files after the EXT-X-PROGRAM-DATE-TIME of their first segment (HLS only)
+    #[arg(long, help_heading = "Output")]
+    pub pdt_filenames: bool,
 
-    /// Number of retries for failed requests (within the total timeout)
-    #[arg(long, default_value = "2")]
-    pub retries: u32,
+    /// Start a new file at every EXT-X-DISCONTINUITY (ad breaks, encoder restarts) (HLS only)
+    #[arg(long, help_heading = "Output")]
+    pub split_on_discontinuity: bool,
 
-    /// Delay in milliseconds between retry attempts
-    #[arg(long, default_value = "500")]
-    pub retry_delay_ms: u64,
+    /// Append a digest of each completed file to a manifest in the output directory (SHA256SUMS), for verifying archives with sha256sum -c
+    #[arg(long, value_enum, value_name = "ALGO", help_heading = "Output")]
+    pub checksums: Option<ChecksumAlgorithm>,
 
-    /// Playlist poll interval in seconds (for live streams)
-    #[arg(long, default_value = "2")]
-    pub poll_interval: u64,
+    /// fsync the file being written at most this many seconds apart, so a power cut loses no more than that
+    #[arg(long, value_name = "SECS", help_heading = "Output")]
+    pub fsync_interval: Option<u64>,
 
-    /// Maximum consecutive playlist fetch/parse failures before giving up (0 = infinite)
-    #[arg(long, default_value = "2")]
-    pub max_failures: u32,
+    /// fsync each file (and its directory) as it's completed, before --on-segment sees it
+    #[arg(long, help_heading = "Output")]
+    pub fsync_on_rotate: bool,
+
+    /// Open files with O_APPEND | O_DSYNC, so every write is on the disk before the next (slow; for flaky storage)
+    #[arg(long, help_heading = "Output")]
+    pub sync_writes: bool,
+
+    /// Write NAME.json next to each completed file, with its times, size, sequence range, discontinuities and bitrate (not for ffmpeg, RTSP or WHEP)
+    #[arg(long, help_heading = "Output")]
+    pub sidecars: bool,
+
+    /// Remux each completed .ts into a faststart .mp4 (deleting the .ts) so browsers can play it, without ffmpeg (needs --features remux)
+    #[arg(long, help_heading = "Output")]
+    pub remux_mp4: bool,
+
+    /// Save progress to this file and resume from it on restart, so a crash or reboot doesn't duplicate or lose segments (HLS only)
+    #[arg(long, value_name = "PATH", help_heading = "Output")]
+    pub state_file: Option<PathBuf>,
+
+    /// After each rotation, delete all but this many of the newest completed files in the output directory (not for ffmpeg, RTSP or WHEP)
+    #[arg(long, value_name = "N", help_heading = "Output")]
+    pub keep_segments: Option<usize>,
+
+    /// After each rotation, delete completed files in the output directory older than this many hours (not for ffmpeg, RTSP or WHEP)
+    #[arg(long, value_name = "HOURS", help_heading = "Output")]
+    pub keep_hours: Option<u64>,
+
+    /// Event capture: keep this many seconds of segments in memory and only save them, and what follows, when triggered by SIGUSR1, --trigger-file or an --onvif event (HLS only)
+    #[arg(long, value_name = "SECS", help_heading = "Output")]
+    pub pre_roll: Option<u64>,
+
+    /// With --pre-roll, keep recording this many seconds after the last trigger
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value = "30",
+        requires = "pre_roll",
+        help_heading = "Output"
+    )]
+    pub post_roll: u64,
+
+    /// With --pre-roll, trigger a clip when this file appears (it's deleted again), e.g. from a motion detector's hook
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "pre_roll",
+        help_heading = "Output"
+    )]
+    pub trigger_file: Option<PathBuf>,
+
+    /// Subscribe to the camera's ONVIF events at this device service URL (e.g. http://camera/onvif/device_service) and note them in events.jsonl; with --pre-roll, a motion event triggers a clip
+    #[arg(long, value_name = "URL", help_heading = "Output")]
+    pub onvif: Option<String>,
+
+    /// With --onvif, only note events whose topic contains this
+    #[arg(
+        long,
+        value_name = "TEXT",
+        default_value = "Motion",
+        requires = "onvif",
+        help_heading = "Output"
+    )]
+    pub onvif_topic: String,
+
+    /// Check the free space on the output filesystem at each new file, and act on --low-disk below this many MB (HLS and RTSP)
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(..=MAX_MB), help_heading = "Output")]
+    pub min_free_mb: Option<u64>,
+
+    /// What to do when free space drops below --min-free-mb
+    #[arg(
+        long,
+        value_enum,
+        default_value = "stop",
+        requires = "min_free_mb",
+        help_heading = "Output"
+    )]
+    pub low_disk: LowDiskAction,
+
+    /// Save a keyframe from the I-frame playlist every this many seconds, into a thumbnails subdirectory (needs ffmpeg)
+    #[arg(long, value_name = "SECS", help_heading = "Output")]
+    pub thumbnails: Option<u64>,
+
+    /// Image format for --thumbnails
+    #[arg(long, value_enum, default_value = "jpg", help_heading = "Output")]
+    pub thumbnail_format: ImageFormat,
+
+    /// Save a JPEG of each completed RTSP file's first keyframe beside it, as NAME.jpg, for previews (needs ffmpeg, or --snapshot-url)
+    #[arg(long, help_heading = "Output")]
+    pub snapshot: bool,
+
+    /// With --snapshot, fetch the image from the camera's snapshot URL when each file is completed, instead of decoding it
+    #[arg(
+        long,
+        value_name = "URL",
+        requires = "snapshot",
+        help_heading = "Output"
+    )]
+    pub snapshot_url: Option<String>,
+
+    /// Command to run once a recording has started: when its first bytes are written, again after each restart.
+    /// Placeholders: {url} = media URL recorded, {resolution} (WxH) and {bandwidth} (bits/s) of the selected variant, {dir} = output directory
+    #[arg(long, help_heading = "Hooks")]
+    pub on_start: Option<String>,
 
     /// Command to run after each segment file is completed.
     /// Use {} as placeholder for the filename (will be replaced).
     /// Example: --on-segment "ffmpeg -i {} -c copy /archive/{}"
-    #[arg(long)]
+    #[arg(long, help_heading = "Hooks")]
     pub on_segment: Option<String>,
 
+    /// Like --on-segment, but run the program directly instead of through sh: split into arguments like a shell would,
+    /// with {} and {pdt} filled in within each argument, so no file name can be misread.
+    /// Example: --on-segment-exec "rclone move {} remote:archive/"
+    #[arg(long, value_name = "PROGRAM ARGS", value_parser = parse_arg_list, conflicts_with = "on_segment", help_heading = "Hooks")]
+    pub on_segment_exec: Option<ArgList>,
+
+    /// Run a failing --on-segment command up to this many more times before noting the file in failed_hooks.txt
+    #[arg(long, default_value = "0", value_name = "N", help_heading = "Hooks")]
+    pub hook_retries: u32,
+
+    /// Seconds before the first --on-segment retry, doubling for each one after (up to 5 minutes)
+    #[arg(long, default_value = "5", value_name = "SECS", help_heading = "Hooks")]
+    pub hook_retry_delay: u64,
+
     /// Command to run when the program exits.
-    /// Placeholders: %d = output directory (last 2 components), %t = total duration (H:M:S or M:S), %s = total size
+    /// Placeholders: %d = output directory (last 2 components), %t = total duration (H:M:S or M:S), %s = total size,
+    /// %r = why: ended (stream ended or a limit was reached), interrupted (Ctrl+C or SIGTERM) or failed
     /// Example: --on-exit "notify-send 'Recording complete' 'Directory: %d, Duration: %t, Size: %s'"
-    #[arg(long)]
+    #[arg(long, help_heading = "Hooks")]
     pub on_exit: Option<String>,
 
-    /// File extension, ts by default
-    #[arg(long, default_value = "ts")]
-    pub file_extension: String,
+    /// Command to run instead of --on-exit when the recording failed, with the same placeholders
+    #[arg(long, help_heading = "Hooks")]
+    pub on_exit_error: Option<String>,
+
+    /// Command to run when the recorder gives up with an error instead of exiting cleanly (--on-exit doesn't run then).
+    /// Placeholders: {error} = error message (inserted shell-quoted), {failures} = failed attempts in a row, {elapsed} = seconds since start, {dir} = output directory
+    #[arg(long, help_heading = "Hooks")]
+    pub on_error: Option<String>,
+
+    /// Command to run at each ad/program marker (EXT-X-CUE-OUT, EXT-X-CUE-IN, EXT-X-DATERANGE).
+    /// Placeholders: {type} = cue-out, cue-in or daterange, {id}, {duration} (seconds), {time} (RFC 3339), {file} = file being written
+    #[arg(long, help_heading = "Hooks")]
+    pub on_marker: Option<String>,
+
+    /// Command to run when free space drops below --min-free-mb.
+    /// Placeholders: {free} = free space in MB, {dir} = output directory
+    #[arg(long, requires = "min_free_mb", help_heading = "Hooks")]
+    pub on_low_disk: Option<String>,
+
+    /// URL to POST a JSON event to at each start, completed segment, exit and error, retried like other requests
+    #[arg(long, value_name = "URL", help_heading = "Hooks")]
+    pub webhook: Option<String>,
+
+    /// Don't download segments between EXT-X-CUE-OUT and EXT-X-CUE-IN (or the cue-out duration) (HLS only)
+    #[arg(long, help_heading = "Hooks")]
+    pub skip_ads: bool,
+
+    #[command(flatten)]
+    pub variant: VariantArgs,
+
+    #[command(flatten)]
+    pub net: NetworkArgs,
+
+    /// Playlist poll interval in seconds (for live streams)
+    #[arg(long, default_value = "2", help_heading = "Network")]
+    pub poll_interval: u64,
+
+    /// Maximum consecutive playlist fetch/parse failures before giving up (0 = infinite)
+    #[arg(long, default_value = "2", help_heading = "Network")]
+    pub max_failures: u32,
+
+    /// Local address of the network interface to join a udp:// or rtp:// multicast group on
+    #[arg(long, value_name = "ADDR", help_heading = "Network")]
+    pub interface: Option<std::net::IpAddr>,
+
+    /// Segments to download in parallel (written in order)
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..=32), help_heading = "Network")]
+    pub concurrency: u16,
+
+    /// Most MB of segment data fetched ahead of the segment being written, across the --concurrency fetches (default: no limit)
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(..=MAX_MB), help_heading = "Network")]
+    pub max_in_flight_mb: Option<u64>,
 
     /// Force ffmpeg mode (useful for audio streams like MP3)
-    #[clap(long, action)]
+    #[arg(long, help_heading = "Stream format")]
     pub ffmpeg: bool,
 
     /// Skip m3u8 parsing, pass URL directly to ffmpeg (use with --ffmpeg)
-    #[clap(long, action)]
+    #[arg(long, help_heading = "Stream format")]
     pub direct: bool,
 
-    /// Disable HTTPS certificate verification (insecure, use with caution)
-    #[clap(long, action)]
-    pub insecure: bool,
+    /// Download the HLS stream ourselves and pipe it into ffmpeg, so the network options apply
+    /// (implies --ffmpeg)
+    #[arg(long, conflicts_with = "direct", help_heading = "Stream format")]
+    pub ffmpeg_stdin: bool,
+
+    /// The ffmpeg binary to run, for ffmpeg mode and thumbnails
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "STREAM_UTILS_FFMPEG",
+        default_value = "ffmpeg",
+        help_heading = "Stream format"
+    )]
+    pub ffmpeg_path: PathBuf,
+
+    /// Extra ffmpeg input options, quoted as one argument and put before each -i, e.g. "-rw_timeout 5000000"
+    #[arg(long, value_name = "ARGS", value_parser = parse_arg_list, allow_hyphen_values = true, help_heading = "Stream format")]
+    pub ffmpeg_args: Option<ArgList>,
+
+    /// Extra ffmpeg output options, put before the output file, e.g. "-c:a aac"
+    #[arg(long, value_name = "ARGS", value_parser = parse_arg_list, allow_hyphen_values = true, help_heading = "Stream format")]
+    pub ffmpeg_output_args: Option<ArgList>,
+
+    /// Re-encode video with this ffmpeg encoder instead of copying it, e.g. libx264 (implies --ffmpeg)
+    #[arg(long, value_name = "CODEC", help_heading = "Stream format")]
+    pub video_codec: Option<String>,
+
+    /// Re-encode audio with this ffmpeg encoder instead of copying it, e.g. aac (implies --ffmpeg)
+    #[arg(long, value_name = "CODEC", help_heading = "Stream format")]
+    pub audio_codec: Option<String>,
+
+    /// Target video bitrate when re-encoding, e.g. 6M (implies --ffmpeg, and libx264 without --video-codec)
+    #[arg(long, value_name = "RATE", help_heading = "Stream format")]
+    pub video_bitrate: Option<String>,
+
+    /// Scale video to WIDTHxHEIGHT, or to a height keeping the aspect ratio, e.g. 1080
+    /// (implies --ffmpeg, and libx264 without --video-codec)
+    #[arg(long, value_name = "SIZE", value_parser = parse_scale, help_heading = "Stream format")]
+    pub scale: Option<String>,
+
+    /// File format for RTSP recordings
+    #[arg(
+        long,
+        value_enum,
+        default_value = "mp4",
+        help_heading = "Stream format"
+    )]
+    pub container: Container,
+
+    /// Write RTSP recordings as fragmented MP4, which play up to the last fragment if the
+    /// recorder is killed mid-file
+    #[arg(long, help_heading = "Stream format")]
+    pub fragmented_mp4: bool,
+
+    /// Also record the camera's ONVIF metadata stream (analytics objects, events) over RTSP, into NAME.metadata.jsonl beside each file
+    #[arg(long, help_heading = "Stream format")]
+    pub rtsp_metadata: bool,
+
+    /// Separate audio rendition handling
+    #[arg(
+        long,
+        value_enum,
+        default_value = "ignore",
+        help_heading = "Stream format"
+    )]
+    pub audio: AudioMode,
+
+    /// Treat the URL as a WHEP (WebRTC-HTTP egress) endpoint (needs --features whep)
+    #[arg(long, conflicts_with_all = ["ffmpeg", "direct"], help_heading = "Stream format")]
+    pub whep: bool,
+
+    /// Stop cleanly after this much wall-clock time, e.g. 2h30m, 90m or 45s (plain numbers are seconds)
+    #[arg(long, value_name = "TIME", value_parser = parse_duration, help_heading = "Supervision")]
+    pub duration: Option<Duration>,
+
+    /// Stop cleanly once this much has been downloaded, e.g. 4G or 500M (plain numbers are bytes)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, help_heading = "Supervision")]
+    pub max_bytes: Option<u64>,
+
+    /// Restart the whole recording (re-resolving the playlist) after it ends or fails
+    #[arg(long, help_heading = "Supervision")]
+    pub restart_on_exit: bool,
+
+    /// Maximum number of restarts with --restart-on-exit (0 = infinite)
+    #[arg(long, default_value = "0", help_heading = "Supervision")]
+    pub max_restarts: u32,
+
+    /// Initial delay in seconds before restarting (doubles after each consecutive restart)
+    #[arg(long, default_value = "5", help_heading = "Supervision")]
+    pub restart_delay: u64,
+
+    /// Upper bound in seconds for the restart backoff delay
+    #[arg(long, default_value = "300", help_heading = "Supervision")]
+    pub restart_max_delay: u64,
+
+    /// Relaunch ffmpeg up to this many times in a row when it fails, backing off like --restart-on-exit (0 = never)
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "5",
+        help_heading = "Supervision"
+    )]
+    pub ffmpeg_restarts: u32,
+
+    /// Show a live status line (bytes, bitrate, segments, live-edge lag, or percent and ETA for VOD).
+    /// Ignored when stderr isn't a terminal
+    #[arg(long, help_heading = "Diagnostics")]
+    pub progress: bool,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    /// Periodically write status.json (current file, bytes, errors, live-edge lag) to the output directory
+    #[arg(long, help_heading = "Diagnostics")]
+    pub status_file: bool,
 
-    /// Username for RTSP authentication
+    /// Seconds between status.json updates
+    #[arg(long, default_value = "5", help_heading = "Diagnostics")]
+    pub status_interval: u64,
+
+    /// At exit, write a JSON summary (times, bytes, segments, errors, average bitrate and every file written) to this
+    /// file, or - for stdout
+    #[arg(long, value_name = "PATH", help_heading = "Diagnostics")]
+    pub stats_json: Option<PathBuf>,
+
+    /// Resolve the stream and print the files and hook commands a recording would
+    /// produce from the current playlist, without downloading or writing anything
+    #[arg(long, help_heading = "Diagnostics")]
+    pub dry_run: bool,
+
+    /// Fake an error on exit
+    #[arg(long, help_heading = "Diagnostics")]
+    pub fake_exit_err: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ProbeArgs {
+    /// M3U8 URL to inspect, or a local playlist file
+    pub url: String,
+
+    /// Print the report as JSON instead of text
     #[arg(long)]
-    pub username: Option<String>,
+    pub json: bool,
+
+    #[command(flatten)]
+    pub variant: VariantArgs,
+
+    #[command(flatten)]
+    pub net: NetworkArgs,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ConvertArgs {
+    /// Files to merge, in order, or a recording directory (uses its most recent session)
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Output file; the container follows the extension (.mp4, .mkv, .m4v, .mov)
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Session to merge when an input is a directory, as the file name prefix (e.g. 2026_02_02-14_30)
+    #[arg(long)]
+    pub session: Option<String>,
+
+    /// Don't add a chapter per input file
+    #[arg(long)]
+    pub no_chapters: bool,
+
+    /// Replace the output file if it exists
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// The ffmpeg binary to run
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "STREAM_UTILS_FFMPEG",
+        default_value = "ffmpeg"
+    )]
+    pub ffmpeg_path: PathBuf,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CleanArgs {
+    /// Recording directory to prune
+    pub dir: PathBuf,
+
+    /// Delete recordings older than this many hours
+    #[arg(long)]
+    pub keep_hours: Option<u64>,
 
-    /// Password for RTSP authentication
+    /// Keep at most this many recording files (newest first)
     #[arg(long)]
+    pub keep_files: Option<usize>,
+
+    /// Keep at most this many MB of recordings (newest first)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(..=MAX_MB))]
+    pub keep_mb: Option<u64>,
+
+    /// The --filename-template the recordings were made with, so files it
+    /// named (in any subdirectories it uses) are found. {stream} matches any
+    /// stream name
+    #[arg(long, value_name = "TEMPLATE")]
+    pub filename_template: Option<FilenameTemplate>,
+
+    /// Print what would be deleted without deleting anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DaemonArgs {
+    /// TOML file with a [[job]] table for each recording: a name, a url and
+    /// any `record` options, over top-level options shared by every job
+    #[arg(required_unless_present = "listen")]
+    pub jobs: Option<PathBuf>,
+
+    /// Serve the control API on this address, e.g. 127.0.0.1:8780, to list,
+    /// start and stop jobs over HTTP. Anything but a loopback address needs
+    /// --api-token
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Option<SocketAddr>,
+
+    /// Require this token on every control API request, as
+    /// `Authorization: Bearer TOKEN`
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "STREAM_UTILS_API_TOKEN",
+        hide_env_values = true,
+        requires = "listen"
+    )]
+    pub api_token: Option<String>,
+
+    /// Keep this JSON file updated with every job's state, restarts, last
+    /// error and bytes
+    #[arg(long, value_name = "FILE")]
+    pub status_file: Option<PathBuf>,
+
+    /// Seconds between status file updates
+    #[arg(long, default_value = "5")]
+    pub status_interval: u64,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+/// Image format for --thumbnails
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ImageFormat {
+    Jpg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// File format for RTSP recordings
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    /// Matroska, which plays up to where it was cut off
+    Mkv,
+}
+
+impl Container {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+        }
+    }
+}
+
+/// HTTP options shared by the commands that fetch playlists
+#[derive(clap::Args, Debug, Clone)]
+pub struct NetworkArgs {
+    /// Total timeout in seconds for a fetch operation (across all retries)
+    #[arg(long, default_value = "15", help_heading = "Network")]
+    pub timeout: u64,
+
+    /// Timeout in seconds for each attempt, so a hung request leaves time for retries (default: the whole --timeout)
+    #[arg(long, value_name = "SECS", help_heading = "Network")]
+    pub request_timeout: Option<u64>,
+
+    /// Number of retries for failed requests (within the total timeout)
+    #[arg(long, default_value = "2", help_heading = "Network")]
+    pub retries: u32,
+
+    /// Delay in milliseconds between retry attempts
+    #[arg(long, default_value = "500", help_heading = "Network")]
+    pub retry_delay_ms: u64,
+
+    /// Disable HTTPS and RTSPS certificate verification (insecure, use with caution)
+    #[arg(long, help_heading = "Network")]
+    pub insecure: bool,
+
+    /// Also trust the CA certificates in this PEM file, e.g. a camera's self-signed one (HTTPS and RTSPS)
+    #[arg(long, value_name = "FILE", help_heading = "Network")]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Redirects to follow per request (0 to treat a redirect as an error)
+    #[arg(long, default_value = "10", help_heading = "Network")]
+    pub max_redirects: usize,
+
+    /// Connect to ADDR for HOST instead of looking it up, as HOST:PORT:ADDR[,ADDR...] like curl (repeatable)
+    #[arg(long, value_name = "HOST:PORT:ADDR", value_parser = parse_resolve, help_heading = "Network")]
+    pub resolve: Vec<(String, Vec<IpAddr>)>,
+
+    /// Only connect over IPv4
+    #[arg(long, short = '4', conflicts_with = "ipv6", help_heading = "Network")]
+    pub ipv4: bool,
+
+    /// Only connect over IPv6
+    #[arg(long, short = '6', help_heading = "Network")]
+    pub ipv6: bool,
+
+    /// Don't offer HTTP/2, for servers whose HTTP/2 support is broken
+    #[arg(long, help_heading = "Network")]
+    pub http1_only: bool,
+
+    /// Extra request header as "Name: value", e.g. a Referer the origin checks (repeatable)
+    #[arg(long = "header", short = 'H', value_name = "HEADER", value_parser = parse_header, help_heading = "Network")]
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Username for HTTP Basic and RTSP authentication
+    #[arg(long, help_heading = "Authentication")]
+    pub username: Option<String>,
+
+    /// Password for HTTP Basic and RTSP authentication
+    #[arg(long, help_heading = "Authentication")]
     pub password: Option<String>,
+
+    /// Bearer token for HTTP requests and the WHEP endpoint
+    #[arg(long, conflicts_with = "username", help_heading = "Authentication")]
+    pub bearer_token: Option<String>,
+
+    /// Client certificate for mutual TLS: a PEM file with --client-key, or a PKCS#12 (.p12/.pfx) bundle on its own
+    #[arg(long, value_name = "FILE", help_heading = "Authentication")]
+    pub client_cert: Option<PathBuf>,
+
+    /// PKCS#8 PEM private key for --client-cert
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "client_cert",
+        help_heading = "Authentication"
+    )]
+    pub client_key: Option<PathBuf>,
+
+    /// Password of a PKCS#12 --client-cert bundle
+    #[arg(
+        long,
+        requires = "client_cert",
+        conflicts_with = "client_key",
+        help_heading = "Authentication"
+    )]
+    pub client_cert_password: Option<String>,
+}
+
+/// `host:port:address[,address...]`, returning the host and its addresses.
+/// The port is required as in curl, but an override applies to any port.
+fn parse_resolve(s: &str) -> Result<(String, Vec<IpAddr>), String> {
+    let mut parts = s.splitn(3, ':');
+    let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("expected HOST:PORT:ADDR, got {s:?}"));
+    };
+    if host.is_empty() {
+        return Err(format!("missing host in {s:?}"));
+    }
+    port.parse::<u16>()
+        .map_err(|_| format!("invalid port {port:?}"))?;
+    let addrs = addrs
+        .split(',')
+        .map(|addr| {
+            let addr = addr.trim().trim_start_matches('[').trim_end_matches(']');
+            addr.parse()
+                .map_err(|_| format!("invalid IP address {addr:?}"))
+        })
+        .collect::<Result<Vec<IpAddr>, String>>()?;
+    Ok((host.to_ascii_lowercase(), addrs))
+}
+
+/// A duration like `2h30m`, `1d`, `90m` or `45s`, or plain seconds. One too
+/// long to count down from now is refused rather than overflowing.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let too_long = || format!("{s:?} is too long");
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(format!("unknown unit {c:?} in {s:?} (use d, h, m, s)")),
+        };
+        if number.is_empty() {
+            return Err(format!("missing number before {c:?} in {s:?}"));
+        }
+        secs = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|value| value.checked_mul(unit))
+            .and_then(|value| secs.checked_add(value))
+            .ok_or_else(too_long)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        if number.len() < s.len() {
+            return Err(format!("missing unit after {number:?} in {s:?}"));
+        }
+        secs = number.parse().map_err(|_| too_long())?;
+    }
+    let duration = Duration::from_secs(secs);
+    std::time::Instant::now()
+        .checked_add(duration)
+        .ok_or_else(too_long)?;
+    Ok(duration)
+}
+
+/// The most MB an option can take and still be a number of bytes
+const MAX_MB: u64 = u64::MAX >> 20;
+
+/// A size like `4G`, `500M`, `1.5G` or `100K` (powers of 1024), or plain bytes
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let upper = s.to_ascii_uppercase();
+    let digits = upper.trim_end_matches('B');
+    let (number, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1u64 << 10),
+        Some('M') => (&digits[..digits.len() - 1], 1 << 20),
+        Some('G') => (&digits[..digits.len() - 1], 1 << 30),
+        Some('T') => (&digits[..digits.len() - 1], 1 << 40),
+        _ => (digits, 1),
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size {s:?} (e.g. 4G, 500M or bytes)"))?;
+    let bytes = value * multiplier as f64;
+    // u64::MAX rounds up to 2^64, which is already too big
+    if !bytes.is_finite() || bytes < 0.0 || bytes >= u64::MAX as f64 {
+        return Err(format!("invalid size {s:?}"));
+    }
+    Ok(bytes as u64)
+}
+
+/// `parse_size` for --segment-bytes, where 0 would rotate on every write
+fn parse_segment_size(s: &str) -> Result<u64, String> {
+    match parse_size(s)? {
+        0 => Err("a segment has to be at least 1 byte".to_string()),
+        bytes => Ok(bytes),
+    }
+}
+
+/// A list of arguments (for ffmpeg, or an --on-segment-exec program) from
+/// one command-line argument, split like a shell would: on whitespace,
+/// except inside quotes or after a backslash
+#[derive(Debug, Clone, Default)]
+pub struct ArgList(pub Vec<String>);
+
+fn parse_arg_list(s: &str) -> Result<ArgList, String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => current.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let escaped = chars.next().ok_or("trailing backslash")?;
+                current.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(q) = quote {
+        return Err(format!("unterminated {q} quote"));
+    }
+    args.extend(current);
+    Ok(ArgList(args))
+}
+
+/// A --scale size as ffmpeg's scale filter takes it: `1280x720` becomes
+/// `1280:720`, and a height alone (`1080` or `1080p`) keeps the aspect
+/// ratio with an even width
+fn parse_scale(s: &str) -> Result<String, String> {
+    let size = |n: &str| {
+        n.parse::<u32>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("invalid size {s:?}, expected e.g. 1280x720 or 720"))
+    };
+    match s.split_once(['x', 'X']) {
+        Some((width, height)) => Ok(format!("{}:{}", size(width)?, size(height)?)),
+        None => Ok(format!("-2:{}", size(s.strip_suffix('p').unwrap_or(s))?)),
+    }
+}
+
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Name: value\", got {s:?}"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", name.trim()))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value for header {name}"))?;
+    Ok((name, value))
+}
+
+/// Logging options shared by every subcommand
+#[derive(clap::Args, Debug, Clone)]
+pub struct LogArgs {
+    /// More detailed logs: -v for each segment and file, -vv also for each playlist refresh
+    /// and HTTP request, -vvv also for the libraries underneath
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet", help_heading = "Diagnostics")]
+    pub verbose: u8,
+
+    /// Only log the error that stops the recording, and suppress progress output (for cron
+    /// and other unattended runs)
+    #[arg(short, long, help_heading = "Diagnostics")]
+    pub quiet: bool,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value = "text", help_heading = "Diagnostics")]
+    pub log_format: LogFormat,
+
+    /// Log level filter with optional per-module directives, overriding -v/-q and RUST_LOG.
+    /// Example: --log-filter "warn,stream_utils::downloader=debug"
+    #[arg(long, help_heading = "Diagnostics")]
+    pub log_filter: Option<String>,
+
+    /// Also write logs to this file (rotated independently of the media segments)
+    #[arg(long, help_heading = "Diagnostics")]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it reaches this size in MB (0 = no size limit)
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(..=MAX_MB), help_heading = "Diagnostics")]
+    pub log_max_size: u64,
+
+    /// Rotate the log file after this many seconds (0 = no time limit)
+    #[arg(long, default_value = "0", help_heading = "Diagnostics")]
+    pub log_rotate_secs: u64,
+
+    /// Number of rotated log files to keep (NAME.1 is the newest)
+    #[arg(long, default_value = "5", help_heading = "Diagnostics")]
+    pub log_keep: u32,
+
+    /// Export trace spans (playlist polls, segment fetches and writes, hooks) to this
+    /// OTLP/gRPC collector, e.g. http://localhost:4317. Requires the otel feature
+    #[arg(long, help_heading = "Diagnostics")]
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Write a completion script for `shell` to stdout
+pub fn print_completions(shell: clap_complete::Shell) {
+    let mut cmd = Cli::command_with_env();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+}
+
+/// Write the roff man page to stdout
+pub fn print_manpage() -> std::io::Result<()> {
+    clap_mangen::Man::new(Cli::command_with_env()).render(&mut std::io::stdout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ffmpeg_path_from_env() {
+        std::env::set_var("STREAM_UTILS_FFMPEG", "/opt/ffmpeg6/bin/ffmpeg");
+        let record = Cli::try_parse_with_default(
+            ["m3u8-dl", "https://example.com/live.m3u8"].map(Into::into),
+        );
+        let convert = Cli::try_parse_with_default(
+            ["m3u8-dl", "convert", "in.ts", "-o", "out.mp4"].map(Into::into),
+        );
+        std::env::remove_var("STREAM_UTILS_FFMPEG");
+        let Command::Record(args) = record.unwrap().command else {
+            unreachable!()
+        };
+        assert_eq!(
+            args.ffmpeg_path,
+            std::path::Path::new("/opt/ffmpeg6/bin/ffmpeg")
+        );
+        let Command::Convert(args) = convert.unwrap().command else {
+            unreachable!()
+        };
+        assert_eq!(
+            args.ffmpeg_path,
+            std::path::Path::new("/opt/ffmpeg6/bin/ffmpeg")
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4G"), Ok(4 << 30));
+        assert_eq!(parse_size("1.5k"), Ok(1536));
+        assert_eq!(parse_size("500MB"), Ok(500 << 20));
+        assert_eq!(parse_size("0"), Ok(0));
+        for bad in [
+            "inf",
+            "NaN",
+            "-1M",
+            "1e30G",
+            "16777216T",
+            "18446744073709551616",
+            "G",
+        ] {
+            assert!(parse_size(bad).is_err(), "{bad}");
+        }
+        assert!(parse_segment_size("0").is_err());
+        assert_eq!(parse_segment_size("1"), Ok(1));
+    }
+
+    #[test]
+    fn mb_options_fit_in_bytes() {
+        let parse = |argv: &[&str]| {
+            let argv = ["m3u8-dl"].iter().chain(argv).map(Into::into);
+            Cli::try_parse_with_default(argv).is_ok()
+        };
+        let url = "https://example.com/live.m3u8";
+        let (max, over) = (MAX_MB.to_string(), (MAX_MB + 1).to_string());
+        assert!(parse(&[url, "--log-max-size", &max]));
+        assert!(!parse(&[url, "--log-max-size", &over]));
+        assert!(parse(&[url, "--max-in-flight-mb", &max]));
+        assert!(!parse(&[url, "--max-in-flight-mb", &over]));
+        assert!(parse(&[url, "--min-free-mb", &max]));
+        assert!(!parse(&[url, "--min-free-mb", &over]));
+        assert!(parse(&["clean", "recordings", "--keep-mb", &max]));
+        assert!(!parse(&["clean", "recordings", "--keep-mb", &over]));
+    }
+
+    #[test]
+    fn version_flags() {
+        for flag in ["-V", "--version"] {
+            let err = Cli::try_parse_with_default(["m3u8-dl", flag].map(Into::into)).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::DisplayVersion);
+        }
+    }
 }
@@ -1,20 +1,545 @@
-use std::path::PathBuf;
+use crate::cli::Args;
+use crate::error::StreamError;
+use crate::http_client::redact_password;
+use crate::output::CompletedFile;
+use crate::playlist::Marker;
+use crate::stats::RecordingStats;
+use crate::webhook::Webhook;
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, info_span, warn, Instrument};
 
-pub fn run_segment_command(cmd_template: &str, filepath: &PathBuf, verbose: bool) {
+/// Hook commands, shared with the recorder so SIGHUP can swap in new ones
+/// from the config file without interrupting the capture
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    pub on_start: Option<String>,
+    pub on_segment: Option<HookCommand>,
+    pub on_exit: Option<String>,
+    pub on_exit_error: Option<String>,
+    pub on_error: Option<String>,
+    pub on_marker: Option<String>,
+    pub on_low_disk: Option<String>,
+    pub webhook: Option<Arc<Webhook>>,
+    /// The URL being recorded, password masked, for STREAMUTILS_URL
+    pub url: String,
+    pub segment_retry: HookRetry,
+    /// Set by a program embedding the recorder rather than by options
+    pub callbacks: Callbacks,
+}
+
+/// A file the recorder finished, as passed to an on_segment callback
+#[derive(Clone, Debug)]
+pub struct SegmentEvent {
+    pub path: PathBuf,
+    /// Its size when it was completed
+    pub bytes: u64,
+    /// When it starts, if the playlist says
+    pub program_date_time: Option<DateTime<FixedOffset>>,
+}
+
+pub type SegmentCallback = Arc<dyn Fn(&SegmentEvent) + Send + Sync>;
+pub type ErrorCallback = Arc<dyn Fn(&StreamError) + Send + Sync>;
+
+/// Functions to call alongside the hook commands, for `Recorder`
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    pub on_segment: Option<SegmentCallback>,
+    /// Called each time recording fails, restarts included
+    pub on_error: Option<ErrorCallback>,
+}
+
+impl std::fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("on_segment", &self.on_segment.is_some())
+            .field("on_error", &self.on_error.is_some())
+            .finish()
+    }
+}
+
+/// Who is told about a completed file before the on-segment hook runs: the
+/// webhook and the on_segment callback
+#[derive(Clone, Default)]
+pub struct SegmentListeners {
+    webhook: Option<Arc<Webhook>>,
+    callback: Option<SegmentCallback>,
+}
+
+impl SegmentListeners {
+    pub fn is_empty(&self) -> bool {
+        self.webhook.is_none() && self.callback.is_none()
+    }
+
+    /// Send the webhook's segment event and call the callback for a
+    /// completed file, before the on-segment hook gets a chance to move it
+    pub fn send(&self, path: &Path, program_date_time: Option<&DateTime<FixedOffset>>) {
+        if self.is_empty() {
+            return;
+        }
+        let bytes = std::fs::metadata(path).map_or(0, |m| m.len());
+        if let Some(webhook) = &self.webhook {
+            webhook.send(
+                "segment",
+                serde_json::json!({
+                    "file": path,
+                    "bytes": bytes,
+                    "pdt": program_date_time.map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                }),
+            );
+        }
+        if let Some(callback) = &self.callback {
+            callback(&SegmentEvent {
+                path: path.to_path_buf(),
+                bytes,
+                program_date_time: program_date_time.cloned(),
+            });
+        }
+    }
+}
+
+/// How a failing on-segment command is run again: up to `retries` more
+/// times, waiting `delay` and then twice as long each time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HookRetry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+/// The longest wait between on-segment retries
+const MAX_HOOK_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Where on-segment commands that kept failing are noted, one file per
+/// line, in the directory of the file
+pub const FAILED_HOOKS_FILE: &str = "failed_hooks.txt";
+
+/// A hook command: a line for `sh -c`, or a program and its arguments run
+/// directly (--on-segment-exec), with the placeholders filled in within
+/// each argument
+#[derive(Clone, Debug)]
+pub enum HookCommand {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+/// The on-segment command from --on-segment-exec or --on-segment
+pub fn segment_command(args: &Args) -> Option<HookCommand> {
+    match (&args.on_segment_exec, &args.on_segment) {
+        (Some(exec), _) if !exec.0.is_empty() => Some(HookCommand::Exec(exec.0.clone())),
+        (_, Some(cmd)) => Some(HookCommand::Shell(cmd.clone())),
+        _ => None,
+    }
+}
+
+/// Everything needed to run the on-segment command for a file
+#[derive(Clone, Debug)]
+pub struct SegmentHook {
+    pub cmd: HookCommand,
+    pub url: String,
+    pub retry: HookRetry,
+}
+
+pub type SharedHooks = Arc<RwLock<Hooks>>;
+
+impl Hooks {
+    pub fn from_args(args: &Args) -> Result<Self, StreamError> {
+        let webhook = match &args.webhook {
+            Some(url) => Some(Arc::new(Webhook::new(url, &args.net)?)),
+            None => None,
+        };
+        Ok(Self {
+            on_start: args.on_start.clone(),
+            on_segment: segment_command(args),
+            on_exit: args.on_exit.clone(),
+            on_exit_error: args.on_exit_error.clone(),
+            on_error: args.on_error.clone(),
+            on_marker: args.on_marker.clone(),
+            on_low_disk: args.on_low_disk.clone(),
+            webhook,
+            url: redact_password(&args.url),
+            segment_retry: HookRetry {
+                retries: args.hook_retries,
+                delay: Duration::from_secs(args.hook_retry_delay),
+            },
+            callbacks: Callbacks::default(),
+        })
+    }
+
+    pub fn url(hooks: &SharedHooks) -> String {
+        hooks.read().unwrap().url.clone()
+    }
+
+    pub fn on_start(hooks: &SharedHooks) -> Option<String> {
+        hooks.read().unwrap().on_start.clone()
+    }
+
+    pub fn on_segment(hooks: &SharedHooks) -> Option<SegmentHook> {
+        let hooks = hooks.read().unwrap();
+        let cmd = hooks.on_segment.clone()?;
+        Some(SegmentHook {
+            cmd,
+            url: hooks.url.clone(),
+            retry: hooks.segment_retry,
+        })
+    }
+
+    pub fn on_marker(hooks: &SharedHooks) -> Option<String> {
+        hooks.read().unwrap().on_marker.clone()
+    }
+
+    pub fn on_low_disk(hooks: &SharedHooks) -> Option<String> {
+        hooks.read().unwrap().on_low_disk.clone()
+    }
+
+    pub fn webhook(hooks: &SharedHooks) -> Option<Arc<Webhook>> {
+        hooks.read().unwrap().webhook.clone()
+    }
+
+    pub fn segment_listeners(hooks: &SharedHooks) -> SegmentListeners {
+        let hooks = hooks.read().unwrap();
+        SegmentListeners {
+            webhook: hooks.webhook.clone(),
+            callback: hooks.callbacks.on_segment.clone(),
+        }
+    }
+
+    /// Call the on_error callback, if any, for a failed attempt
+    pub fn report_error(hooks: &SharedHooks, error: &StreamError) {
+        let callback = hooks.read().unwrap().callbacks.on_error.clone();
+        if let Some(callback) = callback {
+            callback(error);
+        }
+    }
+}
+
+/// What the on-start hook is told about a recording: the media URL it
+/// resolved to, and the variant picked from a master playlist
+#[derive(Clone, Debug, Default)]
+pub struct StartInfo {
+    pub url: String,
+    pub resolution: Option<(u64, u64)>,
+    pub bandwidth: Option<u64>,
+}
+
+/// `sh -c cmd` for a hook. Its values are also exported as STREAMUTILS_*
+/// environment variables, along with the event and the recording's URL,
+/// since a variable can't break the command however odd the file name.
+pub fn shell(cmd: &str, event: &str, url: &str, env: &[(&str, String)]) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    hook_env(&mut command, event, url, env);
+    command
+}
+
+/// A program run directly for a hook, with the same environment as `shell`
+fn exec(argv: &[String], event: &str, url: &str, env: &[(&str, String)]) -> Command {
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    hook_env(&mut command, event, url, env);
+    command
+}
+
+fn hook_env(command: &mut Command, event: &str, url: &str, env: &[(&str, String)]) {
+    command
+        .env("STREAMUTILS_EVENT", event)
+        .env("STREAMUTILS_URL", url);
+    for (name, value) in env {
+        command.env(format!("STREAMUTILS_{name}"), value);
+    }
+}
+
+/// The environment of the on-segment hook
+pub fn segment_env(
+    filepath: &Path,
+    program_date_time: Option<&DateTime<FixedOffset>>,
+) -> Vec<(&'static str, String)> {
+    let bytes = std::fs::metadata(filepath).map_or(0, |m| m.len());
+    let pdt = program_date_time
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_default();
+    vec![
+        ("FILE", filepath.to_string_lossy().into_owned()),
+        ("BYTES", bytes.to_string()),
+        ("PDT", pdt),
+    ]
+}
+
+/// Fill in the on-start placeholders: `{url}` = the media URL being
+/// recorded, `{resolution}` (WxH) and `{bandwidth}` (bits/s) of the variant,
+/// empty without a master playlist, and `{dir}` = the output directory
+pub fn expand_start_command(cmd_template: &str, info: &StartInfo, dir: &Path) -> String {
+    let resolution = info
+        .resolution
+        .map(|(w, h)| format!("{w}x{h}"))
+        .unwrap_or_default();
+    let bandwidth = info.bandwidth.map(|b| b.to_string()).unwrap_or_default();
+    cmd_template
+        .replace("{url}", &info.url)
+        .replace("{resolution}", &resolution)
+        .replace("{bandwidth}", &bandwidth)
+        .replace("{dir}", &dir.to_string_lossy())
+}
+
+/// The on-start hook (and webhook event) of one recording attempt, waiting
+/// for its first bytes. It stops waiting when this is dropped, so an attempt
+/// that fails before writing anything never fires it.
+pub struct StartHook(tokio::task::JoinHandle<()>);
+
+impl Drop for StartHook {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl StartHook {
+    /// Run the on-start command and send the start event once `stats`
+    /// counts more bytes than it does now, with `info` as it stands by then.
+    /// None when there's neither.
+    pub fn spawn(
+        hooks: &SharedHooks,
+        info: Arc<Mutex<StartInfo>>,
+        dir: PathBuf,
+        stats: Arc<RecordingStats>,
+    ) -> Option<Self> {
+        let cmd_template = Hooks::on_start(hooks);
+        let webhook = Hooks::webhook(hooks);
+        if cmd_template.is_none() && webhook.is_none() {
+            return None;
+        }
+        let span = info_span!("hook", hook = "on_start");
+        let before = stats.bytes();
+        Some(Self(tokio::spawn(async move {
+            while stats.bytes() == before {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+            }
+            let info = info.lock().unwrap().clone();
+            if let Some(webhook) = webhook {
+                webhook.send(
+                    "start",
+                    serde_json::json!({
+                        "url": info.url,
+                        "resolution": info.resolution.map(|(w, h)| format!("{w}x{h}")),
+                        "bandwidth": info.bandwidth,
+                        "dir": dir,
+                    }),
+                );
+            }
+            let Some(cmd_template) = cmd_template else {
+                return;
+            };
+            let cmd = expand_start_command(&cmd_template, &info, &dir);
+            let resolution = info
+                .resolution
+                .map(|(w, h)| format!("{w}x{h}"))
+                .unwrap_or_default();
+            let env = [
+                ("RESOLUTION", resolution),
+                (
+                    "BANDWIDTH",
+                    info.bandwidth.map(|b| b.to_string()).unwrap_or_default(),
+                ),
+                ("DIR", dir.to_string_lossy().into_owned()),
+            ];
+            let _ = tokio::task::spawn_blocking(move || {
+                let _span = span.entered();
+                debug!("Running: {cmd}");
+                match shell(&cmd, "start", &info.url, &env).status() {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => warn!("Start command exited with: {status}"),
+                    Err(e) => warn!("Failed to run start command: {e}"),
+                }
+            })
+            .await;
+        })))
+    }
+}
+
+/// Fill in the on-segment placeholders: `{}` = file path, `{pdt}` = the
+/// EXT-X-PROGRAM-DATE-TIME of the file's first segment (RFC 3339, empty if
+/// the playlist has none)
+pub fn expand_segment_command(
+    cmd_template: &str,
+    filepath: &Path,
+    program_date_time: Option<&DateTime<FixedOffset>>,
+) -> String {
+    let pdt = program_date_time
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_default();
+    cmd_template
+        .replace("{pdt}", &pdt)
+        .replace("{}", &filepath.to_string_lossy())
+}
+
+/// Run the on-segment command for a file, retrying it as configured. A file
+/// whose command never succeeds is added to `failed_hooks.txt` beside it,
+/// as is one whose retries are abandoned when the recorder exits.
+pub async fn run_segment_command(
+    hook: &SegmentHook,
+    filepath: &Path,
+    program_date_time: Option<&DateTime<FixedOffset>>,
+) {
     let filename = filepath.to_string_lossy();
-    let cmd = cmd_template.replace("{}", &filename);
+    let env = segment_env(filepath, program_date_time);
+    let (cmd, command) = match &hook.cmd {
+        HookCommand::Shell(template) => {
+            let cmd = expand_segment_command(template, filepath, program_date_time);
+            let command = shell(&cmd, "segment", &hook.url, &env);
+            (cmd, command)
+        }
+        HookCommand::Exec(template) => {
+            let argv = expand_segment_argv(template, filepath, program_date_time);
+            (format!("{argv:?}"), exec(&argv, "segment", &hook.url, &env))
+        }
+    };
+    let mut command = tokio::process::Command::from(command);
+    let span = info_span!("hook", hook = "on_segment", segment = %filename);
 
-    if verbose {
-        eprintln!("Running: {cmd}");
+    async {
+        let mut unfinished = Unfinished(Some(filepath));
+        let mut delay = hook.retry.delay;
+        for attempt in 0..=hook.retry.retries {
+            if attempt > 0 {
+                info!(
+                    segment = %filename,
+                    "Retrying the command in {}s ({attempt}/{})",
+                    delay.as_secs(),
+                    hook.retry.retries
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_HOOK_RETRY_DELAY);
+            }
+            debug!(segment = %filename, "Running: {cmd}");
+            match command.status().await {
+                Ok(status) if status.success() => {
+                    unfinished.0 = None;
+                    return;
+                }
+                Ok(status) => warn!(segment = %filename, "Command exited with: {status}"),
+                Err(e) => warn!(segment = %filename, "Failed to run command: {e}"),
+            }
+        }
     }
-    match Command::new("sh").arg("-c").arg(&cmd).status() {
-        Ok(status) if status.success() => {}
-        Ok(status) => eprintln!("Command exited with: {status}"),
-        Err(e) => eprintln!("Failed to run command: {e}"),
+    .instrument(span)
+    .await
+}
+
+/// An on-segment command that hasn't succeeded yet. Its file is noted in
+/// `failed_hooks.txt` when this is dropped without being cleared, whether
+/// the retries ran out or were abandoned.
+struct Unfinished<'a>(Option<&'a Path>);
+
+impl Drop for Unfinished<'_> {
+    fn drop(&mut self) {
+        if let Some(filepath) = self.0 {
+            note_failed_hook(filepath);
+        }
+    }
+}
+
+/// Append `filepath` to the failed_hooks.txt in its directory, for a later
+/// sweep to pick up
+fn note_failed_hook(filepath: &Path) {
+    let list = filepath
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(FAILED_HOOKS_FILE);
+    let result = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&list)
+        .and_then(|mut file| {
+            use std::io::Write;
+            writeln!(file, "{}", filepath.display())
+        });
+    match result {
+        Ok(()) => warn!(list = %list.display(), "Gave up on the command, noted the file"),
+        Err(e) => warn!(list = %list.display(), "Can't note the failed command: {e}"),
+    }
+}
+
+/// The on-segment command as it would run for `filepath`, for --dry-run
+pub fn describe_segment_command(cmd: &HookCommand, filepath: &Path) -> String {
+    match cmd {
+        HookCommand::Shell(template) => expand_segment_command(template, filepath, None),
+        HookCommand::Exec(template) => {
+            format!("{:?}", expand_segment_argv(template, filepath, None))
+        }
     }
 }
 
+/// --on-segment-exec's arguments with the placeholders filled in, each on
+/// its own
+fn expand_segment_argv(
+    template: &[String],
+    filepath: &Path,
+    program_date_time: Option<&DateTime<FixedOffset>>,
+) -> Vec<String> {
+    template
+        .iter()
+        .map(|arg| expand_segment_command(arg, filepath, program_date_time))
+        .collect()
+}
+
+/// Fill in the on-marker placeholders: `{type}` (cue-out, cue-in or
+/// daterange), `{id}`, `{duration}` in seconds, `{time}` (RFC 3339) and
+/// `{file}`, the output file being written. Unknown values are empty.
+pub fn expand_marker_command(
+    cmd_template: &str,
+    marker: &Marker,
+    time: &DateTime<FixedOffset>,
+    file: &Path,
+) -> String {
+    cmd_template
+        .replace("{type}", marker.kind.as_str())
+        .replace("{id}", marker.id.as_deref().unwrap_or(""))
+        .replace(
+            "{duration}",
+            &marker
+                .duration_secs
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+        )
+        .replace("{time}", &time.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .replace("{file}", &file.to_string_lossy())
+}
+
+/// Run the on-marker hook in the background
+pub fn run_marker_command_async(
+    cmd_template: &str,
+    marker: &Marker,
+    time: &DateTime<FixedOffset>,
+    file: &Path,
+    url: String,
+) -> tokio::task::JoinHandle<()> {
+    let cmd = expand_marker_command(cmd_template, marker, time, file);
+    let env = [
+        ("MARKER_TYPE", marker.kind.as_str().to_string()),
+        ("MARKER_ID", marker.id.clone().unwrap_or_default()),
+        (
+            "MARKER_DURATION",
+            marker
+                .duration_secs
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+        ),
+        ("TIME", time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        ("FILE", file.to_string_lossy().into_owned()),
+    ];
+    let span = info_span!("hook", hook = "on_marker", marker = marker.kind.as_str());
+    tokio::task::spawn_blocking(move || {
+        let _span = span.entered();
+        debug!("Running: {cmd}");
+        match shell(&cmd, "marker", &url, &env).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Marker command exited with: {status}"),
+            Err(e) => warn!("Failed to run marker command: {e}"),
+        }
+    })
+}
+
 pub fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
@@ -31,13 +556,48 @@ pub fn format_bytes(bytes: u64) -> String {
     }
 }
 
-pub fn run_exit_command(
+/// Format a duration as H:M:S (or M:S if < 60 minutes)
+pub fn format_duration(secs: u64) -> String {
+    let hours = secs / 3600;
+    let minutes = (secs % 3600) / 60;
+    let seconds = secs % 60;
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Why the recorder exited, for on-exit's `%r`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The stream ended, or --duration, --max-bytes or low disk space stopped it
+    Ended,
+    /// Ctrl+C or another signal stopped it
+    Interrupted,
+    /// It gave up with an error
+    Failed,
+}
+
+impl ExitReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExitReason::Ended => "ended",
+            ExitReason::Interrupted => "interrupted",
+            ExitReason::Failed => "failed",
+        }
+    }
+}
+
+/// Fill in the on-exit placeholders (%d, %t, %s, %b, %m, %r)
+pub fn expand_exit_command(
     cmd_template: &str,
+    reason: ExitReason,
     duration_secs: u64,
     total_bytes: u64,
-    output_dir: &PathBuf,
-    verbose: bool,
-) {
+    output_dir: &Path,
+) -> String {
     // Get last two path components for %d placeholder
     let dir_str = {
         let components: Vec<_> = output_dir.components().collect();
@@ -55,45 +615,189 @@ pub fn run_exit_command(
         }
     };
 
-    // Format duration as H:M:S (or M:S if < 60 minutes)
-    let duration_str = {
-        let hours = duration_secs / 3600;
-        let minutes = (duration_secs % 3600) / 60;
-        let seconds = duration_secs % 60;
-
-        if hours > 0 {
-            format!("{}:{:02}:{:02}", hours, minutes, seconds)
-        } else {
-            format!("{}:{:02}", minutes, seconds)
-        }
-    };
-
+    let duration_str = format_duration(duration_secs);
     let size_str = format_bytes(total_bytes);
 
-    let cmd = cmd_template
+    cmd_template
         .replace("%d", &dir_str)
         .replace("%t", &duration_str)
         .replace("%s", &size_str)
         .replace("%b", &total_bytes.to_string())
-        .replace("%m", &(total_bytes / 1024 / 1024).to_string());
+        .replace("%m", &(total_bytes / 1024 / 1024).to_string())
+        .replace("%r", reason.as_str())
+}
 
-    if verbose {
-        eprintln!("Running exit command: {cmd}");
+pub fn run_exit_command(
+    cmd_template: &str,
+    reason: ExitReason,
+    duration_secs: u64,
+    total_bytes: u64,
+    output_dir: &Path,
+    url: &str,
+) {
+    let cmd = expand_exit_command(cmd_template, reason, duration_secs, total_bytes, output_dir);
+    let env = [
+        ("REASON", reason.as_str().to_string()),
+        ("DURATION", duration_secs.to_string()),
+        ("BYTES", total_bytes.to_string()),
+        ("DIR", output_dir.to_string_lossy().into_owned()),
+    ];
+    let _span = info_span!("hook", hook = "on_exit").entered();
+    debug!("Running exit command: {cmd}");
+    match shell(&cmd, "exit", url, &env).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Exit command exited with: {status}"),
+        Err(e) => warn!("Failed to run exit command: {e}"),
     }
-    match Command::new("sh").arg("-c").arg(&cmd).status() {
+}
+
+/// Fill in the on-error placeholders: `{error}` = the error message, already
+/// shell-quoted since it can hold anything a server sent, `{failures}` = how
+/// many attempts in a row failed, `{elapsed}` = seconds since the recorder
+/// started, and `{dir}` = the output directory
+pub fn expand_error_command(
+    cmd_template: &str,
+    error: &str,
+    failures: u32,
+    elapsed_secs: u64,
+    output_dir: &Path,
+) -> String {
+    let quoted = format!("'{}'", error.replace('\'', "'\\''"));
+    cmd_template
+        .replace("{error}", &quoted)
+        .replace("{failures}", &failures.to_string())
+        .replace("{elapsed}", &elapsed_secs.to_string())
+        .replace("{dir}", &output_dir.to_string_lossy())
+}
+
+pub fn run_error_command(
+    cmd_template: &str,
+    error: &str,
+    failures: u32,
+    elapsed_secs: u64,
+    output_dir: &Path,
+    url: &str,
+) {
+    let cmd = expand_error_command(cmd_template, error, failures, elapsed_secs, output_dir);
+    let env = [
+        ("ERROR", error.to_string()),
+        ("FAILURES", failures.to_string()),
+        ("DURATION", elapsed_secs.to_string()),
+        ("DIR", output_dir.to_string_lossy().into_owned()),
+    ];
+    let _span = info_span!("hook", hook = "on_error").entered();
+    debug!("Running error command: {cmd}");
+    match shell(&cmd, "error", url, &env).status() {
         Ok(status) if status.success() => {}
-        Ok(status) => eprintln!("Exit command exited with: {status}"),
-        Err(e) => eprintln!("Failed to run exit command: {e}"),
+        Ok(status) => warn!("Error command exited with: {status}"),
+        Err(e) => warn!("Failed to run error command: {e}"),
     }
 }
 
-/// Async version that spawns the command without blocking
+/// Run the on-segment command in the background
 pub fn run_segment_command_async(
-    cmd_template: String,
-    filepath: PathBuf,
-    verbose: bool,
+    hook: SegmentHook,
+    completed: CompletedFile,
 ) -> tokio::task::JoinHandle<()> {
+    // Keep the hook span under the recording span that spawned it
+    tokio::spawn(
+        async move {
+            run_segment_command(&hook, &completed.path, completed.program_date_time.as_ref()).await;
+        }
+        .in_current_span(),
+    )
+}
+
+/// Hand off a completed file: remux it to MP4 when --remux-mp4 asked for
+/// that, send the webhook's segment event, call the on_segment callback and
+/// run the on-segment hook on the result. Returns the background task, if
+/// there is one to wait for.
+pub fn file_completed(
+    hooks: &SharedHooks,
+    completed: CompletedFile,
+) -> Option<tokio::task::JoinHandle<()>> {
+    if completed.pipe {
+        return None;
+    }
+    let hook = Hooks::on_segment(hooks);
+    let listeners = Hooks::segment_listeners(hooks);
+    if !completed.remux_mp4 && listeners.is_empty() {
+        return hook.map(|hook| run_segment_command_async(hook, completed));
+    }
+    Some(tokio::spawn(
+        async move {
+            let path = if completed.remux_mp4 {
+                let ts = completed.path.clone();
+                let checksums = completed.checksums.clone();
+                let parent = tracing::Span::current();
+                tokio::task::spawn_blocking(move || {
+                    let _parent = parent.entered();
+                    remuxed(ts, checksums.as_deref())
+                })
+                .await
+                .unwrap_or(completed.path)
+            } else {
+                completed.path
+            };
+            let pdt = completed.program_date_time.as_ref();
+            listeners.send(&path, pdt);
+            if let Some(hook) = hook {
+                run_segment_command(&hook, &path, pdt).await;
+            }
+        }
+        .in_current_span(),
+    ))
+}
+
+fn remuxed(path: PathBuf, checksums: Option<&Path>) -> PathBuf {
+    #[cfg(feature = "remux")]
+    let path = crate::remux::remux_completed(path, checksums);
+    #[cfg(not(feature = "remux"))]
+    let _ = checksums;
+    path
+}
+
+/// Run the on-low-disk hook in the background. Placeholders: `{free}` =
+/// free space in MB, `{dir}` = the output directory.
+pub fn run_low_disk_command_async(cmd_template: &str, free_bytes: u64, dir: &Path, url: String) {
+    let free_mb = (free_bytes / (1024 * 1024)).to_string();
+    let cmd = cmd_template
+        .replace("{free}", &free_mb)
+        .replace("{dir}", &dir.to_string_lossy());
+    let env = [
+        ("FREE_MB", free_mb),
+        ("DIR", dir.to_string_lossy().into_owned()),
+    ];
+    let span = info_span!("hook", hook = "on_low_disk");
     tokio::task::spawn_blocking(move || {
-        run_segment_command(&cmd_template, &filepath, verbose);
-    })
+        let _span = span.entered();
+        debug!("Running: {cmd}");
+        match shell(&cmd, "low-disk", &url, &env).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Low-disk command exited with: {status}"),
+            Err(e) => warn!("Failed to run low-disk command: {e}"),
+        }
+    });
+}
+
+/// Wait up to 60s for spawned on_segment commands before exiting. Those
+/// still running or retrying after that are abandoned, which notes their
+/// files in failed_hooks.txt.
+pub async fn wait_for_commands(pending_commands: &mut [tokio::task::JoinHandle<()>]) {
+    let unfinished = pending_commands.iter().filter(|p| !p.is_finished()).count();
+    if unfinished > 0 {
+        info!("Waiting for {} pending commands to complete...", unfinished);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+        for handle in pending_commands {
+            if !handle.is_finished()
+                && tokio::time::timeout_at(deadline, &mut *handle)
+                    .await
+                    .is_err()
+            {
+                warn!("on_segment command still unfinished after 60s, giving up on it");
+                handle.abort();
+                let _ = handle.await;
+            }
+        }
+    }
 }
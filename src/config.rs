@@ -0,0 +1,162 @@
+//! `--config` files: a TOML table whose keys are `record` option names
+//! (`segment-secs = 600`, `on-segment = "..."`). Values become the options'
+//! defaults, so environment variables and the command line still win.
+//! `[profile.NAME]` tables hold further sets of options, one of which
+//! `--profile NAME` lays over the top-level ones. Daemon jobs are read from
+//! tables of the same options.
+
+use crate::cli::Args;
+use clap::{Args as _, FromArgMatches};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Find the config file named on the command line (`--config FILE` or
+/// `--config=FILE`) or in STREAM_UTILS_CONFIG, before clap parses anything
+pub fn find_path(args: &[OsString]) -> Option<PathBuf> {
+    find_option(args, "config", "STREAM_UTILS_CONFIG").map(PathBuf::from)
+}
+
+/// Find the profile to use (`--profile NAME` or STREAM_UTILS_PROFILE) the
+/// same way
+pub fn find_profile(args: &[OsString]) -> Option<String> {
+    find_option(args, "profile", "STREAM_UTILS_PROFILE")
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+fn find_option(args: &[OsString], name: &str, env: &str) -> Option<OsString> {
+    let flag = format!("--{name}");
+    let prefix = format!("--{name}=");
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            break;
+        }
+        if *arg == *flag {
+            return iter.next().cloned();
+        }
+        if let Some(value) = arg.to_str().and_then(|a| a.strip_prefix(prefix.as_str())) {
+            return Some(value.into());
+        }
+    }
+    std::env::var_os(env)
+}
+
+fn value_to_strings(key: &str, value: &toml::Value) -> Result<Vec<String>, String> {
+    match value {
+        toml::Value::String(s) => Ok(vec![s.clone()]),
+        toml::Value::Integer(i) => Ok(vec![i.to_string()]),
+        toml::Value::Float(f) => Ok(vec![f.to_string()]),
+        toml::Value::Boolean(b) => Ok(vec![b.to_string()]),
+        toml::Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                toml::Value::Array(_) | toml::Value::Table(_) => {
+                    Err(format!("{key}: nested arrays and tables aren't supported"))
+                }
+                other => value_to_strings(key, other).map(|mut v| v.remove(0)),
+            })
+            .collect(),
+        _ => Err(format!("{key}: unsupported value type")),
+    }
+}
+
+/// Read `path` and install its values as defaults on the `record`
+/// subcommand, with those of `[profile.NAME]` over the top-level ones
+pub fn apply(
+    cmd: clap::Command,
+    path: &Path,
+    profile: Option<&str>,
+) -> Result<clap::Command, clap::Error> {
+    let fail = |cmd: &mut clap::Command, msg: String| {
+        cmd.error(
+            clap::error::ErrorKind::InvalidValue,
+            format!("config file {}: {msg}", path.display()),
+        )
+    };
+    let mut cmd = cmd;
+
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => return Err(fail(&mut cmd, e.to_string())),
+    };
+    let mut table: toml::Table = match text.parse() {
+        Ok(table) => table,
+        Err(e) => return Err(fail(&mut cmd, e.to_string())),
+    };
+    let profiles = match table.remove("profile") {
+        None => toml::Table::new(),
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => {
+            let msg = "`profile` must be tables, like [profile.NAME]".to_string();
+            return Err(fail(&mut cmd, msg));
+        }
+    };
+    if let Some(name) = profile {
+        match profiles.get(name) {
+            Some(toml::Value::Table(options)) => table.extend(options.clone()),
+            Some(_) => return Err(fail(&mut cmd, format!("profile.{name} must be a table"))),
+            None => {
+                let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+                let msg = if known.is_empty() {
+                    format!("no profile `{name}`; the file has none")
+                } else {
+                    format!("no profile `{name}`; it has {}", known.join(", "))
+                };
+                return Err(fail(&mut cmd, msg));
+            }
+        }
+    }
+
+    let record = cmd
+        .find_subcommand("record")
+        .expect("record subcommand exists");
+    match option_defaults(record, &table, &["config", "profile"]) {
+        Ok(defaults) => Ok(cmd.mut_subcommand("record", |record| with_defaults(record, defaults))),
+        Err(msg) => Err(fail(&mut cmd, msg)),
+    }
+}
+
+/// The `record` options set by `table`, as argument ids and values, failing
+/// on keys that aren't options or are in `excluded`
+fn option_defaults(
+    record: &clap::Command,
+    table: &toml::Table,
+    excluded: &[&str],
+) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut defaults = Vec::new();
+    for (key, value) in table {
+        let id = key.replace('-', "_");
+        if excluded.contains(&id.as_str())
+            || !record.get_arguments().any(|a| a.get_id() == id.as_str())
+        {
+            return Err(format!("unknown option `{key}`"));
+        }
+        defaults.push((id, value_to_strings(key, value)?));
+    }
+    Ok(defaults)
+}
+
+fn with_defaults(mut record: clap::Command, defaults: Vec<(String, Vec<String>)>) -> clap::Command {
+    for (id, values) in defaults {
+        record = record.mut_arg(id, |arg| arg.required(false).default_values(values));
+    }
+    record
+}
+
+/// `record` options from a table alone, as for a daemon job: its keys are
+/// option names, and everything else keeps its usual default. Options that
+/// pick several streams or another config aren't allowed.
+pub fn record_args(table: &toml::Table) -> Result<Args, String> {
+    let record = Args::augment_args(clap::Command::new("record"));
+    let excluded = ["config", "profile", "cameras", "input_list", "more_urls"];
+    let defaults = option_defaults(&record, table, &excluded)?;
+    let matches = with_defaults(record, defaults)
+        .try_get_matches_from(["record"])
+        .map_err(|e| {
+            // Just clap's first line, without its "error: " and usage
+            let msg = e.to_string();
+            let line = msg.lines().next().unwrap_or_default();
+            line.strip_prefix("error: ").unwrap_or(line).to_string()
+        })?;
+    Args::from_arg_matches(&matches).map_err(|e| e.to_string())
+}
@@ -0,0 +1,232 @@
+use crate::cli::ConvertArgs;
+use crate::error::StreamError;
+use crate::output::OutputFile;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+/// Expand the inputs into an ordered file list. Files are taken as given;
+/// directories contribute the files of one recording session (--session,
+/// or the most recent one).
+fn collect_inputs(args: &ConvertArgs) -> Result<Vec<PathBuf>, StreamError> {
+    let mut files = Vec::new();
+    for input in &args.inputs {
+        if !input.is_dir() {
+            files.push(input.clone());
+            continue;
+        }
+
+        let mut sessions: BTreeMap<String, Vec<(u32, PathBuf)>> = BTreeMap::new();
+        for entry in std::fs::read_dir(input)? {
+            let path = entry?.path();
+            if path == args.output {
+                continue;
+            }
+            if let Some((session, index)) = OutputFile::parse_filename(&path) {
+                sessions.entry(session).or_default().push((index, path));
+            }
+        }
+
+        let mut session = match args.session {
+            Some(ref name) => sessions
+                .remove(name)
+                .ok_or_else(|| format!("No session {name} in {}", input.display()))?,
+            None => {
+                let (name, session) = sessions
+                    .pop_last()
+                    .ok_or_else(|| format!("No recordings found in {}", input.display()))?;
+                if !sessions.is_empty() {
+                    info!(
+                        "Using most recent session {name} ({} older sessions ignored, see --session)",
+                        sessions.len()
+                    );
+                }
+                session
+            }
+        };
+        session.sort();
+        files.extend(session.into_iter().map(|(_, path)| path));
+    }
+
+    if files.is_empty() {
+        return Err(StreamError::config("No input files"));
+    }
+    Ok(files)
+}
+
+/// The ffprobe that comes with `ffmpeg`: the one next to it when
+/// --ffmpeg-path names a directory, otherwise the one on the PATH
+fn ffprobe_path(ffmpeg: &Path) -> PathBuf {
+    match ffmpeg.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.join("ffprobe"),
+        _ => PathBuf::from("ffprobe"),
+    }
+}
+
+/// Duration of a media file in seconds, via ffprobe
+fn probe_duration(ffprobe: &Path, path: &Path) -> Result<f64, StreamError> {
+    let output = Command::new(ffprobe)
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(StreamError::ffmpeg(format!(
+            "ffprobe failed on {}",
+            path.display()
+        )));
+    }
+    let duration = String::from_utf8_lossy(&output.stdout).trim().parse()?;
+    Ok(duration)
+}
+
+/// Quote a path for an ffmpeg concat list (single quotes, ' escaped as '\'')
+fn concat_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Escape a value for an FFMETADATA file
+fn metadata_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Build the concat list, pinning each file's duration so timestamp jumps
+/// at discontinuities (restarts, stream resets) don't leave gaps or overlaps
+fn concat_list(files: &[(PathBuf, f64)]) -> Result<String, std::io::Error> {
+    let mut list = String::from("ffconcat version 1.0\n");
+    for (path, duration) in files {
+        let path = std::path::absolute(path)?;
+        let _ = writeln!(list, "file {}", concat_quote(&path));
+        let _ = writeln!(list, "duration {duration:.3}");
+    }
+    Ok(list)
+}
+
+/// One chapter per input file, titled after the file name
+fn chapters(files: &[(PathBuf, f64)]) -> String {
+    let mut meta = String::from(";FFMETADATA1\n");
+    let mut start_ms: u64 = 0;
+    for (path, duration) in files {
+        let end_ms = start_ms + (duration * 1000.0) as u64;
+        let title = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let _ = write!(
+            meta,
+            "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={start_ms}\nEND={end_ms}\ntitle={}\n",
+            metadata_escape(&title)
+        );
+        start_ms = end_ms;
+    }
+    meta
+}
+
+/// The `convert` command: merge a session's rotated files into one MP4/MKV
+pub fn run(args: ConvertArgs) -> Result<(), StreamError> {
+    let ext = args
+        .output
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    if !matches!(ext.as_deref(), Some("mp4" | "mkv" | "m4v" | "mov")) {
+        return Err(StreamError::config(
+            "Output must end in .mp4, .mkv, .m4v or .mov",
+        ));
+    }
+    if args.output.exists() && !args.overwrite {
+        return Err(format!(
+            "{} already exists (use --overwrite to replace it)",
+            args.output.display()
+        )
+        .into());
+    }
+
+    let ffprobe = ffprobe_path(&args.ffmpeg_path);
+    let files = collect_inputs(&args)?
+        .into_iter()
+        .filter_map(|path| match probe_duration(&ffprobe, &path) {
+            Ok(duration) if duration > 0.0 => Some((path, duration)),
+            Ok(_) => {
+                warn!("Skipping empty file {}", path.display());
+                None
+            }
+            Err(e) => {
+                warn!("Skipping {}: {e}", path.display());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Err(StreamError::parse(
+            "None of the input files are readable media",
+        ));
+    }
+    let total: f64 = files.iter().map(|(_, d)| d).sum();
+    info!(
+        "Merging {} files ({:.0}s) into {}",
+        files.len(),
+        total,
+        args.output.display()
+    );
+
+    let tmp = std::env::temp_dir();
+    let list_path = tmp.join(format!("m3u8-dl-concat-{}.txt", std::process::id()));
+    let meta_path = tmp.join(format!("m3u8-dl-chapters-{}.txt", std::process::id()));
+    std::fs::write(&list_path, concat_list(&files)?)?;
+    if !args.no_chapters {
+        std::fs::write(&meta_path, chapters(&files))?;
+    }
+
+    let mut cmd = Command::new(&args.ffmpeg_path);
+    cmd.args([
+        "-v", "error", "-fflags", "+genpts", "-f", "concat", "-safe", "0", "-i",
+    ])
+    .arg(&list_path);
+    if !args.no_chapters {
+        cmd.arg("-i")
+            .arg(&meta_path)
+            .args(["-map_metadata", "1", "-map_chapters", "1"]);
+    }
+    cmd.args(["-map", "0", "-c", "copy", "-avoid_negative_ts", "make_zero"]);
+    if ext.as_deref() != Some("mkv") {
+        cmd.args(["-movflags", "+faststart"]);
+    }
+    cmd.arg(if args.overwrite { "-y" } else { "-n" })
+        .arg(&args.output);
+
+    debug!(
+        "Running: {} {:?}",
+        args.ffmpeg_path.display(),
+        cmd.get_args().collect::<Vec<_>>()
+    );
+    let status = cmd.status().map_err(|e| {
+        StreamError::ffmpeg(format!("Failed to run {}: {e}", args.ffmpeg_path.display()))
+    });
+
+    let _ = std::fs::remove_file(&list_path);
+    let _ = std::fs::remove_file(&meta_path);
+
+    let status = status?;
+    if !status.success() {
+        return Err(StreamError::ffmpeg(format!("FFmpeg exited with: {status}")));
+    }
+    info!("Wrote {}", args.output.display());
+
+    Ok(())
+}
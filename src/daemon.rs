@@ -0,0 +1,507 @@
+//! `daemon`: supervise the recordings in a jobs file. Each job records one
+//! stream into its own directory like `record` would; one that fails is
+//! started again after a backoff without disturbing the others, and
+//! `--status-file` keeps a JSON summary of every job's state. With
+//! `--listen`, the control API in `api` lists, starts and stops jobs.
+
+use crate::api;
+use crate::cameras::{self, Camera};
+use crate::cli::{Args, DaemonArgs};
+use crate::commands::{Hooks, SharedHooks};
+use crate::config;
+use crate::error::StreamError as Error;
+use crate::http_client::{build_client, redact_password, HttpClient};
+use crate::record;
+use crate::stats::RecordingStats;
+use crate::status::{self, StatusReport};
+use crate::systemd;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn, Instrument};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    Recording,
+    /// Failed, and waiting out the backoff before starting again
+    Restarting,
+    /// The stream ended, or its --duration or --max-bytes was reached
+    Finished,
+    /// Gave up after --max-restarts
+    Failed,
+    /// Stopped by Ctrl+C or through the control API
+    Stopped,
+}
+
+struct Progress {
+    state: State,
+    restarts: u32,
+    last_error: Option<String>,
+}
+
+/// A job as the daemon tracks it while it runs
+pub struct Job {
+    pub name: String,
+    url: String,
+    output: PathBuf,
+    stats: Arc<RecordingStats>,
+    /// Set for every job on Ctrl+C, or for this one by its own limits or
+    /// the control API
+    shutdown: Arc<AtomicBool>,
+    /// Set when it was stopped, rather than reaching its limits
+    stopped: AtomicBool,
+    rotate: Arc<AtomicBool>,
+    progress: Mutex<Progress>,
+    /// Set once it has stopped for good and run its exit hooks
+    done: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct JobReport<'a> {
+    name: &'a str,
+    url: &'a str,
+    output: &'a Path,
+    state: State,
+    restarts: u32,
+    last_error: Option<String>,
+    #[serde(flatten)]
+    stats: StatusReport,
+}
+
+#[derive(Serialize)]
+struct DaemonReport<'a> {
+    updated_at: String,
+    jobs: Vec<JobReport<'a>>,
+}
+
+impl Job {
+    fn new(name: String, args: &Args, total: Arc<RecordingStats>) -> Self {
+        Self {
+            name,
+            url: redact_password(&args.url),
+            output: args.output.clone(),
+            stats: Arc::new(RecordingStats::with_parent(total)),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            stopped: AtomicBool::new(false),
+            rotate: Arc::new(AtomicBool::new(false)),
+            progress: Mutex::new(Progress {
+                state: State::Recording,
+                restarts: 0,
+                last_error: None,
+            }),
+            done: AtomicBool::new(false),
+        }
+    }
+
+    /// Stop recording, finalizing the current file like Ctrl+C
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    fn set_state(&self, state: State) {
+        self.progress.lock().unwrap().state = state;
+    }
+
+    pub fn report(&self) -> JobReport<'_> {
+        let progress = self.progress.lock().unwrap();
+        JobReport {
+            name: &self.name,
+            url: &self.url,
+            output: &self.output,
+            state: progress.state,
+            restarts: progress.restarts,
+            last_error: progress.last_error.clone(),
+            stats: StatusReport::from_stats(&self.stats),
+        }
+    }
+}
+
+/// Take the name out of a job's table and find its url, to name it after
+/// the URL's host if it has none
+fn job_stream(job: &mut toml::Table, shared: &toml::Table) -> Result<Camera, String> {
+    let name = match job.remove("name") {
+        None => String::new(),
+        Some(toml::Value::String(name)) => name,
+        Some(_) => return Err("name must be a string".to_string()),
+    };
+    let mut stream = match job.get("url").or(shared.get("url")) {
+        Some(toml::Value::String(url)) => Camera::from_url(url),
+        _ => return Err("no url".to_string()),
+    };
+    stream.name = name;
+    Ok(stream)
+}
+
+/// The `record` options of job `name`: its table over the shared one. It
+/// records into `output`/name unless it sets its own output.
+fn job_args(shared: &toml::Table, name: &str, job: &toml::Table) -> Result<Args, String> {
+    let mut options = shared.clone();
+    options.extend(job.clone());
+    let mut args = config::record_args(&options)?;
+    if !job.contains_key("output") {
+        args.output = args.output.join(name);
+    }
+    if !job.contains_key("state-file") {
+        args.state_file = args.state_file.map(|path| {
+            let mut path = path.into_os_string();
+            path.push(format!(".{name}"));
+            PathBuf::from(path)
+        });
+    }
+    record::check_job(&mut args).map_err(|e| e.to_string())?;
+    Ok(args)
+}
+
+/// Read the jobs file: `[[job]]` tables of `record` options, each with a
+/// url and optionally a name, and the top-level options they share
+fn load_jobs(path: &Path) -> Result<(toml::Table, Vec<(String, Args)>), Error> {
+    let fail = |msg: String| Error::config(format!("jobs file {}: {msg}", path.display()));
+    let text = std::fs::read_to_string(path).map_err(|e| fail(e.to_string()))?;
+    let mut shared: toml::Table = text.parse().map_err(|e| fail(format!("{e}")))?;
+    let jobs = match shared.remove("job") {
+        Some(toml::Value::Array(jobs)) if !jobs.is_empty() => jobs,
+        _ => return Err(fail("no [[job]] tables".to_string())),
+    };
+
+    let mut streams = Vec::new();
+    let mut tables = Vec::new();
+    for (i, job) in jobs.into_iter().enumerate() {
+        let toml::Value::Table(mut job) = job else {
+            return Err(fail(format!("job {} must be a table", i + 1)));
+        };
+        let stream =
+            job_stream(&mut job, &shared).map_err(|msg| fail(format!("job {}: {msg}", i + 1)))?;
+        streams.push(stream);
+        tables.push(job);
+    }
+    let streams = cameras::name_cameras(streams).map_err(|e| fail(e.to_string()))?;
+
+    let mut loaded = Vec::new();
+    for (stream, job) in streams.into_iter().zip(tables) {
+        let args = job_args(&shared, &stream.name, &job)
+            .map_err(|msg| fail(format!("job {}: {msg}", stream.name)))?;
+        loaded.push((stream.name, args));
+    }
+    Ok((shared, loaded))
+}
+
+/// Run one job until it finishes or gives up. A failed recording starts
+/// again after --restart-delay, doubling up to --restart-max-delay;
+/// --restart-on-exit restarts a stream that ended as it does for `record`.
+/// This is the only place a job is restarted, and --max-restarts counts
+/// both kinds.
+async fn supervise(job: Arc<Job>, args: Args) -> Result<(), Error> {
+    let recording_start = Instant::now();
+    // A job that can't even be set up fails like one whose recording did
+    let (hooks, client) = match Hooks::from_args(&args) {
+        Ok(hooks) => (Arc::new(RwLock::new(hooks)), setup(&args)),
+        Err(e) => (SharedHooks::default(), Err(e)),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => return finish(&job, &args, &hooks, recording_start, Err(e)).await,
+    };
+    let pre_roll = record::setup_trigger_handler(&args, None);
+    record::setup_limit_handler(&args, job.shutdown.clone(), job.stats.clone());
+    let status_writer = args.status_file.then(|| {
+        status::spawn_status_writer(
+            args.output.join("status.json"),
+            Duration::from_secs(args.status_interval.max(1)),
+            job.stats.clone(),
+        )
+    });
+
+    let initial_delay = Duration::from_secs(args.restart_delay);
+    let max_delay = Duration::from_secs(args.restart_max_delay).max(initial_delay);
+    let mut restart_delay = initial_delay;
+    let result = loop {
+        let attempt_start = Instant::now();
+        job.set_state(State::Recording);
+        let result = record::record_attempt(
+            &client,
+            &args,
+            job.shutdown.clone(),
+            job.rotate.clone(),
+            pre_roll.clone(),
+            hooks.clone(),
+            job.stats.clone(),
+        )
+        .await;
+        if job.shutdown.load(Ordering::SeqCst) || (result.is_ok() && !args.restart_on_exit) {
+            break result;
+        }
+        let restarts = {
+            let mut progress = job.progress.lock().unwrap();
+            if let Err(error) = &result {
+                progress.last_error = Some(error.to_string());
+            }
+            progress.restarts
+        };
+        if args.max_restarts > 0 && restarts >= args.max_restarts {
+            match &result {
+                Ok(()) => error!("Giving up after {restarts} restarts"),
+                Err(error) => error!("Giving up after {restarts} restarts: {error}"),
+            }
+            break result;
+        }
+
+        // A run that stayed up longer than the backoff cap was healthy; start over
+        if attempt_start.elapsed() > max_delay {
+            restart_delay = initial_delay;
+        }
+        match &result {
+            Ok(()) => info!(
+                "Recording ended, restarting in {}s",
+                restart_delay.as_secs()
+            ),
+            Err(error) => warn!(
+                "Recording failed, restarting in {}s: {error}",
+                restart_delay.as_secs()
+            ),
+        }
+        {
+            let mut progress = job.progress.lock().unwrap();
+            progress.state = State::Restarting;
+            progress.restarts += 1;
+        }
+        record::sleep_unless_shutdown(restart_delay, &job.shutdown).await;
+        restart_delay = (restart_delay * 2).min(max_delay);
+    };
+
+    if let Some(status_writer) = status_writer {
+        status_writer.abort();
+    }
+    finish(&job, &args, &hooks, recording_start, result).await
+}
+
+/// The client for a job, with its output directory in place
+fn setup(args: &Args) -> Result<HttpClient, Error> {
+    let client = build_client(&args.net)?.for_origin(&args.url);
+    std::fs::create_dir_all(&args.output)?;
+    Ok(client)
+}
+
+/// Settle a job's final state and run its exit hooks
+async fn finish(
+    job: &Job,
+    args: &Args,
+    hooks: &SharedHooks,
+    recording_start: Instant,
+    result: Result<(), Error>,
+) -> Result<(), Error> {
+    // Stopping one job through the API interrupts it without a signal
+    let interrupted = job.stopped.load(Ordering::SeqCst) || record::interrupted();
+    {
+        let mut progress = job.progress.lock().unwrap();
+        progress.state = match &result {
+            _ if interrupted => State::Stopped,
+            Ok(()) => State::Finished,
+            Err(_) => State::Failed,
+        };
+        if let Err(error) = &result {
+            progress.last_error = Some(error.to_string());
+        }
+    }
+    record::report_exit(
+        args,
+        hooks,
+        &job.stats,
+        recording_start,
+        &result,
+        interrupted,
+    )
+    .await;
+    result
+}
+
+/// The daemon's jobs, shared with the control API
+pub struct Daemon {
+    /// Top-level options of the jobs file, under every job including those
+    /// started through the API
+    shared: toml::Table,
+    jobs: Mutex<Vec<Arc<Job>>>,
+    total: Arc<RecordingStats>,
+    shutdown: Arc<AtomicBool>,
+}
+
+/// Why a job couldn't be started
+pub enum StartError {
+    /// Its options are wrong
+    Invalid(String),
+    /// A running job already has its name
+    Conflict(String),
+    ShuttingDown,
+}
+
+impl Daemon {
+    /// Start supervising job `name` in the background, unless a job of
+    /// that name is still running
+    fn start(&self, name: String, args: Args) -> Result<Arc<Job>, StartError> {
+        let job = Arc::new(Job::new(name, &args, self.total.clone()));
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            let running =
+                |other: &Arc<Job>| other.name == job.name && !other.done.load(Ordering::SeqCst);
+            if jobs.iter().any(running) {
+                return Err(StartError::Conflict(job.name.clone()));
+            }
+            // A stopped job of the same name gives up its place
+            jobs.retain(|other| other.name != job.name);
+            jobs.push(job.clone());
+        }
+        let span = tracing::info_span!("job", job = %job.name);
+        let task_job = job.clone();
+        tokio::spawn(async move {
+            let result = supervise(task_job.clone(), args).instrument(span).await;
+            let bytes = task_job.stats.bytes();
+            match result {
+                Ok(()) => info!(job = %task_job.name, bytes, "Job ended"),
+                Err(e) => error!(job = %task_job.name, bytes, "Job failed: {e}"),
+            }
+            task_job.done.store(true, Ordering::SeqCst);
+        });
+        Ok(job)
+    }
+
+    /// Start a job from a table of `record` options, like a `[[job]]` in
+    /// the jobs file
+    pub fn start_table(&self, mut table: toml::Table) -> Result<Arc<Job>, StartError> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(StartError::ShuttingDown);
+        }
+        let stream = job_stream(&mut table, &self.shared).map_err(StartError::Invalid)?;
+        let name = cameras::name_cameras(vec![stream])
+            .map_err(|e| StartError::Invalid(e.to_string()))?
+            .remove(0)
+            .name;
+        let args = job_args(&self.shared, &name, &table).map_err(StartError::Invalid)?;
+        let job = self.start(name, args)?;
+        info!(job = %job.name, url = %job.url, "Started job");
+        Ok(job)
+    }
+
+    pub fn jobs(&self) -> Vec<Arc<Job>> {
+        self.jobs.lock().unwrap().clone()
+    }
+
+    pub fn find(&self, name: &str) -> Option<Arc<Job>> {
+        self.jobs().into_iter().find(|job| job.name == name)
+    }
+
+    /// Whether every job has stopped and run its exit hooks
+    fn idle(&self) -> bool {
+        self.jobs()
+            .iter()
+            .all(|job| job.done.load(Ordering::SeqCst))
+    }
+}
+
+fn write_report(path: &Path, jobs: &[Arc<Job>]) {
+    let report = DaemonReport {
+        updated_at: chrono::Local::now().to_rfc3339(),
+        jobs: jobs.iter().map(|job| job.report()).collect(),
+    };
+    if let Err(e) = status::write_json(path, &report) {
+        warn!("Failed to write status file {}: {e}", path.display());
+    }
+}
+
+/// Pass Ctrl+C, SIGTERM and SIGHUP rotation on to every job
+fn spawn_relay(daemon: Arc<Daemon>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangup) => {
+                let daemon = daemon.clone();
+                tokio::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        info!("Received SIGHUP, rotating every job's output file");
+                        for job in daemon.jobs() {
+                            job.rotate.store(true, Ordering::SeqCst);
+                        }
+                    }
+                });
+            }
+            Err(e) => warn!("Can't listen for SIGHUP: {e}"),
+        }
+    }
+
+    tokio::spawn(async move {
+        while !daemon.shutdown.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        for job in daemon.jobs() {
+            job.stop();
+        }
+    });
+}
+
+/// The `daemon` command: record every job in the jobs file at once until
+/// they have all finished or given up, or Ctrl+C. With --listen it keeps
+/// running for jobs started through the control API until Ctrl+C.
+pub async fn run(args: DaemonArgs) -> Result<(), Error> {
+    let (shared, specs) = match &args.jobs {
+        Some(path) => load_jobs(path)?,
+        None => (toml::Table::new(), Vec::new()),
+    };
+    let daemon = Arc::new(Daemon {
+        shared,
+        jobs: Mutex::new(Vec::new()),
+        total: Arc::new(RecordingStats::new()),
+        shutdown: record::setup_shutdown_handler(),
+    });
+    systemd::spawn_status_reporter(daemon.total.clone());
+    if let Some(addr) = args.listen {
+        api::spawn(addr, args.api_token.clone(), daemon.clone()).await?;
+    }
+
+    for (name, job_args) in specs {
+        // Names in the file are unique
+        let _ = daemon.start(name, job_args);
+    }
+    info!("Supervising {} jobs", daemon.jobs().len());
+    spawn_relay(daemon.clone());
+    let writer = args.status_file.clone().map(|path| {
+        let daemon = daemon.clone();
+        let interval = Duration::from_secs(args.status_interval.max(1));
+        tokio::spawn(async move {
+            loop {
+                write_report(&path, &daemon.jobs());
+                tokio::time::sleep(interval).await;
+            }
+        })
+    });
+
+    loop {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let serving = args.listen.is_some() && !daemon.shutdown.load(Ordering::SeqCst);
+        if !serving && daemon.idle() {
+            break;
+        }
+    }
+    if let Some(writer) = writer {
+        writer.abort();
+    }
+    let jobs = daemon.jobs();
+    if let Some(path) = &args.status_file {
+        write_report(path, &jobs);
+    }
+    info!(bytes = daemon.total.bytes(), "All jobs stopped");
+
+    let failed: Vec<&str> = jobs
+        .iter()
+        .filter(|job| job.progress.lock().unwrap().state == State::Failed)
+        .map(|job| job.name.as_str())
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("jobs failed: {}", failed.join(", ")).into())
+    }
+}
@@ -0,0 +1,1012 @@
+//! MPEG-DASH input: parse the MPD manifest, pick a representation the way
+//! `playlist::select_variant` picks an HLS variant, and download its
+//! SegmentTemplate init and media segments into the usual rotating files.
+
+use crate::commands::{self, SharedHooks};
+use crate::error::StreamError;
+use crate::http_client::{fetch_if_modified_with_retry, fetch_with_retry, HttpClient, Validators};
+use crate::output::{OutputFile, OutputOptions};
+use crate::playlist::{Quality, VariantArgs};
+use crate::stats::RecordingStats;
+use chrono::{DateTime, FixedOffset, Utc};
+use roxmltree::Node;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
+use url::Url;
+
+/// Segments taken from the live edge on the first poll of a dynamic MPD,
+/// about where an HLS player would start
+const LIVE_START_SEGMENTS: usize = 3;
+
+/// How far back a live template without a SegmentTimeline is listed when
+/// the MPD has no timeShiftBufferDepth
+const DEFAULT_TIME_SHIFT_SECS: f64 = 60.0;
+
+/// Whether `url` points at a DASH manifest
+pub fn is_mpd_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|u| u.path().to_ascii_lowercase().ends_with(".mpd"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Video,
+    Audio,
+}
+
+pub struct DashConfig {
+    pub mpd_url: Url,
+    /// Which adaptation sets to pick the representation from
+    pub content: ContentKind,
+    pub variant: VariantArgs,
+    pub output_dir: PathBuf,
+    pub file_extension: String,
+    pub segment_secs: u64,
+    /// Name files by the wall-clock time of their first segment (live only)
+    pub pdt_filenames: bool,
+    pub output_options: OutputOptions,
+    pub poll_interval: u64,
+    pub max_failures: u32,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+    pub hooks: SharedHooks,
+}
+
+struct Manifest {
+    dynamic: bool,
+    availability_start: Option<DateTime<FixedOffset>>,
+    minimum_update_period: Option<f64>,
+    media_presentation_duration: Option<f64>,
+    time_shift_buffer_depth: Option<f64>,
+    periods: Vec<Period>,
+}
+
+struct Period {
+    /// Period@id, or its position when it has none
+    id: String,
+    start: f64,
+    duration: Option<f64>,
+    representations: Vec<Representation>,
+}
+
+struct Representation {
+    id: String,
+    kind: Option<ContentKind>,
+    bandwidth: u64,
+    height: Option<u64>,
+    frame_rate: Option<f64>,
+    base_url: Url,
+    template: Option<SegmentTemplate>,
+}
+
+/// SegmentTemplate attributes, merged down from Period and AdaptationSet
+#[derive(Debug, Clone, Default)]
+struct SegmentTemplate {
+    initialization: Option<String>,
+    media: Option<String>,
+    start_number: Option<u64>,
+    timescale: Option<u64>,
+    duration: Option<u64>,
+    presentation_time_offset: Option<u64>,
+    timeline: Option<Vec<TimelineEntry>>,
+}
+
+/// One `<S t d r>` element
+#[derive(Debug, Clone)]
+struct TimelineEntry {
+    t: Option<u64>,
+    d: u64,
+    r: i64,
+}
+
+struct Segment {
+    url: Url,
+    /// Start in the representation's timescale; unique within the period
+    time: u64,
+    duration: f64,
+    /// Wall-clock time of the segment's start, for live streams
+    program_date_time: Option<DateTime<FixedOffset>>,
+}
+
+impl SegmentTemplate {
+    fn parse(node: Node) -> Self {
+        let timeline = child(node, "SegmentTimeline").map(|tl| {
+            tl.children()
+                .filter(|n| n.tag_name().name() == "S")
+                .map(|s| TimelineEntry {
+                    t: attr(s, "t"),
+                    d: attr(s, "d").unwrap_or(0),
+                    r: attr(s, "r").unwrap_or(0),
+                })
+                .collect()
+        });
+        Self {
+            initialization: node.attribute("initialization").map(str::to_string),
+            media: node.attribute("media").map(str::to_string),
+            start_number: attr(node, "startNumber"),
+            timescale: attr(node, "timescale"),
+            duration: attr(node, "duration"),
+            presentation_time_offset: attr(node, "presentationTimeOffset"),
+            timeline,
+        }
+    }
+
+    /// Fill in what this level leaves out from the enclosing level
+    fn inherit(self, parent: &SegmentTemplate) -> Self {
+        Self {
+            initialization: self
+                .initialization
+                .or_else(|| parent.initialization.clone()),
+            media: self.media.or_else(|| parent.media.clone()),
+            start_number: self.start_number.or(parent.start_number),
+            timescale: self.timescale.or(parent.timescale),
+            duration: self.duration.or(parent.duration),
+            presentation_time_offset: self
+                .presentation_time_offset
+                .or(parent.presentation_time_offset),
+            timeline: self.timeline.or_else(|| parent.timeline.clone()),
+        }
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.tag_name().name() == name)
+}
+
+fn attr<T: std::str::FromStr>(node: Node, name: &str) -> Option<T> {
+    node.attribute(name)?.trim().parse().ok()
+}
+
+/// Resolve this level's BaseURL, if any, against the enclosing one
+fn base_url(node: Node, parent: &Url) -> Url {
+    child(node, "BaseURL")
+        .and_then(|n| n.text())
+        .and_then(|text| parent.join(text.trim()).ok())
+        .unwrap_or_else(|| parent.clone())
+}
+
+/// The template at this level merged over `parent`
+fn template(node: Node, parent: Option<&SegmentTemplate>) -> Option<SegmentTemplate> {
+    match (
+        child(node, "SegmentTemplate").map(SegmentTemplate::parse),
+        parent,
+    ) {
+        (Some(own), Some(parent)) => Some(own.inherit(parent)),
+        (own, parent) => own.or_else(|| parent.cloned()),
+    }
+}
+
+fn content_kind(node: Node) -> Option<ContentKind> {
+    let kind = node
+        .attribute("contentType")
+        .or_else(|| node.attribute("mimeType"))?;
+    if kind.starts_with("video") {
+        Some(ContentKind::Video)
+    } else if kind.starts_with("audio") {
+        Some(ContentKind::Audio)
+    } else {
+        None
+    }
+}
+
+/// `30000/1001` or `25`
+fn parse_frame_rate(rate: &str) -> Option<f64> {
+    match rate.split_once('/') {
+        Some((num, den)) => Some(num.parse::<f64>().ok()? / den.parse::<f64>().ok()?),
+        None => rate.parse().ok(),
+    }
+}
+
+/// ISO 8601 duration as used by MPDs (`PT1H2M3.5S`, `P1DT2H`), in seconds
+fn parse_duration(duration: &str) -> Option<f64> {
+    let rest = duration.trim().strip_prefix('P')?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+    let mut secs = 0.0;
+    for (part, units) in [
+        (
+            date,
+            &[
+                ('Y', 365.0 * 86400.0),
+                ('M', 30.0 * 86400.0),
+                ('W', 7.0 * 86400.0),
+                ('D', 86400.0),
+            ][..],
+        ),
+        (time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)][..]),
+    ] {
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() || c == '.' {
+                number.push(c);
+                continue;
+            }
+            let (_, scale) = units.iter().find(|(unit, _)| *unit == c)?;
+            secs += number.parse::<f64>().ok()? * scale;
+            number.clear();
+        }
+        if !number.is_empty() {
+            return None;
+        }
+    }
+    Some(secs)
+}
+
+fn parse_manifest(data: &[u8], mpd_url: &Url) -> Result<Manifest, StreamError> {
+    let text = std::str::from_utf8(data).map_err(|_| StreamError::parse("MPD is not UTF-8"))?;
+    let doc = roxmltree::Document::parse(text)
+        .map_err(|e| StreamError::parse(format!("MPD parse error: {e}")))?;
+    let mpd = doc.root_element();
+    if mpd.tag_name().name() != "MPD" {
+        return Err(StreamError::parse("Not a DASH manifest (no MPD element)"));
+    }
+    let duration_attr = |node: Node, name| node.attribute(name).and_then(parse_duration);
+
+    let mpd_base = base_url(mpd, mpd_url);
+    let mut periods = Vec::new();
+    let mut next_start = 0.0;
+    for (index, period) in mpd
+        .children()
+        .filter(|n| n.tag_name().name() == "Period")
+        .enumerate()
+    {
+        // A period without a start follows the one before it
+        let start = duration_attr(period, "start").unwrap_or(next_start);
+        let duration = duration_attr(period, "duration");
+        next_start = start + duration.unwrap_or(0.0);
+
+        let period_base = base_url(period, &mpd_base);
+        let period_template = template(period, None);
+        let mut representations = Vec::new();
+        for set in period
+            .children()
+            .filter(|n| n.tag_name().name() == "AdaptationSet")
+        {
+            let set_base = base_url(set, &period_base);
+            let set_template = template(set, period_template.as_ref());
+            let set_frame_rate = set.attribute("frameRate").and_then(parse_frame_rate);
+            for rep in set
+                .children()
+                .filter(|n| n.tag_name().name() == "Representation")
+            {
+                representations.push(Representation {
+                    id: rep.attribute("id").unwrap_or_default().to_string(),
+                    kind: content_kind(rep).or_else(|| content_kind(set)),
+                    bandwidth: attr(rep, "bandwidth").unwrap_or(0),
+                    height: attr(rep, "height").or_else(|| attr(set, "height")),
+                    frame_rate: rep
+                        .attribute("frameRate")
+                        .and_then(parse_frame_rate)
+                        .or(set_frame_rate),
+                    base_url: base_url(rep, &set_base),
+                    template: template(rep, set_template.as_ref()),
+                });
+            }
+        }
+        periods.push(Period {
+            id: period
+                .attribute("id")
+                .map(str::to_string)
+                .unwrap_or_else(|| index.to_string()),
+            start,
+            duration,
+            representations,
+        });
+    }
+
+    Ok(Manifest {
+        dynamic: mpd.attribute("type") == Some("dynamic"),
+        availability_start: mpd
+            .attribute("availabilityStartTime")
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok()),
+        minimum_update_period: duration_attr(mpd, "minimumUpdatePeriod"),
+        media_presentation_duration: duration_attr(mpd, "mediaPresentationDuration"),
+        time_shift_buffer_depth: duration_attr(mpd, "timeShiftBufferDepth"),
+        periods,
+    })
+}
+
+/// Expand `$RepresentationID$`, `$Number$`, `$Time$` and `$Bandwidth$`
+/// (with an optional `%0<width>d` format) and `$$` in a segment template
+fn expand_template(template: &str, rep: &Representation, number: u64, time: u64) -> String {
+    let mut parts = template.split('$');
+    let mut out = parts.next().unwrap_or_default().to_string();
+    // Identifiers are the odd parts, between pairs of $
+    for (i, part) in parts.enumerate() {
+        if i % 2 == 1 {
+            out.push_str(part);
+            continue;
+        }
+        let (name, format) = match part.split_once('%') {
+            Some((name, format)) => (name, Some(format)),
+            None => (part, None),
+        };
+        let value = match name {
+            "" => {
+                out.push('$');
+                continue;
+            }
+            "RepresentationID" => {
+                out.push_str(&rep.id);
+                continue;
+            }
+            "Number" => number,
+            "Time" => time,
+            "Bandwidth" => rep.bandwidth,
+            _ => {
+                out.push_str(&format!("${part}$"));
+                continue;
+            }
+        };
+        let width = format
+            .and_then(|f| {
+                f.strip_prefix('0')?
+                    .strip_suffix('d')?
+                    .parse::<usize>()
+                    .ok()
+            })
+            .unwrap_or(0);
+        out.push_str(&format!("{value:0width$}"));
+    }
+    out
+}
+
+/// Order representations by height, then frame rate, then bandwidth, like
+/// `playlist::compare_quality`
+fn compare_quality(a: &Representation, b: &Representation) -> CmpOrdering {
+    a.height
+        .cmp(&b.height)
+        .then_with(|| {
+            a.frame_rate
+                .unwrap_or(0.0)
+                .partial_cmp(&b.frame_rate.unwrap_or(0.0))
+                .unwrap_or(CmpOrdering::Equal)
+        })
+        .then_with(|| a.bandwidth.cmp(&b.bandwidth))
+}
+
+/// Pick a representation of `kind`. Video follows the variant selection
+/// options, with `--variant` indexing the video representations in MPD
+/// order; audio takes the highest bandwidth.
+fn select_representation<'a>(
+    period: &'a Period,
+    kind: ContentKind,
+    prefs: &VariantArgs,
+) -> Result<&'a Representation, String> {
+    let mut candidates = period
+        .representations
+        .iter()
+        .filter(|r| r.kind == Some(kind));
+    if kind == ContentKind::Audio {
+        return candidates
+            .max_by_key(|r| r.bandwidth)
+            .ok_or_else(|| "No audio representation in the MPD".to_string());
+    }
+    if let Some(index) = prefs.variant {
+        let count = candidates.clone().count();
+        return candidates.nth(index).ok_or_else(|| {
+            format!("--variant {index} is out of range: the MPD has {count} video representations")
+        });
+    }
+    let candidates = candidates.filter(|r| {
+        prefs
+            .max_height
+            .is_none_or(|max| r.height.is_none_or(|h| h <= max))
+            && prefs.max_bandwidth.is_none_or(|max| r.bandwidth <= max)
+            && prefs.min_bandwidth.is_none_or(|min| r.bandwidth >= min)
+    });
+    match prefs.quality {
+        Quality::Best => candidates.max_by(|a, b| compare_quality(a, b)),
+        Quality::Worst => candidates.min_by(|a, b| compare_quality(a, b)),
+    }
+    .ok_or_else(|| "No representation matches the variant selection options".to_string())
+}
+
+impl Manifest {
+    /// Seconds from the period start to the live edge (dynamic) or the end
+    /// of the period (static)
+    fn period_end(&self, period: &Period, now: DateTime<Utc>) -> Option<f64> {
+        if self.dynamic {
+            let ast = self.availability_start?;
+            Some((now - ast.with_timezone(&Utc)).num_milliseconds() as f64 / 1000.0 - period.start)
+        } else {
+            period
+                .duration
+                .or_else(|| Some(self.media_presentation_duration? - period.start))
+        }
+    }
+
+    fn segments(
+        &self,
+        period: &Period,
+        rep: &Representation,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Segment>, String> {
+        let template = rep
+            .template
+            .as_ref()
+            .ok_or("Only SegmentTemplate addressing is supported")?;
+        let media = template
+            .media
+            .as_deref()
+            .ok_or("SegmentTemplate without a media attribute")?;
+        let timescale = template.timescale.unwrap_or(1).max(1);
+        let offset = template.presentation_time_offset.unwrap_or(0);
+        let start_number = template.start_number.unwrap_or(1);
+        let end = self.period_end(period, now);
+
+        // (number, time, duration) in the timescale
+        let mut entries: Vec<(u64, u64, u64)> = Vec::new();
+        if let Some(ref timeline) = template.timeline {
+            let mut time = offset;
+            let mut number = start_number;
+            for (i, s) in timeline.iter().enumerate() {
+                time = s.t.unwrap_or(time);
+                let d = s.d.max(1);
+                // A negative repeat runs up to the next S, or the period end
+                let repeats = u64::try_from(s.r).unwrap_or_else(|_| {
+                    let until = timeline.get(i + 1).and_then(|next| next.t).or_else(|| {
+                        end.map(|secs| offset + (secs.max(0.0) * timescale as f64) as u64)
+                    });
+                    until.map_or(0, |until| {
+                        (until.saturating_sub(time) / d).saturating_sub(1)
+                    })
+                });
+                for _ in 0..=repeats {
+                    entries.push((number, time, d));
+                    time += d;
+                    number += 1;
+                }
+            }
+        } else {
+            let d = template
+                .duration
+                .ok_or("SegmentTemplate has neither a duration nor a SegmentTimeline")?
+                .max(1);
+            let segment_secs = d as f64 / timescale as f64;
+            let end = end.ok_or("Can't tell where the period ends")?;
+            let (first, count) = if self.dynamic {
+                // Only segments that are complete by now
+                let count = (end / segment_secs).floor().max(0.0) as u64;
+                let window = self
+                    .time_shift_buffer_depth
+                    .unwrap_or(DEFAULT_TIME_SHIFT_SECS);
+                (
+                    count.saturating_sub((window / segment_secs).ceil() as u64),
+                    count,
+                )
+            } else {
+                (0, (end / segment_secs).ceil() as u64)
+            };
+            for n in first..count {
+                entries.push((start_number + n, offset + n * d, d));
+            }
+        }
+
+        let period_start = self
+            .availability_start
+            .filter(|_| self.dynamic)
+            .map(|ast| ast + chrono::Duration::milliseconds((period.start * 1000.0) as i64));
+        entries
+            .into_iter()
+            .map(|(number, time, d)| {
+                let url = rep
+                    .base_url
+                    .join(&expand_template(media, rep, number, time))
+                    .map_err(|e| e.to_string())?;
+                let program_date_time = period_start.map(|start| {
+                    let ms = (time - offset.min(time)) * 1000 / timescale;
+                    start + chrono::Duration::milliseconds(ms as i64)
+                });
+                Ok(Segment {
+                    url,
+                    time,
+                    duration: d as f64 / timescale as f64,
+                    program_date_time,
+                })
+            })
+            .collect()
+    }
+}
+
+fn init_url(rep: &Representation) -> Result<Option<Url>, String> {
+    let Some(init) = rep
+        .template
+        .as_ref()
+        .and_then(|t| t.initialization.as_deref())
+    else {
+        return Ok(None);
+    };
+    rep.base_url
+        .join(&expand_template(init, rep, 0, 0))
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+pub struct DashDownloader {
+    config: DashConfig,
+    output: OutputFile,
+    validators: Validators,
+    /// Chosen representation id per period, so a live recording doesn't
+    /// hop between representations from one MPD refresh to the next
+    chosen: HashMap<String, String>,
+    /// Start time of the newest segment written, per period
+    last_time: HashMap<String, u64>,
+    /// Init segment of the last representation written, and its bytes
+    init: Option<(Url, Vec<u8>)>,
+    /// Init segment at the start of the current file
+    file_init: Option<Url>,
+    consecutive_failures: u32,
+}
+
+impl DashDownloader {
+    pub fn new(config: DashConfig) -> std::io::Result<Self> {
+        let output = OutputFile::new(
+            config.file_extension.clone(),
+            config.output_dir.clone(),
+            Duration::from_secs(config.segment_secs),
+            config.pdt_filenames,
+            config.output_options.clone(),
+        )?;
+        Ok(Self {
+            config,
+            output,
+            validators: Validators::default(),
+            chosen: HashMap::new(),
+            last_time: HashMap::new(),
+            init: None,
+            file_init: None,
+            consecutive_failures: 0,
+        })
+    }
+
+    fn representation<'a>(&mut self, period: &'a Period) -> Result<&'a Representation, String> {
+        if let Some(id) = self.chosen.get(&period.id) {
+            if let Some(rep) = period.representations.iter().find(|r| &r.id == id) {
+                return Ok(rep);
+            }
+        }
+        let rep = select_representation(period, self.config.content, &self.config.variant)?;
+        debug!(
+            period = %period.id,
+            representation = %rep.id,
+            bandwidth = rep.bandwidth,
+            height = rep.height,
+            "Selected DASH representation"
+        );
+        self.chosen.insert(period.id.clone(), rep.id.clone());
+        Ok(rep)
+    }
+
+    /// Bytes of the init segment at `url`, fetched once per representation
+    async fn init_bytes(&mut self, client: &HttpClient, url: &Url) -> Result<Vec<u8>, StreamError> {
+        if let Some((ref cached, ref bytes)) = self.init {
+            if cached == url {
+                return Ok(bytes.clone());
+            }
+        }
+        let bytes = fetch_with_retry(
+            client,
+            url.as_str(),
+            self.config.timeout,
+            self.config.retries,
+            self.config.retry_delay_ms,
+        )
+        .await?;
+        debug!(init = %url, bytes = bytes.len(), "Fetched init segment");
+        self.init = Some((url.clone(), bytes.clone()));
+        Ok(bytes)
+    }
+
+    fn complete(
+        &mut self,
+        completed: crate::output::CompletedFile,
+        stats: &RecordingStats,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) {
+        stats.set_current_file(self.output.current_path());
+        pending_commands.extend(commands::file_completed(&self.config.hooks, completed));
+    }
+
+    async fn write_segment(
+        &mut self,
+        client: &HttpClient,
+        init: Option<&Url>,
+        segment: &Segment,
+        stats: &RecordingStats,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) -> Result<(), StreamError> {
+        // Each file has to start with the init segment of what follows it;
+        // a new representation or period gets a new file
+        if !self.output.is_empty() && self.file_init.as_ref() != init {
+            let completed = self.output.rotate()?;
+            info!(file = %completed.path.display(), "Rotated at new init segment");
+            self.complete(completed, stats, pending_commands);
+        }
+        let data = fetch_with_retry(
+            client,
+            segment.url.as_str(),
+            self.config.timeout,
+            self.config.retries,
+            self.config.retry_delay_ms,
+        )
+        .instrument(info_span!("segment_fetch", segment = %segment.url))
+        .await;
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                stats.add_segment_error();
+                warn!(segment = %segment.url, "Segment error (giving up): {e}");
+                return Ok(());
+            }
+        };
+
+        // Keep the file under --segment-bytes by starting the next one now
+        if let Some(completed) = self.output.make_room(data.len())? {
+            self.complete(completed, stats, pending_commands);
+        }
+        if self.output.is_empty() {
+            if let Some(init) = init {
+                let bytes = self.init_bytes(client, init).await?;
+                self.output.write(&bytes, segment.program_date_time)?;
+                stats.add_bytes(bytes.len() as u64);
+            }
+            self.file_init = init.cloned();
+        }
+        self.output.write(&data, segment.program_date_time)?;
+        self.output
+            .add_segment(None, Duration::from_secs_f64(segment.duration), false);
+        stats.add_segment(data.len() as u64);
+        if let Some(pdt) = segment.program_date_time {
+            let end = pdt + chrono::Duration::milliseconds((segment.duration * 1000.0) as i64);
+            if let Ok(lag) = (Utc::now() - end.with_timezone(&Utc)).to_std() {
+                stats.set_live_lag(lag);
+            }
+        }
+        debug!(segment = %segment.url, bytes = data.len(), "Wrote segment");
+        if let Some(completed) = self.output.maybe_rotate()? {
+            self.complete(completed, stats, pending_commands);
+        }
+        Ok(())
+    }
+
+    pub async fn run(
+        &mut self,
+        client: &HttpClient,
+        shutdown: Arc<AtomicBool>,
+        rotate: Arc<AtomicBool>,
+        stats: Arc<RecordingStats>,
+    ) -> Result<u64, StreamError> {
+        let mut pending_commands: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+        let mut first_poll = true;
+        let mut last_manifest: Option<Manifest> = None;
+        let mut gave_up = None;
+        stats.set_current_file(self.output.current_path());
+
+        loop {
+            stats.touch();
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            // SIGHUP: close the current file now instead of waiting for --segment-secs
+            if rotate.swap(false, Ordering::SeqCst) {
+                let completed = self.output.rotate()?;
+                info!(file = %completed.path.display(), "Rotated on request");
+                self.complete(completed, &stats, &mut pending_commands);
+            }
+
+            let fetched = fetch_if_modified_with_retry(
+                client,
+                self.config.mpd_url.as_str(),
+                &mut self.validators,
+                self.config.timeout,
+                self.config.retries,
+                self.config.retry_delay_ms,
+            )
+            .instrument(info_span!("manifest_poll", url = %self.config.mpd_url))
+            .await;
+            let manifest = match fetched {
+                Ok(Some(data)) => parse_manifest(&data, &client.final_url(&self.config.mpd_url)),
+                // 304: reuse the last manifest. A live template without a
+                // timeline still yields new segments as the clock moves on.
+                Ok(None) => last_manifest
+                    .take()
+                    .ok_or_else(|| StreamError::network("304 Not Modified before any manifest")),
+                Err(e) => Err(e),
+            };
+            let manifest = match manifest {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    stats.add_playlist_error();
+                    self.consecutive_failures += 1;
+                    if self.config.max_failures > 0
+                        && self.consecutive_failures >= self.config.max_failures
+                    {
+                        error!("Manifest fetch error: {e}");
+                        error!(
+                            "Giving up after {} consecutive failures",
+                            self.consecutive_failures
+                        );
+                        gave_up = Some(e.context("Manifest fetch error"));
+                        break;
+                    }
+                    warn!(
+                        "Manifest fetch error (retrying {}/{}): {e}",
+                        self.consecutive_failures, self.config.max_failures
+                    );
+                    tokio::time::sleep(Duration::from_secs(self.config.poll_interval)).await;
+                    continue;
+                }
+            };
+            self.consecutive_failures = 0;
+
+            // Collect everything not yet written, across periods in order
+            let now = Utc::now();
+            let mut queue = Vec::new();
+            for period in &manifest.periods {
+                if manifest
+                    .period_end(period, now)
+                    .is_some_and(|secs| manifest.dynamic && secs < 0.0)
+                {
+                    // A live period that hasn't started yet
+                    continue;
+                }
+                let rep = self.representation(period).map_err(StreamError::config)?;
+                let init = init_url(rep).map_err(StreamError::parse)?;
+                let last = self.last_time.get(&period.id).copied();
+                for segment in manifest
+                    .segments(period, rep, now)
+                    .map_err(StreamError::parse)?
+                {
+                    if last.is_none_or(|last| segment.time > last) {
+                        queue.push((period.id.clone(), init.clone(), segment));
+                    }
+                }
+            }
+            trace!(
+                periods = manifest.periods.len(),
+                new_segments = queue.len(),
+                "Fetched manifest"
+            );
+            if first_poll {
+                if manifest.dynamic {
+                    // Skipped segments count as done, or a period whose
+                    // segments were all skipped would queue them again
+                    let skip = queue.len().saturating_sub(LIVE_START_SEGMENTS);
+                    for (period, _, segment) in queue.drain(..skip) {
+                        self.last_time.insert(period, segment.time);
+                    }
+                } else {
+                    let total: f64 = queue.iter().map(|(_, _, segment)| segment.duration).sum();
+                    info!(
+                        segments = queue.len(),
+                        "Static manifest: {} of media",
+                        commands::format_duration(total as u64)
+                    );
+                    stats.set_expected_segments(queue.len() as u64);
+                    stats.set_expected_duration(Duration::from_secs_f64(total.max(0.0)));
+                }
+                first_poll = false;
+            }
+
+            for (period, init, segment) in queue {
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                self.write_segment(
+                    client,
+                    init.as_ref(),
+                    &segment,
+                    &stats,
+                    &mut pending_commands,
+                )
+                .await?;
+                stats.add_done_duration(Duration::from_secs_f64(segment.duration.max(0.0)));
+                self.last_time.insert(period, segment.time);
+            }
+            // Forget periods that have left the manifest
+            self.last_time
+                .retain(|id, _| manifest.periods.iter().any(|p| &p.id == id));
+            self.chosen
+                .retain(|id, _| manifest.periods.iter().any(|p| &p.id == id));
+
+            if !manifest.dynamic {
+                info!("Stream ended.");
+                break;
+            }
+            let wait = manifest
+                .minimum_update_period
+                .map_or(self.config.poll_interval as f64, |secs| {
+                    secs.clamp(1.0, self.config.poll_interval.max(1) as f64)
+                });
+            last_manifest = Some(manifest);
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+
+        let final_file = self.output.finalize()?;
+        info!(file = %final_file.path.display(), "Flushed current segment");
+        pending_commands.extend(commands::file_completed(&self.config.hooks, final_file));
+        commands::wait_for_commands(&mut pending_commands).await;
+        if let Some(error) = gave_up {
+            return Err(StreamError::GaveUp {
+                failures: self.consecutive_failures,
+                error: Box::new(error),
+            });
+        }
+        Ok(self.output.total_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATIC_MPD: &str = r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT30S">
+  <BaseURL>media/</BaseURL>
+  <Period>
+    <AdaptationSet mimeType="video/mp4" frameRate="25">
+      <SegmentTemplate timescale="90000" duration="360000" startNumber="1"
+          media="$RepresentationID$/$Number%05d$.m4s" initialization="$RepresentationID$/init.mp4"/>
+      <Representation id="720p" height="720" bandwidth="2000000"/>
+      <Representation id="1080p" height="1080" bandwidth="5000000"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio">
+      <SegmentTemplate timescale="48000" duration="192000" media="audio/$Bandwidth$/$Number$.m4s"/>
+      <Representation id="a1" bandwidth="64000"/>
+      <Representation id="a2" bandwidth="128000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    const DYNAMIC_MPD: &str = r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="dynamic"
+    availabilityStartTime="2026-01-01T00:00:00Z" minimumUpdatePeriod="PT2S"
+    timeShiftBufferDepth="PT30S">
+  <Period id="p0" start="PT0S">
+    <AdaptationSet contentType="video">
+      <SegmentTemplate timescale="1000" media="v/$Time$.m4s" initialization="v/init.mp4">
+        <SegmentTimeline>
+          <S t="0" d="2000" r="2"/>
+          <S d="1000" r="-1"/>
+        </SegmentTimeline>
+      </SegmentTemplate>
+      <Representation id="v" height="720" bandwidth="3000000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    fn prefs() -> VariantArgs {
+        VariantArgs {
+            quality: Quality::Best,
+            max_height: None,
+            max_bandwidth: None,
+            min_bandwidth: None,
+            variant: None,
+        }
+    }
+
+    fn at(secs: f64) -> DateTime<Utc> {
+        let ast = DateTime::parse_from_rfc3339("2026-01-01T00:00:00Z").unwrap();
+        ast.with_timezone(&Utc) + chrono::Duration::milliseconds((secs * 1000.0) as i64)
+    }
+
+    #[test]
+    fn static_mpd() {
+        let url = Url::parse("https://cdn.example.com/vod/movie.mpd").unwrap();
+        let mpd = parse_manifest(STATIC_MPD.as_bytes(), &url).unwrap();
+        assert!(!mpd.dynamic);
+        assert_eq!(mpd.periods.len(), 1);
+        let period = &mpd.periods[0];
+        assert_eq!(period.id, "0");
+
+        let video = select_representation(period, ContentKind::Video, &prefs()).unwrap();
+        assert_eq!(video.id, "1080p");
+        assert_eq!(video.frame_rate, Some(25.0));
+        assert_eq!(
+            init_url(video).unwrap().unwrap().as_str(),
+            "https://cdn.example.com/vod/media/1080p/init.mp4"
+        );
+        // 30 seconds in 4-second segments, the last one short
+        let segments = mpd.segments(period, video, at(0.0)).unwrap();
+        assert_eq!(segments.len(), 8);
+        assert_eq!(
+            segments[0].url.as_str(),
+            "https://cdn.example.com/vod/media/1080p/00001.m4s"
+        );
+        assert_eq!(segments[7].time, 7 * 360_000);
+        assert!(segments.iter().all(|s| s.duration == 4.0));
+        assert!(segments[0].program_date_time.is_none());
+
+        let smaller = VariantArgs {
+            max_height: Some(720),
+            ..prefs()
+        };
+        let video = select_representation(period, ContentKind::Video, &smaller).unwrap();
+        assert_eq!(video.id, "720p");
+        let out_of_range = VariantArgs {
+            variant: Some(2),
+            ..prefs()
+        };
+        assert!(select_representation(period, ContentKind::Video, &out_of_range).is_err());
+
+        let audio = select_representation(period, ContentKind::Audio, &prefs()).unwrap();
+        assert_eq!(audio.id, "a2");
+        assert_eq!(init_url(audio).unwrap(), None);
+        let segments = mpd.segments(period, audio, at(0.0)).unwrap();
+        assert_eq!(
+            segments[0].url.as_str(),
+            "https://cdn.example.com/vod/media/audio/128000/1.m4s"
+        );
+    }
+
+    #[test]
+    fn dynamic_mpd() {
+        let url = Url::parse("https://cdn.example.com/live/stream.mpd").unwrap();
+        let mpd = parse_manifest(DYNAMIC_MPD.as_bytes(), &url).unwrap();
+        assert!(mpd.dynamic);
+        assert_eq!(mpd.minimum_update_period, Some(2.0));
+        assert_eq!(mpd.time_shift_buffer_depth, Some(30.0));
+        let period = &mpd.periods[0];
+        assert_eq!(period.id, "p0");
+        let video = select_representation(period, ContentKind::Video, &prefs()).unwrap();
+
+        // The open-ended S repeats up to the live edge, 10.5s in
+        let segments = mpd.segments(period, video, at(10.5)).unwrap();
+        let times: Vec<u64> = segments.iter().map(|s| s.time).collect();
+        assert_eq!(times, [0, 2000, 4000, 6000, 7000, 8000, 9000]);
+        let last = segments.last().unwrap();
+        assert_eq!(last.url.as_str(), "https://cdn.example.com/live/v/9000.m4s");
+        assert_eq!(last.duration, 1.0);
+        assert_eq!(last.program_date_time, Some(at(9.0).fixed_offset()));
+
+        // More of it once the edge has moved on
+        let segments = mpd.segments(period, video, at(14.0)).unwrap();
+        assert_eq!(segments.last().unwrap().time, 13000);
+    }
+
+    #[test]
+    fn dynamic_template_without_timeline() {
+        let mpd = r#"<?xml version="1.0"?>
+<MPD type="dynamic" availabilityStartTime="2026-01-01T00:00:00Z" timeShiftBufferDepth="PT10S">
+  <Period>
+    <AdaptationSet contentType="video">
+      <SegmentTemplate timescale="1" duration="2" media="v/$Number$.m4s"/>
+      <Representation id="v" bandwidth="3000000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        let url = Url::parse("https://cdn.example.com/live/stream.mpd").unwrap();
+        let mpd = parse_manifest(mpd.as_bytes(), &url).unwrap();
+        let period = &mpd.periods[0];
+        let video = select_representation(period, ContentKind::Video, &prefs()).unwrap();
+
+        // Ten segments are complete 21s in; the last 10s of them are listed
+        let segments = mpd.segments(period, video, at(21.0)).unwrap();
+        let paths: Vec<&str> = segments.iter().map(|s| s.url.path()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/live/v/6.m4s",
+                "/live/v/7.m4s",
+                "/live/v/8.m4s",
+                "/live/v/9.m4s",
+                "/live/v/10.m4s"
+            ]
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("P1DT2H"), Some(93600.0));
+        assert_eq!(parse_duration("PT0S"), Some(0.0));
+        assert_eq!(parse_duration("1H"), None);
+        assert_eq!(parse_duration("PT5"), None);
+        assert_eq!(parse_duration("PT5X"), None);
+    }
+}
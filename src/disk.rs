@@ -0,0 +1,144 @@
+//! The low-disk guard for --min-free-mb: free space on the output
+//! filesystem is checked each time a new file is started, so a filling disk
+//! stops or prunes the recording between files instead of failing in the
+//! middle of one.
+
+use crate::cli::LowDiskAction;
+use crate::commands::{self, format_bytes, Hooks, SharedHooks};
+use crate::output::FilenameTemplate;
+use crate::retention;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+/// What --min-free-mb and --low-disk ask for
+#[derive(Debug, Clone, Copy)]
+pub struct LowDiskPolicy {
+    pub min_free_bytes: u64,
+    pub action: LowDiskAction,
+}
+
+pub struct DiskGuard {
+    dir: PathBuf,
+    policy: LowDiskPolicy,
+    hooks: SharedHooks,
+    /// The --filename-template recordings are named by, to find them when
+    /// pruning
+    filenames: Option<FilenameTemplate>,
+    /// The file the last check was made for
+    checked: Option<PathBuf>,
+    /// Below the threshold at the last check, so --on-low-disk fires once
+    /// per drop rather than on every file
+    low: bool,
+}
+
+impl DiskGuard {
+    pub fn new(
+        dir: PathBuf,
+        policy: Option<LowDiskPolicy>,
+        hooks: SharedHooks,
+        filenames: Option<FilenameTemplate>,
+    ) -> Option<Self> {
+        Some(Self {
+            dir,
+            policy: policy?,
+            hooks,
+            filenames,
+            checked: None,
+            low: false,
+        })
+    }
+
+    /// Check the free space when `current` is a file that hasn't been
+    /// checked yet. Returns false when the recording should stop.
+    pub fn check_new_file(&mut self, current: &Path) -> bool {
+        if self.checked.as_deref() == Some(current) {
+            return true;
+        }
+        self.checked = Some(current.to_path_buf());
+
+        let Some(mut free) = self.free_space() else {
+            return true;
+        };
+        if free >= self.policy.min_free_bytes {
+            self.low = false;
+            return true;
+        }
+        warn!(
+            dir = %self.dir.display(),
+            "Low disk space: {} free, below --min-free-mb {}",
+            format_bytes(free),
+            self.policy.min_free_bytes / (1024 * 1024)
+        );
+        if !self.low {
+            self.low = true;
+            if let Some(cmd) = Hooks::on_low_disk(&self.hooks) {
+                commands::run_low_disk_command_async(
+                    &cmd,
+                    free,
+                    &self.dir,
+                    Hooks::url(&self.hooks),
+                );
+            }
+        }
+
+        match self.policy.action {
+            LowDiskAction::Warn => true,
+            LowDiskAction::Stop => {
+                error!("Stopping the recording to leave the disk space free");
+                false
+            }
+            LowDiskAction::Prune => {
+                free = self.prune(current, free);
+                if free >= self.policy.min_free_bytes {
+                    self.low = false;
+                    return true;
+                }
+                error!("Nothing left to prune, stopping the recording");
+                false
+            }
+        }
+    }
+
+    fn free_space(&self) -> Option<u64> {
+        match fs4::available_space(&self.dir) {
+            Ok(free) => Some(free),
+            Err(e) => {
+                warn!(dir = %self.dir.display(), "Can't check free disk space: {e}");
+                None
+            }
+        }
+    }
+
+    /// Delete the oldest recordings in the output directory until the
+    /// threshold is met, returning the free space after. The file being
+    /// written and the newest one are never removed.
+    fn prune(&self, current: &Path, mut free: u64) -> u64 {
+        let files = match retention::scan(&self.dir, self.filenames.as_ref()) {
+            Ok(files) => files,
+            Err(e) => {
+                warn!(dir = %self.dir.display(), "Can't list recordings to prune: {e}");
+                return free;
+            }
+        };
+        for file in files.iter().skip(1).rev() {
+            if free >= self.policy.min_free_bytes {
+                break;
+            }
+            if file.path == current {
+                continue;
+            }
+            match retention::remove(&file.path) {
+                Ok(()) => {
+                    info!(
+                        "Removed {} ({}) to free disk space",
+                        file.path.display(),
+                        format_bytes(file.size)
+                    );
+                    free = self.free_space().unwrap_or(free + file.size);
+                }
+                Err(e) => warn!("Failed to remove {}: {e}", file.path.display()),
+            }
+        }
+        free
+    }
+}
@@ -0,0 +1,101 @@
+//! Name resolution for the HTTP connector: --resolve overrides, then the
+//! system resolver, limited to one address family with --ipv4 / --ipv6.
+
+use crate::cli::NetworkArgs;
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_service::Service;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+}
+
+#[derive(Clone)]
+pub struct Resolver {
+    system: GaiResolver,
+    family: Option<Family>,
+    /// Addresses from --resolve by lowercased host name
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl Resolver {
+    pub fn new(net: &NetworkArgs) -> Self {
+        let family = if net.ipv4 {
+            Some(Family::V4)
+        } else if net.ipv6 {
+            Some(Family::V6)
+        } else {
+            None
+        };
+        let mut overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for (host, addrs) in &net.resolve {
+            overrides.entry(host.clone()).or_default().extend(addrs);
+        }
+        Self {
+            system: GaiResolver::new(),
+            family,
+            overrides: Arc::new(overrides),
+        }
+    }
+}
+
+type ResolveFuture =
+    Pin<Box<dyn Future<Output = Result<std::vec::IntoIter<SocketAddr>, std::io::Error>> + Send>>;
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = ResolveFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.system.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let family = self.family;
+        let host = name.as_str().to_string();
+        let found: Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> =
+            match self.overrides.get(&host.to_ascii_lowercase()) {
+                Some(ips) => {
+                    debug!("Using --resolve for {host}: {ips:?}");
+                    // Port 0 is replaced with the URL's port by the connector
+                    let addrs = ips.iter().map(|&ip| SocketAddr::new(ip, 0)).collect();
+                    Box::pin(std::future::ready(Ok(addrs)))
+                }
+                None => {
+                    let lookup = self.system.call(name);
+                    Box::pin(async move { Ok(lookup.await?.collect()) })
+                }
+            };
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = found
+                .await?
+                .into_iter()
+                .filter(|addr| match family {
+                    Some(Family::V4) => addr.is_ipv4(),
+                    Some(Family::V6) => addr.is_ipv6(),
+                    None => true,
+                })
+                .collect();
+            if let Some(family) = family.filter(|_| addrs.is_empty()) {
+                let wanted = match family {
+                    Family::V4 => "IPv4",
+                    Family::V6 => "IPv6",
+                };
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{host} has no {wanted} address"),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
@@ -1,100 +1,1063 @@
-use crate::commands::run_segment_command_async;
-use crate::http_client::{fetch_with_retry, HttpClient};
-use crate::output::OutputFile;
-use m3u8_rs::{MediaPlaylist, Playlist};
-use std::collections::HashSet;
+use crate::commands::{self, run_marker_command_async};
+use crate::disk::DiskGuard;
+use crate::error::StreamError;
+use crate::http_client::{
+    fetch_if_modified_with_retry, fetch_range_with_retry, fetch_streaming_with_retry,
+    fetch_with_retry, HttpClient, Validators,
+};
+use crate::output::{CompletedFile, OutputFile, OutputOptions};
+use crate::playlist::{self, Marker, MarkerKind};
+use crate::preroll::{PreRoll, PreRollEvent};
+use crate::state::{self, RecordingState};
+use aes::cipher::{block_padding::Pkcs7, BlockModeDecrypt, KeyIvInit};
+use hyper::body::Bytes;
+use m3u8_rs::{Key, KeyMethod, MediaPlaylist, Playlist};
+use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use url::Url;
 
+pub use crate::cli::LowDiskAction;
+pub use crate::commands::{Hooks, SharedHooks};
+pub use crate::disk::LowDiskPolicy;
+pub use crate::preroll::PreRollConfig;
+pub use crate::stats::RecordingStats;
+
 pub struct DownloadConfig {
     pub media_url: Url,
     pub output_dir: PathBuf,
     pub file_extension: String,
     pub segment_secs: u64,
+    /// Name files by EXT-X-PROGRAM-DATE-TIME when the playlist has it
+    pub pdt_filenames: bool,
+    /// Start a new file at every EXT-X-DISCONTINUITY
+    pub split_on_discontinuity: bool,
+    /// Leave out segments inside cue-out (ad break) windows
+    pub skip_ads: bool,
+    /// Log markers and run --on-marker for them. Off for the audio
+    /// rendition, whose playlist repeats the video's markers.
+    pub report_markers: bool,
     pub poll_interval: u64,
     pub max_failures: u32,
     pub timeout: Duration,
     pub retries: u32,
     pub retry_delay_ms: u64,
-    pub on_segment: Option<String>,
-    pub verbose: bool,
-    pub progress: bool,
+    /// Segments fetched at once
+    pub concurrency: usize,
+    /// Limit on bytes fetched ahead of the segment being written, across
+    /// all of the concurrent fetches
+    pub max_in_flight_bytes: Option<u64>,
+    pub output_options: OutputOptions,
+    /// Keep track of progress here, and pick up from it at startup
+    pub state_file: Option<PathBuf>,
+    /// --min-free-mb and what to do below it
+    pub low_disk: Option<LowDiskPolicy>,
+    /// Buffer segments and only save them around a trigger (--pre-roll)
+    pub pre_roll: Option<PreRollConfig>,
+    pub hooks: SharedHooks,
+}
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// AES-128 keys kept around; a playlist rarely references more than two
+const KEY_CACHE_SIZE: usize = 8;
+
+/// An fMP4 init segment (EXT-X-MAP), which has to start every file
+#[derive(Debug, Clone, PartialEq)]
+struct InitSegment {
+    url: Url,
+    /// Offset and length, for an EXT-X-MAP with a BYTERANGE
+    range: Option<(u64, u64)>,
+    /// The EXT-X-KEY in effect at the EXT-X-MAP, which encrypts it too
+    key: Option<Key>,
+}
+
+/// A segment being fetched, waiting for its turn to be written
+struct InFlight {
+    uri: String,
+    url: Url,
+    key: Option<Key>,
+    /// The init segment of an fMP4 stream
+    init: Option<InitSegment>,
+    sequence: u64,
+    lag: Duration,
+    program_date_time: Option<chrono::DateTime<chrono::FixedOffset>>,
+    discontinuity: bool,
+    /// Media duration from EXTINF, for VOD progress
+    duration: Duration,
+    fetch: SegmentFetch,
+}
+
+/// A running download of one segment body
+struct SegmentFetch {
+    /// The body as it arrives
+    chunks: mpsc::UnboundedReceiver<Chunk>,
+    /// Set once the segment is the one being written, which frees it from
+    /// the in-flight limit
+    writing: watch::Sender<bool>,
+    /// Finishes with the body size once every chunk has been sent
+    task: tokio::task::JoinHandle<Result<u64, StreamError>>,
+}
+
+/// Why a segment didn't make it into the output whole
+enum SegmentError {
+    /// The download failed, after its retries
+    Fetch(StreamError),
+    /// The body isn't a usable segment, e.g. an error page served with 200
+    Invalid(String),
+}
+
+impl std::fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SegmentError::Fetch(e) => write!(f, "{e}"),
+            SegmentError::Invalid(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// Checks a segment body as it streams in before it reaches the output.
+/// Anything that starts like markup is an error page, not media. A body
+/// that starts with the TS sync pattern (0x47 every 188 bytes) is also held
+/// back to whole packets, each of which has to start with the sync byte, so
+/// a truncated last packet is never written. Anything else, such as packed
+/// ADTS or ID3-tagged audio, passes through as it is.
+struct SegmentCheck {
+    /// Whether the body is TS, `None` until enough of it has arrived to
+    /// look for a second sync byte
+    ts: Option<bool>,
+    /// Bytes seen so far
+    offset: u64,
+    /// The start of a packet that hasn't fully arrived (TS only)
+    partial: Vec<u8>,
+}
+
+impl SegmentCheck {
+    /// A check that sniffs for TS, or with `sniff` false one that only
+    /// looks for error pages
+    fn new(sniff: bool) -> Self {
+        Self {
+            ts: if sniff { None } else { Some(false) },
+            offset: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    /// The part of `data` that's ready to write
+    fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, SegmentError> {
+        if self.offset == 0 && looks_like_markup(data) {
+            return Err(SegmentError::Invalid(
+                "got an HTML/XML page instead of a media segment".into(),
+            ));
+        }
+        let start = self.offset - self.partial.len() as u64;
+        self.offset += data.len() as u64;
+        self.partial.extend_from_slice(data);
+        if self.ts.is_none() {
+            if self.partial.len() <= TS_PACKET_SIZE {
+                return Ok(Vec::new());
+            }
+            self.ts = Some(has_ts_sync(&self.partial));
+        }
+        if self.ts == Some(false) {
+            return Ok(std::mem::take(&mut self.partial));
+        }
+        self.take_packets(start)
+    }
+
+    /// Split the whole packets off the held-back bytes, which start at
+    /// `start` in the body
+    fn take_packets(&mut self, start: u64) -> Result<Vec<u8>, SegmentError> {
+        let whole = self.partial.len() / TS_PACKET_SIZE * TS_PACKET_SIZE;
+        let rest = self.partial.split_off(whole);
+        let packets = std::mem::replace(&mut self.partial, rest);
+        if let Some(bad) = packets
+            .chunks(TS_PACKET_SIZE)
+            .position(|packet| packet[0] != TS_SYNC_BYTE)
+        {
+            return Err(SegmentError::Invalid(format!(
+                "not MPEG-TS: no sync byte at offset {}",
+                start + (bad * TS_PACKET_SIZE) as u64
+            )));
+        }
+        Ok(packets)
+    }
+
+    /// Check the end of the body, returning what's still to be written and
+    /// how many bytes of an incomplete last packet were left out
+    fn finish(&mut self) -> Result<(Vec<u8>, usize), SegmentError> {
+        let start = self.offset - self.partial.len() as u64;
+        let rest = match self.ts {
+            // Too short for a second sync byte: go by the first
+            None if self.partial.first() != Some(&TS_SYNC_BYTE) => {
+                std::mem::take(&mut self.partial)
+            }
+            None | Some(true) => self.take_packets(start)?,
+            Some(false) => Vec::new(),
+        };
+        let written = self.offset - self.partial.len() as u64;
+        if written == 0 {
+            return Err(SegmentError::Invalid(if looks_like_markup(&self.partial) {
+                "got an HTML/XML page instead of a media segment".into()
+            } else {
+                format!("only {} bytes, not a whole TS packet", self.offset)
+            }));
+        }
+        Ok((rest, self.partial.len()))
+    }
+}
+
+/// Whether a body starts with the TS sync byte repeated a packet apart
+fn has_ts_sync(data: &[u8]) -> bool {
+    data.iter()
+        .step_by(TS_PACKET_SIZE)
+        .take(3)
+        .all(|&b| b == TS_SYNC_BYTE)
+}
+
+/// Whether a body starts like an HTML or XML document
+fn looks_like_markup(data: &[u8]) -> bool {
+    data.iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|&b| b == b'<')
+}
+
+/// Part of a segment body, holding its share of the in-flight limit until
+/// it's written
+struct Chunk {
+    data: Bytes,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Bytes a segment fetch may hold before the writer gets to it
+/// (--max-in-flight-mb), shared by all the fetches
+#[derive(Clone)]
+struct InFlightLimit {
+    semaphore: Arc<Semaphore>,
+    /// Most permits one chunk takes, so a chunk larger than the whole limit
+    /// doesn't wait forever
+    max_permits: u32,
+}
+
+impl InFlightLimit {
+    fn new(bytes: u64) -> Self {
+        let permits = usize::try_from(bytes)
+            .unwrap_or(usize::MAX)
+            .clamp(1, Semaphore::MAX_PERMITS);
+        Self {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            max_permits: u32::try_from(permits).unwrap_or(u32::MAX),
+        }
+    }
+
+    /// Take room for `bytes`, or nothing once the segment is the one being
+    /// written: the writer drains it as it arrives, and making it wait for
+    /// the segments queued behind it would deadlock
+    async fn reserve(
+        &self,
+        bytes: usize,
+        mut writing: watch::Receiver<bool>,
+    ) -> Option<OwnedSemaphorePermit> {
+        let permits = u32::try_from(bytes)
+            .unwrap_or(u32::MAX)
+            .min(self.max_permits);
+        tokio::select! {
+            biased;
+            _ = writing.wait_for(|writing| *writing) => None,
+            permit = self.semaphore.clone().acquire_many_owned(permits) => permit.ok(),
+        }
+    }
 }
 
 pub struct TsDownloader {
     config: DownloadConfig,
     output: OutputFile,
-    seen_segments: HashSet<String>,
+    /// Media sequence number of the newest segment taken from the playlist
+    last_sequence: Option<u64>,
+    /// Media sequence number of the newest segment written (or given up
+    /// on), which is what --state-file records
+    written_sequence: Option<u64>,
     consecutive_failures: u32,
+    /// ETag/Last-Modified of the last playlist response, for conditional polls
+    playlist_validators: Validators,
+    /// Shared limit on bytes fetched but not yet written
+    in_flight_limit: Option<InFlightLimit>,
+    disk: Option<DiskGuard>,
+    pre_roll: Option<PreRoll>,
+    /// Recently used AES-128 keys by absolute key URL, newest last. Streams
+    /// that rotate keys would otherwise grow this forever.
+    keys: VecDeque<(Url, [u8; 16])>,
+    /// EXT-X-DISCONTINUITY tags seen so far
+    discontinuities: u64,
+    /// Newest media sequence number whose markers, discontinuity and ad
+    /// break accounting are done. It runs ahead of `last_sequence` when a
+    /// failed segment is taken again on the next poll.
+    walked_through: Option<u64>,
+    /// Sequence numbers left out as ads, so they stay out when a failed
+    /// segment before them makes the next poll go over them again
+    skipped_ads: VecDeque<u64>,
+    /// Init segment of the segment being written (fMP4), and its bytes
+    init: Option<(InitSegment, Vec<u8>)>,
+    /// Init segment at the start of the current file
+    file_init: Option<InitSegment>,
+    /// Seconds left in the current ad break (infinite until EXT-X-CUE-IN
+    /// when the cue-out had no duration), None outside a break
+    ad_break_left: Option<f64>,
+}
+
+/// Parse an EXT-X-KEY IV attribute (`0x` followed by 32 hex digits)
+fn parse_iv(iv: &str) -> Option<[u8; 16]> {
+    let hex = iv.strip_prefix("0x").or_else(|| iv.strip_prefix("0X"))?;
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut out = [0u8; 16];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
 }
 
 impl TsDownloader {
     pub fn new(config: DownloadConfig) -> std::io::Result<Self> {
-        let output = OutputFile::new(
-            config.file_extension.clone(),
-            config.output_dir.clone(),
-            Duration::from_secs(config.segment_secs),
-            config.verbose,
-        )?;
+        let state = config
+            .state_file
+            .as_deref()
+            .and_then(|path| RecordingState::load(path, &config.media_url));
+        let resumed = state.as_ref().and_then(|state| {
+            OutputFile::resume(
+                config.file_extension.clone(),
+                config.output_dir.clone(),
+                Duration::from_secs(config.segment_secs),
+                config.pdt_filenames,
+                config.output_options.clone(),
+                &state.output,
+            )
+            .inspect_err(|e| {
+                warn!(
+                    "Can't resume {}, starting a new file: {e}",
+                    state.output.path.display()
+                )
+            })
+            .ok()
+        });
+        let output = match resumed {
+            Some(output) => output,
+            None => OutputFile::new(
+                config.file_extension.clone(),
+                config.output_dir.clone(),
+                Duration::from_secs(config.segment_secs),
+                config.pdt_filenames,
+                config.output_options.clone(),
+            )?,
+        };
+        let last_sequence = state.and_then(|state| state.last_sequence);
+        if let Some(sequence) = last_sequence {
+            info!(
+                file = %output.current_path().display(),
+                "Resuming after segment {sequence}"
+            );
+        }
 
+        let in_flight_limit = config.max_in_flight_bytes.map(InFlightLimit::new);
+        let disk = DiskGuard::new(
+            config.output_dir.clone(),
+            config.low_disk,
+            config.hooks.clone(),
+            config.output_options.filename_template.clone(),
+        );
+        let pre_roll = config.pre_roll.clone().map(PreRoll::new);
         Ok(Self {
             config,
             output,
-            seen_segments: HashSet::new(),
+            in_flight_limit,
+            disk,
+            pre_roll,
+            last_sequence,
+            written_sequence: last_sequence,
             consecutive_failures: 0,
+            playlist_validators: Validators::default(),
+            keys: VecDeque::new(),
+            discontinuities: 0,
+            walked_through: last_sequence,
+            skipped_ads: VecDeque::new(),
+            ad_break_left: None,
+            init: None,
+            file_init: None,
         })
     }
 
+    /// Record progress in the --state-file, if there is one
+    fn save_state(&self) {
+        let Some(path) = &self.config.state_file else {
+            return;
+        };
+        let state = RecordingState {
+            stream: state::stream_key(&self.config.media_url),
+            last_sequence: self.written_sequence,
+            output: self.output.resume_point(),
+        };
+        if let Err(e) = state.save(path) {
+            warn!("Failed to write state file {}: {e}", path.display());
+        }
+    }
+
+    /// Write to the current file, or into the pre-roll buffer while there's
+    /// no event to record. A file that `data` would take past
+    /// --segment-bytes is completed first.
+    fn write_output(
+        &mut self,
+        data: &[u8],
+        sequence: u64,
+        program_date_time: Option<chrono::DateTime<chrono::FixedOffset>>,
+        stats: &RecordingStats,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) -> std::io::Result<()> {
+        match &mut self.pre_roll {
+            Some(pre_roll) if !pre_roll.is_recording() => {
+                pre_roll.hold(data, sequence, program_date_time);
+                Ok(())
+            }
+            _ => {
+                self.make_room(data.len(), stats, pending_commands)?;
+                self.start_file(program_date_time)?;
+                self.output.write(data, program_date_time)
+            }
+        }
+    }
+
+    /// Complete the current file if `len` more bytes would take it past
+    /// --segment-bytes
+    fn make_room(
+        &mut self,
+        len: usize,
+        stats: &RecordingStats,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) -> std::io::Result<()> {
+        if let Some(completed) = self.output.make_room(len)? {
+            debug!(file = %completed.path.display(), "Rotated before --segment-bytes");
+            stats.set_current_file(self.output.current_path());
+            pending_commands.extend(commands::file_completed(&self.config.hooks, completed));
+        }
+        Ok(())
+    }
+
+    /// Write the init segment at the start of a new file of an fMP4 stream,
+    /// so each file plays on its own. A pipe has no new file to start, so a
+    /// new init segment goes into the stream where it changes.
+    fn start_file(
+        &mut self,
+        program_date_time: Option<chrono::DateTime<chrono::FixedOffset>>,
+    ) -> std::io::Result<()> {
+        let init = self.init.as_ref().map(|(init, _)| init);
+        if !self.output.is_empty() && self.file_init.as_ref() == init {
+            return Ok(());
+        }
+        if !self.output.is_empty() {
+            debug!("Writing the new init segment into the pipe");
+        }
+        self.file_init = self.init.as_ref().map(|(init, _)| init.clone());
+        if let Some((_, ref bytes)) = self.init {
+            self.output.write(bytes, program_date_time)?;
+        }
+        Ok(())
+    }
+
+    /// Fetch the init segment for the segment about to be written, unless
+    /// it's the one fetched last
+    async fn load_init(
+        &mut self,
+        client: &HttpClient,
+        init: &InitSegment,
+        sequence: u64,
+    ) -> Result<(), StreamError> {
+        if self.init.as_ref().is_some_and(|(cached, _)| cached == init) {
+            return Ok(());
+        }
+        let bytes = fetch_range_with_retry(
+            client,
+            init.url.as_str(),
+            init.range,
+            self.config.timeout,
+            self.config.retries,
+            self.config.retry_delay_ms,
+        )
+        .await?;
+        let bytes = match init.key {
+            Some(ref key) if playlist::is_supported_key(key) => {
+                self.decrypt_segment(client, key, sequence, bytes).await?
+            }
+            _ => bytes,
+        };
+        debug!(init = %init.url, bytes = bytes.len(), "Fetched init segment");
+        self.init = Some((init.clone(), bytes));
+        Ok(())
+    }
+
+    /// Start a clip when the trigger has fired, writing out the pre-roll
+    /// buffer first, and close it once the post-roll is over
+    fn poll_pre_roll(
+        &mut self,
+        stats: &RecordingStats,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) -> std::io::Result<()> {
+        let Some(pre_roll) = &mut self.pre_roll else {
+            return Ok(());
+        };
+        match pre_roll.poll() {
+            PreRollEvent::Continue => {}
+            PreRollEvent::Start(buffered) => {
+                let secs: f64 = buffered.iter().map(|s| s.duration.as_secs_f64()).sum();
+                info!("Triggered, saving {secs:.1}s of pre-roll");
+                self.output.restart_clock();
+                for segment in buffered {
+                    self.make_room(segment.data.len(), stats, pending_commands)?;
+                    self.output.set_sequence(segment.sequence);
+                    self.start_file(segment.program_date_time)?;
+                    self.output
+                        .write(&segment.data, segment.program_date_time)?;
+                    self.output
+                        .add_segment(Some(segment.sequence), segment.duration, false);
+                }
+            }
+            PreRollEvent::Stop => {
+                let completed = self.output.rotate()?;
+                info!(file = %completed.path.display(), "Post-roll over, saved clip");
+                stats.set_current_file(self.output.current_path());
+                pending_commands.extend(commands::file_completed(&self.config.hooks, completed));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the free space once a new file has been started. False when
+    /// --low-disk says to stop.
+    fn check_disk(&mut self) -> bool {
+        let current = self.output.current_path();
+        self.disk
+            .as_mut()
+            .is_none_or(|disk| disk.check_new_file(&current))
+    }
+
+    /// Close the current file at the end of the recording. With --pre-roll
+    /// the file waiting for the next event is removed if nothing triggered.
+    fn finish_output(&mut self) -> std::io::Result<Option<CompletedFile>> {
+        let completed = self.output.finalize()?;
+        self.save_state();
+        if self.pre_roll.is_some() && self.output.is_empty() {
+            std::fs::remove_file(&completed.path)?;
+            return Ok(None);
+        }
+        Ok(Some(completed))
+    }
+
+    /// Start sequence tracking over when the playlist's numbering jumps far
+    /// back, as it does when the encoder restarts. A playlist that's only a
+    /// little behind is a stale CDN copy and is left to the normal dedup.
+    fn check_sequence_reset(&mut self, media_playlist: &MediaPlaylist) {
+        let (Some(last), Some(len)) = (
+            self.last_sequence,
+            std::num::NonZeroU64::new(media_playlist.segments.len() as u64),
+        ) else {
+            return;
+        };
+        let newest = media_playlist.media_sequence + len.get() - 1;
+        if newest + 3 * len.get() < last {
+            warn!(
+                "Media sequence went back from {last} to {newest}, assuming the stream restarted"
+            );
+            self.last_sequence = media_playlist.media_sequence.checked_sub(1);
+            self.walked_through = self.last_sequence;
+            self.skipped_ads.clear();
+        }
+    }
+
+    /// Track ad breaks and fire --on-marker for a marker on a new segment
+    fn handle_marker(
+        &mut self,
+        marker: &Marker,
+        pdt: Option<chrono::DateTime<chrono::FixedOffset>>,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) {
+        match marker.kind {
+            MarkerKind::CueOut => {
+                self.ad_break_left = Some(marker.duration_secs.unwrap_or(f64::INFINITY))
+            }
+            MarkerKind::CueIn => self.ad_break_left = None,
+            MarkerKind::DateRange => {}
+        }
+        if !self.config.report_markers {
+            return;
+        }
+        info!(
+            marker = marker.kind.as_str(),
+            id = marker.id.as_deref().unwrap_or(""),
+            duration_secs = marker.duration_secs,
+            "Marker"
+        );
+        if let Some(cmd) = Hooks::on_marker(&self.config.hooks) {
+            let time = marker
+                .start
+                .or(pdt)
+                .unwrap_or_else(|| chrono::Local::now().fixed_offset());
+            pending_commands.push(run_marker_command_async(
+                &cmd,
+                marker,
+                &time,
+                &self.output.current_path(),
+                Hooks::url(&self.config.hooks),
+            ));
+        }
+    }
+
+    /// Start downloading a segment body in the background
+    fn start_fetch(&self, client: &HttpClient, url: Url, uri: &str, lag: Duration) -> SegmentFetch {
+        let (chunk_tx, chunks) = mpsc::unbounded_channel();
+        let (writing, writing_rx) = watch::channel(false);
+        let client = client.clone();
+        let limit = self.in_flight_limit.clone();
+        let (timeout, retries, retry_delay_ms) = (
+            self.config.timeout,
+            self.config.retries,
+            self.config.retry_delay_ms,
+        );
+        let task = tokio::spawn(
+            async move {
+                fetch_streaming_with_retry(
+                    &client,
+                    url.as_str(),
+                    timeout,
+                    retries,
+                    retry_delay_ms,
+                    |data| {
+                        let (chunk_tx, limit, writing) =
+                            (chunk_tx.clone(), limit.clone(), writing_rx.clone());
+                        async move {
+                            let permit = match limit {
+                                Some(limit) => limit.reserve(data.len(), writing).await,
+                                None => None,
+                            };
+                            chunk_tx
+                                .send(Chunk {
+                                    data,
+                                    _permit: permit,
+                                })
+                                .is_ok()
+                        }
+                    },
+                )
+                .await
+            }
+            .instrument(info_span!(
+                "segment_fetch",
+                segment = %uri,
+                lag_secs = lag.as_secs_f64()
+            )),
+        );
+        SegmentFetch {
+            chunks,
+            writing,
+            task,
+        }
+    }
+
+    /// Write a segment to the output as it's fetched, rotating the file
+    /// when it's due. An encrypted segment is collected and decrypted first.
+    /// A body that isn't a valid segment is fetched again, and skipped with
+    /// a warning if it's still bad, rather than written into the recording.
+    ///
+    /// Returns true if the segment failed with nothing written and
+    /// `retry_later` allows taking it again on the next playlist poll.
+    async fn write_segment(
+        &mut self,
+        client: &HttpClient,
+        mut segment: InFlight,
+        retry_later: bool,
+        stats: &RecordingStats,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) -> Result<bool, StreamError> {
+        self.poll_pre_roll(stats, pending_commands)?;
+        // Each file has to start with the init segment of what follows it;
+        // a new one (a new rendition, say) gets a new file
+        if self.file_init.is_some()
+            && self.file_init != segment.init
+            && !self.output.is_empty()
+            && !self.output.is_pipe()
+        {
+            let completed = self.output.rotate()?;
+            info!(file = %completed.path.display(), "Rotated at new init segment");
+            stats.set_current_file(self.output.current_path());
+            pending_commands.extend(commands::file_completed(&self.config.hooks, completed));
+        }
+        if segment.discontinuity {
+            // Keep timestamp jumps out of the middle of a file
+            if self.config.split_on_discontinuity && !self.output.is_empty() {
+                let completed = self.output.rotate()?;
+                info!(file = %completed.path.display(), "Rotated at discontinuity");
+                stats.set_current_file(self.output.current_path());
+                pending_commands.extend(commands::file_completed(&self.config.hooks, completed));
+            }
+        }
+
+        let write_span = info_span!("segment_write", bytes = tracing::field::Empty);
+        let mut written: u64 = 0;
+        let mut refetches: u32 = 0;
+        let result = loop {
+            let result = self
+                .write_fetched(
+                    client,
+                    &mut segment,
+                    stats,
+                    pending_commands,
+                    &write_span,
+                    &mut written,
+                )
+                .await?;
+            match result {
+                Err(SegmentError::Invalid(reason))
+                    if written == 0 && refetches < self.config.retries =>
+                {
+                    refetches += 1;
+                    warn!(
+                        segment = %segment.uri,
+                        "Bad segment (fetching again {refetches}/{}): {reason}",
+                        self.config.retries
+                    );
+                    tokio::time::sleep(Duration::from_millis(self.config.retry_delay_ms)).await;
+                    segment.fetch =
+                        self.start_fetch(client, segment.url.clone(), &segment.uri, segment.lag);
+                }
+                result => break result,
+            }
+        };
+        write_span.record("bytes", written);
+        match result {
+            Ok(truncated) => {
+                if truncated > 0 {
+                    warn!(
+                        segment = %segment.uri,
+                        "Segment ends in an incomplete TS packet, left out its {truncated} bytes"
+                    );
+                }
+                // The bytes were counted as they were written
+                stats.add_segment(0);
+                stats.set_live_lag(segment.lag);
+                debug!(segment = %segment.uri, bytes = written, "Wrote segment");
+                // Buffered pre-roll doesn't fill files, or rotate them
+                if self.pre_roll.as_ref().is_none_or(PreRoll::is_recording) {
+                    self.output.add_segment(
+                        Some(segment.sequence),
+                        segment.duration,
+                        segment.discontinuity,
+                    );
+                    if let Some(completed) = self.output.maybe_rotate()? {
+                        stats.set_current_file(self.output.current_path());
+                        pending_commands
+                            .extend(commands::file_completed(&self.config.hooks, completed));
+                    }
+                }
+            }
+            Err(e) if written > 0 => {
+                stats.add_segment_error();
+                warn!(
+                    segment = %segment.uri,
+                    "Segment error after {written} bytes were written (giving up on the rest): {e}"
+                );
+            }
+            // An unsupported key won't be any better next time
+            Err(e)
+                if retry_later && segment.key.as_ref().is_none_or(playlist::is_supported_key) =>
+            {
+                stats.add_segment_error();
+                warn!(segment = %segment.uri, "Segment error (trying again on the next poll): {e}");
+                return Ok(true);
+            }
+            Err(e) => {
+                stats.add_segment_error();
+                warn!(segment = %segment.uri, "Segment error (skipped): {e}");
+            }
+        }
+        if let Some(pre_roll) = &mut self.pre_roll {
+            pre_roll.end_segment(segment.duration);
+        }
+        self.written_sequence = Some(segment.sequence);
+        stats.add_done_duration(segment.duration);
+        self.save_state();
+        Ok(false)
+    }
+
+    /// The playlist has ENDLIST, so it's a finite download: report its size
+    /// up front and give progress a total to count towards. Segments from
+    /// before a resume (or written while the stream was still live) count
+    /// as done already.
+    fn start_vod(&self, media_playlist: &MediaPlaylist, stats: &RecordingStats) {
+        let mut total = Duration::ZERO;
+        let mut done = Duration::ZERO;
+        for (index, segment) in media_playlist.segments.iter().enumerate() {
+            let duration = playlist::segment_duration(segment);
+            total += duration;
+            let sequence = media_playlist.media_sequence + index as u64;
+            if self.last_sequence.is_some_and(|last| sequence <= last) {
+                done += duration;
+            }
+        }
+        info!(
+            segments = media_playlist.segments.len(),
+            "VOD playlist: {} of media",
+            commands::format_duration(total.as_secs())
+        );
+        stats.set_expected_segments(media_playlist.segments.len() as u64);
+        stats.set_expected_duration(total);
+        stats.add_done_duration(done);
+    }
+
+    /// Write one fetch of a segment through a `SegmentCheck`, returning the
+    /// bytes of an incomplete last packet that were left out. The outer
+    /// error is a failure to write the output, which ends the recording.
+    async fn write_fetched(
+        &mut self,
+        client: &HttpClient,
+        segment: &mut InFlight,
+        stats: &RecordingStats,
+        pending_commands: &mut Vec<tokio::task::JoinHandle<()>>,
+        write_span: &tracing::Span,
+        written: &mut u64,
+    ) -> Result<Result<usize, SegmentError>, StreamError> {
+        segment.fetch.writing.send_replace(true);
+        self.output.set_sequence(segment.sequence);
+        if let Some(ref init) = segment.init {
+            if let Err(e) = self.load_init(client, init, segment.sequence).await {
+                segment.fetch.task.abort();
+                return Ok(Err(SegmentError::Fetch(
+                    format!("init segment {}: {e}", init.url).into(),
+                )));
+            }
+        }
+        // fMP4 fragments aren't TS, whatever the extension
+        let mut check = SegmentCheck::new(segment.init.is_none());
+        let mut collected = Vec::new();
+        match segment.key {
+            Some(ref k) if playlist::is_supported_key(k) => {
+                let mut data = Vec::new();
+                while let Some(chunk) = segment.fetch.chunks.recv().await {
+                    data.extend_from_slice(&chunk.data);
+                }
+                if let Err(e) = join_fetch(&mut segment.fetch.task).await {
+                    return Ok(Err(SegmentError::Fetch(e)));
+                }
+                let data = match self
+                    .decrypt_segment(client, k, segment.sequence, data)
+                    .await
+                {
+                    Ok(data) => data,
+                    Err(e) => return Ok(Err(SegmentError::Fetch(e))),
+                };
+                let data = match check.push(&data) {
+                    Ok(data) => data,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let _write = write_span.enter();
+                self.write_output(
+                    &data,
+                    segment.sequence,
+                    segment.program_date_time,
+                    stats,
+                    pending_commands,
+                )?;
+                *written += data.len() as u64;
+                stats.add_bytes(data.len() as u64);
+            }
+            Some(ref k) => {
+                segment.fetch.task.abort();
+                return Ok(Err(SegmentError::Fetch(
+                    playlist::unsupported_key_message(k).into(),
+                )));
+            }
+            None => {
+                // With --segment-bytes a file can only be cut between
+                // segments, so the whole segment's size has to be known
+                let whole = self.config.output_options.segment_bytes.is_some();
+                while let Some(chunk) = segment.fetch.chunks.recv().await {
+                    let data = match check.push(&chunk.data) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            segment.fetch.task.abort();
+                            return Ok(Err(e));
+                        }
+                    };
+                    if whole {
+                        collected.extend_from_slice(&data);
+                        continue;
+                    }
+                    let _write = write_span.enter();
+                    self.write_output(
+                        &data,
+                        segment.sequence,
+                        segment.program_date_time,
+                        stats,
+                        pending_commands,
+                    )?;
+                    *written += data.len() as u64;
+                    stats.add_bytes(data.len() as u64);
+                }
+                if let Err(e) = join_fetch(&mut segment.fetch.task).await {
+                    return Ok(Err(SegmentError::Fetch(e)));
+                }
+            }
+        }
+        let (rest, left_out) = match check.finish() {
+            Ok(finished) => finished,
+            Err(e) => return Ok(Err(e)),
+        };
+        collected.extend_from_slice(&rest);
+        let rest = collected;
+        if !rest.is_empty() {
+            let _write = write_span.enter();
+            self.write_output(
+                &rest,
+                segment.sequence,
+                segment.program_date_time,
+                stats,
+                pending_commands,
+            )?;
+            *written += rest.len() as u64;
+            stats.add_bytes(rest.len() as u64);
+        }
+        Ok(Ok(left_out))
+    }
+
+    /// Decrypt an AES-128 segment. Without an explicit IV the segment's
+    /// media sequence number is used, per the HLS spec.
+    async fn decrypt_segment(
+        &mut self,
+        client: &HttpClient,
+        key: &Key,
+        sequence: u64,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, StreamError> {
+        let key_uri = key
+            .uri
+            .as_deref()
+            .ok_or_else(|| StreamError::parse("EXT-X-KEY without a URI"))?;
+        let key_url = client.final_url(&self.config.media_url).join(key_uri)?;
+        let key_bytes = match self.keys.iter().find(|(url, _)| *url == key_url) {
+            Some((_, bytes)) => *bytes,
+            None => {
+                let fetched = fetch_with_retry(
+                    client,
+                    key_url.as_str(),
+                    self.config.timeout,
+                    self.config.retries,
+                    self.config.retry_delay_ms,
+                )
+                .await?;
+                let bytes: [u8; 16] = fetched.as_slice().try_into().map_err(|_| {
+                    format!("Key {key_url} is {} bytes, expected 16", fetched.len())
+                })?;
+                debug!(key = %key_url, "Fetched AES-128 key");
+                if self.keys.len() == KEY_CACHE_SIZE {
+                    self.keys.pop_front();
+                }
+                self.keys.push_back((key_url, bytes));
+                bytes
+            }
+        };
+        let iv = match key.iv.as_deref() {
+            Some(iv) => {
+                parse_iv(iv).ok_or_else(|| StreamError::parse(format!("Invalid IV {iv}")))?
+            }
+            None => u128::from(sequence).to_be_bytes(),
+        };
+
+        Aes128CbcDec::new(&key_bytes.into(), &iv.into())
+            .decrypt_padded_vec::<Pkcs7>(&data)
+            .map_err(|_| {
+                StreamError::parse("AES-128 decryption failed (wrong key or truncated segment)")
+            })
+    }
+
     pub async fn run(
         &mut self,
         client: &HttpClient,
         shutdown: Arc<AtomicBool>,
-    ) -> Result<(u64, Vec<tokio::task::JoinHandle<()>>), Box<dyn std::error::Error + Send + Sync>>
-    {
+        rotate: Arc<AtomicBool>,
+        stats: Arc<RecordingStats>,
+    ) -> Result<(u64, Vec<tokio::task::JoinHandle<()>>), StreamError> {
         let mut finalized = false;
+        let mut gave_up = None;
         let mut pending_commands: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+        stats.set_current_file(self.output.current_path());
 
         loop {
+            stats.touch();
+
+            if !self.check_disk() {
+                shutdown.store(true, Ordering::SeqCst);
+            }
             // Check for shutdown signal
             if shutdown.load(Ordering::SeqCst) {
-                let final_path = self.output.finalize()?;
                 finalized = true;
-                eprintln!("Flushed current segment: {}", final_path.display());
-                if let Some(ref cmd) = self.config.on_segment {
-                    let handle =
-                        run_segment_command_async(cmd.clone(), final_path, self.config.verbose);
-                    pending_commands.push(handle);
+                if let Some(final_file) = self.finish_output()? {
+                    info!(file = %final_file.path.display(), "Flushed current segment");
+                    pending_commands
+                        .extend(commands::file_completed(&self.config.hooks, final_file));
                 }
                 break;
             }
 
-            let media_data = match fetch_with_retry(
+            // SIGHUP: close the current file now instead of waiting for
+            // --segment-secs (while --pre-roll is buffering there's no file)
+            if rotate.swap(false, Ordering::SeqCst)
+                && self.pre_roll.as_ref().is_none_or(PreRoll::is_recording)
+            {
+                let completed = self.output.rotate()?;
+                info!(file = %completed.path.display(), "Rotated on request");
+                stats.set_current_file(self.output.current_path());
+                pending_commands.extend(commands::file_completed(&self.config.hooks, completed));
+            }
+
+            let media_data = match fetch_if_modified_with_retry(
                 client,
                 self.config.media_url.as_str(),
+                &mut self.playlist_validators,
                 self.config.timeout,
                 self.config.retries,
                 self.config.retry_delay_ms,
             )
+            .instrument(info_span!("playlist_poll", url = %self.config.media_url))
             .await
             {
-                Ok(data) => data,
+                Ok(Some(data)) => data,
+                // 304: the playlist hasn't changed, so there are no new segments
+                Ok(None) => {
+                    self.consecutive_failures = 0;
+                    trace!("Playlist not modified");
+                    tokio::time::sleep(Duration::from_secs(self.config.poll_interval)).await;
+                    continue;
+                }
                 Err(e) => {
+                    stats.add_playlist_error();
                     self.consecutive_failures += 1;
                     if self.config.max_failures > 0
                         && self.consecutive_failures >= self.config.max_failures
                     {
-                        eprintln!("\nPlaylist fetch error: {e}");
-                        eprintln!(
+                        error!("Playlist fetch error: {e}");
+                        error!(
                             "Giving up after {} consecutive failures",
                             self.consecutive_failures
                         );
+                        gave_up = Some(e.context("Playlist fetch error"));
                         break;
                     }
-                    eprintln!(
-                        "\nPlaylist fetch error (retrying {}/{}): {e}",
+                    warn!(
+                        "Playlist fetch error (retrying {}/{}): {e}",
                         self.consecutive_failures, self.config.max_failures
                     );
                     tokio::time::sleep(Duration::from_secs(self.config.poll_interval)).await;
@@ -105,19 +1068,21 @@ impl TsDownloader {
             let media_playlist: MediaPlaylist = match m3u8_rs::parse_playlist(&media_data) {
                 Ok((_, Playlist::MediaPlaylist(pl))) => pl,
                 _ => {
+                    stats.add_playlist_error();
                     self.consecutive_failures += 1;
                     if self.config.max_failures > 0
                         && self.consecutive_failures >= self.config.max_failures
                     {
-                        eprintln!("\nFailed to parse media playlist");
-                        eprintln!(
+                        error!("Failed to parse media playlist");
+                        error!(
                             "Giving up after {} consecutive failures",
                             self.consecutive_failures
                         );
+                        gave_up = Some(StreamError::parse("Failed to parse media playlist"));
                         break;
                     }
-                    eprintln!(
-                        "\nFailed to parse media playlist (retrying {}/{})",
+                    warn!(
+                        "Failed to parse media playlist (retrying {}/{})",
                         self.consecutive_failures, self.config.max_failures
                     );
                     tokio::time::sleep(Duration::from_secs(self.config.poll_interval)).await;
@@ -125,63 +1090,172 @@ impl TsDownloader {
                 }
             };
 
+            trace!(
+                media_sequence = media_playlist.media_sequence,
+                segments = media_playlist.segments.len(),
+                "Fetched media playlist"
+            );
             // Reset failure counter on successful fetch+parse
             self.consecutive_failures = 0;
+            if media_playlist.end_list && stats.expected_duration().is_none() {
+                self.start_vod(&media_playlist, &stats);
+            }
+
+            self.check_sequence_reset(&media_playlist);
 
-            for segment in &media_playlist.segments {
+            // Forget skipped ads that have rolled out of the playlist
+            while self
+                .skipped_ads
+                .front()
+                .is_some_and(|&skipped| skipped < media_playlist.media_sequence)
+            {
+                self.skipped_ads.pop_front();
+            }
+
+            // A failed segment is taken again on the next poll while the
+            // playlist still lists it; the ones after it wait so the output
+            // stays in order. A VOD playlist gets no second pass.
+            let retry_later = !media_playlist.end_list;
+            let mut retry_from: Option<u64> = None;
+            // EXT-X-KEY applies to every segment after it, but the parser
+            // only attaches it to the first one
+            let mut key: Option<&Key> = None;
+            let mut init: Option<InitSegment> = None;
+            let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+            for (index, segment) in media_playlist.segments.iter().enumerate() {
                 // Check for shutdown between segments
+                if !self.check_disk() {
+                    shutdown.store(true, Ordering::SeqCst);
+                }
                 if shutdown.load(Ordering::SeqCst) {
                     break;
                 }
 
-                if self.seen_segments.contains(&segment.uri) {
-                    continue;
+                if let Some(ref k) = segment.key {
+                    key = Some(k).filter(|k| k.method != KeyMethod::None);
+                }
+                // EXT-X-MAP (the fMP4 init segment) also holds until the next one
+                if let Some(ref map) = segment.map {
+                    init = Some(InitSegment {
+                        url: client.final_url(&self.config.media_url).join(&map.uri)?,
+                        range: map
+                            .byte_range
+                            .as_ref()
+                            .map(|range| (range.offset.unwrap_or(0), range.length)),
+                        key: key.cloned(),
+                    });
                 }
-                self.seen_segments.insert(segment.uri.clone());
 
-                let segment_url = self.config.media_url.join(&segment.uri)?;
-                if self.config.progress {
-                    eprint!(".");
+                // Dedup on media sequence number rather than URI: CDNs
+                // re-sign URIs of the same segment, and some reuse URIs
+                let sequence = media_playlist.media_sequence + index as u64;
+                if self.last_sequence.is_some_and(|last| sequence <= last) {
+                    continue;
                 }
+                if let Some(last) = self.last_sequence {
+                    if sequence > last + 1 {
+                        warn!(
+                            missed = sequence - last - 1,
+                            "Sequence gap: segments {}..{} left the playlist before they were fetched",
+                            last + 1,
+                            sequence - 1
+                        );
+                    }
+                }
+                self.last_sequence = Some(sequence);
 
-                match fetch_with_retry(
-                    client,
-                    segment_url.as_str(),
-                    self.config.timeout,
-                    self.config.retries,
-                    self.config.retry_delay_ms,
-                )
-                .await
-                {
-                    Ok(data) => {
-                        self.output.write(&data)?;
-                        if let Some(completed_path) =
-                            self.output.maybe_rotate(self.config.verbose)?
-                        {
-                            if let Some(ref cmd) = self.config.on_segment {
-                                let handle = run_segment_command_async(
-                                    cmd.clone(),
-                                    completed_path,
-                                    self.config.verbose,
-                                );
-                                pending_commands.push(handle);
-                            }
+                let pdt = playlist::program_date_time(&media_playlist, index);
+                if self.walked_through.is_some_and(|walked| sequence <= walked) {
+                    // Gone over before a failed segment earlier in the
+                    // playlist; its markers have been reported already
+                    if self.skipped_ads.contains(&sequence) {
+                        continue;
+                    }
+                } else {
+                    self.walked_through = Some(sequence);
+                    if segment.discontinuity {
+                        self.discontinuities += 1;
+                        debug!(
+                            segment = %segment.uri,
+                            count = self.discontinuities,
+                            "Discontinuity"
+                        );
+                    }
+                    for marker in playlist::segment_markers(segment) {
+                        self.handle_marker(&marker, pdt, &mut pending_commands);
+                    }
+                    if self.config.skip_ads {
+                        if let Some(left) = self.ad_break_left {
+                            let left = left - segment.duration as f64;
+                            self.ad_break_left = (left > 0.0).then_some(left);
+                            debug!(segment = %segment.uri, "Skipping ad segment");
+                            self.skipped_ads.push_back(sequence);
+                            stats.add_done_duration(playlist::segment_duration(segment));
+                            continue;
                         }
                     }
-                    Err(e) => eprintln!("\nSegment error (giving up): {e}"),
+                }
+
+                let segment_url = client
+                    .final_url(&self.config.media_url)
+                    .join(&segment.uri)?;
+                let lag = playlist::live_edge_lag(&media_playlist, index);
+                let fetch = self.start_fetch(client, segment_url.clone(), &segment.uri, lag);
+                in_flight.push_back(InFlight {
+                    uri: segment.uri.clone(),
+                    url: segment_url,
+                    key: key.cloned(),
+                    init: init.clone(),
+                    sequence,
+                    lag,
+                    program_date_time: pdt,
+                    discontinuity: segment.discontinuity,
+                    duration: playlist::segment_duration(segment),
+                    fetch,
+                });
+
+                // Write in playlist order; waiting on the oldest fetch is the
+                // backpressure that keeps at most --concurrency segments in
+                // flight (and --max-in-flight-mb bounds what they buffer)
+                if in_flight.len() >= self.config.concurrency {
+                    let next = in_flight.pop_front().expect("queue is not empty");
+                    let sequence = next.sequence;
+                    if self
+                        .write_segment(client, next, retry_later, &stats, &mut pending_commands)
+                        .await?
+                    {
+                        retry_from = Some(sequence);
+                        break;
+                    }
+                }
+            }
+            while retry_from.is_none() {
+                let Some(next) = in_flight.pop_front() else {
+                    break;
+                };
+                let sequence = next.sequence;
+                if self
+                    .write_segment(client, next, retry_later, &stats, &mut pending_commands)
+                    .await?
+                {
+                    retry_from = Some(sequence);
                 }
             }
+            if let Some(sequence) = retry_from {
+                for segment in in_flight.drain(..) {
+                    segment.fetch.task.abort();
+                }
+                self.last_sequence = sequence.checked_sub(1);
+            }
 
             // For live streams, keep polling; for VOD, exit when done
             if media_playlist.end_list {
-                let final_path = self.output.finalize()?;
                 finalized = true;
-                if let Some(ref cmd) = self.config.on_segment {
-                    let handle =
-                        run_segment_command_async(cmd.clone(), final_path, self.config.verbose);
-                    pending_commands.push(handle);
+                if let Some(final_file) = self.finish_output()? {
+                    pending_commands
+                        .extend(commands::file_completed(&self.config.hooks, final_file));
                 }
-                eprintln!("\nStream ended.");
+                info!("Stream ended.");
                 break;
             }
 
@@ -190,32 +1264,32 @@ impl TsDownloader {
 
         // Ensure we finalize and call on_segment for any exit path that didn't already
         if !finalized {
-            let final_path = self.output.finalize()?;
-            eprintln!("Flushed current segment: {}", final_path.display());
-            if let Some(ref cmd) = self.config.on_segment {
-                let handle =
-                    run_segment_command_async(cmd.clone(), final_path, self.config.verbose);
-                pending_commands.push(handle);
+            if let Some(final_file) = self.finish_output()? {
+                info!(file = %final_file.path.display(), "Flushed current segment");
+                pending_commands.extend(commands::file_completed(&self.config.hooks, final_file));
             }
         }
 
         // Wait for all pending on_segment commands to complete before exiting (with timeout)
-        if !pending_commands.is_empty() {
-            let unfinished = pending_commands.iter().filter(|p| !p.is_finished()).count();
-            if unfinished > 0 {
-                eprintln!("Waiting for {} pending commands to complete...", unfinished);
-                for handle in &mut pending_commands {
-                    if !handle.is_finished() {
-                        match tokio::time::timeout(Duration::from_secs(60), handle).await {
-                            Ok(_) => {}
-                            Err(_) => eprintln!("Warning: on_segment command timed out after 60s"),
-                        }
-                    }
-                }
-            }
-        }
+        commands::wait_for_commands(&mut pending_commands).await;
 
+        if let Some(error) = gave_up {
+            return Err(StreamError::GaveUp {
+                failures: self.consecutive_failures,
+                error: Box::new(error),
+            });
+        }
         let total_bytes = self.output.total_bytes();
         Ok((total_bytes, pending_commands))
     }
 }
+
+/// The outcome of a segment fetch task
+async fn join_fetch(
+    task: &mut tokio::task::JoinHandle<Result<u64, StreamError>>,
+) -> Result<u64, StreamError> {
+    match task.await {
+        Ok(result) => result,
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,200 @@
+//! The error type of the whole recorder. Each variant is a class of failure
+//! with its own process exit code, so a wrapper script or a systemd unit can
+//! tell a stream that's down from a full disk or a bad option.
+
+use std::process::ExitCode;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StreamError {
+    /// Bad or conflicting options, or an unreadable config, jobs or
+    /// certificate file
+    #[error(transparent)]
+    Config(BoxError),
+    /// The server couldn't be reached, timed out or answered with an error
+    #[error(transparent)]
+    Network(BoxError),
+    /// A playlist, manifest, URL or media that couldn't be understood
+    #[error(transparent)]
+    Parse(BoxError),
+    /// Reading or writing local files
+    #[error(transparent)]
+    Disk(std::io::Error),
+    /// ffmpeg couldn't be started or failed
+    #[error(transparent)]
+    Ffmpeg(BoxError),
+    /// The RTSP session with a camera failed
+    #[error(transparent)]
+    Rtsp(BoxError),
+    /// Too many attempts in a row failed: --max-failures playlist fetches,
+    /// or --max-restarts restarts. `failures` is how many, for the on-error
+    /// hook; `error` is the last one.
+    #[error("{error}")]
+    GaveUp {
+        failures: u32,
+        error: Box<StreamError>,
+    },
+    /// Anything else
+    #[error(transparent)]
+    Other(BoxError),
+}
+
+impl StreamError {
+    pub fn config(e: impl Into<BoxError>) -> Self {
+        StreamError::Config(e.into())
+    }
+
+    pub fn network(e: impl Into<BoxError>) -> Self {
+        StreamError::Network(e.into())
+    }
+
+    pub fn parse(e: impl Into<BoxError>) -> Self {
+        StreamError::Parse(e.into())
+    }
+
+    pub fn ffmpeg(e: impl Into<BoxError>) -> Self {
+        StreamError::Ffmpeg(e.into())
+    }
+
+    pub fn rtsp(e: impl Into<BoxError>) -> Self {
+        StreamError::Rtsp(e.into())
+    }
+
+    /// Put `what` in front of the message, keeping the class
+    pub fn context(self, what: impl std::fmt::Display) -> Self {
+        let msg = format!("{what}: {self}");
+        match self {
+            StreamError::Config(_) => StreamError::config(msg),
+            StreamError::Network(_) => StreamError::network(msg),
+            StreamError::Parse(_) => StreamError::parse(msg),
+            StreamError::Disk(e) => StreamError::Disk(std::io::Error::new(e.kind(), msg)),
+            StreamError::Ffmpeg(_) => StreamError::ffmpeg(msg),
+            StreamError::Rtsp(_) => StreamError::rtsp(msg),
+            StreamError::GaveUp { failures, error } => StreamError::GaveUp {
+                failures,
+                error: Box::new(error.context(what)),
+            },
+            StreamError::Other(_) => StreamError::Other(msg.into()),
+        }
+    }
+
+    /// The error this one gave up after, or itself
+    pub fn cause(&self) -> &StreamError {
+        match self {
+            StreamError::GaveUp { error, .. } => error.cause(),
+            other => other,
+        }
+    }
+
+    /// The process exit code for this class of failure. 2 is also what the
+    /// command line parser exits with for a bad option.
+    pub fn exit_code(&self) -> u8 {
+        match self.cause() {
+            StreamError::Other(_) | StreamError::GaveUp { .. } => 1,
+            StreamError::Config(_) => 2,
+            StreamError::Network(_) => 3,
+            StreamError::Parse(_) => 4,
+            StreamError::Disk(_) => 5,
+            StreamError::Ffmpeg(_) => 6,
+            StreamError::Rtsp(_) => 7,
+        }
+    }
+}
+
+impl From<&StreamError> for ExitCode {
+    fn from(e: &StreamError) -> Self {
+        ExitCode::from(e.exit_code())
+    }
+}
+
+/// I/O on a socket is a network failure; anything else is taken to be the
+/// disk
+impl From<std::io::Error> for StreamError {
+    fn from(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::AddrInUse
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::TimedOut => StreamError::Network(e.into()),
+            _ => StreamError::Disk(e),
+        }
+    }
+}
+
+/// The file watcher on the output directory
+impl From<notify::Error> for StreamError {
+    fn from(e: notify::Error) -> Self {
+        match e.kind {
+            notify::ErrorKind::Io(io) => StreamError::Disk(io),
+            _ => StreamError::Disk(std::io::Error::other(e)),
+        }
+    }
+}
+
+impl From<String> for StreamError {
+    fn from(msg: String) -> Self {
+        StreamError::Other(msg.into())
+    }
+}
+
+impl From<&str> for StreamError {
+    fn from(msg: &str) -> Self {
+        StreamError::Other(msg.into())
+    }
+}
+
+macro_rules! classify {
+    ($variant:ident: $($error:ty),+ $(,)?) => {
+        $(
+            impl From<$error> for StreamError {
+                fn from(e: $error) -> Self {
+                    StreamError::$variant(e.into())
+                }
+            }
+        )+
+    };
+}
+
+classify!(Network: hyper::Error, hyper_util::client::legacy::Error, tokio::time::error::Elapsed);
+classify!(
+    Parse: url::ParseError,
+    hyper::http::Error,
+    hyper::http::uri::InvalidUri,
+    serde_json::Error,
+    std::string::FromUtf8Error,
+    std::str::Utf8Error,
+    std::num::ParseIntError,
+    std::num::ParseFloatError,
+    httparse::Error,
+    roxmltree::Error,
+);
+classify!(
+    Config: toml::de::Error,
+    hyper::header::InvalidHeaderValue
+);
+classify!(Other: tokio::task::JoinError);
+#[cfg(feature = "native-tls")]
+classify!(Config: native_tls::Error);
+#[cfg(feature = "otel")]
+classify!(Config: opentelemetry_otlp::ExporterBuildError);
+#[cfg(feature = "rustls")]
+classify!(Config: rustls::Error, rustls::pki_types::pem::Error);
+#[cfg(feature = "rtsp")]
+classify!(Rtsp: retina::Error);
+#[cfg(any(feature = "rtsp", feature = "remux", feature = "whep"))]
+classify!(Parse: mp4::Error);
+#[cfg(feature = "whep")]
+classify!(Network: webrtc::Error);
+
+/// Writing the Opus audio of a WHEP recording
+#[cfg(feature = "whep")]
+impl From<webrtc::media::Error> for StreamError {
+    fn from(e: webrtc::media::Error) -> Self {
+        StreamError::Disk(std::io::Error::other(e))
+    }
+}
@@ -1,105 +1,425 @@
-use crate::commands::run_segment_command;
-use chrono::Local;
-use std::path::PathBuf;
-use std::process::Command;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use crate::commands::{self, run_segment_command, Hooks, SharedHooks};
+use crate::error::StreamError;
+use crate::stats::RecordingStats;
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+use notify::Watcher;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use tracing::{debug, info_span, warn};
 use url::Url;
 
-#[cfg(target_os = "linux")]
-pub fn spawn_inotify_watcher(
-    file_extension: String,
-    output_dir: PathBuf,
-    on_segment: Option<String>,
-    verbose: bool,
-    total_bytes_counter: Arc<AtomicU64>,
-) {
-    use tokio_stream::StreamExt;
-
-    tokio::task::spawn(async move {
-        let inotify = inotify::Inotify::init().expect("Failed to initialize inotify");
-        inotify
-            .watches()
-            .add(&output_dir, inotify::WatchMask::CLOSE_WRITE)
-            .expect("Failed to add watch");
-
-        let mut buffer = [0u8; 4096];
-        let mut stream = inotify
-            .into_event_stream(&mut buffer)
-            .expect("Failed to create event stream");
-
-        while let Some(event_or_error) = stream.next().await {
-            let event = match event_or_error {
-                Ok(e) => e,
-                Err(e) => {
-                    eprintln!("inotify error: {e}");
-                    continue;
-                }
-            };
+enum WatchEvent {
+    Fs(notify::Result<notify::Event>),
+    Exited,
+    /// Hand over the hooks still running
+    Pending(tokio::sync::oneshot::Sender<Vec<tokio::task::JoinHandle<()>>>),
+}
+
+/// Runs the segment hooks and counts the files ffmpeg writes. ffmpeg's
+/// segment muxer closes a file before it opens the next, so a file is
+/// complete once a newer one appears in the directory, or once ffmpeg exits.
+/// Only new files are looked for, which every platform's watcher reports.
+/// The bytes are counted from the file being written as it grows: ffmpeg's
+/// own -progress size is N/A for segmented output. Stops when dropped.
+pub struct SegmentWatcher {
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::Sender<WatchEvent>,
+}
 
-            if let Some(name) = event.name {
-                let filename = name.to_string_lossy().to_string();
-                // Only process .ext files
-                if filename.ends_with(format!(".{}", file_extension).as_str()) {
-                    let filepath = output_dir.join(&filename);
+impl SegmentWatcher {
+    pub fn start(
+        file_extension: String,
+        output_dir: &Path,
+        hooks: SharedHooks,
+        stats: Arc<RecordingStats>,
+    ) -> notify::Result<Self> {
+        let (events, rx) = mpsc::channel();
+        let fs_events = events.clone();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = fs_events.send(WatchEvent::Fs(event));
+        })?;
+        watcher.watch(output_dir, notify::RecursiveMode::NonRecursive)?;
 
-                    // Get file size before running command (which might delete it)
-                    if let Ok(metadata) = std::fs::metadata(&filepath) {
-                        total_bytes_counter.fetch_add(metadata.len(), Ordering::SeqCst);
+        // The hooks run on the runtime, so their retries don't hold up this
+        // thread
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::spawn(move || {
+            let size = |path: &Path| std::fs::metadata(path).map_or(0, |m| m.len());
+            let completed = |path: &Path, counted: u64| {
+                // Get file size before running command (which might delete it)
+                stats.add_segment(size(path).saturating_sub(counted));
+                Hooks::segment_listeners(&hooks).send(path, None);
+                let hook = Hooks::on_segment(&hooks)?;
+                let path = path.to_path_buf();
+                Some(runtime.spawn(async move {
+                    run_segment_command(&hook, &path, None).await;
+                }))
+            };
+            // The file ffmpeg is writing, and how much of it is counted
+            let mut writing: Option<(PathBuf, u64)> = None;
+            let mut pending = Vec::new();
+            loop {
+                let event = match rx.recv_timeout(Duration::from_secs(1)) {
+                    Ok(event) => event,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if let Some((path, counted)) = &mut writing {
+                            let now = size(path);
+                            stats.add_bytes(now.saturating_sub(*counted));
+                            *counted = now.max(*counted);
+                        }
+                        continue;
+                    }
+                    // The watcher, and with it the last sender, was dropped
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+                match event {
+                    WatchEvent::Fs(Ok(event)) if event.kind.is_create() => {
+                        // Only process .ext files
+                        for path in event.paths {
+                            if path.extension() != Some(file_extension.as_ref())
+                                || writing.as_ref().is_some_and(|(p, _)| *p == path)
+                            {
+                                continue;
+                            }
+                            stats.set_current_file(path.clone());
+                            if let Some((previous, counted)) = writing.replace((path, 0)) {
+                                pending.retain(|hook: &tokio::task::JoinHandle<()>| {
+                                    !hook.is_finished()
+                                });
+                                pending.extend(completed(&previous, counted));
+                            }
+                        }
+                    }
+                    WatchEvent::Fs(Ok(_)) => {}
+                    WatchEvent::Fs(Err(e)) => warn!("File watcher error: {e}"),
+                    WatchEvent::Exited => {
+                        if let Some((last, counted)) = writing.take() {
+                            pending.extend(completed(&last, counted));
+                        }
+                    }
+                    WatchEvent::Pending(reply) => {
+                        let _ = reply.send(std::mem::take(&mut pending));
                     }
+                }
+            }
+        });
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// ffmpeg has exited, so the file it was writing is complete as well
+    pub fn ffmpeg_exited(&self) {
+        let _ = self.events.send(WatchEvent::Exited);
+    }
+
+    /// Wait for the on-segment hooks started so far, including the one for
+    /// the last file once `ffmpeg_exited` has been called
+    pub async fn wait_for_commands(&self) {
+        let (reply, pending) = tokio::sync::oneshot::channel();
+        let _ = self.events.send(WatchEvent::Pending(reply));
+        if let Ok(mut pending) = pending.await {
+            commands::wait_for_commands(&mut pending).await;
+        }
+    }
+}
+
+/// Lines of ffmpeg's stderr quoted in the error when it fails
+const STDERR_TAIL_LINES: usize = 5;
+
+/// Log ffmpeg's stderr at debug level as it comes, and return the last
+/// lines once ffmpeg closes it
+fn read_stderr(stderr: ChildStderr) -> std::thread::JoinHandle<VecDeque<String>> {
+    let span = tracing::Span::current();
+    std::thread::spawn(move || {
+        let _span = span.entered();
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        for line in BufReader::new(stderr).split(b'\n') {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            if line.is_empty() {
+                continue;
+            }
+            debug!("ffmpeg: {line}");
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        tail
+    })
+}
 
-                    if let Some(ref cmd) = on_segment {
-                        run_segment_command(cmd, &filepath, verbose);
+/// Read ffmpeg's -progress reports: blocks of `key=value` lines, each
+/// ending with `progress=continue` (or `end`). Every report shows ffmpeg is
+/// still getting media, for the watchdog, and the time it has written counts
+/// towards a VOD's progress.
+fn read_progress(stdout: ChildStdout, stats: Arc<RecordingStats>) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut out_time = Duration::ZERO;
+        let mut counted = Duration::ZERO;
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            match line.trim().split_once('=') {
+                // Microseconds, despite the name of the older out_time_ms
+                Some(("out_time_us", us)) => {
+                    if let Ok(us) = us.parse::<u64>() {
+                        out_time = Duration::from_micros(us);
                     }
                 }
+                Some(("progress", _)) => {
+                    stats.touch();
+                    stats.add_done_duration(out_time.saturating_sub(counted));
+                    counted = counted.max(out_time);
+                }
+                _ => {}
             }
         }
-    });
+    })
 }
 
-/// Handle fMP4 streams by shelling out to FFmpeg.
-/// fMP4 requires proper demuxing that's complex to do manually.
-pub fn run_ffmpeg_fmp4(
-    media_url: &Url,
-    file_extension: &str,
-    output_dir: &PathBuf,
-    segment_secs: u64,
-    verbose: bool,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let start_time = Local::now();
-    let timestamp_prefix = start_time.format("%Y_%m_%d-%H_%M").to_string();
-
-    // Find first available segment index (don't overwrite existing files)
-    let mut start_index: u32 = 0;
-    loop {
-        let filename = format!("{}_{}.{}", timestamp_prefix, start_index, file_extension);
-        let path = output_dir.join(&filename);
-        if !path.exists() {
-            break;
+/// How to run ffmpeg: which binary, and extra options from the command line
+#[derive(Debug, Clone)]
+pub struct FfmpegOptions {
+    pub path: PathBuf,
+    /// Put before each input (--ffmpeg-args)
+    pub input_args: Vec<String>,
+    /// Put before the output (--ffmpeg-output-args)
+    pub output_args: Vec<String>,
+    /// Encoders to re-encode with, instead of copying the streams
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub video_bitrate: Option<String>,
+    /// For the scale filter, `W:H`
+    pub scale: Option<String>,
+}
+
+impl FfmpegOptions {
+    /// The video encoder, or `None` to copy the video. Scaling or a bitrate
+    /// need one, so they default to libx264.
+    fn video_encoder(&self) -> Option<&str> {
+        match &self.video_codec {
+            Some(codec) => Some(codec),
+            None if self.scale.is_some() || self.video_bitrate.is_some() => Some("libx264"),
+            None => None,
+        }
+    }
+}
+
+/// What ffmpeg reads
+pub enum FfmpegInput<'a> {
+    /// It fetches the playlist itself, muxing in a separate audio rendition
+    Url {
+        media: &'a Url,
+        audio: Option<&'a Url>,
+        headers: &'a HeaderMap,
+    },
+    /// The stream as we download it, written to its stdin (--ffmpeg-stdin)
+    Stdin,
+}
+
+/// A running ffmpeg, with threads reading its output
+pub struct FfmpegProcess {
+    child: Child,
+    stderr: std::thread::JoinHandle<VecDeque<String>>,
+    progress: std::thread::JoinHandle<()>,
+}
+
+impl FfmpegProcess {
+    /// Where to write the stream, for `FfmpegInput::Stdin`, as a file so it
+    /// can be written like any other output
+    pub fn take_stdin(&mut self) -> Option<std::fs::File> {
+        let stdin = self.child.stdin.take()?;
+        #[cfg(unix)]
+        let file = std::os::fd::OwnedFd::from(stdin).into();
+        #[cfg(windows)]
+        let file = std::os::windows::io::OwnedHandle::from(stdin).into();
+        Some(file)
+    }
+
+    /// Wait for an ffmpeg fetching the stream itself to exit, stopping it
+    /// as Ctrl+C in a terminal would once `shutdown` is set (by a signal,
+    /// --duration, --max-bytes or a stop request), so it finishes its file.
+    /// SIGTERM from `kill` or systemd with `KillMode=mixed` only reaches the
+    /// recorder, and a daemon job or library recording is stopped without
+    /// any signal, so it's up to each recording to stop its own ffmpeg.
+    /// Polls without holding a runtime thread, since a daemon or --cameras
+    /// runs many of these at once.
+    pub async fn wait_until_shutdown(mut self, shutdown: &AtomicBool) -> Result<(), StreamError> {
+        let mut interrupted = false;
+        while self
+            .child
+            .try_wait()
+            .map_err(StreamError::ffmpeg)?
+            .is_none()
+        {
+            if !interrupted && shutdown.load(Ordering::SeqCst) {
+                interrupted = true;
+                self.interrupt();
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
         }
-        start_index += 1;
+        tokio::task::spawn_blocking(move || self.wait()).await?
     }
 
-    let output_pattern = output_dir.join(format!("{}_%d.{}", timestamp_prefix, file_extension));
+    #[cfg(unix)]
+    fn interrupt(&self) {
+        // SAFETY: kill() only takes plain integers, and the child hasn't
+        // been waited for, so its pid can't have been reused
+        unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGINT) };
+    }
+
+    #[cfg(not(unix))]
+    fn interrupt(&mut self) {
+        let _ = self.child.kill();
+    }
 
-    if verbose {
-        eprintln!("Detected fMP4 stream, using FFmpeg for demuxing...");
-        eprintln!("Output pattern: {}", output_pattern.display());
-        if start_index > 0 {
-            eprintln!("Starting at segment index: {}", start_index);
+    /// Wait for ffmpeg to exit, which with `FfmpegInput::Stdin` happens
+    /// once its stdin is closed
+    pub fn wait(mut self) -> Result<(), StreamError> {
+        let status = self.child.wait().map_err(StreamError::ffmpeg)?;
+        let _ = self.progress.join();
+        let tail = self.stderr.join().unwrap_or_default();
+        if !status.success() {
+            if tail.is_empty() {
+                return Err(StreamError::ffmpeg(format!("FFmpeg exited with: {status}")));
+            }
+            let output = Vec::from(tail).join(" | ");
+            let msg = format!("FFmpeg exited with: {status}. Last output: {output}");
+            return Err(StreamError::ffmpeg(msg));
         }
+        Ok(())
     }
+}
 
-    let mut cmd = Command::new("ffmpeg");
+/// Record by shelling out to FFmpeg, for --ffmpeg, --direct and
+/// --audio mux, where the stream has to be remuxed rather than copied, and
+/// to re-encode it with the transcode options.
+/// `output_pattern` is for ffmpeg's segment muxer (from
+/// `FilenameTemplate::ffmpeg_pattern`), numbered from `start_index`, until
+/// ffmpeg exits or `shutdown` is set.
+pub async fn run_ffmpeg_fmp4(
+    input: FfmpegInput<'_>,
+    output_pattern: &Path,
+    start_index: u32,
+    segment_secs: u64,
+    options: &FfmpegOptions,
+    stats: &Arc<RecordingStats>,
+    shutdown: &AtomicBool,
+) -> Result<(), StreamError> {
+    spawn_ffmpeg(
+        input,
+        output_pattern,
+        start_index,
+        segment_secs,
+        options,
+        stats,
+    )?
+    .wait_until_shutdown(shutdown)
+    .await
+}
+
+/// Start ffmpeg recording, as for `run_ffmpeg_fmp4`
+pub fn spawn_ffmpeg(
+    input: FfmpegInput,
+    output_pattern: &Path,
+    start_index: u32,
+    segment_secs: u64,
+    options: &FfmpegOptions,
+    stats: &Arc<RecordingStats>,
+) -> Result<FfmpegProcess, StreamError> {
+    debug!("Recording with FFmpeg...");
+    debug!("Output pattern: {}", output_pattern.display());
+    if start_index > 0 {
+        debug!("Starting at segment index: {}", start_index);
+    }
+
+    let mut cmd = Command::new(&options.path);
+    cmd.args(["-v", "error", "-progress", "pipe:1"]);
+    let source = match input {
+        FfmpegInput::Url {
+            media,
+            audio,
+            headers,
+        } => {
+            // ffmpeg takes extra headers per input, as one CRLF-terminated
+            // block. Credentials are left out: they'd show in the process
+            // list, and ffmpeg would send them to every host it's sent to.
+            let mut header_block = String::new();
+            for (name, value) in headers.iter() {
+                if name == AUTHORIZATION {
+                    warn!("FFmpeg isn't given the credentials; use --ffmpeg-stdin for a stream that needs them");
+                    continue;
+                }
+                match value.to_str() {
+                    Ok(value) => header_block.push_str(&format!("{name}: {value}\r\n")),
+                    Err(_) => {
+                        warn!("Not passing the {name} header to FFmpeg: it isn't plain ASCII")
+                    }
+                }
+            }
+            if !header_block.is_empty() {
+                cmd.args(["-headers", &header_block]);
+            }
+            cmd.args(&options.input_args);
+            cmd.args(["-i", media.as_str()]);
+            // Separate audio rendition: take video from the variant, audio from it
+            if let Some(audio_url) = audio {
+                debug!("Muxing audio rendition: {audio_url}");
+                if !header_block.is_empty() {
+                    cmd.args(["-headers", &header_block]);
+                }
+                cmd.args(&options.input_args);
+                cmd.args(["-i", audio_url.as_str(), "-map", "0:v?", "-map", "1:a"]);
+            }
+            media.as_str()
+        }
+        FfmpegInput::Stdin => {
+            cmd.args(&options.input_args);
+            cmd.args(["-i", "pipe:0"]).stdin(Stdio::piped());
+            // Ctrl+C ends the download, which closes ffmpeg's stdin; ffmpeg
+            // stopping on it as well would break the pipe instead
+            #[cfg(unix)]
+            std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+            "pipe:0"
+        }
+    };
+    let video_codec = options.video_encoder();
     cmd.args([
-        "-v",
-        "error",
-        "-i",
-        media_url.as_str(),
         "-c",
         "copy",
+        "-c:v",
+        video_codec.unwrap_or("copy"),
         "-c:a",
-        "copy",
+        options.audio_codec.as_deref().unwrap_or("copy"),
+    ]);
+    if video_codec.is_some() {
+        if let Some(bitrate) = &options.video_bitrate {
+            cmd.args(["-b:v", bitrate]);
+        }
+        if let Some(scale) = &options.scale {
+            cmd.args(["-vf", &format!("scale={scale}")]);
+        }
+        // The segment muxer only cuts on keyframes, so place them where the
+        // cuts should go rather than wherever the encoder puts them
+        cmd.args([
+            "-force_key_frames",
+            &format!("expr:gte(t,n_forced*{segment_secs})"),
+        ]);
+    }
+    cmd.args([
         "-f",
         "segment",
         "-segment_time",
@@ -111,16 +431,83 @@ pub fn run_ffmpeg_fmp4(
         // "-reset_timestamps",
         // "1",
     ])
+    // Later options win, so these can override the ones above
+    .args(&options.output_args)
     .arg(output_pattern.to_str().unwrap());
 
-    if verbose {
-        eprintln!("Running: ffmpeg {:?}", cmd.get_args().collect::<Vec<_>>());
-    }
+    debug!(
+        "Running: {} {:?}",
+        options.path.display(),
+        cmd.get_args().collect::<Vec<_>>()
+    );
 
-    let status = cmd.status()?;
-    if !status.success() {
-        return Err(format!("FFmpeg exited with: {status}").into());
-    }
+    let _span = info_span!("ffmpeg", url = %source).entered();
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            StreamError::ffmpeg(std::io::Error::new(
+                e.kind(),
+                format!("Failed to run {}: {e}", options.path.display()),
+            ))
+        })?;
+    let stderr = read_stderr(child.stderr.take().expect("stderr is piped"));
+    let progress = read_progress(child.stdout.take().expect("stdout is piped"), stats.clone());
+    Ok(FfmpegProcess {
+        child,
+        stderr,
+        progress,
+    })
+}
 
-    Ok(())
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Several ffmpeg recordings (daemon jobs, --cameras) share the runtime's
+    /// threads: waiting for one mustn't keep the others, or whatever sets
+    /// `shutdown`, from running
+    #[tokio::test(flavor = "current_thread")]
+    async fn ffmpeg_waits_share_one_thread() {
+        let dir = std::env::temp_dir().join(format!("ffmpeg-wait-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Stands in for an ffmpeg recording a live stream
+        let fake = dir.join("ffmpeg");
+        std::fs::write(&fake, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let options = FfmpegOptions {
+            path: fake,
+            input_args: Vec::new(),
+            output_args: Vec::new(),
+            video_codec: None,
+            audio_codec: None,
+            video_bitrate: None,
+            scale: None,
+        };
+        let url = Url::parse("https://example.com/live.m3u8").unwrap();
+        let headers = HeaderMap::new();
+        let stats = Arc::new(RecordingStats::new());
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let stop = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            stop.store(true, Ordering::SeqCst);
+        });
+        let (a, b) = (dir.join("a_%d.ts"), dir.join("b_%d.ts"));
+        let record = |pattern| {
+            let input = FfmpegInput::Url {
+                media: &url,
+                audio: None,
+                headers: &headers,
+            };
+            run_ffmpeg_fmp4(input, pattern, 0, 60, &options, &stats, &shutdown)
+        };
+        let started = std::time::Instant::now();
+        let _ = tokio::join!(record(&a), record(&b));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
 }
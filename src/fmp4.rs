@@ -0,0 +1,517 @@
+//! Fragmented MP4 writing for --fragmented-mp4: the header goes out first
+//! with an empty sample table, then the media as moof/mdat fragments. A file
+//! cut short by a crash plays up to its last complete fragment, where a
+//! regular MP4 is unplayable until its index is written at the end.
+
+use mp4::Mp4Sample;
+use std::io::Write;
+
+/// What a track holds, for its sample entry
+pub enum TrackMedia {
+    Avc {
+        width: u16,
+        height: u16,
+        sps: Vec<u8>,
+        pps: Vec<u8>,
+    },
+    Aac {
+        /// MPEG-4 audio object type, 2 for AAC-LC
+        object_type: u8,
+        /// Index into the AAC sample rate table
+        freq_index: u8,
+        channels: u8,
+    },
+}
+
+impl TrackMedia {
+    /// The codec's setup data: the avcC record for H.264, the
+    /// AudioSpecificConfig for AAC
+    pub fn decoder_config(&self) -> Vec<u8> {
+        match self {
+            TrackMedia::Avc { sps, pps, .. } => {
+                // Profile, compatibility and level come from the SPS
+                let profile = sps.get(1..4).unwrap_or(&[0x42, 0x00, 0x1F]);
+                let mut avcc = vec![1];
+                avcc.extend_from_slice(profile);
+                avcc.extend_from_slice(&[0xFF, 0xE1]); // 4-byte lengths, one SPS
+                avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+                avcc.extend_from_slice(sps);
+                avcc.push(1);
+                avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+                avcc.extend_from_slice(pps);
+                avcc
+            }
+            TrackMedia::Aac {
+                object_type,
+                freq_index,
+                channels,
+            } => {
+                let config = u16::from(*object_type) << 11
+                    | u16::from(*freq_index) << 7
+                    | u16::from(*channels) << 3;
+                config.to_be_bytes().to_vec()
+            }
+        }
+    }
+}
+
+pub struct TrackInfo {
+    pub id: u32,
+    pub timescale: u32,
+    pub media: TrackMedia,
+}
+
+struct TrackState {
+    id: u32,
+    /// Decode time of the next fragment's first sample
+    base_time: u64,
+    /// Samples waiting for the next fragment
+    samples: Vec<Mp4Sample>,
+}
+
+pub struct FragmentedWriter<W: Write> {
+    writer: W,
+    tracks: Vec<TrackState>,
+    sequence: u32,
+}
+
+impl<W: Write> FragmentedWriter<W> {
+    /// Write the file header for `tracks`
+    pub fn start(mut writer: W, tracks: &[TrackInfo]) -> std::io::Result<Self> {
+        writer.write_all(&ftyp())?;
+        writer.write_all(&moov(tracks))?;
+        Ok(Self {
+            writer,
+            tracks: tracks
+                .iter()
+                .map(|track| TrackState {
+                    id: track.id,
+                    base_time: 0,
+                    samples: Vec::new(),
+                })
+                .collect(),
+            sequence: 0,
+        })
+    }
+
+    /// Add a sample to the next fragment. Samples of an unknown track are
+    /// dropped.
+    pub fn write_sample(&mut self, track_id: u32, sample: &Mp4Sample) {
+        if let Some(track) = self.tracks.iter_mut().find(|t| t.id == track_id) {
+            track.samples.push(Mp4Sample {
+                start_time: sample.start_time,
+                duration: sample.duration,
+                rendering_offset: sample.rendering_offset,
+                is_sync: sample.is_sync,
+                bytes: sample.bytes.clone(),
+            });
+        }
+    }
+
+    /// Write the samples so far as one fragment and flush it to the file
+    pub fn flush_fragment(&mut self) -> std::io::Result<()> {
+        if self.tracks.iter().all(|t| t.samples.is_empty()) {
+            return Ok(());
+        }
+        self.sequence += 1;
+        // The trun data offsets count from the start of the moof, so its
+        // size is needed first; it doesn't depend on the offsets
+        let size = moof(self.sequence, &self.tracks, 0).len() as u32;
+        self.writer
+            .write_all(&moof(self.sequence, &self.tracks, size))?;
+
+        let data_size: usize = self
+            .tracks
+            .iter()
+            .flat_map(|t| &t.samples)
+            .map(|s| s.bytes.len())
+            .sum();
+        self.writer
+            .write_all(&(8 + data_size as u32).to_be_bytes())?;
+        self.writer.write_all(b"mdat")?;
+        for track in &mut self.tracks {
+            for sample in track.samples.drain(..) {
+                self.writer.write_all(&sample.bytes)?;
+                track.base_time += u64::from(sample.duration);
+            }
+        }
+        self.writer.flush()
+    }
+
+    /// Write the last fragment, returning the writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.flush_fragment()?;
+        Ok(self.writer)
+    }
+}
+
+/// A box with `body` inside
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + body.len());
+    out.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out
+}
+
+/// A full box: version and flags, then `body`
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut full = (flags & 0x00FF_FFFF | u32::from(version) << 24)
+        .to_be_bytes()
+        .to_vec();
+    full.extend_from_slice(body);
+    mp4_box(kind, &full)
+}
+
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn put_matrix(out: &mut Vec<u8>) {
+    for value in MATRIX {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn ftyp() -> Vec<u8> {
+    let mut body = b"isom".to_vec();
+    body.extend_from_slice(&512u32.to_be_bytes());
+    for brand in [b"isom", b"iso6", b"avc1", b"mp41"] {
+        body.extend_from_slice(brand);
+    }
+    mp4_box(b"ftyp", &body)
+}
+
+fn moov(tracks: &[TrackInfo]) -> Vec<u8> {
+    let mut mvhd = Vec::new();
+    mvhd.extend_from_slice(&[0; 8]); // creation and modification time
+    mvhd.extend_from_slice(&1000u32.to_be_bytes());
+    mvhd.extend_from_slice(&0u32.to_be_bytes()); // duration: in the fragments
+    mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+    mvhd.extend_from_slice(&[0; 10]);
+    put_matrix(&mut mvhd);
+    mvhd.extend_from_slice(&[0; 24]);
+    let next_id = tracks.iter().map(|t| t.id).max().unwrap_or(0) + 1;
+    mvhd.extend_from_slice(&next_id.to_be_bytes());
+
+    let mut body = full_box(b"mvhd", 0, 0, &mvhd);
+    for track in tracks {
+        body.extend_from_slice(&trak(track));
+    }
+    let mut mvex = Vec::new();
+    for track in tracks {
+        let mut trex = track.id.to_be_bytes().to_vec();
+        trex.extend_from_slice(&1u32.to_be_bytes()); // sample description
+        trex.extend_from_slice(&[0; 12]); // duration, size, flags: per sample
+        mvex.extend_from_slice(&full_box(b"trex", 0, 0, &trex));
+    }
+    body.extend_from_slice(&mp4_box(b"mvex", &mvex));
+    mp4_box(b"moov", &body)
+}
+
+fn trak(track: &TrackInfo) -> Vec<u8> {
+    let (width, height, audio) = match track.media {
+        TrackMedia::Avc { width, height, .. } => (width, height, false),
+        TrackMedia::Aac { .. } => (0, 0, true),
+    };
+
+    let mut tkhd = vec![0; 8]; // creation and modification time
+    tkhd.extend_from_slice(&track.id.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 4]);
+    tkhd.extend_from_slice(&0u32.to_be_bytes()); // duration
+    tkhd.extend_from_slice(&[0; 12]); // reserved, layer, alternate group
+    tkhd.extend_from_slice(&(if audio { 0x0100u16 } else { 0 }).to_be_bytes());
+    tkhd.extend_from_slice(&[0; 2]);
+    put_matrix(&mut tkhd);
+    tkhd.extend_from_slice(&(u32::from(width) << 16).to_be_bytes());
+    tkhd.extend_from_slice(&(u32::from(height) << 16).to_be_bytes());
+
+    let mut mdhd = vec![0; 8];
+    mdhd.extend_from_slice(&track.timescale.to_be_bytes());
+    mdhd.extend_from_slice(&0u32.to_be_bytes());
+    mdhd.extend_from_slice(&0x55C4u16.to_be_bytes()); // "und"
+    mdhd.extend_from_slice(&[0; 2]);
+
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(if audio { b"soun" } else { b"vide" });
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(if audio {
+        b"SoundHandler\0"
+    } else {
+        b"VideoHandler\0"
+    });
+
+    let media_header = if audio {
+        full_box(b"smhd", 0, 0, &[0; 4])
+    } else {
+        full_box(b"vmhd", 0, 1, &[0; 8])
+    };
+    let mut dref = 1u32.to_be_bytes().to_vec();
+    dref.extend_from_slice(&full_box(b"url ", 0, 1, &[]));
+    let dinf = mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref));
+
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend_from_slice(&sample_entry(track));
+    let mut stbl = full_box(b"stsd", 0, 0, &stsd);
+    // The samples are all in the fragments
+    for kind in [b"stts", b"stsc", b"stco"] {
+        stbl.extend_from_slice(&full_box(kind, 0, 0, &[0; 4]));
+    }
+    stbl.extend_from_slice(&full_box(b"stsz", 0, 0, &[0; 8]));
+
+    let mut minf = media_header;
+    minf.extend_from_slice(&dinf);
+    minf.extend_from_slice(&mp4_box(b"stbl", &stbl));
+
+    let mut mdia = full_box(b"mdhd", 0, 0, &mdhd);
+    mdia.extend_from_slice(&full_box(b"hdlr", 0, 0, &hdlr));
+    mdia.extend_from_slice(&mp4_box(b"minf", &minf));
+
+    let mut body = full_box(b"tkhd", 0, 3, &tkhd);
+    body.extend_from_slice(&mp4_box(b"mdia", &mdia));
+    mp4_box(b"trak", &body)
+}
+
+fn sample_entry(track: &TrackInfo) -> Vec<u8> {
+    let mut entry = vec![0; 6];
+    entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+    match &track.media {
+        TrackMedia::Avc { width, height, .. } => {
+            entry.extend_from_slice(&[0; 16]);
+            entry.extend_from_slice(&width.to_be_bytes());
+            entry.extend_from_slice(&height.to_be_bytes());
+            entry.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+            entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+            entry.extend_from_slice(&[0; 4]);
+            entry.extend_from_slice(&1u16.to_be_bytes()); // frame count
+            entry.extend_from_slice(&[0; 32]); // compressor name
+            entry.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+            entry.extend_from_slice(&0xFFFFu16.to_be_bytes());
+            entry.extend_from_slice(&mp4_box(b"avcC", &track.media.decoder_config()));
+            mp4_box(b"avc1", &entry)
+        }
+        TrackMedia::Aac { channels, .. } => {
+            entry.extend_from_slice(&[0; 8]);
+            entry.extend_from_slice(&u16::from(*channels).to_be_bytes());
+            entry.extend_from_slice(&16u16.to_be_bytes()); // sample size
+            entry.extend_from_slice(&[0; 4]);
+            entry.extend_from_slice(&(track.timescale.min(0xFFFF) << 16).to_be_bytes());
+
+            let specific = descriptor(0x05, &track.media.decoder_config());
+            let mut decoder = vec![0x40, 0x15, 0, 0, 0]; // AAC, audio stream
+            decoder.extend_from_slice(&[0; 8]); // max and average bitrate
+            decoder.extend_from_slice(&specific);
+            let mut es = vec![0, 0, 0]; // ES ID, flags
+            es.extend_from_slice(&descriptor(0x04, &decoder));
+            es.extend_from_slice(&descriptor(0x06, &[0x02]));
+            entry.extend_from_slice(&full_box(b"esds", 0, 0, &descriptor(0x03, &es)));
+            mp4_box(b"mp4a", &entry)
+        }
+    }
+}
+
+/// An MPEG-4 descriptor; these are all under 128 bytes
+fn descriptor(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag, body.len() as u8];
+    out.extend_from_slice(body);
+    out
+}
+
+/// The fragment header. `moof_size` is its own size, which the data
+/// offsets count from; the samples follow in track order in the mdat.
+fn moof(sequence: u32, tracks: &[TrackState], moof_size: u32) -> Vec<u8> {
+    let mut body = full_box(b"mfhd", 0, 0, &sequence.to_be_bytes());
+    // Past the moof and the mdat header
+    let mut data_offset = moof_size + 8;
+    for track in tracks.iter().filter(|t| !t.samples.is_empty()) {
+        // Base offsets are the start of the moof
+        let tfhd = full_box(b"tfhd", 0, 0x02_0000, &track.id.to_be_bytes());
+        let tfdt = full_box(b"tfdt", 1, 0, &track.base_time.to_be_bytes());
+
+        let mut trun = (track.samples.len() as u32).to_be_bytes().to_vec();
+        trun.extend_from_slice(&data_offset.to_be_bytes());
+        for sample in &track.samples {
+            trun.extend_from_slice(&sample.duration.to_be_bytes());
+            trun.extend_from_slice(&(sample.bytes.len() as u32).to_be_bytes());
+            let flags: u32 = if sample.is_sync {
+                0x0200_0000 // depends on no other sample
+            } else {
+                0x0101_0000 // depends on others, not a sync sample
+            };
+            trun.extend_from_slice(&flags.to_be_bytes());
+            trun.extend_from_slice(&sample.rendering_offset.to_be_bytes());
+            data_offset += sample.bytes.len() as u32;
+        }
+        // Data offset, and duration, size, flags and composition offset
+        // per sample; version 1 for signed composition offsets
+        let trun = full_box(b"trun", 1, 0x0F01, &trun);
+
+        let mut traf = tfhd;
+        traf.extend_from_slice(&tfdt);
+        traf.extend_from_slice(&trun);
+        body.extend_from_slice(&mp4_box(b"traf", &traf));
+    }
+    mp4_box(b"moof", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The boxes in `data`: type, offset of the box in `data`, body
+    fn boxes(data: &[u8]) -> Vec<(&str, usize, &[u8])> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            assert!(
+                size >= 8 && pos + size <= data.len(),
+                "box size {size} at {pos}"
+            );
+            let kind = std::str::from_utf8(&data[pos + 4..pos + 8]).unwrap();
+            out.push((kind, pos, &data[pos + 8..pos + size]));
+            pos += size;
+        }
+        out
+    }
+
+    fn kinds(data: &[u8]) -> Vec<&str> {
+        boxes(data).into_iter().map(|(kind, _, _)| kind).collect()
+    }
+
+    /// The body of the first box of each type in `path`, one inside the
+    /// other. A number skips that many bytes of the body first.
+    fn find<'a>(mut data: &'a [u8], path: &[(&str, usize)]) -> &'a [u8] {
+        for &(kind, skip) in path {
+            let (_, _, body) = boxes(data)
+                .into_iter()
+                .find(|(k, _, _)| *k == kind)
+                .unwrap_or_else(|| panic!("no {kind} box"));
+            data = &body[skip..];
+        }
+        data
+    }
+
+    fn be32(data: &[u8]) -> u32 {
+        u32::from_be_bytes(data[..4].try_into().unwrap())
+    }
+
+    fn tracks() -> Vec<TrackInfo> {
+        vec![
+            TrackInfo {
+                id: 1,
+                timescale: 90000,
+                media: TrackMedia::Avc {
+                    width: 1280,
+                    height: 720,
+                    sps: vec![0x67, 0x42, 0x00, 0x28, 0xf4, 0x02, 0x80, 0x2d, 0xc8],
+                    pps: vec![0x68, 0xce, 0x38, 0x80],
+                },
+            },
+            TrackInfo {
+                id: 2,
+                timescale: 48000,
+                media: TrackMedia::Aac {
+                    object_type: 2,
+                    freq_index: 3,
+                    channels: 2,
+                },
+            },
+        ]
+    }
+
+    fn sample(bytes: &[u8], duration: u32, is_sync: bool) -> Mp4Sample {
+        Mp4Sample {
+            start_time: 0,
+            duration,
+            rendering_offset: 0,
+            is_sync,
+            bytes: mp4::Bytes::copy_from_slice(bytes),
+        }
+    }
+
+    #[test]
+    fn init_segment() {
+        let file = FragmentedWriter::start(Vec::new(), &tracks())
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(kinds(&file), ["ftyp", "moov"]);
+        let moov = find(&file, &[("moov", 0)]);
+        assert_eq!(kinds(moov), ["mvhd", "trak", "trak", "mvex"]);
+        // next_track_ID ends the mvhd
+        let mvhd = find(moov, &[("mvhd", 0)]);
+        assert_eq!(be32(&mvhd[mvhd.len() - 4..]), 3);
+        let mvex = find(moov, &[("mvex", 0)]);
+        let trex: Vec<u32> = boxes(mvex)
+            .iter()
+            .map(|(_, _, body)| be32(&body[4..]))
+            .collect();
+        assert_eq!(trex, [1, 2]);
+
+        // Skipping the stsd's entry count and the visual sample entry fields
+        let avcc = find(
+            moov,
+            &[
+                ("trak", 0),
+                ("mdia", 0),
+                ("minf", 0),
+                ("stbl", 0),
+                ("stsd", 8),
+                ("avc1", 78),
+                ("avcC", 0),
+            ],
+        );
+        assert_eq!(avcc[..6], [1, 0x42, 0x00, 0x28, 0xff, 0xe1]);
+        assert_eq!(avcc.len(), 6 + 2 + 9 + 1 + 2 + 4);
+    }
+
+    #[test]
+    fn aac_config() {
+        // AAC-LC, 48 kHz, stereo
+        let aac = &tracks()[1].media;
+        assert_eq!(aac.decoder_config(), [0x11, 0x90]);
+    }
+
+    #[test]
+    fn fragments() {
+        let mut writer = FragmentedWriter::start(Vec::new(), &tracks()).unwrap();
+        writer.write_sample(1, &sample(&[1; 10], 3000, true));
+        writer.write_sample(1, &sample(&[2; 5], 3000, false));
+        writer.write_sample(2, &sample(&[3; 4], 1024, true));
+        // An unknown track is dropped
+        writer.write_sample(9, &sample(&[9; 4], 1024, true));
+        writer.flush_fragment().unwrap();
+        writer.write_sample(1, &sample(&[4; 6], 3000, true));
+        let file = writer.finish().unwrap();
+
+        let top = boxes(&file);
+        let kinds: Vec<_> = top.iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(kinds, ["ftyp", "moov", "moof", "mdat", "moof", "mdat"]);
+        assert_eq!(top[3].2, [[1; 10].as_slice(), &[2; 5], &[3; 4]].concat());
+        assert_eq!(top[5].2, [4; 6]);
+
+        let (_, moof_start, moof) = top[2];
+        assert_eq!(be32(find(moof, &[("mfhd", 4)])), 1);
+        let trafs: Vec<_> = boxes(moof).into_iter().skip(1).collect();
+        assert_eq!(trafs.len(), 2);
+        for ((_, _, traf), (id, samples, data)) in trafs.iter().zip([
+            (1, 2, [[1; 10].as_slice(), &[2; 5]].concat()),
+            (2, 1, vec![3; 4]),
+        ]) {
+            assert_eq!(be32(find(traf, &[("tfhd", 4)])), id);
+            assert_eq!(find(traf, &[("tfdt", 4)]), 0u64.to_be_bytes());
+            let trun = find(traf, &[("trun", 4)]);
+            assert_eq!(be32(trun), samples);
+            // The data offset counts from the start of the moof
+            let offset = moof_start + be32(&trun[4..]) as usize;
+            assert_eq!(file[offset..offset + data.len()], data);
+        }
+
+        let moof = top[4].2;
+        assert_eq!(be32(find(moof, &[("mfhd", 4)])), 2);
+        assert_eq!(boxes(moof).len(), 2);
+        // Decode time carries on from the first fragment
+        let tfdt = find(moof, &[("traf", 0), ("tfdt", 4)]);
+        assert_eq!(tfdt, 6000u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,198 @@
+//! H.264 bitstream parsing shared by the RTSP and WHEP recorders, for
+//! cameras and endpoints that only send their parameter sets in-band, and by
+//! the MP4 remuxer. The input comes straight off the network, so every
+//! value read from it is checked before it's used in arithmetic.
+
+/// Reads an H.264 RBSP bit by bit
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    /// Reader over a NAL unit payload with emulation prevention bytes removed
+    fn new(nal: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(nal.len());
+        let mut zeros = 0;
+        for &b in nal {
+            if zeros >= 2 && b == 3 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            data.push(b);
+        }
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(u32::from(bit))
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| Some(acc << 1 | self.bit()?))
+    }
+
+    /// Exp-Golomb unsigned
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        ((1u32 << zeros) - 1).checked_add(self.bits(zeros)?)
+    }
+
+    /// Exp-Golomb signed
+    fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+        let magnitude = i32::try_from(v.div_ceil(2)).ok()?;
+        Some(if v % 2 == 1 { magnitude } else { -magnitude })
+    }
+}
+
+/// Picture size from an SPS NAL unit (with its header byte)
+pub fn sps_dimensions(sps: &[u8]) -> Option<(u16, u16)> {
+    let mut r = BitReader::new(sps.get(1..)?);
+    let profile_idc = r.bits(8)?;
+    r.bits(16)?; // constraint flags, level_idc
+    r.ue()?; // seq_parameter_set_id
+    let mut chroma_format_idc = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            r.bit()?; // separate_colour_plane_flag
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bit()?; // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? == 1 {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let (mut last, mut next) = (8i32, 8i32);
+                    for _ in 0..size {
+                        if next != 0 {
+                            next = last.checked_add(r.se()?)?.rem_euclid(256);
+                        }
+                        if next != 0 {
+                            last = next;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bit()?;
+            r.se()?;
+            r.se()?;
+            for _ in 0..r.ue()? {
+                r.se()?;
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_mbs = r.ue()?.checked_add(1)?;
+    let height_map_units = r.ue()?.checked_add(1)?;
+    let frame_mbs_only = r.bit()?;
+    if frame_mbs_only == 0 {
+        r.bit()?; // mb_adaptive_frame_field_flag
+    }
+    r.bit()?; // direct_8x8_inference_flag
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if r.bit()? == 1 {
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        let (sub_width, sub_height) = match chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        crop_x = left.checked_add(right)?.checked_mul(sub_width)?;
+        crop_y = top
+            .checked_add(bottom)?
+            .checked_mul(sub_height * (2 - frame_mbs_only))?;
+    }
+    let width = width_mbs.checked_mul(16)?.checked_sub(crop_x)?;
+    let height = height_map_units
+        .checked_mul(16 * (2 - frame_mbs_only))?
+        .checked_sub(crop_y)?;
+    let dimensions = (u16::try_from(width).ok()?, u16::try_from(height).ok()?);
+    // Cropped down to nothing
+    Some(dimensions).filter(|&(width, height)| width > 0 && height > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn baseline_720p() {
+        assert_eq!(
+            sps_dimensions(&hex("67420028f402802dc8")),
+            Some((1280, 720))
+        );
+    }
+
+    #[test]
+    fn high_1080p_with_cropping() {
+        // 1920x1088 in macroblocks, cropped by 8 lines at the bottom
+        let sps = hex("67640028acb280f0044fca80");
+        assert_eq!(sps_dimensions(&sps), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn emulation_prevention_bytes_are_removed() {
+        let mut r = BitReader::new(&[0, 0, 3, 1, 0, 0, 3]);
+        assert_eq!(r.data, [0, 0, 1, 0, 0]);
+        assert_eq!(r.bits(24), Some(1));
+    }
+
+    #[test]
+    fn truncated_sps() {
+        let sps = hex("67640028acb280f0044fca80");
+        for len in 0..sps.len() - 1 {
+            assert_eq!(sps_dimensions(&sps[..len]), None, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn hostile_sps() {
+        // A width of 4096 macroblocks doesn't fit in a u16
+        assert_eq!(sps_dimensions(&hex("67420028f400080002dc80")), None);
+        // Cropping more than the picture
+        assert_eq!(sps_dimensions(&hex("67420028f4f8197a")), None);
+        // Exp-Golomb codes longer than 32 bits
+        assert_eq!(
+            sps_dimensions(&[0x67, 0x42, 0, 0x28, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        // Garbage whose picture is cropped down to nothing
+        assert_eq!(sps_dimensions(&[0x67; 64]), None);
+        for fill in 0..=255 {
+            if let Some((width, height)) = sps_dimensions(&[fill; 64]) {
+                assert!(width > 0 && height > 0, "{fill:#x}");
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! The recording engine behind the `stream-utils` command, for programs that
+//! would rather embed it than run the binary.
+//!
+//! [`Recorder`] records one stream with the options of `stream-utils record`
+//! and calls back as files are completed, attempts fail and the recording
+//! finishes:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use stream_utils::Recorder;
+//!
+//! # async fn example() -> Result<(), stream_utils::StreamError> {
+//! let recorder = Recorder::builder("https://example.com/live/index.m3u8")
+//!     .output("recordings")
+//!     .segment_duration(Duration::from_secs(600))
+//!     .option("variant", "720p")
+//!     .on_segment(|segment| println!("{}: {} bytes", segment.path.display(), segment.bytes))
+//!     .on_error(|e| eprintln!("Recording failed: {e}"))
+//!     .build()?;
+//!
+//! let stop = recorder.stop_handle();
+//! tokio::spawn(async move {
+//!     tokio::signal::ctrl_c().await.ok();
+//!     stop.stop();
+//! });
+//! let summary = recorder.run().await?;
+//! println!("{} files, {} bytes", summary.files.len(), summary.bytes);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The lower layers are public too: [`downloader`] fetches HLS segments,
+//! [`playlist`] picks variants, [`http_client`] holds the shared client,
+//! [`output`] writes and rotates the files and `rtsp` (with the `rtsp`
+//! feature) records cameras. They follow the command's options closely and
+//! change with it. Types their interfaces take from elsewhere are
+//! re-exported beside them; the other modules serve the binary and are
+//! private.
+
+pub(crate) mod api;
+mod app;
+pub(crate) mod cameras;
+pub(crate) mod cli;
+pub(crate) mod commands;
+pub(crate) mod config;
+pub(crate) mod convert;
+pub(crate) mod daemon;
+pub(crate) mod dash;
+pub(crate) mod disk;
+pub(crate) mod dns;
+pub mod downloader;
+pub mod error;
+pub(crate) mod ffmpeg;
+#[cfg(feature = "rtsp")]
+pub(crate) mod fmp4;
+#[cfg(any(feature = "rtsp", feature = "remux", feature = "whep"))]
+pub(crate) mod h264;
+pub mod http_client;
+pub(crate) mod logging;
+#[cfg(feature = "rtsp")]
+pub(crate) mod mkv;
+#[cfg(feature = "onvif")]
+pub(crate) mod onvif;
+pub mod output;
+pub mod playlist;
+pub(crate) mod preroll;
+pub(crate) mod probe;
+pub(crate) mod progress;
+pub(crate) mod progressive;
+pub(crate) mod record;
+pub mod recorder;
+#[cfg(feature = "remux")]
+pub(crate) mod remux;
+pub(crate) mod retention;
+#[cfg(feature = "rtsp")]
+pub mod rtsp;
+pub(crate) mod sidecar;
+#[cfg(feature = "srt")]
+pub(crate) mod srt;
+pub(crate) mod state;
+pub(crate) mod stats;
+pub(crate) mod status;
+pub(crate) mod systemd;
+#[cfg(feature = "otel")]
+pub(crate) mod telemetry;
+pub(crate) mod thumbnails;
+pub(crate) mod tls;
+pub(crate) mod ts_ingest;
+pub(crate) mod udp;
+pub(crate) mod webhook;
+#[cfg(feature = "whep")]
+pub(crate) mod whep;
+
+#[doc(hidden)]
+pub use app::main;
+pub use commands::{ExitReason, SegmentEvent};
+pub use error::StreamError;
+pub use recorder::{Recorder, RecorderBuilder, RecordingFailed, StopHandle, Summary};
@@ -0,0 +1,205 @@
+use crate::cli::{LogArgs, LogFormat};
+use crate::error::StreamError;
+use crate::progress::SuspendingStderr;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Log file that rotates to `NAME.1`, `NAME.2`, ... when it grows past a size
+/// limit or gets older than a time limit, keeping a bounded number of backups.
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    opened_at: Instant,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    keep: u32,
+}
+
+impl RotatingLog {
+    pub fn open(
+        path: PathBuf,
+        max_bytes: u64,
+        max_age: Option<Duration>,
+        keep: u32,
+    ) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            written,
+            opened_at: Instant::now(),
+            max_bytes,
+            max_age,
+            keep,
+        })
+    }
+
+    fn backup_path(path: &Path, n: u32) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn needs_rotation(&self) -> bool {
+        (self.max_bytes > 0 && self.written >= self.max_bytes)
+            || self
+                .max_age
+                .is_some_and(|age| self.opened_at.elapsed() >= age)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            // No backups wanted, just start over
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(Self::backup_path(&self.path, self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(
+                    Self::backup_path(&self.path, n),
+                    Self::backup_path(&self.path, n + 1),
+                );
+            }
+            std::fs::rename(&self.path, Self::backup_path(&self.path, 1))?;
+            self.file = File::create(&self.path)?;
+        }
+        self.written = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn format_layer<S, W>(
+    format: LogFormat,
+    writer: W,
+    is_file: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let mut layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    if is_file {
+        // No color escapes in files
+        layer = layer.with_ansi(false);
+    }
+    match format {
+        LogFormat::Text => layer.with_target(false).boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).boxed(),
+    }
+}
+
+/// Keeps the trace exporter alive; dropping it flushes pending spans
+pub struct LogGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(ref provider) = self.provider {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Target of the error a command fails with, which gets through any log
+/// filter: a process that exits non-zero should always say why
+pub const FATAL_TARGET: &str = "stream_utils::fatal";
+
+/// Install the global tracing subscriber, logging to stderr and optionally
+/// to a rotating --log-file, and exporting spans if --otlp-endpoint is set.
+/// The log filter comes from --log-filter, then RUST_LOG, then -q/-v.
+pub fn init(args: &LogArgs) -> Result<LogGuard, StreamError> {
+    let filter = match args.log_filter {
+        Some(ref directives) => EnvFilter::new(directives),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            EnvFilter::new(match (args.quiet, args.verbose) {
+                (true, _) => "error",
+                (false, 0) => "warn,stream_utils=info",
+                (false, 1) => "warn,stream_utils=debug",
+                (false, 2) => "warn,stream_utils=trace",
+                (false, _) => "debug,stream_utils=trace",
+            })
+        }),
+    };
+    // The most specific directive wins, so this holds even under `off`
+    let filter = filter.add_directive(
+        format!("{FATAL_TARGET}=error")
+            .parse()
+            .expect("a valid directive"),
+    );
+
+    let file_layer = match args.log_file {
+        Some(ref path) => {
+            let log = RotatingLog::open(
+                path.clone(),
+                args.log_max_size.saturating_mul(1024 * 1024),
+                (args.log_rotate_secs > 0).then(|| Duration::from_secs(args.log_rotate_secs)),
+                args.log_keep,
+            )
+            .map_err(|e| StreamError::config(format!("--log-file {}: {e}", path.display())))?;
+            Some(format_layer(args.log_format, Mutex::new(log), true))
+        }
+        None => None,
+    };
+
+    // The log filter applies to the log output only, so -q doesn't also
+    // silence trace export
+    let log_layers = format_layer(args.log_format, || SuspendingStderr, false)
+        .and_then(file_layer)
+        .with_filter(filter);
+
+    #[cfg(feature = "otel")]
+    let (otel_layer, provider) = match args.otlp_endpoint {
+        Some(ref endpoint) => {
+            let provider = crate::telemetry::provider(endpoint)?;
+            (Some(crate::telemetry::layer(&provider)), Some(provider))
+        }
+        None => (None, None),
+    };
+    #[cfg(not(feature = "otel"))]
+    if args.otlp_endpoint.is_some() {
+        return Err(StreamError::config(
+            "OpenTelemetry support not compiled in. Rebuild with --features otel",
+        ));
+    }
+
+    let registry = tracing_subscriber::registry().with(log_layers);
+    #[cfg(feature = "otel")]
+    registry.with(otel_layer).init();
+    #[cfg(not(feature = "otel"))]
+    registry.init();
+
+    Ok(LogGuard {
+        #[cfg(feature = "otel")]
+        provider,
+    })
+}
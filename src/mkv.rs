@@ -0,0 +1,512 @@
+//! Matroska writing for --container mkv. Like the fragmented MP4 writer,
+//! media goes out as it's recorded, a cluster per keyframe interval, so a
+//! file cut short plays up to its last complete cluster. The segment size is
+//! left unknown; the duration, seek index and cues are filled in when the
+//! file is finished.
+
+use crate::fmp4::{TrackInfo, TrackMedia};
+use mp4::Mp4Sample;
+use std::io::{Seek, SeekFrom, Write};
+
+const EBML: u32 = 0x1A45_DFA3;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549_A966;
+const TRACKS: u32 = 0x1654_AE6B;
+const CLUSTER: u32 = 0x1F43_B675;
+const CUES: u32 = 0x1C53_BB6B;
+const VOID: u32 = 0xEC;
+
+/// Space kept after the EBML header for the seek index, written at the end
+const SEEK_HEAD_SPACE: usize = 96;
+/// A Duration element: two-byte ID, size, 8-byte float
+const DURATION_SPACE: usize = 11;
+/// Blocks are timed relative to their cluster in signed 16-bit
+/// milliseconds, so a cluster is cut short before it spans that
+const MAX_CLUSTER_MS: u64 = 30_000;
+
+/// A sample waiting for its cluster, timed in milliseconds
+struct Block {
+    track: u32,
+    /// Presentation time in ms, which is what Matroska stores
+    time: u64,
+    /// Decode time, which the blocks go in the order of
+    decode: u64,
+    is_sync: bool,
+    bytes: mp4::Bytes,
+}
+
+pub struct MatroskaWriter<W: Write + Seek> {
+    writer: W,
+    /// File offset where the segment's content starts; element positions
+    /// count from here
+    segment_start: u64,
+    /// File offset of the next byte written
+    position: u64,
+    /// (track ID, timescale), to turn sample times into milliseconds
+    timescales: Vec<(u32, u32)>,
+    /// The track whose keyframes the cues point at
+    video_track: Option<u32>,
+    /// Where the placeholders and top-level elements went
+    info_position: u64,
+    duration_position: u64,
+    tracks_position: u64,
+    blocks: Vec<Block>,
+    /// (time, cluster position) of each cluster with a video keyframe
+    cues: Vec<(u64, u64)>,
+    /// End of the last sample written, in milliseconds
+    end_time: u64,
+}
+
+impl<W: Write + Seek> MatroskaWriter<W> {
+    /// Write the file header for `tracks`
+    pub fn start(writer: W, tracks: &[TrackInfo]) -> std::io::Result<Self> {
+        let mut mkv = Self {
+            writer,
+            segment_start: 0,
+            position: 0,
+            timescales: tracks.iter().map(|t| (t.id, t.timescale)).collect(),
+            video_track: tracks
+                .iter()
+                .find(|t| matches!(t.media, TrackMedia::Avc { .. }))
+                .map(|t| t.id),
+            info_position: 0,
+            duration_position: 0,
+            tracks_position: 0,
+            blocks: Vec::new(),
+            cues: Vec::new(),
+            end_time: 0,
+        };
+
+        let mut header = Vec::new();
+        put_uint(&mut header, 0x4286, 1); // EBMLVersion
+        put_uint(&mut header, 0x42F7, 1); // EBMLReadVersion
+        put_uint(&mut header, 0x42F2, 4); // EBMLMaxIDLength
+        put_uint(&mut header, 0x42F3, 8); // EBMLMaxSizeLength
+        put_string(&mut header, 0x4282, "matroska"); // DocType
+        put_uint(&mut header, 0x4287, 4); // DocTypeVersion
+        put_uint(&mut header, 0x4285, 2); // DocTypeReadVersion
+        mkv.write(&element(EBML, &header))?;
+
+        // An unknown size lets a player read the segment to wherever it ends
+        let mut segment = id_bytes(SEGMENT);
+        segment.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        mkv.write(&segment)?;
+        mkv.segment_start = mkv.position;
+        mkv.write(&void(SEEK_HEAD_SPACE))?;
+
+        let mut info = Vec::new();
+        put_uint(&mut info, 0x2A_D7B1, 1_000_000); // TimestampScale: milliseconds
+        put_string(&mut info, 0x4D80, "stream-utils"); // MuxingApp
+        put_string(&mut info, 0x5741, "stream-utils"); // WritingApp
+        let duration_offset = id_bytes(INFO).len() + 8 + info.len();
+        info.extend_from_slice(&void(DURATION_SPACE));
+        mkv.info_position = mkv.position;
+        mkv.duration_position = mkv.position + duration_offset as u64;
+        mkv.write(&element_sized(INFO, &info))?;
+
+        let mut entries = Vec::new();
+        for track in tracks {
+            entries.extend_from_slice(&track_entry(track));
+        }
+        mkv.tracks_position = mkv.position;
+        mkv.write(&element(TRACKS, &entries))?;
+        mkv.writer.flush()?;
+        Ok(mkv)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Add a sample to the next cluster. Samples of an unknown track are
+    /// dropped.
+    pub fn write_sample(&mut self, track_id: u32, sample: &Mp4Sample) -> std::io::Result<()> {
+        let Some(&(_, timescale)) = self.timescales.iter().find(|(id, _)| *id == track_id) else {
+            return Ok(());
+        };
+        let timescale = u64::from(timescale.max(1));
+        let decode = sample.start_time * 1000 / timescale;
+        let presented = sample.start_time as i64 + i64::from(sample.rendering_offset);
+        let time = presented.max(0) as u64 * 1000 / timescale;
+        let first = self.blocks.iter().map(|b| b.time).min();
+        if first.is_some_and(|first| time > first + MAX_CLUSTER_MS) {
+            self.flush_cluster()?;
+        }
+        let end = (presented.max(0) as u64 + u64::from(sample.duration)) * 1000 / timescale;
+        self.end_time = self.end_time.max(end);
+        self.blocks.push(Block {
+            track: track_id,
+            time,
+            decode,
+            is_sync: sample.is_sync,
+            bytes: sample.bytes.clone(),
+        });
+        Ok(())
+    }
+
+    /// Write the samples so far as one cluster and flush it to the file
+    pub fn flush_cluster(&mut self) -> std::io::Result<()> {
+        if self.blocks.is_empty() {
+            return Ok(());
+        }
+        let mut blocks = std::mem::take(&mut self.blocks);
+        blocks.sort_by_key(|b| b.decode);
+        let start = blocks.iter().map(|b| b.time).min().unwrap_or(0);
+
+        let mut body = Vec::new();
+        put_uint(&mut body, 0xE7, start); // Timestamp
+        for block in &blocks {
+            // Track number, relative time and flags, then the frame
+            let mut simple = vec![0x80 | block.track as u8];
+            let offset = (block.time - start).min(i16::MAX as u64) as i16;
+            simple.extend_from_slice(&offset.to_be_bytes());
+            simple.push(if block.is_sync { 0x80 } else { 0 });
+            simple.extend_from_slice(&block.bytes);
+            body.extend_from_slice(&element(0xA3, &simple)); // SimpleBlock
+        }
+        let key = blocks
+            .iter()
+            .find(|b| Some(b.track) == self.video_track && b.is_sync);
+        if let Some(key) = key {
+            self.cues
+                .push((key.time, self.position - self.segment_start));
+        }
+        self.write(&element(CLUSTER, &body))?;
+        self.writer.flush()
+    }
+
+    /// Write the last cluster and the cues, fill in the duration and seek
+    /// index, and return the writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.flush_cluster()?;
+
+        let cues_position = self.position;
+        if let (false, Some(video_track)) = (self.cues.is_empty(), self.video_track) {
+            let mut points = Vec::new();
+            for &(time, cluster) in &self.cues {
+                let mut positions = Vec::new();
+                put_uint(&mut positions, 0xF7, u64::from(video_track)); // CueTrack
+                put_uint(&mut positions, 0xF1, cluster); // CueClusterPosition
+                let mut point = Vec::new();
+                put_uint(&mut point, 0xB3, time); // CueTime
+                point.extend_from_slice(&element(0xB7, &positions)); // CueTrackPositions
+                points.extend_from_slice(&element(0xBB, &point)); // CuePoint
+            }
+            let cues = element(CUES, &points);
+            self.write(&cues)?;
+        }
+
+        let mut seeks = Vec::new();
+        let mut targets = vec![(INFO, self.info_position), (TRACKS, self.tracks_position)];
+        if !self.cues.is_empty() {
+            targets.push((CUES, cues_position));
+        }
+        for (id, position) in targets {
+            let mut seek = Vec::new();
+            let id = id_bytes(id);
+            seek.extend_from_slice(&element(SEEK_ID, &id));
+            put_uint(&mut seek, SEEK_POSITION, position - self.segment_start);
+            seeks.extend_from_slice(&element(SEEK, &seek));
+        }
+        let mut seek_head = element(SEEK_HEAD, &seeks);
+        seek_head.extend_from_slice(&void(SEEK_HEAD_SPACE - seek_head.len()));
+        self.writer.seek(SeekFrom::Start(self.segment_start))?;
+        self.writer.write_all(&seek_head)?;
+
+        let mut duration = id_bytes(0x4489);
+        duration.push(0x88);
+        duration.extend_from_slice(&(self.end_time as f64).to_be_bytes());
+        self.writer.seek(SeekFrom::Start(self.duration_position))?;
+        self.writer.write_all(&duration)?;
+
+        self.writer.seek(SeekFrom::Start(self.position))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn track_entry(track: &TrackInfo) -> Vec<u8> {
+    let mut entry = Vec::new();
+    put_uint(&mut entry, 0xD7, u64::from(track.id)); // TrackNumber
+    put_uint(&mut entry, 0x73C5, u64::from(track.id)); // TrackUID
+    put_uint(&mut entry, 0x9C, 0); // FlagLacing
+    put_string(&mut entry, 0x22_B59C, "und"); // Language
+    match &track.media {
+        TrackMedia::Avc { width, height, .. } => {
+            put_uint(&mut entry, 0x83, 1); // TrackType: video
+            put_string(&mut entry, 0x86, "V_MPEG4/ISO/AVC");
+            let mut video = Vec::new();
+            put_uint(&mut video, 0xB0, u64::from(*width)); // PixelWidth
+            put_uint(&mut video, 0xBA, u64::from(*height)); // PixelHeight
+            entry.extend_from_slice(&element(0xE0, &video));
+        }
+        TrackMedia::Aac { channels, .. } => {
+            put_uint(&mut entry, 0x83, 2); // TrackType: audio
+            put_string(&mut entry, 0x86, "A_AAC");
+            let mut audio = Vec::new();
+            let rate = element(0xB5, &f64::from(track.timescale).to_be_bytes()); // SamplingFrequency
+            audio.extend_from_slice(&rate);
+            put_uint(&mut audio, 0x9F, u64::from(*channels)); // Channels
+            entry.extend_from_slice(&element(0xE1, &audio));
+        }
+    }
+    entry.extend_from_slice(&element(0x63A2, &track.media.decoder_config())); // CodecPrivate
+    element(0xAE, &entry) // TrackEntry
+}
+
+/// An element ID as written: its bytes without leading zeros, which
+/// already carry the length marker
+fn id_bytes(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+/// A size in the shortest variable-length form; all ones is reserved for
+/// "unknown", so a value of 2^(7n) - 1 takes n + 1 bytes
+fn size_bytes(size: u64) -> Vec<u8> {
+    let mut len = 1;
+    while len < 8 && size >= (1 << (7 * len)) - 1 {
+        len += 1;
+    }
+    let marked = size | (1 << (7 * len));
+    marked.to_be_bytes()[8 - len..].to_vec()
+}
+
+fn element(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut out = id_bytes(id);
+    out.extend_from_slice(&size_bytes(body.len() as u64));
+    out.extend_from_slice(body);
+    out
+}
+
+/// An element with an 8-byte size field, so its header length is known
+/// before its body is
+fn element_sized(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut out = id_bytes(id);
+    out.extend_from_slice(&(body.len() as u64 | 1 << 56).to_be_bytes());
+    out.extend_from_slice(body);
+    out
+}
+
+fn put_uint(out: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+    out.extend_from_slice(&element(id, &bytes[skip..]));
+}
+
+fn put_string(out: &mut Vec<u8>, id: u32, value: &str) {
+    out.extend_from_slice(&element(id, value.as_bytes()));
+}
+
+/// A Void element taking up exactly `len` bytes (at least 2), to be
+/// overwritten later
+fn void(len: usize) -> Vec<u8> {
+    // One byte of ID and one of size holds up to 126 bytes of body
+    let mut out = id_bytes(VOID);
+    if len - 2 < 127 {
+        out.extend_from_slice(&size_bytes((len - 2) as u64));
+    } else {
+        out.extend_from_slice(&((len - 9) as u64 | 1 << 56).to_be_bytes());
+    }
+    out.resize(len, 0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A variable-length integer: its length and its value without the
+    /// length marker
+    fn read_vint(data: &[u8]) -> (usize, u64) {
+        let len = data[0].leading_zeros() as usize + 1;
+        let first = u64::from(data[0]) & (0xFF >> len);
+        let value = data[1..len]
+            .iter()
+            .fold(first, |acc, &b| acc << 8 | u64::from(b));
+        (len, value)
+    }
+
+    /// The elements in `data`, as ID and body. An unknown size runs to the
+    /// end.
+    fn elements(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let (id_len, _) = read_vint(&data[pos..]);
+            let id = data[pos..pos + id_len]
+                .iter()
+                .fold(0, |acc, &b| acc << 8 | u32::from(b));
+            pos += id_len;
+            let (size_len, size) = read_vint(&data[pos..]);
+            pos += size_len;
+            let end = if size == (1 << (7 * size_len)) - 1 {
+                data.len()
+            } else {
+                pos + size as usize
+            };
+            assert!(end <= data.len(), "element {id:#x} runs past the end");
+            out.push((id, &data[pos..end]));
+            pos = end;
+        }
+        out
+    }
+
+    fn ids(data: &[u8]) -> Vec<u32> {
+        elements(data).into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn child(data: &[u8], id: u32) -> &[u8] {
+        elements(data)
+            .into_iter()
+            .find(|&(i, _)| i == id)
+            .unwrap_or_else(|| panic!("no element {id:#x}"))
+            .1
+    }
+
+    fn uint(body: &[u8]) -> u64 {
+        body.iter().fold(0, |acc, &b| acc << 8 | u64::from(b))
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(size_bytes(0), [0x80]);
+        assert_eq!(size_bytes(126), [0xFE]);
+        // All ones would mean an unknown size
+        assert_eq!(size_bytes(127), [0x40, 0x7F]);
+        assert_eq!(size_bytes(16382), [0x7F, 0xFE]);
+        assert_eq!(size_bytes(16383), [0x20, 0x3F, 0xFF]);
+        for size in [0, 1, 126, 127, 128, 16383, 1 << 20, (1 << 49) - 1] {
+            assert_eq!(read_vint(&size_bytes(size)).1, size);
+        }
+    }
+
+    #[test]
+    fn ids_and_elements() {
+        assert_eq!(id_bytes(EBML), [0x1A, 0x45, 0xDF, 0xA3]);
+        assert_eq!(id_bytes(SEEK), [0x4D, 0xBB]);
+        assert_eq!(id_bytes(VOID), [0xEC]);
+        assert_eq!(element(0xA3, &[1; 127])[..3], [0xA3, 0x40, 0x7F]);
+
+        let mut out = Vec::new();
+        put_uint(&mut out, 0xE7, 0);
+        put_uint(&mut out, 0xE7, 0x1234);
+        assert_eq!(out, [0xE7, 0x81, 0, 0xE7, 0x82, 0x12, 0x34]);
+
+        let sized = element_sized(INFO, &[7; 3]);
+        assert_eq!(sized.len(), 4 + 8 + 3);
+        assert_eq!(elements(&sized), [(INFO, [7; 3].as_slice())]);
+    }
+
+    #[test]
+    fn voids_fill_their_space() {
+        for len in 2..300 {
+            let void = void(len);
+            assert_eq!(void.len(), len);
+            // One element, whose header and body take exactly that much
+            let parsed = elements(&void);
+            assert_eq!(parsed.len(), 1, "{len} bytes");
+            assert_eq!(parsed[0].0, VOID);
+            assert!(parsed[0].1.iter().all(|&b| b == 0));
+        }
+    }
+
+    fn sample(start_time: u64, duration: u32, is_sync: bool) -> Mp4Sample {
+        Mp4Sample {
+            start_time,
+            duration,
+            rendering_offset: 0,
+            is_sync,
+            bytes: mp4::Bytes::from_static(&[1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn file_layout() {
+        let tracks = [
+            TrackInfo {
+                id: 1,
+                timescale: 90000,
+                media: TrackMedia::Avc {
+                    width: 1280,
+                    height: 720,
+                    sps: vec![0x67, 0x42, 0x00, 0x28],
+                    pps: vec![0x68, 0xce],
+                },
+            },
+            TrackInfo {
+                id: 2,
+                timescale: 48000,
+                media: TrackMedia::Aac {
+                    object_type: 2,
+                    freq_index: 3,
+                    channels: 2,
+                },
+            },
+        ];
+        let mut mkv = MatroskaWriter::start(Cursor::new(Vec::new()), &tracks).unwrap();
+        mkv.write_sample(1, &sample(0, 3000, true)).unwrap();
+        mkv.write_sample(2, &sample(0, 1024, true)).unwrap();
+        mkv.write_sample(1, &sample(3000, 3000, false)).unwrap();
+        mkv.flush_cluster().unwrap();
+        mkv.write_sample(1, &sample(180_000, 3000, true)).unwrap();
+        let file = mkv.finish().unwrap().into_inner();
+
+        assert_eq!(ids(&file), [EBML, SEGMENT]);
+        assert_eq!(child(child(&file, EBML), 0x4282), b"matroska");
+        // The segment's size is unknown, so it runs to the end of the file
+        let segment = child(&file, SEGMENT);
+        assert_eq!(
+            &file[file.len() - segment.len() - 8..][..8],
+            [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            ids(segment),
+            [SEEK_HEAD, VOID, INFO, TRACKS, CLUSTER, CLUSTER, CUES]
+        );
+
+        // Each seek entry points at its element, counting from the segment
+        let seeks = elements(child(segment, SEEK_HEAD));
+        assert_eq!(seeks.len(), 3);
+        for (_, seek) in seeks {
+            let id = child(seek, SEEK_ID);
+            let position = uint(child(seek, SEEK_POSITION)) as usize;
+            assert_eq!(&segment[position..position + id.len()], id);
+        }
+
+        let info = child(segment, INFO);
+        assert_eq!(uint(child(info, 0x2A_D7B1)), 1_000_000);
+        // Up to the end of the last frame, (180000 + 3000) / 90 ms
+        let duration = child(info, 0x4489);
+        assert_eq!(f64::from_be_bytes(duration.try_into().unwrap()), 2033.0);
+        assert_eq!(ids(child(segment, TRACKS)), [0xAE, 0xAE]);
+
+        let clusters: Vec<_> = elements(segment)
+            .into_iter()
+            .filter(|&(id, _)| id == CLUSTER)
+            .map(|(_, body)| body)
+            .collect();
+        assert_eq!(ids(clusters[0]), [0xE7, 0xA3, 0xA3, 0xA3]);
+        assert_eq!(uint(child(clusters[1], 0xE7)), 2000);
+        // Track 1, 33 ms into the cluster, not a keyframe
+        let blocks: Vec<_> = elements(clusters[0]).into_iter().skip(1).collect();
+        assert_eq!(blocks[2].1, [0x81, 0, 33, 0, 1, 2, 3]);
+
+        // A cue for each cluster with a video keyframe
+        let cues = elements(child(segment, CUES));
+        assert_eq!(cues.len(), 2);
+        for ((_, point), time) in cues.into_iter().zip([0, 2000]) {
+            assert_eq!(uint(child(point, 0xB3)), time);
+            let position = uint(child(child(point, 0xB7), 0xF1)) as usize;
+            assert_eq!(&segment[position..position + 4], id_bytes(CLUSTER));
+        }
+    }
+}
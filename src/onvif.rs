@@ -0,0 +1,298 @@
+//! ONVIF events for --onvif: subscribe to the camera's event service with a
+//! pull-point subscription and note each motion (or other --onvif-topic)
+//! event in `events.jsonl` in the output directory. With --pre-roll, an
+//! event becoming active also triggers a clip, like SIGUSR1.
+
+use crate::error::StreamError as Error;
+use crate::tls;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::Request;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use sha1::{Digest, Sha1};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// How long each subscription is asked for; it's renewed at half of this
+const TERMINATION: Duration = Duration::from_secs(120);
+/// How long a pull waits for events before returning empty
+const PULL_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct OnvifConfig {
+    /// The camera's device service, e.g. http://camera/onvif/device_service
+    pub device_url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Only events whose topic contains this are noted (--onvif-topic)
+    pub topic: String,
+    pub connector: tls::HttpsConnector,
+    /// Where each event is appended as a line of JSON
+    pub events_file: PathBuf,
+    /// --pre-roll's trigger counter, bumped when an event becomes active
+    pub triggers: Option<Arc<AtomicU64>>,
+}
+
+/// Watch the camera's events in the background until shutdown, subscribing
+/// again after errors
+pub fn spawn(config: OnvifConfig, shutdown: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        let mut delay = Duration::from_secs(5);
+        while !shutdown.load(Ordering::SeqCst) {
+            match watch(&config, &shutdown).await {
+                Ok(()) => return,
+                Err(e) => warn!("ONVIF events failed, retrying in {}s: {e}", delay.as_secs()),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(60));
+        }
+    });
+}
+
+/// One subscription, pulled until shutdown
+async fn watch(config: &OnvifConfig, shutdown: &AtomicBool) -> Result<(), Error> {
+    let client: Client<_, Full<Bytes>> =
+        Client::builder(TokioExecutor::new()).build(config.connector.clone());
+
+    let capabilities = soap(
+        &client,
+        config,
+        &config.device_url,
+        "",
+        "<GetCapabilities xmlns=\"http://www.onvif.org/ver10/device/wsdl\">\
+         <Category>Events</Category></GetCapabilities>",
+    )
+    .await?;
+    let events_url = text_under(&capabilities, "Events", "XAddr")
+        .ok_or_else(|| Error::network("the camera doesn't offer an ONVIF event service"))?;
+
+    let created = soap(
+        &client,
+        config,
+        &events_url,
+        "",
+        &format!(
+            "<CreatePullPointSubscription xmlns=\"http://www.onvif.org/ver10/events/wsdl\">\
+             <InitialTerminationTime>PT{}S</InitialTerminationTime>\
+             </CreatePullPointSubscription>",
+            TERMINATION.as_secs()
+        ),
+    )
+    .await?;
+    let subscription = text_under(&created, "SubscriptionReference", "Address")
+        .ok_or_else(|| Error::network("no subscription address in the camera's reply"))?;
+    info!(url = %config.device_url, "Subscribed to ONVIF events");
+
+    let mut renewed = Instant::now();
+    let result = loop {
+        if shutdown.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+        if renewed.elapsed() >= TERMINATION / 2 {
+            let renew = format!(
+                "<Renew xmlns=\"http://docs.oasis-open.org/wsn/b-2\">\
+                 <TerminationTime>PT{}S</TerminationTime></Renew>",
+                TERMINATION.as_secs()
+            );
+            if let Err(e) = soap(&client, config, &subscription, &subscription, &renew).await {
+                break Err(e);
+            }
+            renewed = Instant::now();
+        }
+        let pull = format!(
+            "<PullMessages xmlns=\"http://www.onvif.org/ver10/events/wsdl\">\
+             <Timeout>PT{}S</Timeout><MessageLimit>32</MessageLimit></PullMessages>",
+            PULL_TIMEOUT.as_secs()
+        );
+        match soap(&client, config, &subscription, &subscription, &pull).await {
+            Ok(reply) => note_events(config, &reply)?,
+            Err(e) => break Err(e),
+        }
+    };
+
+    let unsubscribe = "<Unsubscribe xmlns=\"http://docs.oasis-open.org/wsn/b-2\"/>";
+    let _ = soap(&client, config, &subscription, &subscription, unsubscribe).await;
+    result
+}
+
+/// Append the matching events in a PullMessages reply to the events file,
+/// triggering a clip for each that's active
+fn note_events(config: &OnvifConfig, reply: &str) -> Result<(), Error> {
+    let doc = roxmltree::Document::parse(reply)?;
+    for message in doc
+        .descendants()
+        .filter(|n| n.tag_name().name() == "NotificationMessage")
+    {
+        let topic = message
+            .descendants()
+            .find(|n| n.tag_name().name() == "Topic")
+            .and_then(|n| n.text())
+            .unwrap_or("")
+            .trim();
+        if !topic.contains(&config.topic) {
+            continue;
+        }
+        let inner = message
+            .descendants()
+            .filter(|n| n.tag_name().name() == "Message")
+            .find(|n| n.attribute("UtcTime").is_some());
+        let time = inner
+            .and_then(|n| n.attribute("UtcTime"))
+            .map(str::to_string)
+            .unwrap_or_else(|| chrono::Utc::now().to_rfc3339());
+        let items = |section: &str| -> serde_json::Map<String, serde_json::Value> {
+            message
+                .descendants()
+                .filter(|n| n.tag_name().name() == section)
+                .flat_map(|n| n.children())
+                .filter(|n| n.tag_name().name() == "SimpleItem")
+                .filter_map(|n| Some((n.attribute("Name")?, n.attribute("Value")?)))
+                .map(|(name, value)| (name.to_string(), value.into()))
+                .collect()
+        };
+        let (source, data) = (items("Source"), items("Data"));
+        let active = data.values().any(|v| v == "true");
+
+        debug!(topic, active, "ONVIF event");
+        let line = serde_json::json!({
+            "time": time,
+            "topic": topic,
+            "active": active,
+            "source": source,
+            "data": data,
+        });
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.events_file)?;
+        writeln!(file, "{line}")?;
+
+        if active {
+            if let Some(triggers) = &config.triggers {
+                info!(topic, "ONVIF event, saving a clip");
+                triggers.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// POST a SOAP request and return the reply. `to` is the WS-Addressing
+/// destination, which subscription requests need.
+async fn soap(
+    client: &Client<tls::HttpsConnector, Full<Bytes>>,
+    config: &OnvifConfig,
+    url: &str,
+    to: &str,
+    body: &str,
+) -> Result<String, Error> {
+    let mut header = security_header(config);
+    if !to.is_empty() {
+        header.push_str(&format!(
+            "<a:To xmlns:a=\"http://www.w3.org/2005/08/addressing\">{}</a:To>",
+            xml_escape(to)
+        ));
+    }
+    let envelope = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\">\
+         <s:Header>{header}</s:Header><s:Body>{body}</s:Body></s:Envelope>"
+    );
+    let req = Request::post(url)
+        .header("Content-Type", "application/soap+xml; charset=utf-8")
+        .header("User-Agent", "stream-utils/1.0")
+        .body(Full::new(Bytes::from(envelope)))?;
+    // A pull holds the request open for up to PULL_TIMEOUT
+    let resp = tokio::time::timeout(PULL_TIMEOUT * 3, client.request(req))
+        .await
+        .map_err(|_| Error::network(format!("ONVIF request to {url} timed out")))??;
+    let status = resp.status();
+    let reply = resp.collect().await?.to_bytes();
+    let reply = String::from_utf8_lossy(&reply).into_owned();
+    if !status.is_success() {
+        let reason = roxmltree::Document::parse(&reply)
+            .ok()
+            .and_then(|doc| {
+                doc.descendants()
+                    .find(|n| n.tag_name().name() == "Text")
+                    .and_then(|n| n.text())
+                    .map(str::to_string)
+            })
+            .unwrap_or_default();
+        return Err(Error::network(format!(
+            "ONVIF request to {url} failed: HTTP {status} {reason}"
+        )));
+    }
+    Ok(reply)
+}
+
+/// A WS-Security UsernameToken with a password digest, which ONVIF cameras
+/// expect; empty without credentials
+fn security_header(config: &OnvifConfig) -> String {
+    let Some(username) = &config.username else {
+        return String::new();
+    };
+    let password = config.password.as_deref().unwrap_or("");
+    let nonce = nonce();
+    let created = chrono::Utc::now()
+        .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        .to_string();
+    let mut hasher = Sha1::new();
+    hasher.update(nonce);
+    hasher.update(created.as_bytes());
+    hasher.update(password.as_bytes());
+    let digest = STANDARD.encode(hasher.finalize());
+    format!(
+        "<Security s:mustUnderstand=\"1\" xmlns=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd\">\
+         <UsernameToken><Username>{}</Username>\
+         <Password Type=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest\">{digest}</Password>\
+         <Nonce EncodingType=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-message-security-1.0#Base64Binary\">{}</Nonce>\
+         <Created xmlns=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd\">{created}</Created>\
+         </UsernameToken></Security>",
+        xml_escape(username),
+        STANDARD.encode(nonce),
+    )
+}
+
+/// 16 bytes that differ on every request, from the standard library's
+/// randomly seeded hasher
+fn nonce() -> [u8; 16] {
+    use std::hash::{BuildHasher, Hasher};
+    let mut nonce = [0; 16];
+    for chunk in nonce.chunks_mut(8) {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        chunk.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    nonce
+}
+
+/// The text of the first `child` element inside the first `parent` element
+fn text_under(xml: &str, parent: &str, child: &str) -> Option<String> {
+    let doc = roxmltree::Document::parse(xml).ok()?;
+    let parent = doc.descendants().find(|n| n.tag_name().name() == parent)?;
+    let text = parent
+        .descendants()
+        .find(|n| n.tag_name().name() == child)?
+        .text()?
+        .trim();
+    Some(text.to_string())
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
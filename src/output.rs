@@ -1,7 +1,448 @@
-use chrono::Local;
-use std::io::Write;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use crate::retention;
+use crate::sidecar::{self, FileInfo};
+use chrono::{DateTime, FixedOffset, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+pub use crate::cli::ChecksumAlgorithm;
+pub use crate::retention::RetentionPolicy;
+
+/// Settings for the files a recorder writes that don't depend on the input
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Add each completed file's digest to a manifest (--checksums)
+    pub checksums: Option<ChecksumAlgorithm>,
+    /// Delete old files from the output directory after each rotation
+    /// (--keep-segments, --keep-hours)
+    pub retention: RetentionPolicy,
+    /// --filename-template, instead of the default names
+    pub filename_template: Option<FilenameTemplate>,
+    /// Also rotate once a file reaches this size (--segment-bytes)
+    pub segment_bytes: Option<u64>,
+    /// fsync the current file at most this far apart (--fsync-interval)
+    pub fsync_interval: Option<Duration>,
+    /// fsync each file and its directory when it's completed
+    pub fsync_on_rotate: bool,
+    /// Open files O_APPEND | O_DSYNC (--sync-writes)
+    pub sync_writes: bool,
+    /// Write everything to this FIFO, or stdout for `-`, instead of files
+    /// (--pipe)
+    pub pipe: Option<PathBuf>,
+    /// The pipe, already open, for one that isn't opened by name (ffmpeg's
+    /// stdin for --ffmpeg-stdin)
+    pub pipe_file: Option<Arc<std::fs::File>>,
+    /// Write a metadata sidecar for each completed file (--sidecars)
+    pub sidecars: bool,
+    /// The input URL, for sidecars
+    pub source: String,
+    /// Remux completed .ts files to .mp4 (--remux-mp4)
+    pub remux_mp4: bool,
+}
+
+impl OutputOptions {
+    /// The template files are named with
+    pub fn filenames(&self) -> FilenameTemplate {
+        self.filename_template.clone().unwrap_or_default()
+    }
+
+    /// The template for files named by EXT-X-PROGRAM-DATE-TIME
+    /// (--pdt-filenames), which goes down to the second by default
+    fn pdt_filenames(&self) -> FilenameTemplate {
+        self.filename_template
+            .clone()
+            .unwrap_or_else(|| FilenameTemplate {
+                pattern: PDT_FILENAME_TEMPLATE.to_string(),
+                stream: String::new(),
+            })
+    }
+}
+
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "%Y_%m_%d-%H_%M_{index}.{ext}";
+const PDT_FILENAME_TEMPLATE: &str = "%Y_%m_%d-%H_%M_%S_{index}.{ext}";
+
+/// How output files are named: strftime specifiers for the time the
+/// session started (or the broadcast time, with --pdt-filenames) plus
+/// `{index}`, `{seq}`, `{stream}` and `{ext}`
+#[derive(Debug, Clone)]
+pub struct FilenameTemplate {
+    pattern: String,
+    /// What `{stream}` stands for
+    stream: String,
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        Self {
+            pattern: DEFAULT_FILENAME_TEMPLATE.to_string(),
+            stream: String::new(),
+        }
+    }
+}
+
+impl std::str::FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        use chrono::format::{Item, StrftimeItems};
+
+        if !s.contains("{index}") && !s.contains("{seq}") && !s.contains('%') {
+            return Err(format!(
+                "{s:?} would give every file the same name; add {{index}}, {{seq}} or a time"
+            ));
+        }
+        if StrftimeItems::new(s).any(|item| matches!(item, Item::Error)) {
+            return Err(format!("invalid strftime specifier in {s:?}"));
+        }
+        Ok(Self {
+            pattern: s.to_string(),
+            stream: String::new(),
+        })
+    }
+}
+
+impl FilenameTemplate {
+    /// Fill in `{stream}` with a name for the input: the last part of the
+    /// URL's path without its extension (`live` for `.../live.m3u8`), or
+    /// the host, with anything but letters, digits, `-` and `_` replaced
+    pub fn with_stream(mut self, url: &str) -> Self {
+        let parsed = url::Url::parse(url).ok();
+        let name = parsed
+            .as_ref()
+            .and_then(|u| u.path_segments()?.rfind(|s| !s.is_empty()))
+            .map(|last| last.split('.').next().unwrap_or(last).to_string())
+            .filter(|name| !name.is_empty())
+            .or_else(|| {
+                parsed
+                    .as_ref()
+                    .and_then(|u| u.host_str().map(str::to_string))
+            })
+            .unwrap_or_else(|| "stream".to_string());
+        self.stream = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self
+    }
+
+    /// Whether names depend on the first segment's sequence number, so a
+    /// file has to be renamed once that's known
+    pub fn uses_sequence(&self) -> bool {
+        self.pattern.contains("{seq}")
+    }
+
+    /// Expand the template: strftime specifiers from `time`, placeholders
+    /// from `placeholder`. Time text goes through `escape`.
+    fn expand(
+        &self,
+        time: &DateTime<Local>,
+        placeholder: impl Fn(&str) -> String,
+        escape: impl Fn(String) -> String,
+    ) -> String {
+        let mut name = String::new();
+        let mut rest = self.pattern.as_str();
+        while !rest.is_empty() {
+            let next = ["{index}", "{seq}", "{stream}", "{ext}"]
+                .iter()
+                .filter_map(|p| rest.find(p).map(|at| (at, *p)))
+                .min();
+            let (literal, found) = match next {
+                Some((at, p)) => (&rest[..at], Some(p)),
+                None => (rest, None),
+            };
+            name.push_str(&escape(time.format(literal).to_string()));
+            match found {
+                Some(p) => {
+                    name.push_str(&placeholder(p));
+                    rest = &rest[literal.len() + p.len()..];
+                }
+                None => rest = "",
+            }
+        }
+        name
+    }
+
+    /// The name of file `index` of a session. `sequence` is the media
+    /// sequence number of its first segment where there is one; `{seq}`
+    /// falls back to the index.
+    pub fn filename(
+        &self,
+        time: &DateTime<Local>,
+        index: u32,
+        sequence: Option<u64>,
+        file_extension: &str,
+    ) -> String {
+        self.expand(
+            time,
+            |p| match p {
+                "{index}" => index.to_string(),
+                "{seq}" => sequence.unwrap_or(u64::from(index)).to_string(),
+                "{stream}" => self.stream.clone(),
+                _ => file_extension.to_string(),
+            },
+            |text| text,
+        )
+    }
+
+    /// The output pattern for ffmpeg's segment muxer, with `%d` where the
+    /// index goes (`{seq}` too, since ffmpeg doesn't see the playlist)
+    pub fn ffmpeg_pattern(&self, time: &DateTime<Local>, file_extension: &str) -> String {
+        self.expand(
+            time,
+            |p| match p {
+                "{index}" | "{seq}" => "%d".to_string(),
+                "{stream}" => self.stream.replace('%', "%%"),
+                _ => file_extension.replace('%', "%%"),
+            },
+            |text| text.replace('%', "%%"),
+        )
+    }
+
+    /// First index whose file doesn't exist yet, so a restart within the
+    /// same minute doesn't overwrite earlier files
+    pub fn first_free_index(
+        &self,
+        output_dir: &Path,
+        start: &DateTime<Local>,
+        file_extension: &str,
+    ) -> u32 {
+        let mut index = 0;
+        while name_taken(&output_dir.join(self.filename(start, index, None, file_extension))) {
+            index += 1;
+        }
+        index
+    }
+
+    /// Whether the template could have named `name`, a path relative to the
+    /// output directory with `/` between components. `{stream}` is the name
+    /// filled in by `with_stream`, or any name without one; `{ext}` is any
+    /// extension but those of the files written alongside recordings.
+    pub fn matches(&self, name: &str) -> bool {
+        match_name(&self.name_parts(), name)
+    }
+
+    /// How many directories deep the template puts files
+    pub fn depth(&self) -> usize {
+        self.name_parts()
+            .iter()
+            .filter(|part| matches!(part, NamePart::Literal('/')))
+            .count()
+    }
+
+    fn name_parts(&self) -> Vec<NamePart> {
+        let mut parts = Vec::new();
+        let mut rest = self.pattern.as_str();
+        while !rest.is_empty() {
+            let next = ["{index}", "{seq}", "{stream}", "{ext}"]
+                .iter()
+                .filter_map(|p| rest.find(p).map(|at| (at, *p)))
+                .min();
+            let (literal, found) = match next {
+                Some((at, p)) => (&rest[..at], Some(p)),
+                None => (rest, None),
+            };
+            time_parts(literal, &mut parts);
+            match found {
+                Some(p) => {
+                    match p {
+                        "{index}" | "{seq}" => parts.push(NamePart::Digits(1, 20)),
+                        "{stream}" if self.stream.is_empty() => parts.push(NamePart::Stream),
+                        "{stream}" => parts.extend(self.stream.chars().map(NamePart::Literal)),
+                        _ => parts.push(NamePart::Extension),
+                    }
+                    rest = &rest[literal.len() + p.len()..];
+                }
+                None => rest = "",
+            }
+        }
+        parts
+    }
+}
+
+/// A piece of a file name template, for matching names against it
+#[derive(Debug, Clone, Copy)]
+enum NamePart {
+    Literal(char),
+    /// Between this many ASCII digits
+    Digits(usize, usize),
+    /// Anything within one path component
+    Text,
+    /// An unknown `{stream}`: letters, digits, `-` and `_`
+    Stream,
+    /// `{ext}`: letters and digits
+    Extension,
+}
+
+/// Split the strftime part of a template into `NamePart`s. Numeric fields
+/// are digits of their width; anything else (month names and the like)
+/// matches any text.
+fn time_parts(pattern: &str, parts: &mut Vec<NamePart>) {
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            parts.push(NamePart::Literal(c));
+            continue;
+        }
+        let padded = match chars.peek() {
+            Some('-' | '_' | '0' | '^' | '#') => chars.next() != Some('-'),
+            _ => true,
+        };
+        let digits = |width| NamePart::Digits(if padded { width } else { 1 }, width);
+        match chars.next() {
+            Some('%') => parts.push(NamePart::Literal('%')),
+            Some('n') => parts.push(NamePart::Literal('\n')),
+            Some('t') => parts.push(NamePart::Literal('\t')),
+            Some('Y' | 'G') => parts.push(digits(4)),
+            Some('C' | 'y' | 'g' | 'm' | 'd' | 'H' | 'I' | 'M' | 'S' | 'U' | 'W' | 'V') => {
+                parts.push(digits(2))
+            }
+            Some('j') => parts.push(digits(3)),
+            Some('u' | 'w') => parts.push(NamePart::Digits(1, 1)),
+            Some('s') => parts.push(NamePart::Digits(1, 20)),
+            Some('F') => time_parts("%Y-%m-%d", parts),
+            Some('D' | 'x') => time_parts("%m/%d/%y", parts),
+            Some('T' | 'X') => time_parts("%H:%M:%S", parts),
+            Some('R') => time_parts("%H:%M", parts),
+            _ => parts.push(NamePart::Text),
+        }
+    }
+}
+
+/// Whether `name` is made up of `parts`
+fn match_name(parts: &[NamePart], name: &str) -> bool {
+    let Some((first, rest)) = parts.split_first() else {
+        return name.is_empty();
+    };
+    let run =
+        |max: usize, allowed: fn(&u8) -> bool| name.bytes().take(max).take_while(allowed).count();
+    match *first {
+        NamePart::Literal(c) => name
+            .strip_prefix(c)
+            .is_some_and(|name| match_name(rest, name)),
+        NamePart::Digits(min, max) => (min..=run(max, u8::is_ascii_digit))
+            .rev()
+            .any(|n| match_name(rest, &name[n..])),
+        NamePart::Text => {
+            let component = &name[..name.find('/').unwrap_or(name.len())];
+            component
+                .char_indices()
+                .map(|(at, c)| at + c.len_utf8())
+                .any(|end| match_name(rest, &name[end..]))
+        }
+        NamePart::Stream => (1..=run(usize::MAX, |b| {
+            b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_'
+        }))
+            .any(|n| match_name(rest, &name[n..])),
+        NamePart::Extension => (1..=run(usize::MAX, u8::is_ascii_alphanumeric))
+            .any(|n| !retention::is_side_extension(&name[..n]) && match_name(rest, &name[n..])),
+    }
+}
+
+/// Where a file is written until it's complete: `NAME.part`, renamed to
+/// `NAME` once finished, so anything under its final name is whole
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    part.into()
+}
+
+/// Whether a file of this name exists, finished or still being written
+pub fn name_taken(path: &Path) -> bool {
+    path.exists() || part_path(path).exists()
+}
+
+/// A path in `dir` named after the local time, the way RTSP and WHEP
+/// recordings are (`20260202_143000.mp4`), with `-2`, `-3`, ... appended
+/// when a file started in the same second already has that name
+pub fn timestamped_path(dir: &Path, extension: &str) -> PathBuf {
+    let ts = Local::now().format("%Y%m%d_%H%M%S").to_string();
+    let mut path = dir.join(format!("{}.{}", ts, extension));
+    let mut n = 2;
+    while name_taken(&path) {
+        path = dir.join(format!("{}-{}.{}", ts, n, extension));
+        n += 1;
+    }
+    path
+}
+
+/// How output files are opened: appending with O_DSYNC for --sync-writes
+fn open_options(options: &OutputOptions) -> std::fs::OpenOptions {
+    let mut open = std::fs::OpenOptions::new();
+    if options.sync_writes {
+        open.append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            open.custom_flags(libc::O_DSYNC);
+        }
+    } else {
+        open.write(true);
+    }
+    open
+}
+
+/// Create the `.part` file for `path`, along with any directories the
+/// template put in its name
+fn create_file(path: &Path, options: &OutputOptions) -> std::io::Result<std::fs::File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = open_options(options).create(true).open(part_path(path))?;
+    // Not truncated on opening, which O_APPEND doesn't allow
+    file.set_len(0)?;
+    Ok(file)
+}
+
+/// Open --pipe: stdout for `-`, otherwise a FIFO, made if it doesn't exist
+/// yet. Opening a FIFO waits for something to read from it.
+fn open_pipe(path: &Path) -> std::io::Result<std::fs::File> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        if path == Path::new("-") {
+            return Ok(std::io::stdout().as_fd().try_clone_to_owned()?.into());
+        }
+        if !path.exists() {
+            let name = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: `name` is a valid NUL-terminated string
+            if unsafe { libc::mkfifo(name.as_ptr(), 0o644) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        info!(pipe = %path.display(), "Waiting for a reader");
+        std::fs::OpenOptions::new().write(true).open(path)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "--pipe needs a Unix system",
+        ))
+    }
+}
+
+/// fsync a directory, so a file created or renamed in it survives a power
+/// cut too
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
 
 pub struct OutputFile {
     file: std::fs::File,
@@ -12,6 +453,114 @@ pub struct OutputFile {
     segment_duration: Duration,
     output_dir: PathBuf,
     total_bytes_written: u64,
+    /// Rename each file after the EXT-X-PROGRAM-DATE-TIME of its first segment
+    name_by_pdt: bool,
+    current_path: PathBuf,
+    bytes_in_file: u64,
+    /// Broadcast time of the first segment in the current file, if known
+    file_pdt: Option<DateTime<FixedOffset>>,
+    /// Media sequence number of the first segment in the current file
+    file_sequence: Option<u64>,
+    /// Media sequence number of the segment being written
+    next_sequence: Option<u64>,
+    options: OutputOptions,
+    /// Digest of the current file so far, when --checksums is on
+    digest: Option<Sha256>,
+    /// `finalize` has been called
+    closed: bool,
+    /// When the current file was last fsynced, for --fsync-interval
+    last_sync: Instant,
+    /// Writing to --pipe: one endless stream, no files to name or rotate
+    pipe: bool,
+    /// What's been written to the current file, for its sidecar
+    info: FileInfo,
+}
+
+/// Where a recording's output stood, for carrying on after a restart with
+/// `OutputFile::resume`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumePoint {
+    /// Start of the session, which names its files (RFC 3339)
+    pub session_start: String,
+    pub segment_index: u32,
+    /// The file being written
+    pub path: PathBuf,
+    /// Its length after the last whole segment
+    pub bytes_in_file: u64,
+    /// Seconds it had been open, towards --segment-secs
+    pub secs_in_file: u64,
+    /// EXT-X-PROGRAM-DATE-TIME of its first segment (RFC 3339)
+    pub program_date_time: Option<String>,
+    pub total_bytes: u64,
+    /// The file was finished (and its hook run) when the recording
+    /// stopped, so a resumed one starts the next file of the session
+    pub closed: bool,
+}
+
+/// A file the recorder has finished writing
+#[derive(Debug, Clone)]
+pub struct CompletedFile {
+    pub path: PathBuf,
+    /// EXT-X-PROGRAM-DATE-TIME of the file's first segment
+    pub program_date_time: Option<DateTime<FixedOffset>>,
+    /// Remux it to MP4 before handing it to the on-segment hook
+    pub remux_mp4: bool,
+    /// It's the pipe, not a file, so there's nothing to hand on
+    pub pipe: bool,
+    /// The --checksums manifest its digest went into
+    pub checksums: Option<PathBuf>,
+}
+
+/// Held while a checksum manifest is changed: the recorder appends to it
+/// while a remux swaps a TS's line for its MP4's
+static MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+
+fn hex_digest(digest: Sha256) -> String {
+    digest
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Replace `old`'s line in a checksum manifest with one for `new`, the file
+/// that replaced it (a TS by its MP4 remux), hashing `new`
+pub fn replace_checksum(manifest: &Path, old: &Path, new: &Path) -> std::io::Result<()> {
+    let mut digest = Sha256::new();
+    let mut file = std::fs::File::open(new)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    let hex = hex_digest(digest);
+    let name = |path: &Path| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    let (old_name, new_name) = (name(old), name(new));
+
+    let _lock = MANIFEST_LOCK.lock().unwrap();
+    let mut updated = String::new();
+    for line in std::fs::read_to_string(manifest)?.lines() {
+        match line.split_once("  ") {
+            Some((_, listed)) if listed == old_name => {
+                updated.push_str(&format!("{hex}  {new_name}\n"));
+            }
+            _ => {
+                updated.push_str(line);
+                updated.push('\n');
+            }
+        }
+    }
+    let part = part_path(manifest);
+    std::fs::write(&part, updated)?;
+    std::fs::rename(&part, manifest)
 }
 
 impl OutputFile {
@@ -19,25 +568,29 @@ impl OutputFile {
         file_extension: String,
         output_dir: PathBuf,
         segment_duration: Duration,
-        verbose: bool,
+        name_by_pdt: bool,
+        options: OutputOptions,
     ) -> std::io::Result<Self> {
         let start_time = Local::now();
-        // Find first available segment index (don't overwrite existing files)
-        let mut segment_index = 0;
-        loop {
-            let filename = Self::format_filename(&start_time, segment_index, &file_extension);
-            let path = output_dir.join(&filename);
-            if !path.exists() {
-                break;
+        let (path, segment_index, file) = match &options.pipe {
+            Some(pipe) => {
+                let file = match &options.pipe_file {
+                    Some(file) => file.try_clone()?,
+                    None => open_pipe(pipe)?,
+                };
+                (pipe.clone(), 0, file)
             }
-            segment_index += 1;
-        }
-        let filename = Self::format_filename(&start_time, segment_index, &file_extension);
-        let path = output_dir.join(&filename);
-        if verbose {
-            eprintln!("Writing to: {}", path.display());
-        }
-        let file = std::fs::File::create(&path)?;
+            None => {
+                let names = options.filenames();
+                let segment_index =
+                    names.first_free_index(&output_dir, &start_time, &file_extension);
+                let filename = names.filename(&start_time, segment_index, None, &file_extension);
+                let path = output_dir.join(&filename);
+                debug!(file = %path.display(), "Writing to new file");
+                let file = create_file(&path, &options)?;
+                (path, segment_index, file)
+            }
+        };
 
         Ok(Self {
             file,
@@ -48,61 +601,437 @@ impl OutputFile {
             segment_duration,
             output_dir,
             total_bytes_written: 0,
+            name_by_pdt,
+            current_path: path,
+            bytes_in_file: 0,
+            file_pdt: None,
+            file_sequence: None,
+            next_sequence: None,
+            digest: options.checksums.map(|_| Sha256::new()),
+            pipe: options.pipe.is_some(),
+            options,
+            closed: false,
+            last_sync: Instant::now(),
+            info: FileInfo::new(start_time),
         })
     }
 
-    fn format_filename(
-        start: &chrono::DateTime<Local>,
-        index: u32,
-        file_extension: &str,
-    ) -> String {
-        format!(
-            "{}_{}.{}",
-            start.format("%Y_%m_%d-%H_%M"),
-            index,
-            file_extension
-        )
+    /// Carry on writing the file a previous run left at `point`. Anything
+    /// written after the point (a segment cut short by a crash) is cut off.
+    /// If the file was closed, the session goes on with the next one.
+    pub fn resume(
+        file_extension: String,
+        output_dir: PathBuf,
+        segment_duration: Duration,
+        name_by_pdt: bool,
+        options: OutputOptions,
+        point: &ResumePoint,
+    ) -> std::io::Result<Self> {
+        let invalid = |what| std::io::Error::new(std::io::ErrorKind::InvalidData, what);
+        let start_time = DateTime::parse_from_rfc3339(&point.session_start)
+            .map_err(|_| invalid("bad session start"))?
+            .with_timezone(&Local);
+        let file_pdt = match &point.program_date_time {
+            Some(pdt) => {
+                Some(DateTime::parse_from_rfc3339(pdt).map_err(|_| invalid("bad date-time"))?)
+            }
+            None => None,
+        };
+        if point.closed {
+            let names = options.filenames();
+            let mut segment_index = point.segment_index + 1;
+            while name_taken(&output_dir.join(names.filename(
+                &start_time,
+                segment_index,
+                None,
+                &file_extension,
+            ))) {
+                segment_index += 1;
+            }
+            let path =
+                output_dir.join(names.filename(&start_time, segment_index, None, &file_extension));
+            debug!(file = %path.display(), "Resuming session with a new file");
+            return Ok(Self {
+                file: create_file(&path, &options)?,
+                file_extension,
+                start_time,
+                segment_index,
+                segment_start: Instant::now(),
+                segment_duration,
+                output_dir,
+                total_bytes_written: point.total_bytes,
+                name_by_pdt,
+                current_path: path,
+                bytes_in_file: 0,
+                file_pdt: None,
+                file_sequence: None,
+                next_sequence: None,
+                digest: options.checksums.map(|_| Sha256::new()),
+                options,
+                closed: false,
+                last_sync: Instant::now(),
+                pipe: false,
+                info: FileInfo::new(Local::now()),
+            });
+        }
+        // A state file from before .part files points at the file itself
+        let part = part_path(&point.path);
+        if !part.exists() && point.path.exists() {
+            std::fs::rename(&point.path, &part)?;
+        }
+        let mut file = open_options(&options).read(true).open(&part)?;
+        if file.metadata()?.len() < point.bytes_in_file {
+            return Err(invalid("file is shorter than recorded"));
+        }
+        file.set_len(point.bytes_in_file)?;
+        let mut digest = options.checksums.map(|_| Sha256::new());
+        if let Some(digest) = &mut digest {
+            // The manifest entry covers the whole file, not just this run
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                digest.update(&buf[..n]);
+            }
+        }
+        file.seek(std::io::SeekFrom::End(0))?;
+        let segment_start = Instant::now()
+            .checked_sub(Duration::from_secs(point.secs_in_file))
+            .unwrap_or_else(Instant::now);
+        debug!(file = %point.path.display(), bytes = point.bytes_in_file, "Resuming file");
+
+        Ok(Self {
+            file,
+            file_extension,
+            start_time,
+            segment_index: point.segment_index,
+            segment_start,
+            segment_duration,
+            output_dir,
+            total_bytes_written: point.total_bytes,
+            name_by_pdt,
+            current_path: point.path.clone(),
+            bytes_in_file: point.bytes_in_file,
+            file_pdt,
+            file_sequence: None,
+            next_sequence: None,
+            digest,
+            options,
+            closed: false,
+            last_sync: Instant::now(),
+            pipe: false,
+            // Only what's written from here on is counted
+            info: FileInfo::new(
+                Local::now() - chrono::Duration::seconds(point.secs_in_file as i64),
+            ),
+        })
     }
 
-    fn current_path(&self) -> PathBuf {
-        self.output_dir.join(Self::format_filename(
-            &self.start_time,
-            self.segment_index,
-            &self.file_extension,
-        ))
+    /// Where the output stands, for `resume`
+    pub fn resume_point(&self) -> ResumePoint {
+        ResumePoint {
+            session_start: self.start_time.to_rfc3339(),
+            segment_index: self.segment_index,
+            path: self.current_path.clone(),
+            bytes_in_file: self.bytes_in_file,
+            secs_in_file: self.segment_start.elapsed().as_secs(),
+            program_date_time: self.file_pdt.map(|pdt| pdt.to_rfc3339()),
+            total_bytes: self.total_bytes_written,
+            closed: self.closed,
+        }
+    }
+
+    /// File name for a file whose first segment was broadcast at `pdt`, in
+    /// local time like the default names but to the second:
+    /// `2026_02_02-14_30_05_0.ts`. The index only goes up on a collision.
+    fn pdt_path(&self, pdt: &DateTime<FixedOffset>) -> PathBuf {
+        let names = self.options.pdt_filenames();
+        let time = pdt.with_timezone(&Local);
+        let mut index = 0;
+        loop {
+            let path = self.output_dir.join(names.filename(
+                &time,
+                index,
+                self.file_sequence,
+                &self.file_extension,
+            ));
+            if !name_taken(&path) {
+                return path;
+            }
+            index += 1;
+        }
+    }
+
+    /// Split a default file name back into its session
+    /// prefix and index (`2026_02_02-14_30_3.ts` -> `2026_02_02-14_30`, 3)
+    pub fn parse_filename(path: &Path) -> Option<(String, u32)> {
+        let stem = path.file_stem()?.to_str()?;
+        let (session, index) = stem.rsplit_once('_')?;
+        // --pdt-filenames names go down to the second
+        chrono::NaiveDateTime::parse_from_str(session, "%Y_%m_%d-%H_%M")
+            .or_else(|_| chrono::NaiveDateTime::parse_from_str(session, "%Y_%m_%d-%H_%M_%S"))
+            .ok()?;
+        Some((session.to_string(), index.parse().ok()?))
+    }
+
+    /// Path of the file currently being written, under its final name.
+    /// Until it's complete it's at `part_path` of this.
+    pub fn current_path(&self) -> PathBuf {
+        self.current_path.clone()
     }
 
-    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+    /// Append a segment. `program_date_time` is its broadcast time, used to
+    /// name the file when it's the file's first segment.
+    pub fn write(
+        &mut self,
+        data: &[u8],
+        program_date_time: Option<DateTime<FixedOffset>>,
+    ) -> std::io::Result<()> {
+        if self.bytes_in_file == 0 && !self.pipe {
+            self.file_pdt = program_date_time;
+            self.file_sequence = self.next_sequence;
+            if let Some(pdt) = program_date_time.filter(|_| self.name_by_pdt) {
+                let path = self.pdt_path(&pdt);
+                self.rename_current(path)?;
+                debug!(file = %self.current_path.display(), "Named file by program date-time");
+            } else if self.file_sequence.is_some() && self.options.filenames().uses_sequence() {
+                let path = self.output_dir.join(self.options.filenames().filename(
+                    &self.start_time,
+                    self.segment_index,
+                    self.file_sequence,
+                    &self.file_extension,
+                ));
+                self.rename_current(path)?;
+                debug!(file = %self.current_path.display(), "Named file by sequence number");
+            }
+        }
         self.file.write_all(data)?;
+        if let Some(digest) = &mut self.digest {
+            digest.update(data);
+        }
+        self.bytes_in_file += data.len() as u64;
         self.total_bytes_written += data.len() as u64;
+        if let Some(interval) = self.options.fsync_interval {
+            if self.last_sync.elapsed() >= interval {
+                self.file.sync_data()?;
+                self.last_sync = Instant::now();
+            }
+        }
         Ok(())
     }
 
-    /// Check if rotation is needed. Returns the completed file path if rotated.
-    pub fn maybe_rotate(&mut self, verbose: bool) -> std::io::Result<Option<PathBuf>> {
-        if self.segment_start.elapsed() >= self.segment_duration {
-            self.file.flush()?;
-            let completed_path = self.current_path();
+    /// The media sequence number of the segment about to be written, for
+    /// `{seq}` in --filename-template
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.next_sequence = Some(sequence);
+    }
 
-            self.segment_index += 1;
-            let filename =
-                Self::format_filename(&self.start_time, self.segment_index, &self.file_extension);
-            let path = self.output_dir.join(&filename);
-            if verbose {
-                eprintln!("\nRotating to: {}", path.display());
-            }
-            self.file = std::fs::File::create(&path)?;
-            self.segment_start = Instant::now();
+    fn rename_current(&mut self, path: PathBuf) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(part_path(&self.current_path), part_path(&path))?;
+        self.current_path = path;
+        Ok(())
+    }
 
-            return Ok(Some(completed_path));
+    /// Give the finished file its final name, syncing it first with
+    /// --fsync-on-rotate. Its sidecar is written first, so a watcher that
+    /// picks up the file under its final name finds the sidecar there too.
+    fn complete_current(&self) -> std::io::Result<()> {
+        if self.pipe {
+            return Ok(());
+        }
+        if self.options.fsync_on_rotate {
+            self.file.sync_all()?;
+        }
+        if self.options.sidecars {
+            sidecar::write(
+                &self.current_path,
+                &self.options.source,
+                &self.info,
+                self.bytes_in_file,
+                self.file_pdt,
+            )?;
+        }
+        std::fs::rename(part_path(&self.current_path), &self.current_path)?;
+        if self.options.fsync_on_rotate {
+            sync_dir(self.current_path.parent().unwrap_or(&self.output_dir))?;
+        }
+        Ok(())
+    }
+
+    /// Whether nothing has been written to the current file yet
+    pub fn is_empty(&self) -> bool {
+        self.bytes_in_file == 0
+    }
+
+    pub fn is_pipe(&self) -> bool {
+        self.pipe
+    }
+
+    fn completed(&self) -> CompletedFile {
+        CompletedFile {
+            path: self.current_path.clone(),
+            program_date_time: self.file_pdt,
+            remux_mp4: self.options.remux_mp4
+                && !self.pipe
+                && self.file_extension.eq_ignore_ascii_case("ts"),
+            pipe: self.pipe,
+            checksums: self.manifest_path().filter(|_| !self.pipe),
+        }
+    }
+
+    /// Time the current file's --segment-secs from now, as when a clip
+    /// starts in a file that has been waiting for it
+    pub fn restart_clock(&mut self) {
+        self.segment_start = Instant::now();
+        self.info = FileInfo::new(Local::now());
+    }
+
+    /// Count a whole segment written to the current file, for its sidecar
+    pub fn add_segment(&mut self, sequence: Option<u64>, duration: Duration, discontinuity: bool) {
+        self.info.add_segment(sequence, duration, discontinuity);
+    }
+
+    /// Whether the current file has run for its full duration, or reached
+    /// --segment-bytes
+    pub fn rotation_due(&self) -> bool {
+        !self.pipe
+            && (self.segment_start.elapsed() >= self.segment_duration
+                || self
+                    .options
+                    .segment_bytes
+                    .is_some_and(|max| self.bytes_in_file >= max))
+    }
+
+    /// Close the current file if writing `len` more bytes would take it past
+    /// --segment-bytes, returning it. A write bigger than the limit on its
+    /// own still goes into a file, which is then over it.
+    pub fn make_room(&mut self, len: usize) -> std::io::Result<Option<CompletedFile>> {
+        let full = self
+            .options
+            .segment_bytes
+            .is_some_and(|max| self.bytes_in_file + len as u64 > max);
+        if self.pipe || self.bytes_in_file == 0 || !full {
+            return Ok(None);
+        }
+        self.rotate().map(Some)
+    }
+
+    /// The --checksums manifest in the output directory
+    fn manifest_path(&self) -> Option<PathBuf> {
+        let manifest = match self.options.checksums? {
+            ChecksumAlgorithm::Sha256 => "SHA256SUMS",
+        };
+        Some(self.output_dir.join(manifest))
+    }
+
+    /// Add the current file's digest to the manifest in the output
+    /// directory, in the format `sha256sum -c` reads
+    fn record_checksum(&mut self) -> std::io::Result<()> {
+        let Some(manifest) = self.manifest_path() else {
+            return Ok(());
+        };
+        let digest = self.digest.replace(Sha256::new()).unwrap_or_default();
+        let hex = hex_digest(digest);
+        let name = self
+            .current_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let _lock = MANIFEST_LOCK.lock().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(manifest)?;
+        writeln!(file, "{hex}  {name}")?;
+        debug!(file = %self.current_path.display(), digest = %hex, "Recorded checksum");
+        Ok(())
+    }
+
+    /// Check if rotation is needed. Returns the completed file if rotated.
+    pub fn maybe_rotate(&mut self) -> std::io::Result<Option<CompletedFile>> {
+        if self.rotation_due() {
+            return self.rotate().map(Some);
         }
         Ok(None)
     }
 
-    /// Finalize the current segment (flush and return path)
-    pub fn finalize(&mut self) -> std::io::Result<PathBuf> {
+    /// Close the current file and start the next one now, returning the
+    /// completed file
+    pub fn rotate(&mut self) -> std::io::Result<CompletedFile> {
+        if self.pipe {
+            return Ok(self.completed());
+        }
+        self.file.flush()?;
+        self.record_checksum()?;
+        self.complete_current()?;
+        let completed = self.completed();
+
+        self.segment_index += 1;
+        let filename = self.options.filenames().filename(
+            &self.start_time,
+            self.segment_index,
+            None,
+            &self.file_extension,
+        );
+        let path = self.output_dir.join(&filename);
+        debug!(
+            file = %path.display(),
+            bytes = self.total_bytes_written,
+            "Rotating to new file"
+        );
+        self.file = create_file(&path, &self.options)?;
+        self.current_path = path;
+        self.bytes_in_file = 0;
+        self.file_pdt = None;
+        self.file_sequence = None;
+        self.info = FileInfo::new(Local::now());
+        self.segment_start = Instant::now();
+        self.last_sync = Instant::now();
+        self.apply_retention();
+
+        Ok(completed)
+    }
+
+    /// Delete the files in the output directory that fall outside the
+    /// retention policy. The file being written is always kept.
+    fn apply_retention(&self) {
+        if self.options.retention.is_empty() {
+            return;
+        }
+        let files = match retention::scan(&self.output_dir, self.options.filename_template.as_ref())
+        {
+            Ok(files) => files,
+            Err(e) => {
+                warn!(dir = %self.output_dir.display(), "Can't list old recordings: {e}");
+                return;
+            }
+        };
+        for file in retention::expired(files, &self.options.retention, SystemTime::now()) {
+            if file.path == self.current_path {
+                continue;
+            }
+            match retention::remove(&file.path) {
+                Ok(()) => info!(file = %file.path.display(), "Removed old recording"),
+                Err(e) => warn!("Failed to remove {}: {e}", file.path.display()),
+            }
+        }
+    }
+
+    /// Finalize the current segment (flush and return it)
+    pub fn finalize(&mut self) -> std::io::Result<CompletedFile> {
+        if self.closed {
+            return Ok(self.completed());
+        }
         self.file.flush()?;
-        Ok(self.current_path())
+        self.record_checksum()?;
+        self.complete_current()?;
+        self.closed = true;
+        Ok(self.completed())
     }
 
     /// Get total bytes written across all segments
@@ -110,3 +1039,43 @@ impl OutputFile {
         self.total_bytes_written
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_stay_under_segment_bytes() {
+        let dir = std::env::temp_dir().join(format!("segment-bytes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = OutputOptions {
+            segment_bytes: Some(30_000),
+            ..OutputOptions::default()
+        };
+        let mut output = OutputFile::new(
+            "ts".to_string(),
+            dir.clone(),
+            Duration::from_secs(3600),
+            false,
+            options,
+        )
+        .unwrap();
+        // Written the way the HLS and DASH recorders do, a segment at a time
+        let segment = vec![0x47; 4700];
+        for _ in 0..20 {
+            output.make_room(segment.len()).unwrap();
+            output.write(&segment, None).unwrap();
+            output.maybe_rotate().unwrap();
+        }
+        output.finalize().unwrap();
+
+        let sizes: Vec<u64> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(sizes.len() > 1);
+        assert!(sizes.iter().all(|&size| size <= 30_000), "{sizes:?}");
+        assert_eq!(sizes.iter().sum::<u64>(), 20 * 4700);
+    }
+}
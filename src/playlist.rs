@@ -1,6 +1,50 @@
-use m3u8_rs::{MasterPlaylist, MediaPlaylist};
+use clap::ValueEnum;
+use m3u8_rs::{
+    AlternativeMedia, AlternativeMediaType, Key, KeyMethod, MasterPlaylist, MediaPlaylist,
+    MediaSegment, VariantStream,
+};
+use std::time::Duration;
+use tracing::debug;
 use url::Url;
 
+/// Which end of the quality range --quality picks from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    /// Highest resolution, then frame rate, then bandwidth
+    Best,
+    /// Lowest resolution, then frame rate, then bandwidth
+    Worst,
+}
+
+/// How to choose a variant from a master playlist, shared by record and probe
+#[derive(clap::Args, Debug, Clone)]
+pub struct VariantArgs {
+    /// Pick the best or worst variant that passes the limits below
+    #[arg(
+        long,
+        value_enum,
+        default_value = "best",
+        help_heading = "Variant selection"
+    )]
+    pub quality: Quality,
+
+    /// Skip variants taller than this many pixels (e.g. 720)
+    #[arg(long, help_heading = "Variant selection")]
+    pub max_height: Option<u64>,
+
+    /// Skip variants above this bandwidth in bits/s
+    #[arg(long, help_heading = "Variant selection")]
+    pub max_bandwidth: Option<u64>,
+
+    /// Skip variants below this bandwidth in bits/s
+    #[arg(long, help_heading = "Variant selection")]
+    pub min_bandwidth: Option<u64>,
+
+    /// Record the variant at this position in the master playlist (0-based, as listed by probe)
+    #[arg(long, conflicts_with_all = ["quality", "max_height", "max_bandwidth", "min_bandwidth"], help_heading = "Variant selection")]
+    pub variant: Option<usize>,
+}
+
 /// Try to extract FPS value from a string like "FPS:30.0" or containing "FPS:30.0"
 fn parse_fps_from_string(s: &str) -> Option<f64> {
     // Try exact match first (e.g., "FPS:30.0")
@@ -25,7 +69,7 @@ fn parse_fps_from_string(s: &str) -> Option<f64> {
 
 /// Extract frame rate from variant - checks standard frame_rate field first,
 /// then falls back to other_attributes NAME (e.g., NAME="FPS:30.0")
-fn extract_frame_rate(variant: &m3u8_rs::VariantStream) -> f64 {
+pub fn extract_frame_rate(variant: &m3u8_rs::VariantStream) -> f64 {
     // Standard FRAME-RATE attribute
     if let Some(fps) = variant.frame_rate {
         if fps > 0.0 {
@@ -56,31 +100,318 @@ pub fn is_fmp4_playlist(playlist: &MediaPlaylist) -> bool {
     playlist.segments.iter().any(|s| s.map.is_some())
 }
 
-/// Select best variant: highest resolution, then highest framerate at that resolution
-pub fn select_best_variant(master: &MasterPlaylist, base_url: &Url, verbose: bool) -> Option<Url> {
-    let best = master.variants.iter().max_by(|a, b| {
-        let res_a = a.resolution.map(|r| r.width * r.height).unwrap_or(0);
-        let res_b = b.resolution.map(|r| r.width * r.height).unwrap_or(0);
-        let fps_a = extract_frame_rate(a);
-        let fps_b = extract_frame_rate(b);
+/// Encryption method named by the playlist's EXT-X-KEY tags, if any segment is encrypted
+pub fn encryption_method(playlist: &MediaPlaylist) -> Option<String> {
+    playlist
+        .segments
+        .iter()
+        .filter_map(|s| s.key.as_ref())
+        .find_map(|key| match key.method {
+            KeyMethod::None => None,
+            KeyMethod::AES128 => Some("AES-128".to_string()),
+            KeyMethod::SampleAES => Some("SAMPLE-AES".to_string()),
+            KeyMethod::Other(ref method) => Some(method.clone()),
+        })
+}
+
+/// Whether segments under `key` can be decrypted natively: clear, or AES-128
+/// with a plain key file. SAMPLE-AES and vendor KEYFORMATs (FairPlay,
+/// Widevine) are DRM schemes we don't unwrap.
+pub fn is_supported_key(key: &Key) -> bool {
+    match key.method {
+        KeyMethod::None => true,
+        KeyMethod::AES128 => key.keyformat.as_deref().is_none_or(|f| f == "identity"),
+        _ => false,
+    }
+}
+
+/// Refuse a playlist whose encryption we can't undo, instead of recording
+/// files nothing can play
+pub fn check_encryption(playlist: &MediaPlaylist) -> Result<(), String> {
+    match playlist
+        .segments
+        .iter()
+        .filter_map(|s| s.key.as_ref())
+        .find(|key| !is_supported_key(key))
+    {
+        Some(key) => Err(unsupported_key_message(key)),
+        None => Ok(()),
+    }
+}
+
+pub fn unsupported_key_message(key: &Key) -> String {
+    let method = match key.method {
+        KeyMethod::SampleAES => "SAMPLE-AES",
+        KeyMethod::Other(ref method) => method,
+        _ => "AES-128",
+    };
+    match key.keyformat.as_deref() {
+        Some(format) if format != "identity" => format!(
+            "Stream uses {method} encryption with KEYFORMAT {format} (DRM), which isn't supported"
+        ),
+        _ => format!("Stream uses {method} encryption, which isn't supported"),
+    }
+}
+
+/// Broadcast time of the segment at `index`: its own EXT-X-PROGRAM-DATE-TIME,
+/// or the nearest earlier one plus the durations in between
+pub fn program_date_time(
+    playlist: &MediaPlaylist,
+    index: usize,
+) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    let mut offset_secs = 0.0;
+    for (i, segment) in playlist.segments[..=index].iter().enumerate().rev() {
+        if i < index {
+            offset_secs += segment.duration as f64;
+        }
+        if let Some(pdt) = segment.program_date_time {
+            return Some(pdt + chrono::TimeDelta::milliseconds((offset_secs * 1000.0) as i64));
+        }
+    }
+    None
+}
+
+/// Kind of ad/program marker a segment carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerKind {
+    /// Start of an ad break (EXT-X-CUE-OUT or a DATERANGE with SCTE35-OUT)
+    CueOut,
+    /// End of an ad break (EXT-X-CUE-IN or a DATERANGE with SCTE35-IN)
+    CueIn,
+    /// Any other EXT-X-DATERANGE
+    DateRange,
+}
+
+impl MarkerKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MarkerKind::CueOut => "cue-out",
+            MarkerKind::CueIn => "cue-in",
+            MarkerKind::DateRange => "daterange",
+        }
+    }
+}
+
+/// An EXT-X-CUE-OUT/IN or EXT-X-DATERANGE tag found before a segment
+#[derive(Debug, Clone)]
+pub struct Marker {
+    pub kind: MarkerKind,
+    /// DATERANGE ID
+    pub id: Option<String>,
+    pub duration_secs: Option<f64>,
+    /// DATERANGE START-DATE
+    pub start: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
+
+/// Parse a cue-out duration: `30`, `30.0` or `DURATION=30`
+fn parse_cue_duration(rest: &str) -> Option<f64> {
+    rest.split(',')
+        .map(|part| part.trim().trim_start_matches("DURATION="))
+        .find_map(|value| value.parse().ok())
+}
+
+/// Markers attached to a segment, in tag order: cue tags, then DATERANGE
+pub fn segment_markers(segment: &MediaSegment) -> Vec<Marker> {
+    let mut markers: Vec<Marker> = segment
+        .unknown_tags
+        .iter()
+        .filter_map(|tag| {
+            let kind = match tag.tag.as_str() {
+                "X-CUE-OUT" => MarkerKind::CueOut,
+                "X-CUE-IN" => MarkerKind::CueIn,
+                _ => return None,
+            };
+            Some(Marker {
+                kind,
+                id: None,
+                duration_secs: tag.rest.as_deref().and_then(parse_cue_duration),
+                start: None,
+            })
+        })
+        .collect();
+
+    if let Some(ref range) = segment.daterange {
+        let has = |attr: &str| {
+            range
+                .other_attributes
+                .as_ref()
+                .is_some_and(|attrs| attrs.contains_key(attr))
+        };
+        let kind = if has("SCTE35-OUT") {
+            MarkerKind::CueOut
+        } else if has("SCTE35-IN") {
+            MarkerKind::CueIn
+        } else {
+            MarkerKind::DateRange
+        };
+        markers.push(Marker {
+            kind,
+            id: Some(range.id.clone()),
+            duration_secs: range.duration.or(range.planned_duration),
+            start: Some(range.start_date),
+        });
+    }
+    markers
+}
+
+/// A segment's EXTINF duration (a negative one counts as zero)
+pub fn segment_duration(segment: &MediaSegment) -> Duration {
+    Duration::from_secs_f64(segment.duration.max(0.0) as f64)
+}
+
+/// How far behind the live edge the segment at `index` is: wall clock versus
+/// the end of the segment when it carries EXT-X-PROGRAM-DATE-TIME, otherwise
+/// the duration of the playlist that follows it.
+pub fn live_edge_lag(playlist: &MediaPlaylist, index: usize) -> Duration {
+    let segment = &playlist.segments[index];
+    if let Some(pdt) = segment.program_date_time {
+        let end = pdt + chrono::TimeDelta::milliseconds((segment.duration * 1000.0) as i64);
+        return chrono::Utc::now()
+            .signed_duration_since(end)
+            .to_std()
+            .unwrap_or_default();
+    }
+    let remaining: f32 = playlist.segments[index + 1..]
+        .iter()
+        .map(|s| s.duration)
+        .sum();
+    Duration::from_secs_f32(remaining.max(0.0))
+}
+
+/// The variant chosen from a master playlist, plus its audio rendition's
+/// playlist when audio isn't muxed into the variant
+#[derive(Debug, Clone)]
+pub struct SelectedVariant {
+    pub video: Url,
+    pub audio: Option<Url>,
+    /// I-frame playlist (EXT-X-I-FRAME-STREAM-INF) closest to the variant's resolution
+    pub i_frames: Option<Url>,
+    /// The variant's RESOLUTION (width, height) and BANDWIDTH, when it was
+    /// picked from a master playlist
+    pub resolution: Option<(u64, u64)>,
+    pub bandwidth: Option<u64>,
+}
+
+impl SelectedVariant {
+    /// A media playlist URL on its own, without a master playlist around it
+    pub fn media(url: Url) -> Self {
+        Self {
+            video: url,
+            audio: None,
+            i_frames: None,
+            resolution: None,
+            bandwidth: None,
+        }
+    }
+}
 
-        res_a.cmp(&res_b).then_with(|| {
+/// The EXT-X-MEDIA audio rendition a variant's AUDIO group points to:
+/// the DEFAULT=YES entry, else AUTOSELECT=YES, else the first. Renditions
+/// without a URI are carried in the variant itself and don't count.
+pub fn audio_rendition<'a>(
+    master: &'a MasterPlaylist,
+    variant: &VariantStream,
+) -> Option<&'a AlternativeMedia> {
+    let group = variant.audio.as_deref()?;
+    let candidates: Vec<_> = master
+        .alternatives
+        .iter()
+        .filter(|m| {
+            m.media_type == AlternativeMediaType::Audio && m.group_id == group && m.uri.is_some()
+        })
+        .collect();
+    candidates
+        .iter()
+        .find(|m| m.default)
+        .or_else(|| candidates.iter().find(|m| m.autoselect))
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+/// Order variants by quality: resolution, then frame rate, then bandwidth
+fn compare_quality(a: &VariantStream, b: &VariantStream) -> std::cmp::Ordering {
+    let res_a = a.resolution.map(|r| r.width * r.height).unwrap_or(0);
+    let res_b = b.resolution.map(|r| r.width * r.height).unwrap_or(0);
+    let fps_a = extract_frame_rate(a);
+    let fps_b = extract_frame_rate(b);
+
+    res_a
+        .cmp(&res_b)
+        .then_with(|| {
             fps_a
                 .partial_cmp(&fps_b)
                 .unwrap_or(std::cmp::Ordering::Equal)
         })
-    })?;
-
-    let variant_url = base_url.join(&best.uri).ok()?;
-    if verbose {
-        if let Some(res) = best.resolution {
-            eprintln!(
-                "Selected: {}x{} @ {:.1} fps",
-                res.width,
-                res.height,
-                extract_frame_rate(best)
-            );
+        .then_with(|| a.bandwidth.cmp(&b.bandwidth))
+}
+
+/// Pick the variant to record. `--variant` names one directly; otherwise the
+/// best (or worst) variant within the height and bandwidth limits wins.
+/// I-frame-only variants are never picked.
+pub fn select_variant(
+    master: &MasterPlaylist,
+    base_url: &Url,
+    prefs: &VariantArgs,
+) -> Result<SelectedVariant, String> {
+    let best = if let Some(index) = prefs.variant {
+        let variant = master.variants.get(index).ok_or_else(|| {
+            format!(
+                "--variant {index} is out of range: the playlist has {} variants",
+                master.variants.len()
+            )
+        })?;
+        if variant.is_i_frame {
+            return Err(format!(
+                "--variant {index} is an I-frame-only variant, which can't be recorded"
+            ));
+        }
+        variant
+    } else {
+        let candidates = master.variants.iter().filter(|v| {
+            let height = v.resolution.map(|r| r.height);
+            !v.is_i_frame
+                && prefs
+                    .max_height
+                    .is_none_or(|max| height.is_none_or(|h| h <= max))
+                && prefs.max_bandwidth.is_none_or(|max| v.bandwidth <= max)
+                && prefs.min_bandwidth.is_none_or(|min| v.bandwidth >= min)
+        });
+        match prefs.quality {
+            Quality::Best => candidates.max_by(|a, b| compare_quality(a, b)),
+            Quality::Worst => candidates.min_by(|a, b| compare_quality(a, b)),
         }
+        .ok_or("No variant matches the variant selection options")?
+    };
+
+    let variant_url = base_url.join(&best.uri).map_err(|e| e.to_string())?;
+    if let Some(res) = best.resolution {
+        debug!(
+            "Selected: {}x{} @ {:.1} fps",
+            res.width,
+            res.height,
+            extract_frame_rate(best)
+        );
     }
-    Some(variant_url)
+    let audio_url = audio_rendition(master, best).and_then(|media| {
+        debug!(
+            "Audio rendition: {} ({})",
+            media.name,
+            media.language.as_deref().unwrap_or("no language")
+        );
+        base_url.join(media.uri.as_deref()?).ok()
+    });
+    let height = best.resolution.map(|r| r.height).unwrap_or(0);
+    let i_frames = master
+        .variants
+        .iter()
+        .filter(|v| v.is_i_frame)
+        .min_by_key(|v| v.resolution.map(|r| r.height).unwrap_or(0).abs_diff(height))
+        .and_then(|v| base_url.join(&v.uri).ok());
+    Ok(SelectedVariant {
+        video: variant_url,
+        audio: audio_url,
+        i_frames,
+        resolution: best.resolution.map(|r| (r.width, r.height)),
+        bandwidth: Some(best.bandwidth),
+    })
 }
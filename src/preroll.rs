@@ -0,0 +1,130 @@
+//! Event capture for --pre-roll: segments are held in a rolling buffer in
+//! memory instead of being written, and only saved when a trigger arrives
+//! (SIGUSR1 or --trigger-file), so a clip includes the seconds before the
+//! event. Recording carries on for --post-roll after the last trigger.
+
+use chrono::{DateTime, FixedOffset};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct PreRollConfig {
+    /// How much media to keep buffered
+    pub buffer: Duration,
+    /// How long to keep recording after the last trigger
+    pub post_roll: Duration,
+    /// Counts triggers, so the video and audio recorders can each see them
+    pub triggers: Arc<AtomicU64>,
+}
+
+/// A whole segment waiting in the buffer
+pub struct BufferedSegment {
+    pub data: Vec<u8>,
+    pub duration: Duration,
+    pub sequence: u64,
+    pub program_date_time: Option<DateTime<FixedOffset>>,
+}
+
+/// What the recorder should do before the next segment
+pub enum PreRollEvent {
+    /// Keep buffering, or keep recording
+    Continue,
+    /// A trigger arrived: write out the buffer, then record through
+    Start(VecDeque<BufferedSegment>),
+    /// The post-roll is over: close the clip and go back to buffering
+    Stop,
+}
+
+pub struct PreRoll {
+    config: PreRollConfig,
+    ring: VecDeque<BufferedSegment>,
+    /// Total duration of `ring`
+    buffered: Duration,
+    /// The segment being received, while not recording
+    current: Option<BufferedSegment>,
+    /// Writing through to the output until then
+    recording_until: Option<Instant>,
+    /// Triggers seen so far
+    seen: u64,
+}
+
+impl PreRoll {
+    pub fn new(config: PreRollConfig) -> Self {
+        Self {
+            seen: config.triggers.load(Ordering::SeqCst),
+            config,
+            ring: VecDeque::new(),
+            buffered: Duration::ZERO,
+            current: None,
+            recording_until: None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording_until.is_some()
+    }
+
+    /// Check the trigger and the post-roll deadline. Called between
+    /// segments, so a segment is either all buffered or all written.
+    pub fn poll(&mut self) -> PreRollEvent {
+        let triggers = self.config.triggers.load(Ordering::SeqCst);
+        let triggered = triggers != self.seen;
+        self.seen = triggers;
+        let now = Instant::now();
+        match self.recording_until {
+            _ if triggered => {
+                let started = self.recording_until.is_none();
+                self.recording_until = Some(now + self.config.post_roll);
+                if started {
+                    self.buffered = Duration::ZERO;
+                    PreRollEvent::Start(std::mem::take(&mut self.ring))
+                } else {
+                    PreRollEvent::Continue
+                }
+            }
+            Some(until) if now >= until => {
+                self.recording_until = None;
+                PreRollEvent::Stop
+            }
+            _ => PreRollEvent::Continue,
+        }
+    }
+
+    /// Buffer part of the segment being received
+    pub fn hold(
+        &mut self,
+        data: &[u8],
+        sequence: u64,
+        program_date_time: Option<DateTime<FixedOffset>>,
+    ) {
+        self.current
+            .get_or_insert_with(|| BufferedSegment {
+                data: Vec::new(),
+                duration: Duration::ZERO,
+                sequence,
+                program_date_time,
+            })
+            .data
+            .extend_from_slice(data);
+    }
+
+    /// Move the segment that was being received into the buffer, dropping
+    /// the oldest ones that are no longer needed to cover --pre-roll
+    pub fn end_segment(&mut self, duration: Duration) {
+        let Some(mut segment) = self.current.take() else {
+            return;
+        };
+        segment.duration = duration;
+        self.buffered += duration;
+        self.ring.push_back(segment);
+        while let Some(oldest) = self.ring.front() {
+            if self.buffered - oldest.duration < self.config.buffer {
+                break;
+            }
+            self.buffered -= oldest.duration;
+            self.ring.pop_front();
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::cli::ProbeArgs;
+use crate::error::StreamError;
+use crate::http_client::{build_client, fetch_with_retry, input_url, HttpClient};
+use crate::playlist;
+use m3u8_rs::{AlternativeMediaType, MediaPlaylistType, Playlist};
+use serde::Serialize;
+use std::time::Duration;
+use url::Url;
+
+/// One EXT-X-STREAM-INF entry of a master playlist
+#[derive(Serialize)]
+struct VariantInfo {
+    uri: String,
+    bandwidth: u64,
+    average_bandwidth: Option<u64>,
+    resolution: Option<String>,
+    frame_rate: Option<f64>,
+    codecs: Option<String>,
+    audio_group: Option<String>,
+    /// EXT-X-I-FRAME-STREAM-INF (trick play), never recorded
+    i_frame: bool,
+    selected: bool,
+}
+
+/// One EXT-X-MEDIA:TYPE=AUDIO entry of a master playlist
+#[derive(Serialize)]
+struct AudioInfo {
+    group: String,
+    name: String,
+    language: Option<String>,
+    channels: Option<String>,
+    default: bool,
+    /// None when the audio is carried inside the variants
+    uri: Option<String>,
+    selected: bool,
+}
+
+/// What `probe` found out about a stream
+#[derive(Serialize)]
+struct ProbeReport {
+    url: String,
+    /// "master" or "media", for the playlist at `url`
+    playlist: &'static str,
+    variants: Vec<VariantInfo>,
+    audio: Vec<AudioInfo>,
+    media_url: String,
+    /// Separate audio rendition `record` would use with the selected variant
+    audio_url: Option<String>,
+    /// "ts" or "fmp4"
+    format: &'static str,
+    encryption: Option<String>,
+    target_duration_secs: u64,
+    /// true while the playlist has no EXT-X-ENDLIST
+    live: bool,
+    playlist_type: Option<String>,
+    media_sequence: u64,
+    segments: usize,
+    playlist_duration_secs: f64,
+}
+
+async fn fetch_playlist(
+    client: &HttpClient,
+    url: &str,
+    args: &ProbeArgs,
+) -> Result<Playlist, StreamError> {
+    let data = fetch_with_retry(
+        client,
+        url,
+        Duration::from_secs(args.net.timeout),
+        args.net.retries,
+        args.net.retry_delay_ms,
+    )
+    .await?;
+    let playlist = m3u8_rs::parse_playlist(&data)
+        .map_err(|e| StreamError::parse(format!("Parse error: {e:?}")))?
+        .1;
+    Ok(playlist)
+}
+
+async fn probe(args: &ProbeArgs) -> Result<ProbeReport, StreamError> {
+    let client = build_client(&args.net)?.for_origin(&args.url);
+    let playlist = fetch_playlist(&client, &args.url, args).await?;
+    let base_url = client.final_url(&Url::parse(&args.url)?);
+
+    let (kind, variants, audio, media_url, audio_url) = match playlist {
+        Playlist::MasterPlaylist(master) => {
+            let selected = playlist::select_variant(&master, &base_url, &args.variant)
+                .map_err(StreamError::config)?;
+            let media_url = selected.video;
+            let audio_url = selected.audio;
+            let variants = master
+                .variants
+                .iter()
+                .map(|v| {
+                    let fps = playlist::extract_frame_rate(v);
+                    VariantInfo {
+                        uri: v.uri.clone(),
+                        bandwidth: v.bandwidth,
+                        average_bandwidth: v.average_bandwidth,
+                        resolution: v.resolution.map(|r| format!("{}x{}", r.width, r.height)),
+                        frame_rate: (fps > 0.0).then_some(fps),
+                        codecs: v.codecs.clone(),
+                        audio_group: v.audio.clone(),
+                        i_frame: v.is_i_frame,
+                        selected: base_url.join(&v.uri).ok().as_ref() == Some(&media_url),
+                    }
+                })
+                .collect();
+            let audio = master
+                .alternatives
+                .iter()
+                .filter(|m| m.media_type == AlternativeMediaType::Audio)
+                .map(|m| AudioInfo {
+                    group: m.group_id.clone(),
+                    name: m.name.clone(),
+                    language: m.language.clone(),
+                    channels: m.channels.clone(),
+                    default: m.default,
+                    uri: m.uri.clone(),
+                    selected: m.uri.as_ref().and_then(|u| base_url.join(u).ok()) == audio_url,
+                })
+                .collect();
+            ("master", variants, audio, media_url, audio_url)
+        }
+        Playlist::MediaPlaylist(_) => ("media", Vec::new(), Vec::new(), base_url, None),
+    };
+
+    let media = match fetch_playlist(&client, media_url.as_str(), args).await? {
+        Playlist::MediaPlaylist(pl) => pl,
+        Playlist::MasterPlaylist(_) => {
+            return Err(StreamError::parse(
+                "Variant URL points to another master playlist",
+            ))
+        }
+    };
+
+    Ok(ProbeReport {
+        url: args.url.clone(),
+        playlist: kind,
+        variants,
+        audio,
+        media_url: media_url.to_string(),
+        audio_url: audio_url.map(|u| u.to_string()),
+        format: if playlist::is_fmp4_playlist(&media) {
+            "fmp4"
+        } else {
+            "ts"
+        },
+        encryption: playlist::encryption_method(&media),
+        target_duration_secs: media.target_duration,
+        live: !media.end_list,
+        playlist_type: media.playlist_type.as_ref().map(|t| match t {
+            MediaPlaylistType::Event => "EVENT".to_string(),
+            MediaPlaylistType::Vod => "VOD".to_string(),
+            MediaPlaylistType::Other(other) => other.clone(),
+        }),
+        media_sequence: media.media_sequence,
+        segments: media.segments.len(),
+        playlist_duration_secs: media.segments.iter().map(|s| s.duration as f64).sum(),
+    })
+}
+
+fn live_or_vod(live: bool) -> &'static str {
+    if live {
+        "live"
+    } else {
+        "VOD"
+    }
+}
+
+fn print_report(report: &ProbeReport) {
+    println!("URL:             {}", report.url);
+    println!("Playlist:        {}", report.playlist);
+    if !report.variants.is_empty() {
+        println!("Variants:");
+        for (index, v) in report.variants.iter().enumerate() {
+            let marker = if v.selected { "*" } else { " " };
+            let resolution = v.resolution.as_deref().unwrap_or("-");
+            let fps = v
+                .frame_rate
+                .map(|f| format!("{f:.1} fps"))
+                .unwrap_or_else(|| "-".to_string());
+            let codecs = v.codecs.as_deref().unwrap_or("-");
+            let mut extra = String::new();
+            if let Some(ref group) = v.audio_group {
+                extra.push_str(&format!("  audio={group}"));
+            }
+            if v.i_frame {
+                extra.push_str("  (I-frame only)");
+            }
+            println!(
+                "  {marker} [{index}] {:>6} kbps  {resolution:<10} {fps:<9} {codecs}  {}{extra}",
+                v.bandwidth / 1000,
+                v.uri
+            );
+        }
+    }
+    if !report.audio.is_empty() {
+        println!("Audio renditions:");
+        for a in &report.audio {
+            let marker = if a.selected { "*" } else { " " };
+            let language = a.language.as_deref().unwrap_or("-");
+            let channels = a
+                .channels
+                .as_deref()
+                .map(|c| format!("{c}ch"))
+                .unwrap_or_default();
+            let default = if a.default { "  (default)" } else { "" };
+            println!(
+                "  {marker} {:<10} {:<16} {language:<5} {channels:<5} {}{default}",
+                a.group,
+                a.name,
+                a.uri.as_deref().unwrap_or("(in variant)")
+            );
+        }
+    }
+    println!("Media playlist:  {}", report.media_url);
+    if let Some(ref audio_url) = report.audio_url {
+        println!("Audio playlist:  {audio_url}");
+    }
+    println!("Format:          {}", report.format.to_uppercase());
+    println!(
+        "Encryption:      {}",
+        report.encryption.as_deref().unwrap_or("none")
+    );
+    println!("Target duration: {}s", report.target_duration_secs);
+    match report.playlist_type {
+        Some(ref t) => println!("Type:            {} ({t})", live_or_vod(report.live)),
+        None => println!("Type:            {}", live_or_vod(report.live)),
+    }
+    println!("Media sequence:  {}", report.media_sequence);
+    println!(
+        "Segments:        {} ({:.1}s)",
+        report.segments, report.playlist_duration_secs
+    );
+}
+
+/// The `probe` command: resolve a stream, print what `record` would see, and exit
+pub async fn run(mut args: ProbeArgs) -> Result<(), StreamError> {
+    args.url = input_url(&args.url)?;
+    let report = probe(&args).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
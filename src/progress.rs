@@ -0,0 +1,263 @@
+//! Live status line for --progress: elapsed time, bytes, bitrate, segments,
+//! the file being written, live-edge lag, and percent/ETA once the playlist
+//! turns out to be VOD. Without the line, a VOD download logs its progress
+//! every 10% instead, and --progress on a live stream logs a status line
+//! every minute.
+
+use crate::commands::{format_bytes, format_duration};
+use crate::stats::RecordingStats;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// The bar currently on screen, so log lines can be printed above it
+static ACTIVE: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+const TICK: Duration = Duration::from_millis(500);
+/// Window the bitrate is averaged over
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// How often --progress logs a live stream's status when stderr isn't a
+/// terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Download rate over the last RATE_WINDOW
+struct RateMeter {
+    samples: Vec<(Instant, u64)>,
+}
+
+impl RateMeter {
+    fn new(bytes: u64) -> Self {
+        Self {
+            samples: vec![(Instant::now(), bytes)],
+        }
+    }
+
+    /// Add a sample of the byte count and return the rate in bits/s
+    fn update(&mut self, bytes: u64) -> f64 {
+        let now = Instant::now();
+        self.samples.push((now, bytes));
+        self.samples
+            .retain(|(t, _)| now.duration_since(*t) <= RATE_WINDOW);
+        let (oldest_t, oldest_bytes) = self.samples[0];
+        let window = now.duration_since(oldest_t).as_secs_f64();
+        if window > 0.0 {
+            (bytes - oldest_bytes) as f64 * 8.0 / window
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Name of the file being written, for the status line
+fn current_file_name(stats: &RecordingStats) -> Option<String> {
+    stats.current_file().and_then(|path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    })
+}
+
+pub struct ProgressDisplay {
+    bar: ProgressBar,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ProgressDisplay {
+    /// Start drawing on stderr. Returns None when stderr isn't a terminal,
+    /// so redirected and cron output stays clean.
+    pub fn start(stats: Arc<RecordingStats>) -> Option<Self> {
+        if !std::io::stderr().is_terminal() {
+            return None;
+        }
+
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(
+            ProgressStyle::with_template("{spinner} [{elapsed_precise}] {msg}")
+                .expect("valid template"),
+        );
+        *ACTIVE.lock().unwrap() = Some(bar.clone());
+
+        let task_bar = bar.clone();
+        let task = tokio::spawn(async move {
+            let bar = task_bar;
+            let mut rate = RateMeter::new(stats.bytes());
+            let mut is_vod = false;
+            loop {
+                tokio::time::sleep(TICK).await;
+
+                let bytes = stats.bytes();
+                let bits_per_sec = rate.update(bytes);
+
+                // Count media time when the segment durations are known, so
+                // long and short segments weigh what they're worth
+                let (done, total) = match stats.expected_duration() {
+                    Some(total) => (
+                        stats.done_duration().as_millis() as u64,
+                        Some(total.as_millis() as u64),
+                    ),
+                    None => (
+                        stats.segments() + stats.segment_errors(),
+                        stats.expected_segments(),
+                    ),
+                };
+                if let Some(total) = total {
+                    if !is_vod {
+                        is_vod = true;
+                        bar.set_style(
+                            ProgressStyle::with_template(
+                                "{spinner} [{elapsed_precise}] [{bar:30}] {percent:>3}% ETA {eta} {msg}",
+                            )
+                            .expect("valid template")
+                            .progress_chars("=> "),
+                        );
+                    }
+                    bar.set_length(total);
+                    bar.set_position(done.min(total));
+                }
+
+                let mut msg = format!(
+                    "{}  {}  {} segments",
+                    format_bytes(bytes),
+                    format_bitrate(bits_per_sec),
+                    stats.segments()
+                );
+                if let Some(total) = stats.expected_duration().filter(|_| is_vod) {
+                    msg.push_str(&format!(
+                        "  {} / {}",
+                        format_duration(stats.done_duration().as_secs()),
+                        format_duration(total.as_secs())
+                    ));
+                } else if let Some(lag) = stats.live_lag() {
+                    msg.push_str(&format!("  {:.1}s behind live", lag.as_secs_f64()));
+                }
+                if let Some(name) = current_file_name(&stats) {
+                    msg.push_str(&format!("  {name}"));
+                }
+                bar.set_message(msg);
+                bar.tick();
+            }
+        });
+
+        Some(Self { bar, task })
+    }
+
+    /// Stop updating and remove the line
+    pub fn finish(self) {
+        self.task.abort();
+        *ACTIVE.lock().unwrap() = None;
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Log a VOD download's progress at every 10% when there's no progress
+/// line to show it: percent, media time, download rate and ETA. Does
+/// nothing for a live stream.
+pub fn spawn_vod_log(stats: Arc<RecordingStats>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        // Wait for the playlist to turn out to be VOD
+        let total = loop {
+            if let Some(total) = stats.expected_duration() {
+                break total;
+            }
+            tokio::time::sleep(TICK).await;
+        };
+        // The rate is measured from here, so a resume's earlier segments
+        // don't make the rest look fast
+        let start = Instant::now();
+        let start_done = stats.done_duration();
+        let start_bytes = stats.bytes();
+        let mut reported = 0;
+        loop {
+            tokio::time::sleep(TICK).await;
+            let done = stats.done_duration().min(total);
+            let percent = (done.as_secs_f64() * 100.0 / total.as_secs_f64().max(0.001)) as u64;
+            let step = percent / 10 * 10;
+            if step <= reported {
+                continue;
+            }
+            reported = step;
+
+            let elapsed = start.elapsed().as_secs_f64();
+            let fetched = done.saturating_sub(start_done).as_secs_f64();
+            let eta = (fetched > 0.0)
+                .then(|| (total - done).as_secs_f64() * elapsed / fetched)
+                .map_or("unknown".to_string(), |secs| format_duration(secs as u64));
+            let bytes = stats.bytes();
+            let bits_per_sec = (bytes - start_bytes) as f64 * 8.0 / elapsed.max(0.001);
+            info!(
+                "Downloaded {step}% ({} / {}), {} at {}, ETA {eta}",
+                format_duration(done.as_secs()),
+                format_duration(total.as_secs()),
+                format_bytes(bytes),
+                format_bitrate(bits_per_sec)
+            );
+            if step >= 100 {
+                break;
+            }
+        }
+    })
+}
+
+/// Log a live stream's status every LOG_INTERVAL, for --progress when
+/// stderr isn't a terminal to draw the line on. A VOD download is left to
+/// spawn_vod_log.
+pub fn spawn_live_log(stats: Arc<RecordingStats>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut rate = RateMeter::new(stats.bytes());
+        let mut last_log = Instant::now();
+        loop {
+            tokio::time::sleep(TICK).await;
+            let bytes = stats.bytes();
+            let bits_per_sec = rate.update(bytes);
+            if stats.expected_duration().is_some() || stats.expected_segments().is_some() {
+                return;
+            }
+            if last_log.elapsed() < LOG_INTERVAL {
+                continue;
+            }
+            last_log = Instant::now();
+
+            let mut msg = format!(
+                "Recording for {}: {} at {}, {} segments",
+                format_duration(stats.uptime().as_secs()),
+                format_bytes(bytes),
+                format_bitrate(bits_per_sec),
+                stats.segments()
+            );
+            if let Some(lag) = stats.live_lag() {
+                msg.push_str(&format!(", {:.1}s behind live", lag.as_secs_f64()));
+            }
+            match current_file_name(&stats) {
+                Some(name) => info!(file = %name, "{msg}"),
+                None => info!("{msg}"),
+            }
+        }
+    })
+}
+
+fn format_bitrate(bits_per_sec: f64) -> String {
+    if bits_per_sec >= 1_000_000.0 {
+        format!("{:.2} Mbit/s", bits_per_sec / 1_000_000.0)
+    } else {
+        format!("{:.0} kbit/s", bits_per_sec / 1000.0)
+    }
+}
+
+/// Stderr writer for the log layer that hides the progress line while a log
+/// line is printed, so the two don't interleave
+pub struct SuspendingStderr;
+
+impl Write for SuspendingStderr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let active = ACTIVE.lock().unwrap().clone();
+        match active {
+            Some(bar) => bar.suspend(|| std::io::stderr().write(buf)),
+            None => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
@@ -0,0 +1,322 @@
+//! Progressive HTTP: a plain file or endless stream (a large .mp4 or .ts,
+//! an Icecast mount) fetched as one response body and written straight into
+//! the output files, resuming with Range requests when the connection drops.
+
+use crate::commands::{self, SharedHooks};
+use crate::error::StreamError;
+use crate::http_client::{open_stream, HttpClient};
+use crate::output::{OutputFile, OutputOptions};
+use crate::stats::RecordingStats;
+use crate::ts_ingest::TsIngest;
+use http_body_util::BodyExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use url::Url;
+
+pub struct ProgressiveConfig {
+    pub url: String,
+    pub output_dir: PathBuf,
+    pub file_extension: String,
+    pub segment_secs: u64,
+    pub output_options: OutputOptions,
+    /// How long to wait for the response to start or for more data
+    pub timeout: Duration,
+    /// Reconnects in a row without new data before giving up
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+    pub hooks: SharedHooks,
+}
+
+/// Whether `url` serves something other than an HLS playlist. Decided by the
+/// URL's extension where it has one, otherwise by the Content-Type and the
+/// first bytes of the body (the rest isn't downloaded).
+pub async fn is_progressive(
+    client: &HttpClient,
+    url: &str,
+    timeout: Duration,
+) -> Result<bool, StreamError> {
+    let parsed = Url::parse(url)?;
+    let path = parsed.path().to_ascii_lowercase();
+    // Local input is always a saved playlist
+    if parsed.scheme() == "file" || path.ends_with(".m3u8") || path.ends_with(".m3u") {
+        return Ok(false);
+    }
+    let sniff = async {
+        let resp = open_stream(client, url, 0).await?;
+        let content_type = resp
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if content_type.contains("mpegurl") {
+            return Ok(false);
+        }
+        let mut body = resp.into_body();
+        let mut head = Vec::new();
+        while head.len() < 16 {
+            match body.frame().await {
+                Some(frame) => {
+                    if let Ok(data) = frame?.into_data() {
+                        head.extend_from_slice(&data);
+                    }
+                }
+                None => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&head);
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+        Ok::<_, StreamError>(!text.starts_with("#EXTM3U"))
+    };
+    tokio::time::timeout(timeout, sniff)
+        .await
+        .map_err(|_| StreamError::network(format!("Timed out probing {url}")))?
+}
+
+/// Where the body goes, chosen from its first bytes
+enum Sink {
+    /// MPEG-TS, rotated at keyframes
+    Ts(TsIngest),
+    /// An MP4/MOV file only plays whole, so it isn't rotated. Anything else
+    /// (MP3, ADTS, FLV) rotates on time wherever a read ends.
+    Raw {
+        output: OutputFile,
+        rotate: bool,
+        pending_commands: Vec<tokio::task::JoinHandle<()>>,
+    },
+}
+
+impl Sink {
+    fn new(
+        head: &[u8],
+        config: &ProgressiveConfig,
+        stats: &Arc<RecordingStats>,
+    ) -> std::io::Result<Self> {
+        if head.first() == Some(&0x47) && head.get(188).is_none_or(|&b| b == 0x47) {
+            debug!("Progressive body is MPEG-TS");
+            return TsIngest::new(
+                config.output_dir.clone(),
+                config.file_extension.clone(),
+                config.segment_secs,
+                config.output_options.clone(),
+                config.hooks.clone(),
+                stats.clone(),
+            )
+            .map(Sink::Ts);
+        }
+        let mp4 = head.get(4..8) == Some(b"ftyp".as_slice());
+        if mp4 {
+            info!("Progressive body is MP4, writing it to one file");
+        }
+        let output = OutputFile::new(
+            config.file_extension.clone(),
+            config.output_dir.clone(),
+            Duration::from_secs(config.segment_secs),
+            false,
+            config.output_options.clone(),
+        )?;
+        stats.set_current_file(output.current_path());
+        Ok(Sink::Raw {
+            output,
+            rotate: !mp4,
+            pending_commands: Vec::new(),
+        })
+    }
+
+    fn write(
+        &mut self,
+        data: &[u8],
+        rotate_now: bool,
+        hooks: &SharedHooks,
+        stats: &RecordingStats,
+    ) -> std::io::Result<()> {
+        match self {
+            Sink::Ts(ingest) => {
+                if rotate_now {
+                    ingest.request_rotate();
+                }
+                ingest.push(data)
+            }
+            Sink::Raw {
+                output,
+                rotate,
+                pending_commands,
+            } => {
+                if *rotate && !output.is_empty() && (rotate_now || output.rotation_due()) {
+                    let completed = output.rotate()?;
+                    if rotate_now {
+                        info!(file = %completed.path.display(), "Rotated on request");
+                    }
+                    stats.set_current_file(output.current_path());
+                    pending_commands.extend(commands::file_completed(hooks, completed));
+                }
+                if *rotate {
+                    if let Some(completed) = output.make_room(data.len())? {
+                        stats.set_current_file(output.current_path());
+                        pending_commands.extend(commands::file_completed(hooks, completed));
+                    }
+                }
+                output.write(data, None)?;
+                stats.add_bytes(data.len() as u64);
+                Ok(())
+            }
+        }
+    }
+
+    async fn finish(self, hooks: &SharedHooks) -> std::io::Result<u64> {
+        match self {
+            Sink::Ts(ingest) => ingest.finish().await,
+            Sink::Raw {
+                mut output,
+                mut pending_commands,
+                ..
+            } => {
+                let final_file = output.finalize()?;
+                info!(file = %final_file.path.display(), "Flushed current segment");
+                pending_commands.extend(commands::file_completed(hooks, final_file));
+                commands::wait_for_commands(&mut pending_commands).await;
+                Ok(output.total_bytes())
+            }
+        }
+    }
+}
+
+pub async fn handle_progressive_stream(
+    client: &HttpClient,
+    config: ProgressiveConfig,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    stats: Arc<RecordingStats>,
+) -> Result<u64, StreamError> {
+    let mut sink: Option<Sink> = None;
+    // Bytes of the body received so far, where a resumed request starts
+    let mut received: u64 = 0;
+    // Full body size, when the server says (a live stream doesn't)
+    let mut total: Option<u64> = None;
+    let mut failures: u32 = 0;
+
+    let result = 'download: loop {
+        let resp =
+            match tokio::time::timeout(config.timeout, open_stream(client, &config.url, received))
+                .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(StreamError::network("Request timed out")),
+            };
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) if failures < config.retries && received > 0 => {
+                failures += 1;
+                warn!(
+                    "Reconnect failed (retrying {failures}/{}): {e}",
+                    config.retries
+                );
+                tokio::time::sleep(Duration::from_millis(config.retry_delay_ms)).await;
+                continue;
+            }
+            Err(e) => break Err(e),
+        };
+
+        // Without Range support the body starts over; skip what we have
+        let mut skip = 0;
+        if received > 0 && resp.status() != hyper::StatusCode::PARTIAL_CONTENT {
+            if total.is_some() {
+                warn!("Server ignored the Range request, skipping {received} bytes it resent");
+                skip = received;
+            } else {
+                // A live stream just carries on from now
+                info!("Reconnected to live stream");
+            }
+        }
+        if total.is_none() && received == 0 {
+            total = resp
+                .headers()
+                .get("content-length")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            if let Some(bytes) = total {
+                info!("Downloading {}", commands::format_bytes(bytes));
+            }
+        }
+
+        let mut body = resp.into_body();
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                break 'download Ok(());
+            }
+            let frame = tokio::select! {
+                frame = tokio::time::timeout(config.timeout, body.frame()) => frame,
+                _ = wait_for(&shutdown) => break 'download Ok(()),
+            };
+            let frame = match frame {
+                Err(_) => Err(StreamError::network("No data received before the timeout")),
+                Ok(None) => break,
+                Ok(Some(frame)) => frame.map_err(Into::into),
+            };
+            let data = match frame {
+                Ok(frame) => match frame.into_data() {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                },
+                Err(e) => {
+                    let e: StreamError = e;
+                    warn!("Connection lost after {received} bytes: {e}");
+                    break;
+                }
+            };
+            let data = if skip > 0 {
+                let n = skip.min(data.len() as u64);
+                skip -= n;
+                data.slice(n as usize..)
+            } else {
+                data
+            };
+            if data.is_empty() {
+                continue;
+            }
+            failures = 0;
+            stats.touch();
+
+            let sink = match sink {
+                Some(ref mut sink) => sink,
+                None => sink.insert(Sink::new(&data, &config, &stats)?),
+            };
+            let rotate_now = rotate.swap(false, Ordering::SeqCst);
+            sink.write(&data, rotate_now, &config.hooks, &stats)?;
+            received += data.len() as u64;
+        }
+
+        // The body ended: done if it was all there, otherwise resume
+        if total.is_none_or(|total| received >= total) && received > 0 {
+            info!("Download complete");
+            break Ok(());
+        }
+        if failures >= config.retries {
+            break Err(StreamError::network(format!(
+                "Giving up after {failures} reconnects without new data"
+            )));
+        }
+        failures += 1;
+        info!(
+            "Resuming at byte {received} ({failures}/{})",
+            config.retries
+        );
+        tokio::time::sleep(Duration::from_millis(config.retry_delay_ms)).await;
+    };
+
+    let bytes = match sink {
+        Some(sink) => sink.finish(&config.hooks).await?,
+        None => 0,
+    };
+    result.map(|()| bytes)
+}
+
+/// Resolve once shutdown is requested
+async fn wait_for(shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
@@ -0,0 +1,1926 @@
+use crate::cameras::{self, Camera};
+use crate::cli::{Args, AudioMode, Cli, Command, Container};
+use crate::commands::{self, ExitReason, HookCommand, Hooks, SharedHooks, StartHook, StartInfo};
+use crate::dash::{self, ContentKind, DashConfig, DashDownloader};
+use crate::disk::LowDiskPolicy;
+use crate::downloader::{DownloadConfig, TsDownloader};
+use crate::error::StreamError;
+use crate::ffmpeg;
+use crate::http_client::{build_client, fetch_with_retry, input_url, redact_password, HttpClient};
+use crate::output::OutputOptions;
+use crate::playlist::{self, SelectedVariant};
+use crate::preroll::PreRollConfig;
+use crate::progress::{self, ProgressDisplay};
+use crate::progressive::{self, ProgressiveConfig};
+use crate::retention::RetentionPolicy;
+#[cfg(feature = "rtsp")]
+use crate::rtsp;
+#[cfg(feature = "srt")]
+use crate::srt;
+use crate::stats::RecordingStats;
+use crate::status;
+use crate::systemd;
+use crate::thumbnails::{ThumbnailConfig, Thumbnailer};
+use crate::udp;
+#[cfg(feature = "whep")]
+use crate::whep;
+use m3u8_rs::{MediaPlaylist, Playlist};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn, Instrument};
+use url::Url;
+
+/// Set when a signal stopped the recording, rather than the stream ending
+/// or a limit, for on-exit's `%r`
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Wait for Ctrl+C or, on Unix, SIGTERM, and return which it was
+async fn termination_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => return "Ctrl+C",
+                    _ = term.recv() => return "SIGTERM",
+                }
+            }
+            Err(e) => warn!("Can't listen for SIGTERM: {e}"),
+        }
+    }
+    tokio::signal::ctrl_c().await.ok();
+    "Ctrl+C"
+}
+
+/// Stop on Ctrl+C or SIGTERM, finishing the current file and running the
+/// on-exit hook
+pub fn setup_shutdown_handler() -> Arc<AtomicBool> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_clone = shutdown.clone();
+    tokio::spawn(async move {
+        let signal = termination_signal().await;
+        info!("Received {signal}, shutting down gracefully...");
+        systemd::notify_stopping();
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        shutdown_clone.store(true, Ordering::SeqCst);
+    });
+    shutdown
+}
+
+/// Stop the recording like Ctrl+C once --duration has passed or --max-bytes
+/// have been downloaded, so the current file is finalized and --on-exit runs
+pub fn setup_limit_handler(args: &Args, shutdown: Arc<AtomicBool>, stats: Arc<RecordingStats>) {
+    if args.duration.is_none() && args.max_bytes.is_none() {
+        return;
+    }
+    // Too far off to represent is never reached
+    let deadline = args.duration.and_then(|d| Instant::now().checked_add(d));
+    let max_bytes = args.max_bytes;
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            if shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            let reason = if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                "--duration reached"
+            } else if max_bytes.is_some_and(|max| stats.bytes() >= max) {
+                "--max-bytes reached"
+            } else {
+                continue;
+            };
+            info!("{reason}, stopping");
+            systemd::notify_stopping();
+            shutdown.store(true, Ordering::SeqCst);
+            return;
+        }
+    });
+}
+
+/// With --pre-roll, count triggers from SIGUSR1 and --trigger-file for the
+/// recorders to start a clip on. A library caller passes its own counter,
+/// and SIGUSR1 is left to the program.
+pub fn setup_trigger_handler(
+    args: &Args,
+    triggers: Option<Arc<AtomicU64>>,
+) -> Option<PreRollConfig> {
+    let buffer = Duration::from_secs(args.pre_roll?);
+    #[cfg(unix)]
+    let signal = triggers.is_none();
+    let triggers = triggers.unwrap_or_default();
+
+    #[cfg(unix)]
+    if signal {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined1()) {
+            Ok(mut usr1) => {
+                let triggers = triggers.clone();
+                tokio::spawn(async move {
+                    while usr1.recv().await.is_some() {
+                        info!("Received SIGUSR1, saving a clip");
+                        triggers.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+            Err(e) => warn!("Can't listen for SIGUSR1: {e}"),
+        }
+    }
+
+    if let Some(path) = args.trigger_file.clone() {
+        let triggers = triggers.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                if std::fs::remove_file(&path).is_ok() {
+                    info!(file = %path.display(), "Trigger file appeared, saving a clip");
+                    triggers.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+    }
+
+    Some(PreRollConfig {
+        buffer,
+        post_roll: Duration::from_secs(args.post_roll),
+        triggers,
+    })
+}
+
+/// The --onvif watcher's settings. It logs in with --username/--password,
+/// or else with the credentials in the recording URL.
+#[cfg(feature = "onvif")]
+fn onvif_config(
+    args: &Args,
+    device_url: &str,
+    pre_roll: Option<&PreRollConfig>,
+) -> Result<crate::onvif::OnvifConfig, StreamError> {
+    use crate::http_client::percent_decode;
+
+    let (mut username, mut password) = (args.net.username.clone(), args.net.password.clone());
+    if username.is_none() {
+        if let Ok(url) = url::Url::parse(&args.url) {
+            if !url.username().is_empty() {
+                username = Some(percent_decode(url.username()));
+                password = url.password().map(percent_decode);
+            }
+        }
+    }
+    Ok(crate::onvif::OnvifConfig {
+        device_url: device_url.to_string(),
+        username,
+        password,
+        topic: args.onvif_topic.clone(),
+        connector: crate::tls::build_connector(&args.net)?,
+        events_file: args.output.join("events.jsonl"),
+        triggers: pre_roll.map(|p| p.triggers.clone()),
+    })
+}
+
+/// On SIGHUP, start a new output file and, with --config, re-read the hook
+/// settings from the config file. The capture itself keeps running.
+fn setup_reload_handler(args: &Args, rotate: Arc<AtomicBool>, hooks: SharedHooks) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let reload_config = args.config.is_some();
+        let piping = args.pipe.is_some();
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Can't listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                // A pipe has no files to rotate
+                if !piping {
+                    info!("Received SIGHUP, rotating output file");
+                    rotate.store(true, Ordering::SeqCst);
+                }
+                if reload_config {
+                    match reload_hooks() {
+                        Ok(new) => {
+                            *hooks.write().unwrap() = new;
+                            info!("Reloaded hook settings from config file");
+                        }
+                        Err(e) => warn!("Config reload failed, keeping current settings: {e}"),
+                    }
+                }
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    let _ = (args, rotate, hooks);
+}
+
+/// Parse the command line again, picking up the edited config file
+fn reload_hooks() -> Result<Hooks, StreamError> {
+    match Cli::try_parse_with_default(std::env::args_os())
+        .map_err(StreamError::config)?
+        .command
+    {
+        Command::Record(args) => Hooks::from_args(&args),
+        _ => Err("config reload only applies to record".into()),
+    }
+}
+
+async fn resolve_media_url(
+    client: &HttpClient,
+    args: &Args,
+    timeout: Duration,
+) -> Result<SelectedVariant, StreamError> {
+    let data = fetch_with_retry(
+        client,
+        &args.url,
+        timeout,
+        args.net.retries,
+        args.net.retry_delay_ms,
+    )
+    .await?;
+    // Variant URIs are relative to where a redirect ended up
+    let base_url = client.final_url(&Url::parse(&args.url)?);
+    let playlist = m3u8_rs::parse_playlist(&data)
+        .map_err(|e| StreamError::parse(format!("Parse error: {e:?}")))?
+        .1;
+
+    // Resolve to media playlist URL
+    let selected = match playlist {
+        Playlist::MasterPlaylist(master) => {
+            playlist::select_variant(&master, &base_url, &args.variant)
+                .map_err(StreamError::config)?
+        }
+        Playlist::MediaPlaylist(_) => SelectedVariant::media(base_url),
+    };
+
+    Ok(selected)
+}
+
+async fn fetch_media_playlist(
+    client: &HttpClient,
+    media_url: &Url,
+    timeout: Duration,
+    retries: u32,
+    retry_delay_ms: u64,
+) -> Result<MediaPlaylist, StreamError> {
+    let data =
+        fetch_with_retry(client, media_url.as_str(), timeout, retries, retry_delay_ms).await?;
+    match m3u8_rs::parse_playlist(&data) {
+        Ok((_, Playlist::MediaPlaylist(pl))) => Ok(pl),
+        _ => Err(StreamError::parse("Failed to parse media playlist")),
+    }
+}
+
+async fn detect_format(
+    client: &HttpClient,
+    media_url: &Url,
+    timeout: Duration,
+    retries: u32,
+    retry_delay_ms: u64,
+) -> Result<StreamFormat, StreamError> {
+    // Fetch media playlist once to detect format
+    let initial_playlist =
+        fetch_media_playlist(client, media_url, timeout, retries, retry_delay_ms).await?;
+    playlist::check_encryption(&initial_playlist)?;
+
+    // Check if this is an fMP4 stream
+    if playlist::is_fmp4_playlist(&initial_playlist) {
+        Ok(StreamFormat::FMP4)
+    } else {
+        Ok(StreamFormat::TS)
+    }
+}
+
+/// ffmpeg couldn't be started at all, which trying again won't fix
+fn missing_ffmpeg(e: &StreamError) -> bool {
+    matches!(e, StreamError::Ffmpeg(inner) if inner.is::<std::io::Error>())
+}
+
+/// Record through ffmpeg, relaunching it with backoff when it fails
+/// (--ffmpeg-restarts)
+async fn handle_ffmpeg_stream(
+    client: &HttpClient,
+    media_url: &Url,
+    audio_url: Option<&Url>,
+    args: &Args,
+    shutdown: &AtomicBool,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    // ffmpeg reports the media time it has written; for a VOD playlist that
+    // gives a percentage. --direct URLs may not be playlists at all.
+    if !args.direct {
+        let timeout = Duration::from_secs(args.net.timeout);
+        match fetch_media_playlist(client, media_url, timeout, 0, 0).await {
+            Ok(pl) if pl.end_list => {
+                let total = pl.segments.iter().map(playlist::segment_duration).sum();
+                stats.set_expected_segments(pl.segments.len() as u64);
+                stats.set_expected_duration(total);
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Couldn't check whether the playlist is VOD: {e}"),
+        }
+    }
+
+    // The time part of the names is fixed when ffmpeg starts; it numbers
+    // the files itself, from the first index that's free
+    let names = output_options(args).filenames();
+    let start = chrono::Local::now();
+    let output_pattern = args
+        .output
+        .join(names.ffmpeg_pattern(&start, &args.file_extension));
+    let output_dir = output_pattern
+        .parent()
+        .unwrap_or(&args.output)
+        .to_path_buf();
+    std::fs::create_dir_all(&output_dir)?;
+
+    // Watch even without --on-segment: a config reload may add one later
+    let watcher = ffmpeg::SegmentWatcher::start(
+        args.file_extension.clone(),
+        &output_dir,
+        hooks,
+        stats.clone(),
+    )?;
+
+    let initial_delay = Duration::from_secs(args.restart_delay);
+    let max_delay = Duration::from_secs(args.restart_max_delay).max(initial_delay);
+    let mut restart_delay = initial_delay;
+    let mut restarts: u32 = 0;
+    let result = loop {
+        // A relaunched ffmpeg carries on after the files already written,
+        // including the one it was writing when it died
+        let start_index = names.first_free_index(&args.output, &start, &args.file_extension);
+        let attempt_start = Instant::now();
+        let input = ffmpeg::FfmpegInput::Url {
+            media: media_url,
+            audio: audio_url,
+            headers: client.headers(),
+        };
+        let result = ffmpeg::run_ffmpeg_fmp4(
+            input,
+            &output_pattern,
+            start_index,
+            args.segment_secs,
+            &ffmpeg_options(args),
+            &stats,
+            shutdown,
+        )
+        .await;
+        watcher.ffmpeg_exited();
+        let e = match result {
+            Ok(()) => break Ok(()),
+            // Ctrl+C reaches ffmpeg too; a missing binary won't come back
+            Err(e) if shutdown.load(Ordering::SeqCst) || missing_ffmpeg(&e) => break Err(e),
+            Err(e) => e,
+        };
+        // A run that stayed up longer than the backoff cap was healthy
+        if attempt_start.elapsed() > max_delay {
+            restarts = 0;
+            restart_delay = initial_delay;
+        }
+        if restarts >= args.ffmpeg_restarts {
+            break Err(e);
+        }
+        restarts += 1;
+        warn!(
+            "{e} (relaunching in {}s, {restarts}/{})",
+            restart_delay.as_secs(),
+            args.ffmpeg_restarts
+        );
+        sleep_unless_shutdown(restart_delay, shutdown).await;
+        if shutdown.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+        restart_delay = (restart_delay * 2).min(max_delay);
+    };
+    watcher.wait_for_commands().await;
+    result
+}
+
+/// --ffmpeg-stdin: download the playlist ourselves, with the retries,
+/// headers and TLS settings that ffmpeg's own HTTP client wouldn't use, and
+/// pipe the stream into ffmpeg, which only splits it into files
+async fn handle_ffmpeg_stdin_stream(
+    client: &HttpClient,
+    media_url: &Url,
+    args: &Args,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    let names = output_options(args).filenames();
+    let start = chrono::Local::now();
+    let output_pattern = args
+        .output
+        .join(names.ffmpeg_pattern(&start, &args.file_extension));
+    let output_dir = output_pattern
+        .parent()
+        .unwrap_or(&args.output)
+        .to_path_buf();
+    std::fs::create_dir_all(&output_dir)?;
+
+    // The downloader counts what it fetches, so ffmpeg's side is only
+    // watched for the hooks
+    let ffmpeg_stats = Arc::new(RecordingStats::new());
+    let watcher = ffmpeg::SegmentWatcher::start(
+        args.file_extension.clone(),
+        &output_dir,
+        hooks.clone(),
+        ffmpeg_stats.clone(),
+    )?;
+    let start_index = names.first_free_index(&args.output, &start, &args.file_extension);
+    let mut ffmpeg = ffmpeg::spawn_ffmpeg(
+        ffmpeg::FfmpegInput::Stdin,
+        &output_pattern,
+        start_index,
+        args.segment_secs,
+        &ffmpeg_options(args),
+        &ffmpeg_stats,
+    )?;
+    let stdin = ffmpeg.take_stdin().expect("stdin is piped");
+
+    let mut config = hls_download_config(media_url, args.file_extension.clone(), args, None, hooks);
+    config.output_options.pipe = Some(PathBuf::from("pipe:0"));
+    config.output_options.pipe_file = Some(Arc::new(stdin));
+    config.state_file = None;
+    // Dropping the downloader closes ffmpeg's stdin, so it finishes the
+    // last file and exits
+    let result = match TsDownloader::new(config) {
+        Ok(mut downloader) => downloader
+            .run(client, shutdown, rotate, stats)
+            .await
+            .map(|_| ()),
+        Err(e) => Err(e.into()),
+    };
+    let exited = tokio::task::spawn_blocking(move || ffmpeg.wait()).await?;
+    watcher.ffmpeg_exited();
+    watcher.wait_for_commands().await;
+    // A write to a pipe ffmpeg stopped reading fails too; its error says why
+    exited.and(result)
+}
+
+/// Record an HLS media playlist natively, TS or fMP4
+#[allow(clippy::too_many_arguments)]
+async fn handle_hls_stream(
+    client: &HttpClient,
+    media_url: &Url,
+    file_extension: String,
+    args: &Args,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    pre_roll: Option<PreRollConfig>,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    let config = hls_download_config(media_url, file_extension, args, pre_roll, hooks);
+    let mut downloader = TsDownloader::new(config)?;
+    downloader.run(client, shutdown, rotate, stats).await?;
+
+    Ok(())
+}
+
+/// How the native downloader records `media_url`, from the command line
+fn hls_download_config(
+    media_url: &Url,
+    file_extension: String,
+    args: &Args,
+    pre_roll: Option<PreRollConfig>,
+    hooks: SharedHooks,
+) -> DownloadConfig {
+    DownloadConfig {
+        media_url: media_url.clone(),
+        output_dir: args.output.clone(),
+        file_extension,
+        segment_secs: args.segment_secs,
+        pdt_filenames: args.pdt_filenames,
+        split_on_discontinuity: args.split_on_discontinuity,
+        skip_ads: args.skip_ads,
+        report_markers: true,
+        poll_interval: args.poll_interval,
+        max_failures: args.max_failures,
+        timeout: Duration::from_secs(args.net.timeout),
+        retries: args.net.retries,
+        retry_delay_ms: args.net.retry_delay_ms,
+        concurrency: usize::from(args.concurrency),
+        max_in_flight_bytes: args
+            .max_in_flight_mb
+            .map(|mb| mb.saturating_mul(1024 * 1024)),
+        output_options: output_options(args),
+        state_file: args.state_file.clone(),
+        low_disk: low_disk_policy(args),
+        pre_roll,
+        hooks,
+    }
+}
+
+/// --audio store: record the audio rendition with a second downloader into
+/// `<output>/audio`, alongside the video. Runs until `stop` is set.
+async fn spawn_audio_downloader(
+    client: &HttpClient,
+    audio_url: &Url,
+    args: &Args,
+    stop: Arc<AtomicBool>,
+    pre_roll: Option<PreRollConfig>,
+    hooks: SharedHooks,
+) -> Result<tokio::task::JoinHandle<()>, StreamError> {
+    let timeout = Duration::from_secs(args.net.timeout);
+    let audio_playlist = fetch_media_playlist(
+        client,
+        audio_url,
+        timeout,
+        args.net.retries,
+        args.net.retry_delay_ms,
+    )
+    .await?;
+    // Packed audio renditions are usually .aac; keep whatever the segments
+    // use, except fMP4 fragments (.m4s), which become .m4a files
+    let file_extension = if playlist::is_fmp4_playlist(&audio_playlist) {
+        "m4a".to_string()
+    } else {
+        audio_playlist
+            .segments
+            .first()
+            .and_then(|s| audio_url.join(&s.uri).ok())
+            .and_then(|u| {
+                std::path::Path::new(u.path())
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| "ts".to_string())
+    };
+
+    let output_dir = args.output.join("audio");
+    std::fs::create_dir_all(&output_dir)?;
+    let config = DownloadConfig {
+        media_url: audio_url.clone(),
+        output_dir,
+        file_extension,
+        segment_secs: args.segment_secs,
+        pdt_filenames: args.pdt_filenames,
+        split_on_discontinuity: args.split_on_discontinuity,
+        skip_ads: args.skip_ads,
+        report_markers: false,
+        poll_interval: args.poll_interval,
+        max_failures: args.max_failures,
+        timeout,
+        retries: args.net.retries,
+        retry_delay_ms: args.net.retry_delay_ms,
+        concurrency: usize::from(args.concurrency),
+        max_in_flight_bytes: args
+            .max_in_flight_mb
+            .map(|mb| mb.saturating_mul(1024 * 1024)),
+        output_options: output_options(args),
+        // The audio rendition keeps its own state next to the video's
+        state_file: args.state_file.as_ref().map(|path| {
+            let mut path = path.clone().into_os_string();
+            path.push(".audio");
+            std::path::PathBuf::from(path)
+        }),
+        // The video recording watches the disk the two share
+        low_disk: None,
+        pre_roll,
+        hooks,
+    };
+    let mut downloader = TsDownloader::new(config)?;
+    let client = client.clone();
+    info!("Recording audio rendition {audio_url}");
+    Ok(tokio::spawn(async move {
+        // Audio files rotate on --segment-secs only, not on SIGHUP
+        let rotate = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(RecordingStats::new());
+        if let Err(e) = downloader.run(&client, stop, rotate, stats).await {
+            warn!("Audio rendition recording failed: {e}");
+        }
+    }))
+}
+
+/// Record an MPEG-DASH manifest natively, with the audio representation
+/// alongside in `<output>/audio` unless --audio ignore
+async fn handle_dash_stream(
+    client: &HttpClient,
+    args: &Args,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    debug!("Detected DASH manifest, processing natively...");
+    let mpd_url = Url::parse(&args.url)?;
+    let config = |content, output_dir, file_extension| DashConfig {
+        mpd_url: mpd_url.clone(),
+        content,
+        variant: args.variant.clone(),
+        output_dir,
+        file_extension,
+        segment_secs: args.segment_secs,
+        pdt_filenames: args.pdt_filenames,
+        output_options: output_options(args),
+        poll_interval: args.poll_interval,
+        max_failures: args.max_failures,
+        timeout: Duration::from_secs(args.net.timeout),
+        retries: args.net.retries,
+        retry_delay_ms: args.net.retry_delay_ms,
+        hooks: hooks.clone(),
+    };
+    let file_extension = fmp4_extension(args);
+
+    let audio_stop = Arc::new(AtomicBool::new(false));
+    let audio_task = if args.audio == AudioMode::Ignore {
+        None
+    } else {
+        if args.audio == AudioMode::Mux {
+            warn!("--audio mux isn't supported for DASH, recording audio separately");
+        }
+        let output_dir = args.output.join("audio");
+        std::fs::create_dir_all(&output_dir)?;
+        let mut audio =
+            DashDownloader::new(config(ContentKind::Audio, output_dir, "m4a".to_string()))?;
+        let (client, stop) = (client.clone(), audio_stop.clone());
+        Some(tokio::spawn(async move {
+            // Audio files rotate on --segment-secs only, not on SIGHUP
+            let rotate = Arc::new(AtomicBool::new(false));
+            let stats = Arc::new(RecordingStats::new());
+            if let Err(e) = audio.run(&client, stop, rotate, stats).await {
+                warn!("Audio representation recording failed: {e}");
+            }
+        }))
+    };
+
+    systemd::notify_ready();
+    let mut video = DashDownloader::new(config(
+        ContentKind::Video,
+        args.output.clone(),
+        file_extension,
+    ))?;
+    let result = video.run(client, shutdown, rotate, stats).await;
+    // The audio follows the video: stop it when the video ends
+    if let Some(task) = audio_task {
+        audio_stop.store(true, Ordering::SeqCst);
+        let _ = task.await;
+    }
+    result?;
+    Ok(())
+}
+
+/// The extension for files made of fMP4 segments (DASH, fMP4 HLS): the
+/// default `ts` would mislabel them, so it becomes `mp4`
+fn fmp4_extension(args: &Args) -> String {
+    if args.file_extension == "ts" {
+        "mp4".to_string()
+    } else {
+        args.file_extension.clone()
+    }
+}
+
+/// How to run ffmpeg, from the command line
+fn ffmpeg_options(args: &Args) -> ffmpeg::FfmpegOptions {
+    ffmpeg::FfmpegOptions {
+        path: args.ffmpeg_path.clone(),
+        input_args: args.ffmpeg_args.clone().unwrap_or_default().0,
+        output_args: args.ffmpeg_output_args.clone().unwrap_or_default().0,
+        video_codec: args.video_codec.clone(),
+        audio_codec: args.audio_codec.clone(),
+        video_bitrate: args.video_bitrate.clone(),
+        scale: args.scale.clone(),
+    }
+}
+
+/// Whether any of the transcode options are set. Re-encoding is done by
+/// ffmpeg, so they imply --ffmpeg.
+fn transcodes(args: &Args) -> bool {
+    args.video_codec.is_some()
+        || args.audio_codec.is_some()
+        || args.video_bitrate.is_some()
+        || args.scale.is_some()
+}
+
+/// An option set that implies --ffmpeg, for the error when the stream
+/// isn't HLS
+fn ffmpeg_only_option(args: &Args) -> Option<&'static str> {
+    if args.ffmpeg_stdin {
+        Some("--ffmpeg-stdin")
+    } else if transcodes(args) {
+        Some("transcoding")
+    } else {
+        None
+    }
+}
+
+/// The `OutputFile` settings from the command line
+fn output_options(args: &Args) -> OutputOptions {
+    OutputOptions {
+        checksums: args.checksums,
+        retention: RetentionPolicy {
+            max_age: args.keep_hours.map(|h| Duration::from_secs(h * 3600)),
+            // The file being written is still a .part, so it isn't counted
+            max_files: args.keep_segments,
+            max_bytes: None,
+        },
+        filename_template: args
+            .filename_template
+            .clone()
+            .map(|template| template.with_stream(&args.url)),
+        segment_bytes: args.segment_bytes,
+        fsync_interval: args.fsync_interval.map(Duration::from_secs),
+        fsync_on_rotate: args.fsync_on_rotate,
+        sync_writes: args.sync_writes,
+        pipe: args.pipe.clone(),
+        pipe_file: None,
+        sidecars: args.sidecars,
+        source: redact_password(&args.url),
+        remux_mp4: args.remux_mp4,
+    }
+}
+
+/// Refuse options that need files when writing to --pipe, rather than
+/// quietly ignoring them
+fn check_pipe_args(args: &Args) -> Result<(), StreamError> {
+    let rtsp = args.url.starts_with("rtsp://") || args.url.starts_with("rtsps://");
+    let file_only = [
+        (args.ffmpeg, "--ffmpeg"),
+        (transcodes(args), "transcoding"),
+        (args.ffmpeg_stdin, "--ffmpeg-stdin"),
+        (args.whep, "--whep"),
+        (rtsp, "an RTSP URL"),
+        (
+            args.cameras.is_some() || !args.more_urls.is_empty() || args.input_list.is_some(),
+            "several streams",
+        ),
+        (args.on_segment.is_some(), "--on-segment"),
+        (args.on_segment_exec.is_some(), "--on-segment-exec"),
+        (args.state_file.is_some(), "--state-file"),
+        (args.split_on_discontinuity, "--split-on-discontinuity"),
+        (args.pre_roll.is_some(), "--pre-roll"),
+        (args.segment_bytes.is_some(), "--segment-bytes"),
+        (args.keep_segments.is_some(), "--keep-segments"),
+        (args.keep_hours.is_some(), "--keep-hours"),
+        (args.checksums.is_some(), "--checksums"),
+        (args.sidecars, "--sidecars"),
+        (args.remux_mp4, "--remux-mp4"),
+        (args.min_free_mb.is_some(), "--min-free-mb"),
+        (args.fsync_interval.is_some(), "--fsync-interval"),
+        (args.fsync_on_rotate, "--fsync-on-rotate"),
+    ];
+    match file_only.iter().find(|(set, _)| *set) {
+        Some((_, what)) => Err(StreamError::config(format!(
+            "writing to a pipe doesn't work with {what}"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Refuse options that work on the downloaded segments or the files they
+/// go into with --ffmpeg-stdin, where ffmpeg decides the files instead
+fn check_ffmpeg_stdin_args(args: &Args) -> Result<(), StreamError> {
+    let ignored = [
+        (args.pre_roll.is_some(), "--pre-roll"),
+        (args.state_file.is_some(), "--state-file"),
+        (args.split_on_discontinuity, "--split-on-discontinuity"),
+        (args.segment_bytes.is_some(), "--segment-bytes"),
+        (args.keep_segments.is_some(), "--keep-segments"),
+        (args.keep_hours.is_some(), "--keep-hours"),
+        (args.checksums.is_some(), "--checksums"),
+        (args.sidecars, "--sidecars"),
+    ];
+    match ignored.iter().find(|(set, _)| *set) {
+        Some((_, what)) => Err(StreamError::config(format!(
+            "--ffmpeg-stdin doesn't work with {what}"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn low_disk_policy(args: &Args) -> Option<LowDiskPolicy> {
+    args.min_free_mb.map(|mb| LowDiskPolicy {
+        min_free_bytes: mb.saturating_mul(1024 * 1024),
+        action: args.low_disk,
+    })
+}
+
+/// The URL's own extension for a progressive download (a .mp4 stays .mp4)
+/// unless --file-extension was given
+fn progressive_extension(args: &Args) -> String {
+    if args.file_extension != "ts" {
+        return args.file_extension.clone();
+    }
+    Url::parse(&args.url)
+        .ok()
+        .and_then(|url| {
+            let name = url.path_segments()?.next_back()?.to_string();
+            let (_, ext) = name.rsplit_once('.')?;
+            (!ext.is_empty() && ext.len() <= 4 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+                .then(|| ext.to_ascii_lowercase())
+        })
+        .unwrap_or_else(|| args.file_extension.clone())
+}
+
+enum StreamFormat {
+    FMP4,
+    TS,
+}
+
+/// Run one recording attempt: resolve the URL, detect the format and record
+/// until the stream ends, fails, or shutdown is requested.
+async fn record_stream(
+    client: &HttpClient,
+    args: &Args,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    pre_roll: Option<PreRollConfig>,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    // --on-start fires at this attempt's first bytes, with what was
+    // resolved by then
+    let start_info = Arc::new(Mutex::new(StartInfo {
+        url: redact_password(&args.url),
+        ..StartInfo::default()
+    }));
+    let _on_start = StartHook::spawn(
+        &hooks,
+        start_info.clone(),
+        args.output.clone(),
+        stats.clone(),
+    );
+
+    // Check if this is an RTSP URL
+    if args.url.starts_with("rtsp://") || args.url.starts_with("rtsps://") {
+        #[cfg(feature = "rtsp")]
+        {
+            debug!("Detected RTSP stream...");
+            let rtsp_config = rtsp::RtspConfig {
+                url: args.url.clone(),
+                username: args.net.username.clone(),
+                password: args.net.password.clone(),
+                output_dir: args.output.clone(),
+                segment_secs: args.segment_secs,
+                low_disk: low_disk_policy(args),
+                segment_bytes: args.segment_bytes,
+                filename_template: args
+                    .filename_template
+                    .clone()
+                    .map(|t| t.with_stream(&args.url)),
+                container: args.container,
+                fragmented: args.fragmented_mp4,
+                metadata: args.rtsp_metadata,
+                tls: args
+                    .url
+                    .starts_with("rtsps://")
+                    .then(|| crate::tls::rtsps_connector(&args.net))
+                    .transpose()?,
+                snapshot: args.snapshot.then(|| {
+                    Arc::new(match &args.snapshot_url {
+                        Some(url) => rtsp::Snapshot::Fetch {
+                            client: Box::new(client.clone()),
+                            url: url.clone(),
+                            timeout: Duration::from_secs(args.net.timeout),
+                            retries: args.net.retries,
+                            retry_delay_ms: args.net.retry_delay_ms,
+                        },
+                        None => rtsp::Snapshot::Decode(args.ffmpeg_path.clone()),
+                    })
+                }),
+                hooks,
+            };
+
+            systemd::notify_ready();
+            rtsp::handle_rtsp_stream(rtsp_config, shutdown, rotate, stats).await?;
+            return Ok(());
+        }
+
+        #[cfg(not(feature = "rtsp"))]
+        {
+            return Err(StreamError::config(
+                "RTSP support not compiled in. Rebuild with --features rtsp",
+            ));
+        }
+    }
+
+    if args.url.starts_with("srt://") {
+        #[cfg(feature = "srt")]
+        {
+            debug!("Detected SRT stream...");
+            let srt_config = srt::SrtConfig {
+                url: args.url.clone(),
+                output_dir: args.output.clone(),
+                file_extension: args.file_extension.clone(),
+                segment_secs: args.segment_secs,
+                output_options: output_options(args),
+                hooks,
+            };
+
+            systemd::notify_ready();
+            srt::handle_srt_stream(srt_config, shutdown, rotate, stats).await?;
+            return Ok(());
+        }
+
+        #[cfg(not(feature = "srt"))]
+        {
+            return Err(StreamError::config(
+                "SRT support not compiled in. Rebuild with --features srt",
+            ));
+        }
+    }
+
+    if args.whep {
+        #[cfg(feature = "whep")]
+        {
+            let whep_config = whep::WhepConfig {
+                url: args.url.clone(),
+                bearer_token: args.net.bearer_token.clone(),
+                connector: crate::tls::build_connector(&args.net)?,
+                output_dir: args.output.clone(),
+                segment_secs: args.segment_secs,
+                hooks,
+            };
+
+            systemd::notify_ready();
+            whep::handle_whep_stream(whep_config, shutdown, rotate, stats).await?;
+            return Ok(());
+        }
+
+        #[cfg(not(feature = "whep"))]
+        {
+            return Err(StreamError::config(
+                "WHEP support not compiled in. Rebuild with --features whep",
+            ));
+        }
+    }
+
+    if udp::is_udp_url(&args.url) {
+        debug!("Detected UDP/RTP stream...");
+        let udp_config = udp::UdpConfig {
+            url: args.url.clone(),
+            interface: args.interface,
+            output_dir: args.output.clone(),
+            file_extension: args.file_extension.clone(),
+            segment_secs: args.segment_secs,
+            output_options: output_options(args),
+            hooks,
+        };
+
+        systemd::notify_ready();
+        udp::handle_udp_stream(udp_config, shutdown, rotate, stats).await?;
+        return Ok(());
+    }
+
+    if dash::is_mpd_url(&args.url) {
+        return handle_dash_stream(client, args, shutdown, rotate, hooks, stats).await;
+    }
+
+    let timeout = Duration::from_secs(args.net.timeout);
+
+    if !args.direct && progressive::is_progressive(client, &args.url, timeout).await? {
+        if let Some(what) = ffmpeg_only_option(args) {
+            return Err(StreamError::config(format!(
+                "{what} is only supported for HLS, not progressive downloads"
+            )));
+        }
+        debug!("Not a playlist, downloading progressively...");
+        let progressive_config = ProgressiveConfig {
+            url: args.url.clone(),
+            output_dir: args.output.clone(),
+            file_extension: progressive_extension(args),
+            segment_secs: args.segment_secs,
+            output_options: output_options(args),
+            timeout,
+            retries: args.net.retries,
+            retry_delay_ms: args.net.retry_delay_ms,
+            hooks,
+        };
+
+        systemd::notify_ready();
+        progressive::handle_progressive_stream(client, progressive_config, shutdown, rotate, stats)
+            .await?;
+        return Ok(());
+    }
+
+    // Fetch and resolve playlist (skip if --direct)
+    let selected = if args.direct {
+        SelectedVariant::media(Url::parse(&args.url)?)
+    } else {
+        resolve_media_url(client, args, timeout).await?
+    };
+    *start_info.lock().unwrap() = StartInfo {
+        url: redact_password(selected.video.as_str()),
+        resolution: selected.resolution,
+        bandwidth: selected.bandwidth,
+    };
+    let media_url = selected.video;
+    let audio_url = selected.audio.filter(|_| args.audio != AudioMode::Ignore);
+
+    // Stops when this attempt ends
+    let _thumbnails = match (args.thumbnails, selected.i_frames) {
+        (Some(every), Some(playlist_url)) => {
+            let config = ThumbnailConfig {
+                playlist_url,
+                output_dir: args.output.join("thumbnails"),
+                every: Duration::from_secs(every.max(1)),
+                format: args.thumbnail_format,
+                poll_interval: args.poll_interval,
+                timeout,
+                retries: args.net.retries,
+                retry_delay_ms: args.net.retry_delay_ms,
+                ffmpeg: args.ffmpeg_path.clone(),
+            };
+            Some(Thumbnailer::start(client, config)?)
+        }
+        (Some(_), None) => {
+            warn!("No I-frame playlist in the master playlist, not saving thumbnails");
+            None
+        }
+        (None, _) => None,
+    };
+
+    // Detect format and dispatch (skip detection if --ffmpeg is set)
+    if args.ffmpeg_stdin {
+        if audio_url.is_some() {
+            warn!(
+                "Not recording the separate audio rendition: only one stream fits ffmpeg's stdin"
+            );
+        }
+        systemd::notify_ready();
+        handle_ffmpeg_stdin_stream(client, &media_url, args, shutdown, rotate, hooks, stats)
+            .await?;
+    } else if args.ffmpeg || args.direct {
+        debug!("Forcing ffmpeg mode...");
+        systemd::notify_ready();
+        handle_ffmpeg_stream(
+            client,
+            &media_url,
+            audio_url.as_ref(),
+            args,
+            &shutdown,
+            hooks,
+            stats,
+        )
+        .await?;
+    } else if audio_url.is_some() && args.audio == AudioMode::Mux {
+        debug!("Muxing separate audio with ffmpeg...");
+        systemd::notify_ready();
+        handle_ffmpeg_stream(
+            client,
+            &media_url,
+            audio_url.as_ref(),
+            args,
+            &shutdown,
+            hooks,
+            stats,
+        )
+        .await?;
+    } else {
+        let format = detect_format(
+            client,
+            &media_url,
+            timeout,
+            args.net.retries,
+            args.net.retry_delay_ms,
+        )
+        .await?;
+
+        systemd::notify_ready();
+        let file_extension = match format {
+            StreamFormat::FMP4 => {
+                debug!("Detected fMP4 stream, processing natively...");
+                fmp4_extension(args)
+            }
+            StreamFormat::TS => {
+                debug!("Detected TS stream, processing natively...");
+                args.file_extension.clone()
+            }
+        };
+        let audio_stop = Arc::new(AtomicBool::new(false));
+        let audio_task = match audio_url {
+            // Two streams can't share the pipe
+            Some(_) if args.pipe.is_some() => {
+                warn!("Not recording the separate audio rendition into the pipe");
+                None
+            }
+            Some(ref audio_url) => match spawn_audio_downloader(
+                client,
+                audio_url,
+                args,
+                audio_stop.clone(),
+                pre_roll.clone(),
+                hooks.clone(),
+            )
+            .await
+            {
+                Ok(task) => Some(task),
+                Err(e) => {
+                    warn!("Not recording audio rendition: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let result = handle_hls_stream(
+            client,
+            &media_url,
+            file_extension,
+            args,
+            shutdown,
+            rotate,
+            pre_roll,
+            hooks,
+            stats,
+        )
+        .await;
+        // The audio follows the video: stop it when the video ends
+        if let Some(task) = audio_task {
+            audio_stop.store(true, Ordering::SeqCst);
+            let _ = task.await;
+        }
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Print what ffmpeg mode would write
+fn print_ffmpeg_plan(args: &Args, start: &chrono::DateTime<chrono::Local>, first_index: u32) {
+    let first = args.output.join(output_options(args).filenames().filename(
+        start,
+        first_index,
+        None,
+        &args.file_extension,
+    ));
+    println!(
+        "Would run ffmpeg, writing {} onwards, rotating every {}s",
+        first.display(),
+        args.segment_secs
+    );
+    if let Some(ref cmd) = commands::segment_command(args) {
+        println!(
+            "  on-segment per completed file: {}",
+            commands::describe_segment_command(cmd, &first)
+        );
+    }
+}
+
+/// --dry-run: resolve the stream and report the files and hooks a recording
+/// would produce from the current playlist, without downloading anything
+async fn dry_run(client: &HttpClient, args: &Args) -> Result<(), StreamError> {
+    let start = chrono::Local::now();
+    let names = output_options(args).filenames();
+    let first_index = names.first_free_index(&args.output, &start, &args.file_extension);
+    let mut stream_secs = 0.0;
+
+    if args.whep || args.url.starts_with("rtsp://") || args.url.starts_with("rtsps://") {
+        // The RTSP and WHEP recorders name files by segment start time,
+        // unless RTSP is given --filename-template
+        let extension = if args.whep {
+            "mp4"
+        } else {
+            args.container.extension()
+        };
+        let first = match &args.filename_template {
+            Some(_) if !args.whep => args.output.join(names.filename(
+                &start,
+                names.first_free_index(&args.output, &start, extension),
+                None,
+                extension,
+            )),
+            _ => args
+                .output
+                .join(format!("{}.{}", start.format("%Y%m%d_%H%M%S"), extension)),
+        };
+        println!(
+            "Would record {} stream {} to {} onwards, rotating every {}s",
+            if args.whep { "WHEP" } else { "RTSP" },
+            redact_password(&args.url),
+            first.display(),
+            args.segment_secs
+        );
+    } else if args.url.starts_with("srt://") || udp::is_udp_url(&args.url) {
+        let first =
+            args.output
+                .join(names.filename(&start, first_index, None, &args.file_extension));
+        println!(
+            "Would receive {} to {} onwards, rotating every {}s at the next keyframe",
+            redact_password(&args.url),
+            first.display(),
+            args.segment_secs
+        );
+    } else if dash::is_mpd_url(&args.url) {
+        println!(
+            "Would record DASH manifest {} natively, rotating every {}s",
+            redact_password(&args.url),
+            args.segment_secs
+        );
+    } else if !args.direct
+        && progressive::is_progressive(client, &args.url, Duration::from_secs(args.net.timeout))
+            .await?
+    {
+        let first = args.output.join(names.filename(
+            &start,
+            first_index,
+            None,
+            &progressive_extension(args),
+        ));
+        println!(
+            "Would download {} progressively to {} onwards, resuming with Range requests",
+            redact_password(&args.url),
+            first.display()
+        );
+    } else {
+        let timeout = Duration::from_secs(args.net.timeout);
+        let (media_url, audio_url) = if args.direct {
+            (Url::parse(&args.url)?, None)
+        } else {
+            let selected = resolve_media_url(client, args, timeout).await?;
+            (selected.video, selected.audio)
+        };
+        println!("Media playlist: {}", redact_password(media_url.as_str()));
+        if let Some(audio_url) = audio_url.as_ref().map(|url| redact_password(url.as_str())) {
+            match args.audio {
+                AudioMode::Ignore => println!("Audio rendition {audio_url} (ignored)"),
+                AudioMode::Store => println!(
+                    "Audio rendition {audio_url}, recorded into {}",
+                    args.output.join("audio").display()
+                ),
+                AudioMode::Mux => println!("Audio rendition {audio_url}, muxed in by ffmpeg"),
+            }
+        }
+        let mux_audio = audio_url.is_some() && args.audio == AudioMode::Mux;
+
+        let media = if args.ffmpeg || args.direct || mux_audio {
+            None
+        } else {
+            Some(
+                fetch_media_playlist(
+                    client,
+                    &media_url,
+                    timeout,
+                    args.net.retries,
+                    args.net.retry_delay_ms,
+                )
+                .await?,
+            )
+        };
+
+        match media {
+            Some(ref pl) => {
+                let fmp4 = playlist::is_fmp4_playlist(pl);
+                let file_extension = if fmp4 {
+                    fmp4_extension(args)
+                } else {
+                    args.file_extension.clone()
+                };
+                println!(
+                    "{} stream, {} segments in the playlist ({})",
+                    if fmp4 { "fMP4" } else { "TS" },
+                    pl.segments.len(),
+                    if pl.end_list { "VOD" } else { "live" }
+                );
+
+                // Files rotate on wall-clock time; replay the playlist's
+                // segment durations as if they arrived in real time
+                let mut index = names.first_free_index(&args.output, &start, &file_extension);
+                let mut file_secs = 0.0;
+                let path_for = |i| {
+                    args.output
+                        .join(names.filename(&start, i, None, &file_extension))
+                };
+                let complete = |i| {
+                    let path = path_for(i);
+                    println!("  completes {}", path.display());
+                    if let Some(ref cmd) = commands::segment_command(args) {
+                        println!(
+                            "    on-segment: {}",
+                            commands::describe_segment_command(cmd, &path)
+                        );
+                    }
+                };
+
+                println!("Would write {}", path_for(index).display());
+                for segment in &pl.segments {
+                    if segment.discontinuity && args.split_on_discontinuity && file_secs > 0.0 {
+                        println!("  discontinuity");
+                        complete(index);
+                        index += 1;
+                        file_secs = 0.0;
+                        println!("Would write {}", path_for(index).display());
+                    }
+                    file_secs += segment.duration as f64;
+                    stream_secs += segment.duration as f64;
+                    if file_secs >= args.segment_secs as f64 {
+                        complete(index);
+                        index += 1;
+                        file_secs = 0.0;
+                        println!("Would write {}", path_for(index).display());
+                    }
+                }
+                if pl.end_list {
+                    complete(index);
+                    println!(
+                        "Note: VOD downloads run faster than real time, so fewer files are likely"
+                    );
+                } else {
+                    println!(
+                        "Then poll every {}s and keep appending new segments",
+                        args.poll_interval
+                    );
+                }
+            }
+            None => print_ffmpeg_plan(args, &start, first_index),
+        }
+    }
+
+    if let Some(ref cmd) = args.on_exit {
+        println!(
+            "on-exit: {}",
+            commands::expand_exit_command(
+                cmd,
+                ExitReason::Ended,
+                stream_secs as u64,
+                0,
+                &args.output
+            )
+        );
+        println!("  (%s, %b and %m are shown as 0 since nothing is downloaded)");
+    }
+    Ok(())
+}
+
+/// Sleep for `delay`, waking early if shutdown is requested
+pub async fn sleep_unless_shutdown(delay: Duration, shutdown: &AtomicBool) {
+    let deadline = Instant::now() + delay;
+    while Instant::now() < deadline && !shutdown.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Make one recording attempt, running --on-error if it fails. A reader
+/// closing --pipe ends the recording without an error.
+pub async fn record_attempt(
+    client: &HttpClient,
+    args: &Args,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    pre_roll: Option<PreRollConfig>,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    // Each attempt starts a new file anyway
+    rotate.store(false, Ordering::SeqCst);
+    let result = record_stream(
+        client,
+        args,
+        shutdown,
+        rotate,
+        pre_roll,
+        hooks.clone(),
+        stats,
+    )
+    .instrument(tracing::info_span!("record", stream = %redact_password(&args.url)))
+    .await;
+    let result = result.or_else(|e| match &e {
+        StreamError::Disk(io)
+            if args.pipe.is_some() && io.kind() == std::io::ErrorKind::BrokenPipe =>
+        {
+            info!("The reader closed the pipe");
+            Ok(())
+        }
+        _ => Err(e),
+    });
+    if let Err(e) = &result {
+        Hooks::report_error(&hooks, e);
+    }
+    result
+}
+
+/// Record until the stream ends or shutdown, restarting the whole pipeline
+/// if --restart-on-exit is set
+pub async fn record_with_restarts(
+    client: &HttpClient,
+    args: &Args,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    pre_roll: Option<PreRollConfig>,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    let mut restarts: u32 = 0;
+    // Attempts in a row that failed, for the on-error hook
+    let mut failed: u32 = 0;
+    let initial_delay = Duration::from_secs(args.restart_delay);
+    let mut restart_delay = initial_delay;
+    loop {
+        let attempt_start = Instant::now();
+        let result = record_attempt(
+            client,
+            args,
+            shutdown.clone(),
+            rotate.clone(),
+            pre_roll.clone(),
+            hooks.clone(),
+            stats.clone(),
+        )
+        .await;
+
+        if !args.restart_on_exit || shutdown.load(Ordering::SeqCst) {
+            return result;
+        }
+        failed = if result.is_ok() { 0 } else { failed + 1 };
+        if args.max_restarts > 0 && restarts >= args.max_restarts {
+            error!("Giving up after {restarts} restarts");
+            return result.map_err(|error| {
+                if matches!(error, StreamError::GaveUp { .. }) {
+                    error
+                } else {
+                    StreamError::GaveUp {
+                        failures: failed,
+                        error: Box::new(error),
+                    }
+                }
+            });
+        }
+        match &result {
+            Ok(()) => info!("Recording ended"),
+            Err(e) => warn!("Recording failed: {e}"),
+        }
+
+        // A run that stayed up longer than the backoff cap was healthy; start over
+        let max_delay = Duration::from_secs(args.restart_max_delay).max(initial_delay);
+        if attempt_start.elapsed() > max_delay {
+            restart_delay = initial_delay;
+        }
+
+        restarts += 1;
+        info!(
+            "Restarting in {}s (restart {restarts})...",
+            restart_delay.as_secs()
+        );
+        sleep_unless_shutdown(restart_delay, &shutdown).await;
+        if shutdown.load(Ordering::SeqCst) {
+            return result;
+        }
+        restart_delay = (restart_delay * 2).min(max_delay);
+    }
+}
+
+/// The options for recording one of several streams: its URL and
+/// credentials, into its own subdirectory, with its own state file
+fn camera_args(args: &Args, camera: &Camera) -> Args {
+    let mut args = args.clone();
+    args.url = camera.url.clone();
+    args.more_urls.clear();
+    args.cameras = None;
+    args.input_list = None;
+    args.output = args.output.join(&camera.name);
+    args.state_file = args.state_file.map(|path| {
+        let mut path = path.into_os_string();
+        path.push(format!(".{}", camera.name));
+        PathBuf::from(path)
+    });
+    if camera.username.is_some() {
+        args.net.username = camera.username.clone();
+        args.net.password = camera.password.clone();
+    }
+    args
+}
+
+/// The hooks for one camera: the global ones, with its own --on-segment
+/// and URL
+fn hooks_for_camera(global: &Hooks, camera: &Camera) -> Hooks {
+    Hooks {
+        on_segment: camera
+            .on_segment
+            .clone()
+            .map(HookCommand::Shell)
+            .or(global.on_segment.clone()),
+        url: redact_password(&camera.url),
+        ..global.clone()
+    }
+}
+
+/// Record several cameras at once, each restarting on its own with
+/// --restart-on-exit. Their stats add up in `stats`; one camera failing
+/// doesn't stop the others.
+async fn record_cameras(
+    client: &HttpClient,
+    args: &Args,
+    cameras: Vec<Camera>,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+) -> Result<(), StreamError> {
+    let mut recorders = tokio::task::JoinSet::new();
+    let mut links = Vec::new();
+    for camera in cameras {
+        let camera_args = camera_args(args, &camera);
+        let camera_rotate = Arc::new(AtomicBool::new(false));
+        let camera_hooks: SharedHooks = Arc::new(RwLock::new(hooks_for_camera(
+            &hooks.read().unwrap(),
+            &camera,
+        )));
+        let camera_stats = Arc::new(RecordingStats::with_parent(stats.clone()));
+        links.push((camera.clone(), camera_rotate.clone(), camera_hooks.clone()));
+
+        let client = client.for_origin(&camera.url);
+        let shutdown = shutdown.clone();
+        recorders.spawn(async move {
+            let result = record_with_restarts(
+                &client,
+                &camera_args,
+                shutdown,
+                camera_rotate,
+                None,
+                camera_hooks,
+                camera_stats.clone(),
+            )
+            .await;
+            (camera.name, camera_stats.bytes(), result)
+        });
+    }
+
+    // Pass SIGHUP rotation and reloaded hooks on to every camera
+    let relay = {
+        let hooks = hooks.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                if rotate.swap(false, Ordering::SeqCst) {
+                    for (_, camera_rotate, _) in &links {
+                        camera_rotate.store(true, Ordering::SeqCst);
+                    }
+                }
+                let global = hooks.read().unwrap().clone();
+                for (camera, _, camera_hooks) in &links {
+                    *camera_hooks.write().unwrap() = hooks_for_camera(&global, camera);
+                }
+            }
+        })
+    };
+
+    let mut failed = Vec::new();
+    while let Some(joined) = recorders.join_next().await {
+        let (name, bytes, result) = joined?;
+        match result {
+            Ok(()) => info!(camera = %name, bytes, "Camera recording ended"),
+            Err(e) => {
+                error!(camera = %name, bytes, "Camera recording failed: {e}");
+                failed.push(name);
+            }
+        }
+    }
+    relay.abort();
+    info!(bytes = stats.bytes(), "All cameras stopped");
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("recording failed for {}", failed.join(", ")).into())
+    }
+}
+
+/// Whether a signal stopped the recording
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Report how the recording ended: the webhook's error or exit event,
+/// --on-error after a failure, then the on-exit command. `interrupted` is
+/// whether it was stopped rather than ending by itself.
+pub async fn report_exit(
+    args: &Args,
+    hooks: &SharedHooks,
+    stats: &RecordingStats,
+    recording_start: Instant,
+    result: &Result<(), StreamError>,
+    interrupted: bool,
+) -> ExitReason {
+    let webhook = Hooks::webhook(hooks);
+    let reason = match result {
+        Err(_) => ExitReason::Failed,
+        Ok(()) if interrupted => ExitReason::Interrupted,
+        Ok(()) => ExitReason::Ended,
+    };
+    if let Some(path) = &args.stats_json {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let url = redact_password(&args.url);
+        let written =
+            status::write_summary(path, &url, &args.output, reason.as_str(), error, stats);
+        if let Err(e) = written {
+            warn!("Failed to write --stats-json {}: {e}", path.display());
+        }
+    }
+    if let Err(e) = result {
+        let failures = match e {
+            StreamError::GaveUp { failures, .. } => *failures,
+            _ => 1,
+        };
+        if let Some(webhook) = webhook {
+            let _ = webhook
+                .send(
+                    "error",
+                    serde_json::json!({
+                        "error": e.to_string(),
+                        "failures": failures,
+                        "duration": recording_start.elapsed().as_secs(),
+                        "bytes": stats.bytes(),
+                        "dir": args.output,
+                    }),
+                )
+                .await;
+        }
+        let on_error = hooks.read().unwrap().on_error.clone();
+        if let Some(ref cmd) = on_error {
+            commands::run_error_command(
+                cmd,
+                &e.to_string(),
+                failures,
+                recording_start.elapsed().as_secs(),
+                &args.output,
+                &Hooks::url(hooks),
+            );
+        }
+    } else if let Some(webhook) = webhook {
+        let _ = webhook
+            .send(
+                "exit",
+                serde_json::json!({
+                    "reason": reason.as_str(),
+                    "duration": recording_start.elapsed().as_secs(),
+                    "bytes": stats.bytes(),
+                    "dir": args.output,
+                }),
+            )
+            .await;
+    }
+    // Run on-exit command, or --on-exit-error's after a failure
+    let on_exit = {
+        let hooks = hooks.read().unwrap();
+        match reason {
+            ExitReason::Failed => hooks.on_exit_error.clone().or(hooks.on_exit.clone()),
+            _ => hooks.on_exit.clone(),
+        }
+    };
+    if let Some(ref cmd) = on_exit {
+        commands::run_exit_command(
+            cmd,
+            reason,
+            recording_start.elapsed().as_secs(),
+            stats.bytes(),
+            &args.output,
+            &Hooks::url(hooks),
+        );
+    }
+    reason
+}
+
+/// Check the options against each other and against the build, filling in
+/// what they imply: `--output -` pipes, and ffmpeg-only options need ffmpeg.
+/// `several` is set when recording several streams at once.
+fn check_args(args: &mut Args, several: bool) -> Result<(), StreamError> {
+    if args.output == Path::new("-") {
+        args.pipe = Some(PathBuf::from("-"));
+        args.output = PathBuf::from(".");
+    }
+    if args.pipe.is_some() {
+        check_pipe_args(args)?;
+    }
+    if args.ffmpeg_stdin {
+        check_ffmpeg_stdin_args(args)?;
+    }
+    let stdout = Path::new("-");
+    if args.pipe.as_deref() == Some(stdout) && args.stats_json.as_deref() == Some(stdout) {
+        return Err(StreamError::config(
+            "--stats-json - would write into the stream piped to stdout",
+        ));
+    }
+    if args.remux_mp4 && !cfg!(feature = "remux") {
+        return Err(StreamError::config(
+            "MP4 remuxing not compiled in. Rebuild with --features remux",
+        ));
+    }
+    if args.onvif.is_some() && !cfg!(feature = "onvif") {
+        return Err(StreamError::config(
+            "ONVIF support not compiled in. Rebuild with --features onvif",
+        ));
+    }
+    if args.onvif.is_some() && several {
+        return Err(StreamError::config(
+            "--onvif watches one camera and can't be used with several",
+        ));
+    }
+    let not_rtsp =
+        !several && !args.url.starts_with("rtsp://") && !args.url.starts_with("rtsps://");
+    if args.snapshot && not_rtsp {
+        return Err(StreamError::config(
+            "--snapshot only applies to RTSP recordings",
+        ));
+    }
+    if args.rtsp_metadata && not_rtsp {
+        return Err(StreamError::config(
+            "--rtsp-metadata only applies to RTSP recordings",
+        ));
+    }
+    if args.fragmented_mp4 && args.container == Container::Mkv {
+        return Err(StreamError::config(
+            "--fragmented-mp4 doesn't apply to --container mkv",
+        ));
+    }
+    if let Some(what) = ffmpeg_only_option(args) {
+        let url = args.url.as_str();
+        let native = ["rtsp://", "rtsps://", "srt://"]
+            .iter()
+            .any(|scheme| url.starts_with(scheme));
+        if native || args.whep || udp::is_udp_url(url) || dash::is_mpd_url(url) {
+            return Err(StreamError::config(format!(
+                "{what} is only supported for HLS, which is recorded with ffmpeg"
+            )));
+        }
+        args.ffmpeg = true;
+    }
+    Ok(())
+}
+
+/// Check a daemon job's options like `record` would, before the daemon
+/// starts anything
+pub fn check_job(args: &mut Args) -> Result<(), StreamError> {
+    args.url = input_url(&args.url)?;
+    if args.pipe.is_some() || args.output == Path::new("-") {
+        return Err(StreamError::config("a job can't write to a pipe"));
+    }
+    if args.onvif.is_some() {
+        return Err(StreamError::config("--onvif isn't supported for jobs"));
+    }
+    if args.dry_run {
+        return Err(StreamError::config("--dry-run isn't supported for jobs"));
+    }
+    check_args(args, false)
+}
+
+/// The `record` command: download the stream until it ends or Ctrl+C,
+/// then run the on-exit hook.
+pub async fn run(mut args: Args) -> Result<(), StreamError> {
+    if args.url.is_empty() && args.cameras.is_none() && args.input_list.is_none() {
+        return Err(StreamError::config(
+            "no URL to record: give one, --input-list or --cameras",
+        ));
+    }
+    let recording_start = Instant::now();
+    let mut cameras = match &args.cameras {
+        Some(path) => cameras::load(path)?,
+        None if !args.more_urls.is_empty() || args.input_list.is_some() => {
+            let urls: Vec<String> = std::iter::once(&args.url)
+                .chain(&args.more_urls)
+                .filter(|url| !url.is_empty())
+                .cloned()
+                .collect();
+            cameras::from_urls(&urls, args.input_list.as_deref())?
+        }
+        None => Vec::new(),
+    };
+    for camera in &mut cameras {
+        camera.url = input_url(&camera.url)?;
+    }
+    if cameras.is_empty() {
+        args.url = input_url(&args.url)?;
+    }
+    check_args(&mut args, !cameras.is_empty())?;
+
+    // Setup
+    let client = build_client(&args.net)?.for_origin(&args.url);
+    if args.dry_run {
+        if cameras.is_empty() {
+            return dry_run(&client, &args).await;
+        }
+        for camera in &cameras {
+            dry_run(&client.for_origin(&camera.url), &camera_args(&args, camera)).await?;
+        }
+        return Ok(());
+    }
+    let shutdown = setup_shutdown_handler();
+    let rotate = Arc::new(AtomicBool::new(false));
+    let hooks: SharedHooks = Arc::new(RwLock::new(Hooks::from_args(&args)?));
+    setup_reload_handler(&args, rotate.clone(), hooks.clone());
+    let pre_roll = setup_trigger_handler(&args, None);
+    let stats = Arc::new(RecordingStats::new());
+    setup_limit_handler(&args, shutdown.clone(), stats.clone());
+    std::fs::create_dir_all(&args.output)?;
+    #[cfg(feature = "onvif")]
+    if let Some(device_url) = &args.onvif {
+        crate::onvif::spawn(
+            onvif_config(&args, device_url, pre_roll.as_ref())?,
+            shutdown.clone(),
+        );
+    }
+    systemd::spawn_status_reporter(stats.clone());
+    if args.status_file {
+        status::spawn_status_writer(
+            args.output.join("status.json"),
+            Duration::from_secs(args.status_interval.max(1)),
+            stats.clone(),
+        );
+    }
+
+    let progress = if args.progress && !args.log.quiet {
+        ProgressDisplay::start(stats.clone())
+    } else {
+        None
+    };
+    // Without the progress line, a VOD download reports in the log, and
+    // so does a live one with --progress
+    let vod_log =
+        (progress.is_none() && !args.log.quiet).then(|| progress::spawn_vod_log(stats.clone()));
+    let live_log = (progress.is_none() && args.progress && !args.log.quiet)
+        .then(|| progress::spawn_live_log(stats.clone()));
+
+    let result = if cameras.is_empty() {
+        record_with_restarts(
+            &client,
+            &args,
+            shutdown.clone(),
+            rotate.clone(),
+            pre_roll.clone(),
+            hooks.clone(),
+            stats.clone(),
+        )
+        .await
+    } else {
+        record_cameras(
+            &client,
+            &args,
+            cameras,
+            shutdown.clone(),
+            rotate.clone(),
+            hooks.clone(),
+            stats.clone(),
+        )
+        .await
+    };
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    if let Some(vod_log) = vod_log {
+        vod_log.abort();
+    }
+    if let Some(live_log) = live_log {
+        live_log.abort();
+    }
+    systemd::notify_stopping();
+    report_exit(
+        &args,
+        &hooks,
+        &stats,
+        recording_start,
+        &result,
+        interrupted(),
+    )
+    .await;
+    result?;
+
+    if args.fake_exit_err {
+        std::process::exit(130);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, Command};
+
+    fn record_args(argv: &[&str]) -> Args {
+        let argv = ["m3u8-dl", "record", "https://example.com/live.m3u8"]
+            .iter()
+            .chain(argv)
+            .map(Into::into);
+        match Cli::try_parse_with_default(argv).unwrap().command {
+            Command::Record(args) => *args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn ffmpeg_stdin_refuses_file_options() {
+        assert!(check_ffmpeg_stdin_args(&record_args(&["--ffmpeg-stdin"])).is_ok());
+        for option in [
+            &["--checksums", "sha256"][..],
+            &["--sidecars"],
+            &["--segment-bytes", "1G"],
+            &["--keep-segments", "5"],
+            &["--keep-hours", "24"],
+            &["--state-file", "state.json"],
+        ] {
+            let argv: Vec<&str> = ["--ffmpeg-stdin"].iter().chain(option).copied().collect();
+            let err = check_ffmpeg_stdin_args(&record_args(&argv)).unwrap_err();
+            assert!(err.to_string().contains(option[0]), "{err}");
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Recording from another program: a [`Recorder`] records one stream the
+//! way `stream-utils record` does, hooks and all, and calls back as files
+//! are completed, attempts fail and the recording finishes. It prints
+//! nothing and installs no Ctrl+C or SIGHUP handler: its log lines go to the
+//! program's `tracing` subscriber, and a [`StopHandle`] stops or rotates it,
+//! or triggers a --pre-roll clip in place of SIGUSR1.
+
+use crate::commands::{Callbacks, ExitReason, Hooks, SegmentEvent, SharedHooks};
+use crate::config;
+use crate::error::StreamError;
+use crate::http_client::build_client;
+use crate::record;
+use crate::stats::RecordingStats;
+use crate::status;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub type FinishedCallback = Arc<dyn Fn(&Summary) + Send + Sync>;
+
+/// How a recording went, as returned by [`Recorder::run`] and passed to the
+/// on_finished callback
+#[derive(Clone, Debug)]
+pub struct Summary {
+    pub reason: ExitReason,
+    /// The error it gave up with, when it failed
+    pub error: Option<String>,
+    pub duration: Duration,
+    pub bytes: u64,
+    pub segments: u64,
+    /// Every file written, oldest first
+    pub files: Vec<PathBuf>,
+}
+
+impl Summary {
+    fn new(
+        reason: ExitReason,
+        error: Option<&StreamError>,
+        start: Instant,
+        stats: &RecordingStats,
+    ) -> Self {
+        Summary {
+            reason,
+            error: error.map(|e| e.to_string()),
+            duration: start.elapsed(),
+            bytes: stats.bytes(),
+            segments: stats.segments(),
+            files: stats.files().into_iter().map(|file| file.path).collect(),
+        }
+    }
+}
+
+/// A recording that failed, with how far it got. It converts into its
+/// [`StreamError`], so `?` works in a function returning one.
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub struct RecordingFailed {
+    pub error: StreamError,
+    pub summary: Summary,
+}
+
+impl From<RecordingFailed> for StreamError {
+    fn from(failed: RecordingFailed) -> Self {
+        failed.error
+    }
+}
+
+/// Stops or rotates a [`Recorder`] from another task
+#[derive(Clone, Default)]
+pub struct StopHandle {
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    /// Set by stop() rather than a limit, so the recording counts as
+    /// interrupted
+    stopped: Arc<AtomicBool>,
+    /// --pre-roll's trigger count
+    triggers: Arc<AtomicU64>,
+}
+
+impl StopHandle {
+    /// Finish the current file and end the recording, like Ctrl+C does for
+    /// the command
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Close the current file and start a new one, like SIGHUP
+    pub fn rotate(&self) {
+        self.rotate.store(true, Ordering::SeqCst);
+    }
+
+    /// With --pre-roll, save a clip, like SIGUSR1
+    pub fn trigger(&self) {
+        self.triggers.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Sets up a [`Recorder`]. Options are those of `record`, set by their long
+/// names as in a config file; the common ones have methods of their own.
+pub struct RecorderBuilder {
+    options: toml::Table,
+    callbacks: Callbacks,
+    on_finished: Option<FinishedCallback>,
+}
+
+impl RecorderBuilder {
+    /// Directory to write into (--output)
+    pub fn output(self, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref().to_string_lossy().into_owned();
+        self.option("output", dir)
+    }
+
+    /// How long each file is (--segment-secs)
+    pub fn segment_duration(self, duration: Duration) -> Self {
+        self.option("segment-secs", duration.as_secs() as i64)
+    }
+
+    /// Stop after this long (--duration)
+    pub fn duration(self, duration: Duration) -> Self {
+        self.option("duration", duration.as_secs() as i64)
+    }
+
+    /// Start over when the stream ends or fails (--restart-on-exit)
+    pub fn restart_on_exit(self, restart: bool) -> Self {
+        self.option("restart-on-exit", restart)
+    }
+
+    /// Set any `record` option by its long name, e.g. `option("variant",
+    /// "720p")`. Flags take `true`, and options given several times an
+    /// array. Bad names and values are reported by build().
+    pub fn option(mut self, name: &str, value: impl Into<toml::Value>) -> Self {
+        self.options.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Call `callback` with each completed file, before --on-segment runs
+    /// on it. It's called from a background thread, so it shouldn't block
+    /// for long.
+    pub fn on_segment(mut self, callback: impl Fn(&SegmentEvent) + Send + Sync + 'static) -> Self {
+        self.callbacks.on_segment = Some(Arc::new(callback));
+        self
+    }
+
+    /// Call `callback` each time recording fails, including the failures
+    /// that --restart-on-exit recovers from
+    pub fn on_error(mut self, callback: impl Fn(&StreamError) + Send + Sync + 'static) -> Self {
+        self.callbacks.on_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Call `callback` once the recording is over, however it ended, after
+    /// the on-exit hook
+    pub fn on_finished(mut self, callback: impl Fn(&Summary) + Send + Sync + 'static) -> Self {
+        self.on_finished = Some(Arc::new(callback));
+        self
+    }
+
+    /// Check the options against each other, as `record` would
+    pub fn build(self) -> Result<Recorder, StreamError> {
+        let mut args = config::record_args(&self.options)?;
+        record::check_job(&mut args)?;
+        Ok(Recorder {
+            args,
+            callbacks: self.callbacks,
+            on_finished: self.on_finished,
+            handle: StopHandle::default(),
+        })
+    }
+}
+
+/// Records one stream; see the crate documentation for an example
+pub struct Recorder {
+    args: crate::cli::Args,
+    callbacks: Callbacks,
+    on_finished: Option<FinishedCallback>,
+    handle: StopHandle,
+}
+
+impl Recorder {
+    /// Start setting up a recording of `url`: an HLS or DASH playlist, or
+    /// anything else `record` takes
+    pub fn builder(url: impl Into<String>) -> RecorderBuilder {
+        let mut options = toml::Table::new();
+        options.insert("url".to_string(), toml::Value::String(url.into()));
+        RecorderBuilder {
+            options,
+            callbacks: Callbacks::default(),
+            on_finished: None,
+        }
+    }
+
+    /// A handle to stop or rotate the recording while run() is going
+    pub fn stop_handle(&self) -> StopHandle {
+        self.handle.clone()
+    }
+
+    /// Record until the stream ends, a limit is reached or the recording is
+    /// stopped, running the hooks and webhook the options ask for. A failed
+    /// recording still has its [`Summary`], in the error.
+    pub async fn run(self) -> Result<Summary, RecordingFailed> {
+        let args = self.args;
+        let recording_start = Instant::now();
+        let stats = Arc::new(RecordingStats::new());
+        let setup = || -> Result<_, StreamError> {
+            let client = build_client(&args.net)?.for_origin(&args.url);
+            let hooks = Hooks::from_args(&args)?;
+            std::fs::create_dir_all(&args.output)?;
+            Ok((client, hooks))
+        };
+        let (client, mut hooks) = match setup() {
+            Ok(setup) => setup,
+            Err(error) => {
+                let summary =
+                    Summary::new(ExitReason::Failed, Some(&error), recording_start, &stats);
+                return Err(RecordingFailed { error, summary });
+            }
+        };
+        hooks.callbacks = self.callbacks;
+        let hooks: SharedHooks = Arc::new(RwLock::new(hooks));
+        let pre_roll = record::setup_trigger_handler(&args, Some(self.handle.triggers.clone()));
+        record::setup_limit_handler(&args, self.handle.shutdown.clone(), stats.clone());
+        let status_writer = args.status_file.then(|| {
+            status::spawn_status_writer(
+                args.output.join("status.json"),
+                Duration::from_secs(args.status_interval.max(1)),
+                stats.clone(),
+            )
+        });
+
+        let result = record::record_with_restarts(
+            &client,
+            &args,
+            self.handle.shutdown.clone(),
+            self.handle.rotate.clone(),
+            pre_roll,
+            hooks.clone(),
+            stats.clone(),
+        )
+        .await;
+        if let Some(status_writer) = status_writer {
+            status_writer.abort();
+        }
+        let interrupted = self.handle.stopped.load(Ordering::SeqCst);
+        let reason =
+            record::report_exit(&args, &hooks, &stats, recording_start, &result, interrupted).await;
+
+        let summary = Summary::new(reason, result.as_ref().err(), recording_start, &stats);
+        if let Some(on_finished) = &self.on_finished {
+            on_finished(&summary);
+        }
+        match result {
+            Ok(()) => Ok(summary),
+            Err(error) => Err(RecordingFailed { error, summary }),
+        }
+    }
+}
@@ -0,0 +1,667 @@
+//! Native TS to MP4 remuxing for --remux-mp4: a completed `.ts` is
+//! demuxed (H.264 video, AAC audio) and repackaged into a faststart `.mp4`
+//! that browsers can play directly, without running ffmpeg.
+
+use crate::error::StreamError as Error;
+use crate::h264::sps_dimensions;
+use crate::output;
+use crate::sidecar;
+use mp4::{AacConfig, AvcConfig, MediaConfig, Mp4Config, Mp4Sample, Mp4Writer, TrackConfig};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+const STREAM_TYPE_AAC: u8 = 0x0F;
+const STREAM_TYPE_H264: u8 = 0x1B;
+/// PTS and DTS count in 90 kHz and wrap at 33 bits
+const TIMESCALE: u32 = 90_000;
+const PTS_WRAP: i64 = 1 << 33;
+const VIDEO_TRACK: u32 = 1;
+const AUDIO_TRACK: u32 = 2;
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Remux a completed recording to MP4 next to it, then delete the TS,
+/// listing the MP4 in the `checksums` manifest in its place. Returns the
+/// file to hand on to --on-segment: the MP4, or the TS if the remux failed.
+pub fn remux_completed(ts: PathBuf, checksums: Option<&Path>) -> PathBuf {
+    let mp4 = ts.with_extension("mp4");
+    if output::name_taken(&mp4) {
+        // From an earlier run that used the same name
+        warn!(file = %mp4.display(), "Not remuxing to MP4, the file exists");
+        return ts;
+    }
+    match ts_to_mp4(&ts, &mp4) {
+        Ok(()) => {
+            if let Err(e) = sidecar::moved(&ts, &mp4) {
+                warn!(file = %ts.display(), "Failed to move the sidecar to the MP4: {e}");
+            }
+            if let Some(manifest) = checksums {
+                if let Err(e) = output::replace_checksum(manifest, &ts, &mp4) {
+                    warn!(file = %mp4.display(), "Failed to list the MP4's checksum: {e}");
+                }
+            }
+            if let Err(e) = std::fs::remove_file(&ts) {
+                warn!(file = %ts.display(), "Failed to remove the remuxed TS: {e}");
+            }
+            info!(file = %mp4.display(), "Remuxed to MP4");
+            mp4
+        }
+        Err(e) => {
+            warn!(file = %ts.display(), "Remux to MP4 failed, keeping the TS: {e}");
+            ts
+        }
+    }
+}
+
+/// Remux `ts` into a faststart MP4 at `mp4`. The MP4 is written under
+/// temporary names and only appears once it's complete.
+pub fn ts_to_mp4(ts: &Path, mp4: &Path) -> Result<(), Error> {
+    let streams = probe(ts)?;
+    let moov_last = temp_path(mp4, "moov-last");
+    let result = write_mp4(ts, &moov_last, &streams).and_then(|()| {
+        let part = output::part_path(mp4);
+        faststart(&moov_last, &part)?;
+        std::fs::rename(&part, mp4)?;
+        Ok(())
+    });
+    let _ = std::fs::remove_file(&moov_last);
+    result
+}
+
+fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{suffix}.part"));
+    temp.into()
+}
+
+/// What the MP4's tracks are set up from
+struct Streams {
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+    width: u16,
+    height: u16,
+    audio: Option<AdtsConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AdtsConfig {
+    /// MPEG-4 audio object type (2 for AAC LC)
+    object_type: u8,
+    freq_index: u8,
+    channels: u8,
+}
+
+impl AdtsConfig {
+    fn sample_rate(&self) -> u32 {
+        AAC_SAMPLE_RATES
+            .get(self.freq_index as usize)
+            .copied()
+            .unwrap_or(48000)
+    }
+}
+
+/// A complete PES packet from one elementary stream
+struct Pes {
+    stream_type: u8,
+    pts: Option<i64>,
+    dts: Option<i64>,
+    data: Vec<u8>,
+}
+
+/// Reassembles PES packets from TS packets, following the PAT and PMT of
+/// the first program
+#[derive(Default)]
+struct Demuxer {
+    pmt_pid: Option<u16>,
+    /// Stream type by PID, for the streams that can be remuxed
+    streams: HashMap<u16, u8>,
+    /// The PES packet being collected by PID
+    pending: HashMap<u16, Vec<u8>>,
+    /// Stream types there's no support for, warned about once
+    skipped: Vec<u8>,
+}
+
+impl Demuxer {
+    /// Take one TS packet, returning a PES packet it completed
+    fn push(&mut self, packet: &[u8]) -> Option<Pes> {
+        if packet.len() != TS_PACKET_SIZE || packet[0] != TS_SYNC_BYTE {
+            return None;
+        }
+        let pid = u16::from_be_bytes([packet[1] & 0x1F, packet[2]]);
+        let unit_start = packet[1] & 0x40 != 0;
+        let control = (packet[3] >> 4) & 0x3;
+        if control & 0x1 == 0 {
+            return None;
+        }
+        let mut start = 4;
+        if control & 0x2 != 0 {
+            start += 1 + packet[4] as usize;
+        }
+        let payload = packet.get(start..)?;
+
+        if pid == 0 {
+            if unit_start {
+                self.pmt_pid = parse_pat(payload);
+            }
+            return None;
+        }
+        if Some(pid) == self.pmt_pid {
+            if unit_start && self.streams.is_empty() {
+                self.parse_pmt(payload);
+            }
+            return None;
+        }
+        let stream_type = *self.streams.get(&pid)?;
+        let finished = if unit_start {
+            self.pending.insert(pid, payload.to_vec())
+        } else {
+            if let Some(pes) = self.pending.get_mut(&pid) {
+                pes.extend_from_slice(payload);
+            }
+            None
+        };
+        finished.and_then(|data| parse_pes(stream_type, &data))
+    }
+
+    /// The PES packets still being collected at the end of the file
+    fn flush(&mut self) -> Vec<Pes> {
+        let mut pids: Vec<u16> = self.pending.keys().copied().collect();
+        pids.sort_unstable();
+        pids.into_iter()
+            .filter_map(|pid| {
+                let data = self.pending.remove(&pid)?;
+                parse_pes(self.streams[&pid], &data)
+            })
+            .collect()
+    }
+
+    fn parse_pmt(&mut self, payload: &[u8]) {
+        let Some(section) = section(payload) else {
+            return;
+        };
+        if section.len() < 4 {
+            return;
+        }
+        let info_len = (u16::from_be_bytes([section[2], section[3]]) & 0x0FFF) as usize;
+        let mut pos = 4 + info_len;
+        while pos + 5 <= section.len() {
+            let stream_type = section[pos];
+            let pid = u16::from_be_bytes([section[pos + 1], section[pos + 2]]) & 0x1FFF;
+            let es_info_len =
+                (u16::from_be_bytes([section[pos + 3], section[pos + 4]]) & 0x0FFF) as usize;
+            pos += 5 + es_info_len;
+            match stream_type {
+                STREAM_TYPE_H264 | STREAM_TYPE_AAC
+                    if !self.streams.values().any(|&t| t == stream_type) =>
+                {
+                    self.streams.insert(pid, stream_type);
+                }
+                // Only the first stream of each kind goes in the MP4
+                STREAM_TYPE_H264 | STREAM_TYPE_AAC => {}
+                other if !self.skipped.contains(&other) => {
+                    self.skipped.push(other);
+                    debug!("Leaving out stream type {other:#04x}, which can't be remuxed");
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The body of a PSI section (after its 8-byte header, before the CRC)
+fn section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let table = payload.get(1 + pointer..)?;
+    if table.len() < 3 {
+        return None;
+    }
+    let length = (u16::from_be_bytes([table[1], table[2]]) & 0x0FFF) as usize;
+    // The header after the length field is 5 bytes, the CRC 4
+    table.get(8..(3 + length).checked_sub(4)?)
+}
+
+/// PID of the first program's PMT
+fn parse_pat(payload: &[u8]) -> Option<u16> {
+    section(payload)?.chunks_exact(4).find_map(|entry| {
+        let program = u16::from_be_bytes([entry[0], entry[1]]);
+        (program != 0).then(|| u16::from_be_bytes([entry[2], entry[3]]) & 0x1FFF)
+    })
+}
+
+fn parse_timestamp(bytes: &[u8]) -> i64 {
+    (((bytes[0] as i64 >> 1) & 0x07) << 30)
+        | ((bytes[1] as i64) << 22)
+        | ((bytes[2] as i64 >> 1) << 15)
+        | ((bytes[3] as i64) << 7)
+        | (bytes[4] as i64 >> 1)
+}
+
+fn parse_pes(stream_type: u8, data: &[u8]) -> Option<Pes> {
+    if data.len() < 9 || data[..3] != [0, 0, 1] {
+        return None;
+    }
+    let flags = data[7] >> 6;
+    let header_len = data[8] as usize;
+    let header = data.get(9..9 + header_len)?;
+    let pts = (flags & 0x2 != 0 && header.len() >= 5).then(|| parse_timestamp(&header[..5]));
+    let dts = (flags == 0x3 && header.len() >= 10).then(|| parse_timestamp(&header[5..10]));
+    Some(Pes {
+        stream_type,
+        pts,
+        dts,
+        data: data[9 + header_len..].to_vec(),
+    })
+}
+
+/// The NAL units in an Annex B byte stream, without their start codes
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let mut end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+            // A four-byte start code leaves a zero behind
+            while end > start && data[end - 1] == 0 {
+                end -= 1;
+            }
+            &data[start..end]
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// The ADTS frames in an AAC PES packet, as (config, raw frame)
+fn adts_frames(mut data: &[u8]) -> Vec<(AdtsConfig, &[u8])> {
+    let mut frames = Vec::new();
+    while data.len() >= 7 && data[0] == 0xFF && data[1] & 0xF0 == 0xF0 {
+        let header_len = if data[1] & 0x01 != 0 { 7 } else { 9 };
+        let frame_len =
+            ((data[3] as usize & 0x03) << 11) | ((data[4] as usize) << 3) | (data[5] as usize >> 5);
+        if frame_len < header_len || frame_len > data.len() {
+            break;
+        }
+        let config = AdtsConfig {
+            object_type: (data[2] >> 6) + 1,
+            freq_index: (data[2] >> 2) & 0x0F,
+            channels: ((data[2] & 0x01) << 2) | (data[3] >> 6),
+        };
+        frames.push((config, &data[header_len..frame_len]));
+        data = &data[frame_len..];
+    }
+    frames
+}
+
+/// Read TS packets from `path`, one PES packet at a time. `f` returns
+/// false to stop early.
+fn demux(path: &Path, mut f: impl FnMut(Pes) -> Result<bool, Error>) -> Result<(), Error> {
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(path)?);
+    let mut demuxer = Demuxer::default();
+    let mut packet = [0u8; TS_PACKET_SIZE];
+    loop {
+        match reader.read_exact(&mut packet) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(pes) = demuxer.push(&packet) {
+            if !f(pes)? {
+                return Ok(());
+            }
+        }
+    }
+    for pes in demuxer.flush() {
+        if !f(pes)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// First pass: find the parameter sets and audio format the tracks need
+fn probe(ts: &Path) -> Result<Streams, Error> {
+    let mut sps = None;
+    let mut pps = None;
+    let mut audio = None;
+    // Video seen since the parameter sets, while waiting for audio
+    let mut video_after = 0;
+    demux(ts, |pes| {
+        match pes.stream_type {
+            STREAM_TYPE_H264 => {
+                for nal in nal_units(&pes.data) {
+                    match nal[0] & 0x1F {
+                        7 if sps.is_none() => sps = Some(nal.to_vec()),
+                        8 if pps.is_none() => pps = Some(nal.to_vec()),
+                        _ => {}
+                    }
+                }
+                if sps.is_some() && pps.is_some() {
+                    video_after += 1;
+                }
+            }
+            _ => {
+                if audio.is_none() {
+                    audio = adts_frames(&pes.data).first().map(|(config, _)| *config);
+                }
+            }
+        }
+        // Audio can start a little after the video; give up on it after
+        // about ten seconds of frames
+        Ok(sps.is_none() || pps.is_none() || (audio.is_none() && video_after < 300))
+    })?;
+    let (Some(sps), Some(pps)) = (sps, pps) else {
+        return Err(Error::parse("no H.264 video with SPS and PPS found"));
+    };
+    let (width, height) = sps_dimensions(&sps)
+        .ok_or_else(|| Error::parse("can't read the video size from the SPS"))?;
+    Ok(Streams {
+        sps,
+        pps,
+        width,
+        height,
+        audio,
+    })
+}
+
+/// A sample held back until the next one gives its duration
+struct Held {
+    time: i64,
+    offset: i32,
+    is_sync: bool,
+    data: Vec<u8>,
+}
+
+/// Keeps a track's timestamps increasing across 33-bit wraps
+#[derive(Default)]
+struct Unwrap {
+    last: Option<i64>,
+    base: i64,
+}
+
+impl Unwrap {
+    fn unwrap(&mut self, ts: i64) -> i64 {
+        if let Some(last) = self.last {
+            if ts + self.base < last - PTS_WRAP / 2 {
+                self.base += PTS_WRAP;
+            }
+        }
+        let unwrapped = ts + self.base;
+        self.last = Some(unwrapped);
+        unwrapped
+    }
+}
+
+/// Second pass: write every sample into an MP4 with the index at the end
+fn write_mp4(ts: &Path, out: &Path, streams: &Streams) -> Result<(), Error> {
+    let config = Mp4Config {
+        major_brand: str::parse("isom").unwrap(),
+        minor_version: 512,
+        compatible_brands: vec![
+            str::parse("isom").unwrap(),
+            str::parse("iso2").unwrap(),
+            str::parse("avc1").unwrap(),
+            str::parse("mp41").unwrap(),
+        ],
+        timescale: 1000,
+    };
+    let mut writer = Mp4Writer::write_start(BufWriter::new(File::create(out)?), &config)?;
+    writer.add_track(&TrackConfig {
+        track_type: mp4::TrackType::Video,
+        timescale: TIMESCALE,
+        language: "und".to_string(),
+        media_conf: MediaConfig::AvcConfig(AvcConfig {
+            width: streams.width,
+            height: streams.height,
+            seq_param_set: streams.sps.clone(),
+            pic_param_set: streams.pps.clone(),
+        }),
+    })?;
+    if let Some(audio) = streams.audio {
+        writer.add_track(&TrackConfig {
+            track_type: mp4::TrackType::Audio,
+            timescale: audio.sample_rate(),
+            language: "und".to_string(),
+            media_conf: MediaConfig::AacConfig(AacConfig {
+                bitrate: 128000,
+                profile: mp4::AudioObjectType::try_from(audio.object_type)?,
+                freq_index: mp4::SampleFreqIndex::try_from(audio.freq_index)?,
+                chan_conf: mp4::ChannelConfig::try_from(audio.channels)?,
+            }),
+        })?;
+    }
+
+    let mut video_clock = Unwrap::default();
+    let mut audio_clock = Unwrap::default();
+    // The MP4 starts at the first video frame's decode time
+    let mut start: Option<i64> = None;
+    let mut held: Option<Held> = None;
+    let mut last_duration: u32 = 3000;
+    let mut audio_started = false;
+    let mut audio_frames: u64 = 0;
+
+    demux(ts, |pes| {
+        match pes.stream_type {
+            STREAM_TYPE_H264 => {
+                let Some(pts) = pes.pts else {
+                    return Ok(true);
+                };
+                let pts = video_clock.unwrap(pts);
+                let dts = pes
+                    .dts
+                    .map_or(pts, |dts| pts - (pts - dts).rem_euclid(PTS_WRAP));
+                let mut data = Vec::with_capacity(pes.data.len() + 16);
+                let mut is_sync = false;
+                for nal in nal_units(&pes.data) {
+                    match nal[0] & 0x1F {
+                        // Parameter sets are in the sample entry; access
+                        // unit delimiters aren't used in MP4
+                        7..=9 => continue,
+                        5 => is_sync = true,
+                        _ => {}
+                    }
+                    data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    data.extend_from_slice(nal);
+                }
+                // Start on a keyframe, so the file plays from the top
+                if data.is_empty() || (start.is_none() && !is_sync) {
+                    return Ok(true);
+                }
+                let start = *start.get_or_insert(dts);
+                let time = dts - start;
+                let sample = Held {
+                    time,
+                    offset: (pts - dts) as i32,
+                    is_sync,
+                    data,
+                };
+                if let Some(prev) = held.replace(sample) {
+                    last_duration = u32::try_from(time - prev.time)
+                        .ok()
+                        .filter(|&d| d > 0)
+                        .unwrap_or(last_duration);
+                    write_video(&mut writer, prev, last_duration)?;
+                }
+            }
+            _ => {
+                let Some(config) = streams.audio else {
+                    return Ok(true);
+                };
+                let Some(start) = start else {
+                    return Ok(true);
+                };
+                // Leave out audio from before the first video frame, so
+                // the two tracks start together
+                if !audio_started {
+                    let Some(pts) = pes.pts else {
+                        return Ok(true);
+                    };
+                    if audio_clock.unwrap(pts) < start {
+                        return Ok(true);
+                    }
+                    audio_started = true;
+                }
+                for (frame_config, frame) in adts_frames(&pes.data) {
+                    if frame_config != config {
+                        continue;
+                    }
+                    writer.write_sample(
+                        AUDIO_TRACK,
+                        &Mp4Sample {
+                            start_time: audio_frames * 1024,
+                            duration: 1024,
+                            rendering_offset: 0,
+                            is_sync: true,
+                            bytes: mp4::Bytes::copy_from_slice(frame),
+                        },
+                    )?;
+                    audio_frames += 1;
+                }
+            }
+        }
+        Ok(true)
+    })?;
+    if let Some(last) = held.take() {
+        write_video(&mut writer, last, last_duration)?;
+    }
+    writer.write_end()?;
+    writer.into_writer().flush()?;
+    Ok(())
+}
+
+fn write_video(
+    writer: &mut Mp4Writer<BufWriter<File>>,
+    sample: Held,
+    duration: u32,
+) -> Result<(), Error> {
+    writer.write_sample(
+        VIDEO_TRACK,
+        &Mp4Sample {
+            start_time: sample.time as u64,
+            duration,
+            rendering_offset: sample.offset,
+            is_sync: sample.is_sync,
+            bytes: mp4::Bytes::from(sample.data),
+        },
+    )?;
+    Ok(())
+}
+
+/// A top-level box: where it starts and how long it is, header included
+struct BoxRange {
+    kind: [u8; 4],
+    start: u64,
+    size: u64,
+}
+
+fn top_level_boxes(file: &mut File) -> Result<Vec<BoxRange>, Error> {
+    let len = file.metadata()?.len();
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])?;
+        let mut size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        if size == 1 {
+            file.read_exact(&mut header[8..])?;
+            size = u64::from_be_bytes(header[8..].try_into().unwrap());
+        } else if size == 0 {
+            size = len - pos;
+        }
+        if size < 8 || pos + size > len {
+            return Err(Error::parse("damaged MP4 box structure"));
+        }
+        boxes.push(BoxRange {
+            kind: header[4..8].try_into().unwrap(),
+            start: pos,
+            size,
+        });
+        pos += size;
+    }
+    Ok(boxes)
+}
+
+/// Add `shift` to every chunk offset (stco, co64) in a moov box
+fn shift_chunk_offsets(data: &mut [u8], shift: u64) -> Result<(), Error> {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        if size < 8 || pos + size > data.len() {
+            return Err(Error::parse("damaged moov box"));
+        }
+        let kind: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+        let body = &mut data[pos + 8..pos + size];
+        match &kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => shift_chunk_offsets(body, shift)?,
+            b"stco" => {
+                for entry in body.get_mut(8..).unwrap_or_default().chunks_exact_mut(4) {
+                    let offset = u32::from_be_bytes(entry.try_into().unwrap()) as u64 + shift;
+                    let offset = u32::try_from(offset).map_err(|_| "file too big for stco")?;
+                    entry.copy_from_slice(&offset.to_be_bytes());
+                }
+            }
+            b"co64" => {
+                for entry in body.get_mut(8..).unwrap_or_default().chunks_exact_mut(8) {
+                    let offset = u64::from_be_bytes(entry.try_into().unwrap()) + shift;
+                    entry.copy_from_slice(&offset.to_be_bytes());
+                }
+            }
+            _ => {}
+        }
+        pos += size;
+    }
+    Ok(())
+}
+
+/// Rewrite `input` to `output` with the moov box ahead of the media data,
+/// so a player can start before it has the whole file
+fn faststart(input: &Path, output: &Path) -> Result<(), Error> {
+    let mut file = File::open(input)?;
+    let boxes = top_level_boxes(&mut file)?;
+    let moov = boxes
+        .iter()
+        .find(|b| &b.kind == b"moov")
+        .ok_or_else(|| Error::parse("no moov box"))?;
+    let mdat = boxes
+        .iter()
+        .position(|b| &b.kind == b"mdat")
+        .ok_or_else(|| Error::parse("no mdat box"))?;
+
+    let mut moov_data = vec![0u8; moov.size as usize];
+    file.seek(SeekFrom::Start(moov.start))?;
+    file.read_exact(&mut moov_data)?;
+    // Everything from the mdat on moves back by the size of the moov
+    if moov.start > boxes[mdat].start {
+        shift_chunk_offsets(&mut moov_data[8..], moov.size)?;
+    }
+
+    let mut out = BufWriter::new(File::create(output)?);
+    for (i, b) in boxes.iter().enumerate() {
+        if &b.kind == b"moov" {
+            continue;
+        }
+        if i == mdat {
+            out.write_all(&moov_data)?;
+        }
+        file.seek(SeekFrom::Start(b.start))?;
+        std::io::copy(&mut (&mut file).take(b.size), &mut out)?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use crate::cli::CleanArgs;
+use crate::commands::format_bytes;
+use crate::error::StreamError;
+use crate::output::{FilenameTemplate, OutputFile};
+use crate::sidecar;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Limits on what an archive directory may hold. Unset limits don't apply.
+#[derive(Debug, Default, Clone)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_files: Option<usize>,
+    pub max_bytes: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_age.is_none() && self.max_files.is_none() && self.max_bytes.is_none()
+    }
+}
+
+/// A recording file found in the archive
+#[derive(Debug)]
+pub struct ArchiveFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// When it was finished, by its sidecar where it has one, otherwise
+    /// when it was last modified
+    pub modified: SystemTime,
+}
+
+/// Whether `path` is named like an RTSP recording (`20260202_143000.mp4`,
+/// or `20260202_143000-2.mp4` for a second file started in the same second)
+fn is_rtsp_filename(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|stem| match stem.split_once('-') {
+            Some((ts, n)) if n.parse::<u32>().is_ok() => ts,
+            _ => stem,
+        })
+        .is_some_and(|stem| chrono::NaiveDateTime::parse_from_str(stem, "%Y%m%d_%H%M%S").is_ok())
+}
+
+/// Extensions of the files written next to recordings (`.part` while
+/// writing, sidecars, thumbnails, RTSP metadata), which aren't recordings
+/// themselves
+const SIDE_EXTENSIONS: &[&str] = &["part", "json", "jsonl", "jpg", "tmp"];
+
+/// Whether a file with extension `extension` is written alongside
+/// recordings rather than being one
+pub fn is_side_extension(extension: &str) -> bool {
+    SIDE_EXTENSIONS
+        .iter()
+        .any(|side| side.eq_ignore_ascii_case(extension))
+}
+
+/// List the recording files in `dir`, newest first. With a
+/// --filename-template those are the names it could have given, in the
+/// subdirectories it puts them in; otherwise they're the default names
+/// (`2026_02_02-14_30_0.ts`) and RTSP ones (`20260202_143000.mp4`) in `dir`
+/// itself.
+pub fn scan(dir: &Path, template: Option<&FilenameTemplate>) -> std::io::Result<Vec<ArchiveFile>> {
+    let mut files = Vec::new();
+    let depth = template.map_or(0, FilenameTemplate::depth);
+    scan_dir(dir, "", depth, template, &mut files)?;
+    files.sort_by_key(|f| std::cmp::Reverse(f.modified));
+    Ok(files)
+}
+
+/// Add the recordings in `dir`, which is `prefix` under the output
+/// directory, and those up to `depth` directories below it to `files`
+fn scan_dir(
+    dir: &Path,
+    prefix: &str,
+    depth: usize,
+    template: Option<&FilenameTemplate>,
+    files: &mut Vec<ArchiveFile>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        if depth > 0 && entry.file_type()?.is_dir() {
+            if let Some(name) = name.to_str() {
+                let prefix = format!("{prefix}{name}/");
+                scan_dir(&path, &prefix, depth - 1, template, files)?;
+            }
+            continue;
+        }
+        let recording = match template {
+            Some(template) => name
+                .to_str()
+                .is_some_and(|name| template.matches(&format!("{prefix}{name}"))),
+            None => {
+                (OutputFile::parse_filename(&path).is_some() || is_rtsp_filename(&path))
+                    && !path
+                        .extension()
+                        .and_then(|ext| ext.to_str())
+                        .is_some_and(is_side_extension)
+            }
+        };
+        if !recording {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let modified = match sidecar::finished(&path) {
+            Some(end) => end,
+            None => metadata.modified()?,
+        };
+        files.push(ArchiveFile {
+            path,
+            size: metadata.len(),
+            modified,
+        });
+    }
+    Ok(())
+}
+
+/// Split `files` (newest first) into the ones to keep and the ones the
+/// policy expires. The newest file is always kept since it may still be
+/// recording.
+pub fn expired(
+    files: Vec<ArchiveFile>,
+    policy: &RetentionPolicy,
+    now: SystemTime,
+) -> Vec<ArchiveFile> {
+    let mut kept_bytes: u64 = 0;
+    let mut expired = Vec::new();
+    for (i, file) in files.into_iter().enumerate() {
+        if i == 0 {
+            kept_bytes += file.size;
+            continue;
+        }
+        let too_old = policy
+            .max_age
+            .is_some_and(|max| now.duration_since(file.modified).is_ok_and(|age| age > max));
+        let too_many = policy.max_files.is_some_and(|max| i >= max);
+        let too_big = policy
+            .max_bytes
+            .is_some_and(|max| kept_bytes + file.size > max);
+        if too_old || too_many || too_big {
+            expired.push(file);
+        } else {
+            kept_bytes += file.size;
+        }
+    }
+    expired
+}
+
+/// Delete a recording, along with its --sidecars metadata, its --snapshot
+/// image and its --rtsp-metadata stream if it has them
+pub fn remove(path: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(path)?;
+    let companions = [
+        sidecar::path_for(path),
+        path.with_extension("jpg"),
+        path.with_extension("metadata.jsonl"),
+    ];
+    for companion in companions {
+        match std::fs::remove_file(companion) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The `clean` command: delete recordings in a directory that fall outside
+/// the retention policy
+pub fn run(args: CleanArgs) -> Result<(), StreamError> {
+    let policy = RetentionPolicy {
+        max_age: args.keep_hours.map(|h| Duration::from_secs(h * 3600)),
+        max_files: args.keep_files,
+        max_bytes: args.keep_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+    };
+    if policy.is_empty() {
+        return Err(StreamError::config(
+            "Nothing to do: set at least one of --keep-hours, --keep-files, --keep-mb",
+        ));
+    }
+
+    let files = scan(&args.dir, args.filename_template.as_ref())?;
+    let expired = expired(files, &policy, SystemTime::now());
+    let mut removed = 0;
+    let mut removed_bytes: u64 = 0;
+    for file in &expired {
+        if args.dry_run {
+            info!(
+                "Would remove {} ({})",
+                file.path.display(),
+                format_bytes(file.size)
+            );
+        } else {
+            match remove(&file.path) {
+                Ok(()) => {
+                    info!(
+                        "Removed {} ({})",
+                        file.path.display(),
+                        format_bytes(file.size)
+                    );
+                    removed += 1;
+                    removed_bytes += file.size;
+                }
+                Err(e) => warn!("Failed to remove {}: {e}", file.path.display()),
+            }
+        }
+    }
+
+    if args.dry_run {
+        let total: u64 = expired.iter().map(|f| f.size).sum();
+        info!(
+            "Dry run: {} files ({}) would be removed",
+            expired.len(),
+            format_bytes(total)
+        );
+    } else {
+        info!("Removed {removed} files ({})", format_bytes(removed_bytes));
+    }
+    Ok(())
+}
@@ -1,14 +1,30 @@
+use crate::commands;
+use crate::disk::DiskGuard;
+use crate::error::StreamError as Error;
+use crate::fmp4::{FragmentedWriter, TrackInfo, TrackMedia};
+use crate::h264;
+use crate::http_client::{fetch_with_retry, percent_decode, HttpClient};
+use crate::mkv::MatroskaWriter;
+use crate::output::{self, FilenameTemplate};
+use crate::thumbnails;
+use crate::tls;
 use chrono::Local;
 use futures::StreamExt;
 use mp4::{AacConfig, AvcConfig, MediaConfig, Mp4Config, Mp4Sample, Mp4Writer, TrackConfig};
 use retina::client::{SessionGroup, SetupOptions};
 use retina::codec::{CodecItem, ParametersRef};
 use std::fs::File;
-use std::io::BufWriter;
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+pub use crate::cli::{Container, LowDiskAction};
+pub use crate::commands::{Hooks, SharedHooks};
+pub use crate::disk::LowDiskPolicy;
+pub use crate::stats::RecordingStats;
 
 pub struct RtspConfig {
     pub url: String,
@@ -16,9 +32,226 @@ pub struct RtspConfig {
     pub password: Option<String>,
     pub output_dir: PathBuf,
     pub segment_secs: u64,
-    pub on_segment: Option<String>,
-    pub verbose: bool,
-    pub progress: bool,
+    /// Also rotate once a file reaches this size (--segment-bytes)
+    pub segment_bytes: Option<u64>,
+    /// --min-free-mb and what to do below it
+    pub low_disk: Option<LowDiskPolicy>,
+    /// --filename-template; files are named by their start time otherwise
+    pub filename_template: Option<FilenameTemplate>,
+    /// MP4 or Matroska (--container)
+    pub container: Container,
+    /// Write fragmented MP4 (--fragmented-mp4)
+    pub fragmented: bool,
+    /// Connects to rtsps:// cameras
+    pub tls: Option<tls::HttpsConnector>,
+    /// Save an image beside each completed file (--snapshot)
+    pub snapshot: Option<Arc<Snapshot>>,
+    /// Record the ONVIF metadata stream too (--rtsp-metadata)
+    pub metadata: bool,
+    pub hooks: SharedHooks,
+}
+
+/// Where the --snapshot image of a file comes from
+pub enum Snapshot {
+    /// Decode the file's first keyframe with this ffmpeg
+    Decode(PathBuf),
+    /// Fetch the camera's snapshot URL (--snapshot-url)
+    Fetch {
+        client: Box<HttpClient>,
+        url: String,
+        timeout: Duration,
+        retries: u32,
+        retry_delay_ms: u64,
+    },
+}
+
+impl Snapshot {
+    /// Save the image for a file to `path`; `keyframe` is the file's first
+    /// keyframe as an Annex B stream
+    async fn save(&self, keyframe: Option<&[u8]>, path: &Path) -> Result<(), Error> {
+        match self {
+            Snapshot::Decode(ffmpeg) => {
+                let keyframe = keyframe.ok_or("the file doesn't start with a keyframe")?;
+                thumbnails::extract_frame(ffmpeg, Some("h264"), keyframe, path).await
+            }
+            Snapshot::Fetch {
+                client,
+                url,
+                timeout,
+                retries,
+                retry_delay_ms,
+            } => {
+                let image =
+                    fetch_with_retry(client, url, *timeout, *retries, *retry_delay_ms).await?;
+                // Under a temporary name first, so a UI never shows half
+                // an image
+                let part = output::part_path(path);
+                std::fs::write(&part, image)?;
+                std::fs::rename(&part, path)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A keyframe as an Annex B stream that ffmpeg can decode on its own: the
+/// parameter sets, then the frame's NAL units, each behind a start code
+fn annex_b(params: &VideoParams, frame: &[u8]) -> Vec<u8> {
+    const START: [u8; 4] = [0, 0, 0, 1];
+    let mut out = Vec::with_capacity(frame.len() + params.sps.len() + params.pps.len() + 8);
+    for nal in [&params.sps, &params.pps] {
+        out.extend_from_slice(&START);
+        out.extend_from_slice(nal);
+    }
+    let mut rest = frame;
+    while rest.len() >= 4 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let Some(nal) = rest.get(4..4 + len) else {
+            break;
+        };
+        out.extend_from_slice(&START);
+        out.extend_from_slice(nal);
+        rest = &rest[4 + len..];
+    }
+    out
+}
+
+/// --rtsp-metadata's sidecar for one file, `NAME.metadata.jsonl`: a line of
+/// JSON for each metadata document, with the time it arrived both on the
+/// clock and from the start of the file
+struct MetadataSidecar {
+    file: BufWriter<File>,
+    path: PathBuf,
+    /// The metadata is compressed (vnd.onvif.metadata.gzip)
+    gzip: bool,
+}
+
+impl MetadataSidecar {
+    /// Start the sidecar for the file at `media_path`, as a `.part` until
+    /// the file is done
+    fn create(media_path: &Path, gzip: bool) -> Result<Self, Error> {
+        let path = media_path.with_extension("metadata.jsonl");
+        let file = BufWriter::new(File::create(output::part_path(&path))?);
+        Ok(Self { file, path, gzip })
+    }
+
+    fn write(&mut self, offset: Duration, payload: &[u8]) -> Result<(), Error> {
+        let xml = if self.gzip {
+            let mut xml = Vec::new();
+            let decoded =
+                std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(payload), &mut xml);
+            if let Err(e) = decoded {
+                debug!("Skipping ONVIF metadata that doesn't decompress: {e}");
+                return Ok(());
+            }
+            xml
+        } else {
+            payload.to_vec()
+        };
+        let line = serde_json::json!({
+            "time": Local::now().to_rfc3339(),
+            "offset": (offset.as_secs_f64() * 1000.0).round() / 1000.0,
+            "xml": String::from_utf8_lossy(&xml),
+        });
+        writeln!(self.file, "{line}")?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        std::fs::rename(output::part_path(&self.path), &self.path)?;
+        Ok(())
+    }
+}
+
+/// A file is complete: save its --snapshot image, then send the webhook
+/// event, call the callback and run --on-segment, which can then pick the
+/// image up
+fn segment_done(
+    config: &RtspConfig,
+    path: PathBuf,
+    keyframe: Option<Vec<u8>>,
+) -> tokio::task::JoinHandle<()> {
+    let snapshot = config.snapshot.clone();
+    let hook = Hooks::on_segment(&config.hooks);
+    let listeners = Hooks::segment_listeners(&config.hooks);
+    tokio::spawn(async move {
+        if let Some(snapshot) = snapshot {
+            let image = path.with_extension("jpg");
+            if let Err(e) = snapshot.save(keyframe.as_deref(), &image).await {
+                warn!(file = %image.display(), "Couldn't save the snapshot: {e}");
+            }
+        }
+        listeners.send(&path, None);
+        if let Some(hook) = hook {
+            commands::run_segment_command(&hook, &path, None).await;
+        }
+    })
+}
+
+/// retina only speaks plain RTSP, so an rtsps:// camera is reached through a
+/// relay on localhost that does the TLS. Dropping it stops the relay.
+struct TlsRelay(tokio::task::JoinHandle<()>);
+
+impl Drop for TlsRelay {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Start a relay to the rtsps:// camera in `url` and point the URL at it
+async fn tls_relay(url: &mut url::Url, connector: tls::HttpsConnector) -> Result<TlsRelay, Error> {
+    use tower_service::Service;
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::config("RTSPS URL without a host"))?;
+    // 322 is RTSPS's registered port
+    let target: hyper::Uri = format!("https://{host}:{}/", url.port().unwrap_or(322)).parse()?;
+    let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+    let port = listener.local_addr()?.port();
+    let task = tokio::spawn(async move {
+        while let Ok((mut local, _)) = listener.accept().await {
+            let mut connector = connector.clone();
+            let target = target.clone();
+            tokio::spawn(async move {
+                let remote = match std::future::poll_fn(|cx| connector.poll_ready(cx)).await {
+                    Ok(()) => connector.call(target.clone()).await,
+                    Err(e) => Err(e),
+                };
+                match remote {
+                    Ok(remote) => {
+                        let mut remote = hyper_util::rt::TokioIo::new(remote);
+                        let _ = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+                    }
+                    Err(e) => warn!("RTSPS connection to {target} failed: {e}"),
+                }
+            });
+        }
+    });
+    // Both are non-special schemes, so these can't fail
+    let _ = url.set_scheme("rtsp");
+    let _ = url.set_host(Some("127.0.0.1"));
+    let _ = url.set_port(Some(port));
+    Ok(TlsRelay(task))
+}
+
+/// Take `user:pass@` out of an RTSP URL, which retina won't accept, and
+/// return it as credentials. Both parts are percent-decoded, so a password
+/// with `@` or `:` in it can be given as `%40` or `%3A`.
+fn split_credentials(url: &str) -> Result<(url::Url, Option<retina::client::Credentials>), Error> {
+    let mut url = url::Url::parse(url)?;
+    if url.username().is_empty() && url.password().is_none() {
+        return Ok((url, None));
+    }
+    let creds = retina::client::Credentials {
+        username: percent_decode(url.username()),
+        password: percent_decode(url.password().unwrap_or("")),
+    };
+    // Clearing can only fail for URLs that can't have credentials anyway
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    Ok((url, Some(creds)))
 }
 
 /// Extract SPS and PPS from AVCC extra_data
@@ -61,17 +294,322 @@ fn parse_avcc(extra: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
     }
 }
 
+/// The H.264 setup the video track is written with
+struct VideoParams {
+    width: u16,
+    height: u16,
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+}
+
+/// SPS and PPS sent in-band in a frame (length-prefixed NAL units, as
+/// retina hands them over), with the picture size read from the SPS
+fn in_band_params(data: &[u8]) -> Option<VideoParams> {
+    let (mut sps, mut pps) = (None, None);
+    let mut rest = data;
+    while let Some((len, tail)) = rest.split_first_chunk::<4>() {
+        let len = u32::from_be_bytes(*len) as usize;
+        let nal = tail.get(..len)?;
+        match nal.first().map(|header| header & 0x1F) {
+            Some(7) => sps = Some(nal),
+            Some(8) => pps = Some(nal),
+            _ => {}
+        }
+        rest = &tail[len..];
+    }
+    let (sps, pps) = (sps?, pps?);
+    let (width, height) = h264::sps_dimensions(sps)?;
+    Some(VideoParams {
+        width,
+        height,
+        sps: sps.to_vec(),
+        pps: pps.to_vec(),
+    })
+}
+
+/// The AAC sample rates, by the frequency index in an AudioSpecificConfig
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// How the camera's AAC is set up
+#[derive(Clone, Copy)]
+struct AacParams {
+    /// The RTP clock rate, which for AAC is the sample rate
+    clock_rate: u32,
+    /// MPEG-4 audio object type, 2 for AAC-LC
+    object_type: u8,
+    freq_index: u8,
+    channels: u8,
+}
+
+impl AacParams {
+    /// Read the AudioSpecificConfig from the SDP (`config=` in the fmtp
+    /// line), going by the clock rate and channel count where it's missing
+    /// or uses forms the files can't describe
+    fn new(config: &[u8], clock_rate: u32, channels: Option<u16>) -> Self {
+        let rate_index = AAC_SAMPLE_RATES
+            .iter()
+            .position(|&rate| rate == clock_rate)
+            .unwrap_or(3) as u8;
+        let mut params = Self {
+            clock_rate,
+            object_type: 2,
+            freq_index: rate_index,
+            channels: channels.map_or(1, |c| c.clamp(1, 7) as u8),
+        };
+        if let [first, second, ..] = *config {
+            let object_type = first >> 3;
+            let freq_index = (first & 0x07) << 1 | second >> 7;
+            let channels = (second >> 3) & 0x0F;
+            // 31 escapes to a longer object type, 15 to an explicit rate,
+            // and channel configuration 0 is described in-band
+            if object_type != 0 && object_type != 31 {
+                params.object_type = object_type;
+            }
+            if (freq_index as usize) < AAC_SAMPLE_RATES.len() {
+                params.freq_index = freq_index;
+            }
+            if (1..=7).contains(&channels) {
+                params.channels = channels;
+            }
+        }
+        params
+    }
+}
+
+/// The tracks for a fragmented MP4 or Matroska file
+fn track_infos(
+    video_clock: u32,
+    video: &VideoParams,
+    audio_params: Option<AacParams>,
+) -> Vec<TrackInfo> {
+    let mut tracks = vec![TrackInfo {
+        id: 1,
+        timescale: video_clock,
+        media: TrackMedia::Avc {
+            width: video.width,
+            height: video.height,
+            sps: video.sps.clone(),
+            pps: video.pps.clone(),
+        },
+    }];
+    if let Some(aac) = audio_params {
+        tracks.push(TrackInfo {
+            id: 2,
+            timescale: aac.clock_rate,
+            media: TrackMedia::Aac {
+                object_type: aac.object_type,
+                freq_index: aac.freq_index,
+                channels: aac.channels,
+            },
+        });
+    }
+    tracks
+}
+
+/// Most frames held waiting for a keyframe, so a camera that stops sending
+/// them can't use up the memory
+const MAX_PENDING: usize = 600;
+
+/// A frame waiting for the next one on its track
+struct PendingSample {
+    /// RTP timestamp, in the track's clock rate
+    timestamp: i64,
+    is_sync: bool,
+    bytes: mp4::Bytes,
+    /// Duration to use when there's no next timestamp to go by
+    nominal: u32,
+}
+
+/// One track of the file being written. A frame is held back until the
+/// next one arrives, since its duration is the gap to the next RTP
+/// timestamp; that follows the camera's real frame rate, variable or not,
+/// instead of assuming one.
+///
+/// RTP timestamps are presentation times, and with B-frames the frames
+/// arrive in decode order, out of order by those. Sorting a whole group of
+/// pictures gives the decode times: the same timestamps, in order. So the
+/// frames are held until the next keyframe, for the file's first group and
+/// after that as long as the camera turns out to reorder frames.
+struct Track {
+    id: u32,
+    pending: Vec<PendingSample>,
+    /// Where the next sample starts, in the track's timescale
+    time: u64,
+    /// The last duration written, for a frame with nothing to go by
+    last_duration: u32,
+    /// How far presentation lags decoding, so that no composition offset
+    /// is negative; taken from the file's first group of pictures, and 0
+    /// for a camera without B-frames
+    reorder_delay: Option<i64>,
+}
+
+impl Track {
+    fn new(id: u32, nominal: u32) -> Self {
+        Self {
+            id,
+            pending: Vec::new(),
+            time: 0,
+            last_duration: nominal,
+            reorder_delay: None,
+        }
+    }
+
+    /// Hold a frame, writing the ones before it once their durations are
+    /// known. Without `nominal`, the last duration stands in for the
+    /// frame's own if it turns out to be the last.
+    fn push(
+        &mut self,
+        writer: &mut MediaWriter,
+        timestamp: i64,
+        is_sync: bool,
+        data: &[u8],
+        nominal: Option<u32>,
+    ) -> Result<(), Error> {
+        if is_sync || self.reorder_delay == Some(0) || self.pending.len() >= MAX_PENDING {
+            self.flush(writer, Some(timestamp))?;
+        }
+        self.pending.push(PendingSample {
+            timestamp,
+            is_sync,
+            bytes: mp4::Bytes::copy_from_slice(data),
+            nominal: nominal.unwrap_or(self.last_duration),
+        });
+        Ok(())
+    }
+
+    /// Write the held frames, the last lasting until `next` if that's later
+    fn flush(&mut self, writer: &mut MediaWriter, next: Option<i64>) -> Result<(), Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let mut decode: Vec<i64> = pending.iter().map(|s| s.timestamp).collect();
+        decode.sort_unstable();
+        let delay = *self.reorder_delay.get_or_insert_with(|| {
+            let lag = pending.iter().zip(&decode).map(|(s, d)| d - s.timestamp);
+            lag.max().unwrap_or(0).max(0)
+        });
+        for (i, (pending, &dts)) in pending.into_iter().zip(&decode).enumerate() {
+            // Timestamps that stand still (lost packets or a confused
+            // camera) can't give a duration
+            let duration = decode
+                .get(i + 1)
+                .copied()
+                .or(next)
+                .and_then(|next| u32::try_from(next - dts).ok())
+                .filter(|&d| d > 0)
+                .unwrap_or(pending.nominal);
+            // A later group reordered more than the first is shown a
+            // little early rather than given a negative offset
+            let offset = i32::try_from(pending.timestamp - dts + delay).unwrap_or(0);
+            let sample = Mp4Sample {
+                start_time: self.time,
+                duration,
+                rendering_offset: offset.max(0),
+                is_sync: pending.is_sync,
+                bytes: pending.bytes,
+            };
+            writer.write_sample(self.id, &sample)?;
+            self.time += u64::from(duration);
+            self.last_duration = duration;
+        }
+        Ok(())
+    }
+}
+
+/// The file being written: a regular MP4, which only plays once its index
+/// is written at the end, a fragmented one (--fragmented-mp4) that plays up
+/// to the last fragment written, or Matroska (--container mkv), which does
+/// the same with clusters
+enum MediaWriter {
+    Indexed(Mp4Writer<BufWriter<File>>),
+    Fragmented(FragmentedWriter<BufWriter<File>>),
+    Matroska(MatroskaWriter<BufWriter<File>>),
+}
+
+impl MediaWriter {
+    fn write_sample(&mut self, track_id: u32, sample: &Mp4Sample) -> Result<(), Error> {
+        match self {
+            MediaWriter::Indexed(writer) => writer.write_sample(track_id, sample)?,
+            MediaWriter::Fragmented(writer) => writer.write_sample(track_id, sample),
+            MediaWriter::Matroska(writer) => writer.write_sample(track_id, sample)?,
+        }
+        Ok(())
+    }
+
+    /// Write out the samples so far as a fragment or cluster, for the
+    /// formats that have them
+    fn flush_fragment(&mut self) -> std::io::Result<()> {
+        match self {
+            MediaWriter::Indexed(_) => Ok(()),
+            MediaWriter::Fragmented(writer) => writer.flush_fragment(),
+            MediaWriter::Matroska(writer) => writer.flush_cluster(),
+        }
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        let mut file = match self {
+            MediaWriter::Indexed(mut writer) => {
+                writer.write_end()?;
+                writer.into_writer()
+            }
+            MediaWriter::Fragmented(writer) => writer.finish()?,
+            MediaWriter::Matroska(writer) => writer.finish()?,
+        };
+        file.flush()?;
+        Ok(())
+    }
+}
+
 struct Segment {
-    writer: Mp4Writer<BufWriter<File>>,
+    writer: MediaWriter,
     path: PathBuf,
-    has_audio: bool,
+    video: Track,
+    audio: Option<Track>,
+    /// Sample bytes written so far
+    bytes: u64,
+    /// The first keyframe, for --snapshot to decode
+    keyframe: Option<Vec<u8>>,
+    metadata: Option<MetadataSidecar>,
+}
+
+impl Segment {
+    /// Write the held frames and the index, move the file from `.part` to
+    /// its final name, returning that. `next_video` is the timestamp of the
+    /// keyframe starting the next file, which the last frame lasts until.
+    fn finish(self, next_video: Option<i64>) -> Result<PathBuf, Error> {
+        let Segment {
+            mut writer,
+            path,
+            mut video,
+            audio,
+            metadata,
+            ..
+        } = self;
+        video.flush(&mut writer, next_video)?;
+        if let Some(mut audio) = audio {
+            audio.flush(&mut writer, None)?;
+        }
+        writer.finish()?;
+        std::fs::rename(output::part_path(&path), &path)?;
+        if let Some(metadata) = metadata {
+            metadata.finish()?;
+        }
+        Ok(path)
+    }
 }
 
 pub async fn handle_rtsp_stream(
     config: RtspConfig,
     shutdown: Arc<AtomicBool>,
-) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    rotate: Arc<AtomicBool>,
+    stats: Arc<RecordingStats>,
+) -> Result<u64, Error> {
+    let (mut url, url_creds) = split_credentials(&config.url)?;
     let creds = match (&config.username, &config.password) {
+        _ if url_creds.is_some() => url_creds,
         (Some(u), Some(p)) => Some(retina::client::Credentials {
             username: u.clone(),
             password: p.clone(),
@@ -79,9 +617,14 @@ pub async fn handle_rtsp_stream(
         _ => None,
     };
 
+    let _relay = match config.tls.clone() {
+        Some(connector) if url.scheme() == "rtsps" => Some(tls_relay(&mut url, connector).await?),
+        _ => None,
+    };
+
     let session_group = Arc::new(SessionGroup::default());
     let mut session = retina::client::Session::describe(
-        url::Url::parse(&config.url)?,
+        url,
         retina::client::SessionOptions::default()
             .creds(creds)
             .session_group(session_group)
@@ -89,63 +632,123 @@ pub async fn handle_rtsp_stream(
     )
     .await?;
 
-    if config.verbose {
-        eprintln!("RTSP session established");
-    }
+    debug!("RTSP session established");
 
     // Find video stream
     let video_idx = session
         .streams()
         .iter()
         .position(|s| s.media() == "video")
-        .ok_or("No video stream found")?;
+        .ok_or_else(|| Error::rtsp("No video stream found"))?;
 
     session.setup(video_idx, SetupOptions::default()).await?;
 
-    // Find and setup audio stream (optional)
+    // Find and setup audio stream (optional). Only AAC fits the audio track,
+    // so other codecs, like the G.711 and G.726 many cameras send, aren't
+    // set up at all rather than written into it as garbage.
     let audio_idx = session
         .streams()
         .iter()
-        .position(|s| s.media() == "audio");
+        .position(|s| s.media() == "audio")
+        .filter(|&idx| {
+            let stream = &session.streams()[idx];
+            let aac = stream.encoding_name().eq_ignore_ascii_case("mpeg4-generic");
+            if !aac {
+                warn!(
+                    codec = stream.encoding_name(),
+                    "The camera's audio isn't AAC, recording video only"
+                );
+            }
+            aac
+        });
 
     if let Some(idx) = audio_idx {
         let _ = session.setup(idx, SetupOptions::default()).await;
     }
 
-    // Get video params
-    let (width, height, sps, pps) = session.streams()[video_idx]
-        .parameters()
-        .and_then(|p| {
-            if let ParametersRef::Video(vp) = p {
-                let (w, h) = vp.pixel_dimensions();
-                let extra = vp.extra_data();
-                if let Some((sps, pps)) = parse_avcc(extra) {
-                    Some((w as u16, h as u16, sps, pps))
-                } else {
-                    Some((w as u16, h as u16, Vec::new(), Vec::new()))
-                }
-            } else {
-                None
+    // With --rtsp-metadata, the ONVIF metadata stream, as plain or gzipped
+    // XML. The EXI-compressed forms would need an EXI decoder.
+    let metadata = config
+        .metadata
+        .then(|| {
+            let found = session.streams().iter().position(|s| {
+                s.media() == "application"
+                    && s.encoding_name()
+                        .to_ascii_lowercase()
+                        .starts_with("vnd.onvif.metadata")
+            });
+            if found.is_none() {
+                warn!("The camera has no ONVIF metadata stream");
             }
+            found
         })
-        .unwrap_or((1920, 1080, Vec::new(), Vec::new()));
+        .flatten()
+        .and_then(|idx| {
+            let encoding = session.streams()[idx].encoding_name().to_ascii_lowercase();
+            match encoding.as_str() {
+                "vnd.onvif.metadata" => Some((idx, false)),
+                "vnd.onvif.metadata.gzip" => Some((idx, true)),
+                _ => {
+                    warn!(
+                        encoding,
+                        "Can't read this ONVIF metadata encoding, not recording it"
+                    );
+                    None
+                }
+            }
+        });
+    if let Some((idx, _)) = metadata {
+        session.setup(idx, SetupOptions::default()).await?;
+    }
+
+    // Get video params. Without sprop-parameter-sets in the SDP they're
+    // unknown until a keyframe brings them in-band.
+    let mut video_params = session.streams()[video_idx].parameters().and_then(|p| {
+        if let ParametersRef::Video(vp) = p {
+            let (w, h) = vp.pixel_dimensions();
+            let (sps, pps) = parse_avcc(vp.extra_data())?;
+            Some(VideoParams {
+                width: w as u16,
+                height: h as u16,
+                sps,
+                pps,
+            })
+        } else {
+            None
+        }
+    });
 
     // Get audio params (if audio stream exists)
-    let audio_params: Option<u32> = audio_idx.and_then(|idx| {
-        session.streams()[idx].parameters().and_then(|p| {
+    let audio_params: Option<AacParams> = audio_idx.and_then(|idx| {
+        let stream = &session.streams()[idx];
+        stream.parameters().and_then(|p| {
             if let ParametersRef::Audio(ap) = p {
-                Some(ap.clock_rate())
+                Some(AacParams::new(
+                    ap.extra_data(),
+                    ap.clock_rate(),
+                    stream.channels().map(|c| c.get()),
+                ))
             } else {
                 None
             }
         })
     });
 
-    if config.verbose {
-        eprintln!("Video: {}x{}, SPS: {} bytes, PPS: {} bytes", width, height, sps.len(), pps.len());
-        if audio_params.is_some() {
-            eprintln!("Audio: enabled");
-        }
+    match &video_params {
+        Some(params) => debug!(
+            "Video: {}x{}, SPS: {} bytes, PPS: {} bytes",
+            params.width,
+            params.height,
+            params.sps.len(),
+            params.pps.len()
+        ),
+        None => debug!("No SPS/PPS in the SDP, waiting for them in-band"),
+    }
+    if let Some(aac) = audio_params {
+        debug!(
+            "Audio: AAC, {} Hz, {} channels",
+            aac.clock_rate, aac.channels
+        );
     }
 
     let mut session = session
@@ -153,16 +756,20 @@ pub async fn handle_rtsp_stream(
         .await?
         .demuxed()?;
 
-    if config.verbose {
-        eprintln!("Playback started");
-    }
+    debug!("Playback started");
 
     let mut total_bytes: u64 = 0;
+    let mut disk = DiskGuard::new(
+        config.output_dir.clone(),
+        config.low_disk,
+        config.hooks.clone(),
+        config.filename_template.clone(),
+    );
     let mut segment: Option<Segment> = None;
+    let mut file_index: u32 = 0;
     let mut segment_start = Instant::now();
     let segment_duration = std::time::Duration::from_secs(config.segment_secs);
-    let mut video_sample_time: u64 = 0;
-    let mut audio_sample_time: u64 = 0;
+    let extension = config.container.extension();
 
     while let Some(item) = session.next().await {
         if shutdown.load(Ordering::SeqCst) {
@@ -173,129 +780,213 @@ pub async fn handle_rtsp_stream(
             CodecItem::VideoFrame(frame) => {
                 let is_key = frame.is_random_access_point();
                 let data = frame.data();
-
-                // Rotate segment on keyframe after duration
-                let need_new = segment.is_none()
-                    || (is_key && segment_start.elapsed() >= segment_duration);
+                let timestamp = frame.timestamp();
+
+                // Parameter sets sent in-band take over from the SDP's, and
+                // new ones need a new file for the track to describe them
+                let mut params_changed = false;
+                if let Some(params) = is_key.then(|| in_band_params(data)).flatten() {
+                    let changed = video_params
+                        .as_ref()
+                        .is_none_or(|v| v.sps != params.sps || v.pps != params.pps);
+                    if changed {
+                        if video_params.is_some() {
+                            debug!(
+                                width = params.width,
+                                height = params.height,
+                                "SPS/PPS changed, starting a new file"
+                            );
+                        }
+                        params_changed = true;
+                        video_params = Some(params);
+                    }
+                }
+                let Some(params) = &video_params else {
+                    continue;
+                };
+
+                // Rotate segment on keyframe after duration or size, or on
+                // the next keyframe after SIGHUP
+                let need_new = match &segment {
+                    None => true,
+                    Some(seg) => {
+                        is_key
+                            && (segment_start.elapsed() >= segment_duration
+                                || config.segment_bytes.is_some_and(|max| seg.bytes >= max)
+                                || params_changed
+                                || rotate.load(Ordering::SeqCst))
+                    }
+                };
 
                 if need_new {
+                    rotate.store(false, Ordering::SeqCst);
                     // Close old segment
                     if let Some(mut seg) = segment.take() {
-                        seg.writer.write_end()?;
-                        if let Some(ref cmd) = config.on_segment {
-                            let cmd = cmd.replace("{}", &seg.path.to_string_lossy());
-                            tokio::spawn(async move {
-                                let _ = tokio::process::Command::new("sh")
-                                    .arg("-c").arg(&cmd).status().await;
-                            });
-                        }
+                        let keyframe = seg.keyframe.take();
+                        let path = seg.finish(Some(timestamp.timestamp()))?;
+                        // Runs in the background while recording goes on
+                        drop(segment_done(&config, path, keyframe));
                     }
 
                     // New segment
-                    let ts = Local::now().format("%Y%m%d_%H%M%S");
-                    let path = config.output_dir.join(format!("{}.mp4", ts));
-                    if config.verbose {
-                        eprintln!("New segment: {}", path.display());
-                    }
-
-                    let file = BufWriter::new(File::create(&path)?);
-                    let mp4_config = Mp4Config {
-                        major_brand: str::parse("isom").unwrap(),
-                        minor_version: 512,
-                        compatible_brands: vec![
-                            str::parse("isom").unwrap(),
-                            str::parse("iso2").unwrap(),
-                            str::parse("avc1").unwrap(),
-                            str::parse("mp41").unwrap(),
-                        ],
-                        timescale: 90000,
+                    let path = match &config.filename_template {
+                        Some(names) => {
+                            let now = Local::now();
+                            let mut path;
+                            loop {
+                                path = config
+                                    .output_dir
+                                    .join(names.filename(&now, file_index, None, extension));
+                                file_index += 1;
+                                if !output::name_taken(&path) {
+                                    break;
+                                }
+                            }
+                            if let Some(parent) = path.parent() {
+                                std::fs::create_dir_all(parent)?;
+                            }
+                            path
+                        }
+                        None => output::timestamped_path(&config.output_dir, extension),
                     };
+                    if !disk.as_mut().is_none_or(|disk| disk.check_new_file(&path)) {
+                        shutdown.store(true, Ordering::SeqCst);
+                        break;
+                    }
+                    debug!(file = %path.display(), "New segment");
+
+                    let file = BufWriter::new(File::create(output::part_path(&path))?);
+                    stats.set_current_file(path.clone());
+                    // The track counts in the RTP clock, so timestamp
+                    // differences are durations as they are
+                    let video_clock = timestamp.clock_rate().get();
+                    let (writer, has_audio) = if config.container == Container::Mkv {
+                        let tracks = track_infos(video_clock, params, audio_params);
+                        let writer = MatroskaWriter::start(file, &tracks)?;
+                        (MediaWriter::Matroska(writer), audio_params.is_some())
+                    } else if config.fragmented {
+                        let tracks = track_infos(video_clock, params, audio_params);
+                        let writer = FragmentedWriter::start(file, &tracks)?;
+                        (MediaWriter::Fragmented(writer), audio_params.is_some())
+                    } else {
+                        let mp4_config = Mp4Config {
+                            major_brand: str::parse("isom").unwrap(),
+                            minor_version: 512,
+                            compatible_brands: vec![
+                                str::parse("isom").unwrap(),
+                                str::parse("iso2").unwrap(),
+                                str::parse("avc1").unwrap(),
+                                str::parse("mp41").unwrap(),
+                            ],
+                            timescale: 90000,
+                        };
 
-                    let mut writer = Mp4Writer::write_start(file, &mp4_config)?;
-
-                    let track_config = TrackConfig {
-                        track_type: mp4::TrackType::Video,
-                        timescale: 90000,
-                        language: "und".to_string(),
-                        media_conf: MediaConfig::AvcConfig(AvcConfig {
-                            width,
-                            height,
-                            seq_param_set: sps.clone(),
-                            pic_param_set: pps.clone(),
-                        }),
-                    };
-                    writer.add_track(&track_config)?;
+                        let mut writer = Mp4Writer::write_start(file, &mp4_config)?;
 
-                    // Add audio track if available
-                    let has_audio = if let Some(sample_rate) = audio_params {
-                        let audio_config = TrackConfig {
-                            track_type: mp4::TrackType::Audio,
-                            timescale: sample_rate,
+                        let track_config = TrackConfig {
+                            track_type: mp4::TrackType::Video,
+                            timescale: video_clock,
                             language: "und".to_string(),
-                            media_conf: MediaConfig::AacConfig(AacConfig {
-                                bitrate: 128000,
-                                profile: mp4::AudioObjectType::AacLowComplexity,
-                                freq_index: mp4::SampleFreqIndex::Freq48000,
-                                chan_conf: mp4::ChannelConfig::Stereo,
+                            media_conf: MediaConfig::AvcConfig(AvcConfig {
+                                width: params.width,
+                                height: params.height,
+                                seq_param_set: params.sps.clone(),
+                                pic_param_set: params.pps.clone(),
                             }),
                         };
-                        writer.add_track(&audio_config).is_ok()
-                    } else {
-                        false
+                        writer.add_track(&track_config)?;
+
+                        // Add audio track if available
+                        let has_audio = if let Some(aac) = audio_params {
+                            let audio_config = TrackConfig {
+                                track_type: mp4::TrackType::Audio,
+                                timescale: aac.clock_rate,
+                                language: "und".to_string(),
+                                media_conf: MediaConfig::AacConfig(AacConfig {
+                                    bitrate: 128000,
+                                    profile: mp4::AudioObjectType::try_from(aac.object_type)
+                                        .unwrap_or(mp4::AudioObjectType::AacLowComplexity),
+                                    freq_index: mp4::SampleFreqIndex::try_from(aac.freq_index)
+                                        .unwrap_or(mp4::SampleFreqIndex::Freq48000),
+                                    chan_conf: mp4::ChannelConfig::try_from(aac.channels)
+                                        .unwrap_or(mp4::ChannelConfig::Stereo),
+                                }),
+                            };
+                            writer.add_track(&audio_config).is_ok()
+                        } else {
+                            false
+                        };
+                        (MediaWriter::Indexed(writer), has_audio)
                     };
 
-                    segment = Some(Segment { writer, path, has_audio });
+                    let sidecar = metadata
+                        .map(|(_, gzip)| MetadataSidecar::create(&path, gzip))
+                        .transpose()?;
+                    segment = Some(Segment {
+                        writer,
+                        path,
+                        // Until a second frame shows the real rate, assume 30fps
+                        video: Track::new(1, video_clock / 30),
+                        audio: has_audio.then(|| Track::new(2, 1024)),
+                        bytes: 0,
+                        keyframe: match config.snapshot.as_deref() {
+                            Some(Snapshot::Decode(_)) if is_key => Some(annex_b(params, data)),
+                            _ => None,
+                        },
+                        metadata: sidecar,
+                    });
                     segment_start = Instant::now();
-                    video_sample_time = 0;
-                    audio_sample_time = 0;
                 }
 
                 if let Some(ref mut seg) = segment {
-                    let sample = Mp4Sample {
-                        start_time: video_sample_time,
-                        duration: 3000, // ~30fps at 90kHz timescale
-                        rendering_offset: 0,
-                        is_sync: is_key,
-                        bytes: mp4::Bytes::copy_from_slice(data),
-                    };
-                    seg.writer.write_sample(1, &sample)?;
-                    total_bytes += data.len() as u64;
-                    video_sample_time += 3000;
-
-                    if config.progress {
-                        eprint!(".");
+                    seg.video
+                        .push(&mut seg.writer, timestamp.timestamp(), is_key, data, None)?;
+                    // A fragment per GOP: the keyframe is held back, so
+                    // this writes out the group before it
+                    if is_key {
+                        seg.writer.flush_fragment()?;
                     }
+                    seg.bytes += data.len() as u64;
+                    total_bytes += data.len() as u64;
+                    stats.add_bytes(data.len() as u64);
                 }
             }
             CodecItem::AudioFrame(frame) => {
                 if let Some(ref mut seg) = segment {
-                    if seg.has_audio {
+                    if let Some(audio) = &mut seg.audio {
                         let data = frame.data();
-                        let sample = Mp4Sample {
-                            start_time: audio_sample_time,
-                            duration: 1024, // typical AAC frame duration
-                            rendering_offset: 0,
-                            is_sync: true,
-                            bytes: mp4::Bytes::copy_from_slice(data),
-                        };
-                        let _ = seg.writer.write_sample(2, &sample); // track 2 = audio
+                        // The clock is the sample rate, so an AAC frame lasts
+                        // its length in samples
+                        let frame_length = frame.frame_length().get();
+                        let _ = audio.push(
+                            &mut seg.writer,
+                            frame.timestamp().timestamp(),
+                            true,
+                            data,
+                            Some(frame_length),
+                        );
+                        seg.bytes += data.len() as u64;
                         total_bytes += data.len() as u64;
-                        audio_sample_time += 1024;
+                        stats.add_bytes(data.len() as u64);
                     }
                 }
             }
+            CodecItem::MessageFrame(frame) => {
+                // Metadata from before the first file has nowhere to go
+                let sidecar = segment.as_mut().and_then(|seg| seg.metadata.as_mut());
+                if let Some(sidecar) = sidecar {
+                    sidecar.write(segment_start.elapsed(), frame.data())?;
+                }
+            }
             _ => {}
         }
     }
 
     // Close final segment
     if let Some(mut seg) = segment.take() {
-        seg.writer.write_end()?;
-        if let Some(ref cmd) = config.on_segment {
-            let cmd = cmd.replace("{}", &seg.path.to_string_lossy());
-            let _ = tokio::process::Command::new("sh")
-                .arg("-c").arg(&cmd).status().await;
-        }
+        let keyframe = seg.keyframe.take();
+        let path = seg.finish(None)?;
+        let _ = segment_done(&config, path, keyframe).await;
     }
 
     Ok(total_bytes)
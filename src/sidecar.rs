@@ -0,0 +1,154 @@
+//! Metadata sidecars for --sidecars: a `NAME.json` next to each completed
+//! file, so media asset systems can ingest recordings without probing them.
+
+use chrono::{DateTime, FixedOffset, Local};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// What's known about the file being written, for its sidecar
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    /// When the file was started
+    pub started: DateTime<Local>,
+    /// Total duration of the segments written, where they have one
+    pub media: Duration,
+    pub segments: u32,
+    pub first_sequence: Option<u64>,
+    pub last_sequence: Option<u64>,
+    /// Discontinuities after the file's first segment
+    pub discontinuities: u32,
+}
+
+impl FileInfo {
+    pub fn new(started: DateTime<Local>) -> Self {
+        Self {
+            started,
+            media: Duration::ZERO,
+            segments: 0,
+            first_sequence: None,
+            last_sequence: None,
+            discontinuities: 0,
+        }
+    }
+
+    /// Count a segment that has been written to the file
+    pub fn add_segment(&mut self, sequence: Option<u64>, duration: Duration, discontinuity: bool) {
+        if discontinuity && self.segments > 0 {
+            self.discontinuities += 1;
+        }
+        self.segments += 1;
+        self.media += duration;
+        if self.first_sequence.is_none() {
+            self.first_sequence = sequence;
+        }
+        self.last_sequence = sequence.or(self.last_sequence);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Sidecar<'a> {
+    file: String,
+    source: &'a str,
+    start: String,
+    end: String,
+    bytes: u64,
+    /// Media duration where segments have one, otherwise wall-clock time
+    duration_secs: f64,
+    segments: u32,
+    first_sequence: Option<u64>,
+    last_sequence: Option<u64>,
+    discontinuities: u32,
+    /// Average, in bits per second
+    bitrate: u64,
+    program_date_time: Option<String>,
+}
+
+/// Where the sidecar of `file` goes: `NAME.json`, with the file's own
+/// extension kept so a `.ts` and an `.mp4` of the same name don't collide
+pub fn path_for(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".json");
+    path.into()
+}
+
+/// When `file` was finished, from the `end` of its sidecar if it has one.
+/// Unlike the file's mtime it survives a copy or a `touch`.
+pub fn finished(file: &Path) -> Option<SystemTime> {
+    let data = std::fs::read(path_for(file)).ok()?;
+    let sidecar: serde_json::Value = serde_json::from_slice(&data).ok()?;
+    let end = DateTime::parse_from_rfc3339(sidecar["end"].as_str()?).ok()?;
+    Some(end.into())
+}
+
+/// Point the sidecar of `from`, if it has one, at `to` instead, when a file
+/// is replaced by another (a TS by its MP4 remux)
+#[cfg(feature = "remux")]
+pub fn moved(from: &Path, to: &Path) -> std::io::Result<()> {
+    let old = path_for(from);
+    let mut sidecar: serde_json::Value = match std::fs::read(&old) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    sidecar["file"] = to
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+        .into();
+    let bytes = std::fs::metadata(to)?.len();
+    sidecar["bytes"] = bytes.into();
+    if let Some(secs) = sidecar["duration_secs"].as_f64().filter(|&s| s > 0.0) {
+        sidecar["bitrate"] = ((bytes as f64 * 8.0 / secs) as u64).into();
+    }
+    let path = path_for(to);
+    let part = crate::output::part_path(&path);
+    std::fs::write(&part, serde_json::to_vec_pretty(&sidecar)?)?;
+    std::fs::rename(part, path)?;
+    std::fs::remove_file(old)
+}
+
+/// Write the sidecar of a completed `file`. It's written under a temporary
+/// name and renamed, like the file itself.
+pub fn write(
+    file: &Path,
+    source: &str,
+    info: &FileInfo,
+    bytes: u64,
+    program_date_time: Option<DateTime<FixedOffset>>,
+) -> std::io::Result<()> {
+    let end = Local::now();
+    let duration = if info.media.is_zero() {
+        (end - info.started).to_std().unwrap_or_default()
+    } else {
+        info.media
+    };
+    let bitrate = if duration.is_zero() {
+        0
+    } else {
+        (bytes as f64 * 8.0 / duration.as_secs_f64()) as u64
+    };
+    let sidecar = Sidecar {
+        file: file
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        source,
+        start: info.started.to_rfc3339(),
+        end: end.to_rfc3339(),
+        bytes,
+        duration_secs: duration.as_secs_f64(),
+        segments: info.segments,
+        first_sequence: info.first_sequence,
+        last_sequence: info.last_sequence,
+        discontinuities: info.discontinuities,
+        bitrate,
+        program_date_time: program_date_time.map(|pdt| pdt.to_rfc3339()),
+    };
+    let path = path_for(file);
+    let part = crate::output::part_path(&path);
+    std::fs::write(&part, serde_json::to_vec_pretty(&sidecar)?)?;
+    std::fs::rename(part, path)
+}
@@ -0,0 +1,174 @@
+//! SRT ingest: receive an MPEG-TS stream over SRT, either by calling an
+//! encoder or by listening for one to push, and write it into the usual
+//! rotating files.
+
+use crate::commands::SharedHooks;
+use crate::error::StreamError;
+use crate::output::OutputOptions;
+use crate::stats::RecordingStats;
+use crate::ts_ingest::TsIngest;
+use futures::StreamExt;
+use srt_tokio::SrtSocket;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+pub struct SrtConfig {
+    pub url: String,
+    pub output_dir: PathBuf,
+    pub file_extension: String,
+    pub segment_secs: u64,
+    pub output_options: OutputOptions,
+    pub hooks: SharedHooks,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Mode {
+    Caller,
+    Listener,
+}
+
+/// Connection settings from an `srt://` URL
+#[derive(Debug)]
+struct SrtOptions {
+    mode: Mode,
+    /// `host:port` to call, or `:port` to listen on
+    address: String,
+    stream_id: Option<String>,
+    passphrase: Option<String>,
+    latency: Option<Duration>,
+}
+
+/// `srt://host:port` calls the encoder; `srt://:port` or `?mode=listener`
+/// waits for it to connect. `streamid`, `passphrase` and `latency` (ms) are
+/// taken from the query string, as ffmpeg and srt-live-transmit spell them.
+fn parse_srt_url(url: &str) -> Result<SrtOptions, String> {
+    let rest = url
+        .strip_prefix("srt://")
+        .ok_or_else(|| format!("Not an srt:// URL: {url}"))?;
+    let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let authority = authority.trim_end_matches('/');
+    if !authority.contains(':') {
+        return Err(format!("SRT URL needs a port: {url}"));
+    }
+
+    let mut options = SrtOptions {
+        mode: if authority.starts_with(':') {
+            Mode::Listener
+        } else {
+            Mode::Caller
+        },
+        address: authority.to_string(),
+        stream_id: None,
+        passphrase: None,
+        latency: None,
+    };
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "mode" => {
+                options.mode = match value.as_ref() {
+                    "caller" => Mode::Caller,
+                    "listener" => Mode::Listener,
+                    other => return Err(format!("Unsupported SRT mode: {other}")),
+                }
+            }
+            "streamid" => options.stream_id = Some(value.into_owned()),
+            "passphrase" => options.passphrase = Some(value.into_owned()),
+            "latency" => {
+                let ms = value
+                    .parse()
+                    .map_err(|_| format!("Invalid SRT latency: {value}"))?;
+                options.latency = Some(Duration::from_millis(ms));
+            }
+            _ => debug!("Ignoring SRT option {key}"),
+        }
+    }
+    // A listener binds all interfaces on the given port
+    if options.mode == Mode::Listener {
+        let port = authority.rsplit_once(':').map_or("", |(_, port)| port);
+        options.address = format!(":{port}");
+    }
+    Ok(options)
+}
+
+async fn connect(options: &SrtOptions) -> Result<SrtSocket, StreamError> {
+    let mut builder = SrtSocket::builder();
+    if let Some(latency) = options.latency {
+        builder = builder.latency(latency);
+    }
+    if let Some(ref passphrase) = options.passphrase {
+        builder = builder.encryption(16, passphrase.clone());
+    }
+    let socket = match options.mode {
+        Mode::Caller => {
+            info!("Connecting to SRT sender {}", options.address);
+            builder
+                .call(options.address.as_str(), options.stream_id.as_deref())
+                .await
+                .map_err(StreamError::network)?
+        }
+        Mode::Listener => {
+            info!("Waiting for an SRT sender on {}", options.address);
+            builder
+                .listen_on(options.address.as_str())
+                .await
+                .map_err(StreamError::network)?
+        }
+    };
+    debug!("SRT connection established");
+    Ok(socket)
+}
+
+pub async fn handle_srt_stream(
+    config: SrtConfig,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    stats: Arc<RecordingStats>,
+) -> Result<u64, StreamError> {
+    let options = parse_srt_url(&config.url).map_err(StreamError::config)?;
+    let mut socket = tokio::select! {
+        socket = connect(&options) => socket?,
+        _ = wait_for(&shutdown) => return Ok(0),
+    };
+
+    let mut ingest = TsIngest::new(
+        config.output_dir,
+        config.file_extension,
+        config.segment_secs,
+        config.output_options,
+        config.hooks,
+        stats.clone(),
+    )?;
+
+    loop {
+        stats.touch();
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if rotate.swap(false, Ordering::SeqCst) {
+            ingest.request_rotate();
+        }
+
+        // Wake up now and then to check for shutdown while the sender is quiet
+        match tokio::time::timeout(Duration::from_millis(500), socket.next()).await {
+            Err(_) => continue,
+            Ok(None) => {
+                info!("SRT sender disconnected");
+                break;
+            }
+            Ok(Some(Err(e))) => return Err(StreamError::network(e)),
+            Ok(Some(Ok((_, data)))) => ingest.push(&data)?,
+        }
+    }
+
+    Ok(ingest.finish().await?)
+}
+
+/// Resolve once shutdown is requested
+async fn wait_for(shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
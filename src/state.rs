@@ -0,0 +1,66 @@
+//! --state-file: where an HLS recording got to, so a restart after a crash
+//! or reboot carries on without writing any segment twice.
+
+use crate::output::ResumePoint;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::warn;
+use url::Url;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordingState {
+    /// Media playlist URL without its query, which CDNs re-sign
+    pub stream: String,
+    /// Media sequence number of the last segment written
+    pub last_sequence: Option<u64>,
+    pub output: ResumePoint,
+}
+
+/// How a media playlist URL is matched against a saved state
+pub fn stream_key(media_url: &Url) -> String {
+    let mut url = media_url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url.to_string()
+}
+
+impl RecordingState {
+    /// The state saved at `path` for `media_url`, if there is one. A state
+    /// for another stream is ignored with a warning.
+    pub fn load(path: &Path, media_url: &Url) -> Option<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Can't read state file {}: {e}", path.display());
+                return None;
+            }
+        };
+        let state: Self = match serde_json::from_slice(&data) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Ignoring state file {}: {e}", path.display());
+                return None;
+            }
+        };
+        if state.stream != stream_key(media_url) {
+            warn!(
+                "Ignoring state file {}: it's for {}, not this stream",
+                path.display(),
+                state.stream
+            );
+            return None;
+        }
+        Some(state)
+    }
+
+    /// Write the state via a temp file + rename, so a crash mid-write
+    /// leaves the previous state behind
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+    }
+}
@@ -0,0 +1,277 @@
+use chrono::{DateTime, Local};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Sentinel for "live-edge lag not known yet"
+const LAG_UNKNOWN: u64 = u64::MAX;
+
+/// A file a recording wrote, and when
+#[derive(Clone)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub started_at: DateTime<Local>,
+    /// When the next file took over; None for the last one
+    pub ended_at: Option<DateTime<Local>>,
+    /// Bytes counted while it was the current file
+    pub bytes: u64,
+}
+
+/// Live counters shared between the active recorder and anything that reports
+/// on it (systemd status, watchdog, status file).
+pub struct RecordingStats {
+    started: Instant,
+    started_at: DateTime<Local>,
+    bytes: AtomicU64,
+    segments: AtomicU64,
+    playlist_errors: AtomicU64,
+    segment_errors: AtomicU64,
+    /// Milliseconds since `started` at which the recorder last made progress
+    last_activity_ms: AtomicU64,
+    /// How far behind the live edge the last written segment was, in milliseconds
+    live_lag_ms: AtomicU64,
+    /// Total segments in a VOD playlist (0 = live or unknown)
+    expected_segments: AtomicU64,
+    /// Total media duration of a VOD playlist in milliseconds (0 = live or unknown)
+    expected_duration_ms: AtomicU64,
+    /// Media duration of the segments dealt with so far (written or
+    /// skipped), in milliseconds
+    done_duration_ms: AtomicU64,
+    last_segment_at: Mutex<Option<DateTime<Local>>>,
+    current_file: Mutex<Option<PathBuf>>,
+    /// Every file written, for --stats-json, including those of the
+    /// recordings counting towards this one
+    files: Mutex<Vec<Arc<Mutex<FileRecord>>>>,
+    /// This recording's own file being written
+    current_record: Mutex<Option<Arc<Mutex<FileRecord>>>>,
+    /// Combined stats this recording also counts towards, when it's one of
+    /// several (--cameras)
+    parent: Option<Arc<RecordingStats>>,
+}
+
+impl RecordingStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: Local::now(),
+            bytes: AtomicU64::new(0),
+            segments: AtomicU64::new(0),
+            playlist_errors: AtomicU64::new(0),
+            segment_errors: AtomicU64::new(0),
+            last_activity_ms: AtomicU64::new(0),
+            live_lag_ms: AtomicU64::new(LAG_UNKNOWN),
+            expected_segments: AtomicU64::new(0),
+            expected_duration_ms: AtomicU64::new(0),
+            done_duration_ms: AtomicU64::new(0),
+            last_segment_at: Mutex::new(None),
+            current_file: Mutex::new(None),
+            files: Mutex::new(Vec::new()),
+            current_record: Mutex::new(None),
+            parent: None,
+        }
+    }
+
+    /// Stats for one of several recordings, whose data and errors also
+    /// count towards `parent`
+    pub fn with_parent(parent: Arc<RecordingStats>) -> Self {
+        Self {
+            parent: Some(parent),
+            ..Self::new()
+        }
+    }
+
+    /// Record a downloaded media segment (or completed output file)
+    pub fn add_segment(&self, bytes: u64) {
+        self.segments.fetch_add(1, Ordering::SeqCst);
+        *self.last_segment_at.lock().unwrap() = Some(Local::now());
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+        self.add_file_bytes(bytes);
+        self.mark_active();
+        if let Some(parent) = &self.parent {
+            parent.add_segment(bytes);
+        }
+    }
+
+    /// Record bytes written outside of whole segments (e.g. RTSP frames)
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+        self.add_file_bytes(bytes);
+        self.mark_active();
+        if let Some(parent) = &self.parent {
+            parent.add_bytes(bytes);
+        }
+    }
+
+    /// Mark the recorder as alive without new data (e.g. a playlist poll)
+    pub fn touch(&self) {
+        self.mark_active();
+        if let Some(parent) = &self.parent {
+            parent.touch();
+        }
+    }
+
+    fn mark_active(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_activity_ms.store(now, Ordering::SeqCst);
+    }
+
+    pub fn add_playlist_error(&self) {
+        self.playlist_errors.fetch_add(1, Ordering::SeqCst);
+        if let Some(parent) = &self.parent {
+            parent.add_playlist_error();
+        }
+    }
+
+    pub fn add_segment_error(&self) {
+        self.segment_errors.fetch_add(1, Ordering::SeqCst);
+        if let Some(parent) = &self.parent {
+            parent.add_segment_error();
+        }
+    }
+
+    pub fn set_live_lag(&self, lag: Duration) {
+        self.live_lag_ms
+            .store(lag.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Set once the playlist turns out to be VOD, so progress can show a percentage
+    pub fn set_expected_segments(&self, segments: u64) {
+        self.expected_segments.store(segments, Ordering::SeqCst);
+    }
+
+    /// Set with `set_expected_segments` when the segment durations are known
+    pub fn set_expected_duration(&self, duration: Duration) {
+        self.expected_duration_ms
+            .store(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Count a segment's media duration towards the VOD progress
+    pub fn add_done_duration(&self, duration: Duration) {
+        self.done_duration_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// A new file is being written; the previous one, if any, is done
+    pub fn set_current_file(&self, path: PathBuf) {
+        {
+            let mut current = self.current_record.lock().unwrap();
+            if current
+                .as_ref()
+                .is_none_or(|record| record.lock().unwrap().path != path)
+            {
+                let now = Local::now();
+                if let Some(previous) = current.take() {
+                    previous.lock().unwrap().ended_at = Some(now);
+                }
+                let record = Arc::new(Mutex::new(FileRecord {
+                    path: path.clone(),
+                    started_at: now,
+                    ended_at: None,
+                    bytes: 0,
+                }));
+                self.add_file(record.clone());
+                *current = Some(record);
+            }
+        }
+        self.show_current_file(path);
+    }
+
+    fn show_current_file(&self, path: PathBuf) {
+        if let Some(parent) = &self.parent {
+            parent.show_current_file(path.clone());
+        }
+        *self.current_file.lock().unwrap() = Some(path);
+    }
+
+    fn add_file(&self, record: Arc<Mutex<FileRecord>>) {
+        if let Some(parent) = &self.parent {
+            parent.add_file(record.clone());
+        }
+        self.files.lock().unwrap().push(record);
+    }
+
+    fn add_file_bytes(&self, bytes: u64) {
+        if let Some(record) = &*self.current_record.lock().unwrap() {
+            record.lock().unwrap().bytes += bytes;
+        }
+    }
+
+    /// The files written so far, oldest first
+    pub fn files(&self) -> Vec<FileRecord> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .map(|record| record.lock().unwrap().clone())
+            .collect()
+    }
+
+    pub fn started_at(&self) -> DateTime<Local> {
+        self.started_at
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn segments(&self) -> u64 {
+        self.segments.load(Ordering::SeqCst)
+    }
+
+    pub fn playlist_errors(&self) -> u64 {
+        self.playlist_errors.load(Ordering::SeqCst)
+    }
+
+    pub fn segment_errors(&self) -> u64 {
+        self.segment_errors.load(Ordering::SeqCst)
+    }
+
+    pub fn live_lag(&self) -> Option<Duration> {
+        match self.live_lag_ms.load(Ordering::SeqCst) {
+            LAG_UNKNOWN => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn expected_segments(&self) -> Option<u64> {
+        match self.expected_segments.load(Ordering::SeqCst) {
+            0 => None,
+            n => Some(n),
+        }
+    }
+
+    pub fn expected_duration(&self) -> Option<Duration> {
+        match self.expected_duration_ms.load(Ordering::SeqCst) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn done_duration(&self) -> Duration {
+        Duration::from_millis(self.done_duration_ms.load(Ordering::SeqCst))
+    }
+
+    pub fn last_segment_at(&self) -> Option<DateTime<Local>> {
+        *self.last_segment_at.lock().unwrap()
+    }
+
+    pub fn current_file(&self) -> Option<PathBuf> {
+        self.current_file.lock().unwrap().clone()
+    }
+
+    /// Time since the recorder last reported any activity
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_activity_ms.load(Ordering::SeqCst));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+impl Default for RecordingStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
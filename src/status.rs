@@ -0,0 +1,148 @@
+use crate::stats::RecordingStats;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Snapshot of recorder health written to the status file
+#[derive(Serialize)]
+pub struct StatusReport {
+    updated_at: String,
+    started_at: String,
+    uptime_secs: u64,
+    current_file: Option<String>,
+    bytes: u64,
+    segments: u64,
+    last_segment_at: Option<String>,
+    playlist_errors: u64,
+    segment_errors: u64,
+    live_edge_lag_secs: Option<f64>,
+}
+
+impl StatusReport {
+    pub fn from_stats(stats: &RecordingStats) -> Self {
+        Self {
+            updated_at: chrono::Local::now().to_rfc3339(),
+            started_at: stats.started_at().to_rfc3339(),
+            uptime_secs: stats.uptime().as_secs(),
+            current_file: stats
+                .current_file()
+                .map(|p| p.to_string_lossy().to_string()),
+            bytes: stats.bytes(),
+            segments: stats.segments(),
+            last_segment_at: stats.last_segment_at().map(|t| t.to_rfc3339()),
+            playlist_errors: stats.playlist_errors(),
+            segment_errors: stats.segment_errors(),
+            live_edge_lag_secs: stats.live_lag().map(|lag| lag.as_secs_f64()),
+        }
+    }
+}
+
+/// Write `value` as JSON via a temp file + rename so readers never see a
+/// partial file
+pub fn write_json(path: &Path, value: &impl Serialize) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(value)?;
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn write_status(path: &Path, stats: &RecordingStats) -> std::io::Result<()> {
+    write_json(path, &StatusReport::from_stats(stats))
+}
+
+/// --stats-json: how a recording went, written once it's over
+#[derive(Serialize)]
+struct Summary<'a> {
+    url: &'a str,
+    output: &'a Path,
+    /// ended, interrupted or failed
+    reason: &'static str,
+    error: Option<String>,
+    started_at: String,
+    ended_at: String,
+    duration_secs: f64,
+    bytes: u64,
+    segments: u64,
+    /// Over the whole run, in bits per second
+    average_bitrate: u64,
+    playlist_errors: u64,
+    segment_errors: u64,
+    files: Vec<FileSummary>,
+}
+
+#[derive(Serialize)]
+struct FileSummary {
+    path: PathBuf,
+    started_at: String,
+    /// How long it was being written
+    duration_secs: f64,
+    /// Its size, or the bytes counted into it if it has been moved away
+    bytes: u64,
+}
+
+/// Write the --stats-json summary to `path`, or stdout for `-`
+pub fn write_summary(
+    path: &Path,
+    url: &str,
+    output: &Path,
+    reason: &'static str,
+    error: Option<String>,
+    stats: &RecordingStats,
+) -> std::io::Result<()> {
+    let now = chrono::Local::now();
+    let secs_between = |from: chrono::DateTime<chrono::Local>,
+                        to: chrono::DateTime<chrono::Local>| {
+        (to - from).num_milliseconds().max(0) as f64 / 1000.0
+    };
+    let duration = stats.uptime().as_secs_f64();
+    let files = stats
+        .files()
+        .into_iter()
+        .map(|file| FileSummary {
+            started_at: file.started_at.to_rfc3339(),
+            duration_secs: secs_between(file.started_at, file.ended_at.unwrap_or(now)),
+            bytes: std::fs::metadata(&file.path).map_or(file.bytes, |m| m.len()),
+            path: file.path,
+        })
+        .collect();
+    let summary = Summary {
+        url,
+        output,
+        reason,
+        error,
+        started_at: stats.started_at().to_rfc3339(),
+        ended_at: now.to_rfc3339(),
+        duration_secs: duration,
+        bytes: stats.bytes(),
+        segments: stats.segments(),
+        average_bitrate: (stats.bytes() as f64 * 8.0 / duration.max(0.001)) as u64,
+        playlist_errors: stats.playlist_errors(),
+        segment_errors: stats.segment_errors(),
+        files,
+    };
+    if path == Path::new("-") {
+        let json = serde_json::to_string_pretty(&summary)?;
+        println!("{json}");
+        Ok(())
+    } else {
+        write_json(path, &summary)
+    }
+}
+
+/// Periodically rewrite `path` with the current recorder status
+pub fn spawn_status_writer(
+    path: PathBuf,
+    interval: Duration,
+    stats: Arc<RecordingStats>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = write_status(&path, &stats) {
+                warn!("Failed to write status file {}: {e}", path.display());
+            }
+            tokio::time::sleep(interval).await;
+        }
+    })
+}
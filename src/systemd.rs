@@ -0,0 +1,86 @@
+//! systemd service notifications for `Type=notify` units.
+//! Every call here is a no-op when not started by systemd (no NOTIFY_SOCKET).
+
+use crate::stats::RecordingStats;
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use tracing::warn;
+
+#[cfg(target_os = "linux")]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(state) {
+        warn!("sd_notify failed: {e}");
+    }
+}
+
+/// Tell systemd the recording is up and running
+pub fn notify_ready() {
+    #[cfg(target_os = "linux")]
+    notify(&[sd_notify::NotifyState::Ready]);
+}
+
+/// Tell systemd we are shutting down (flushing the last segment, running hooks)
+pub fn notify_stopping() {
+    #[cfg(target_os = "linux")]
+    notify(&[
+        sd_notify::NotifyState::Stopping,
+        sd_notify::NotifyState::Status("Stopping, flushing current segment"),
+    ]);
+}
+
+/// Periodically publish throughput and segment info as STATUS=, and ping the
+/// watchdog (if WatchdogSec= is set) for as long as the recorder keeps making
+/// progress. A recorder that stops polling/receiving is left to time out so
+/// systemd can restart it.
+pub fn spawn_status_reporter(stats: Arc<RecordingStats>) {
+    #[cfg(target_os = "linux")]
+    {
+        use crate::commands::format_bytes;
+        use std::time::{Duration, Instant};
+
+        if std::env::var_os("NOTIFY_SOCKET").is_none() {
+            return;
+        }
+
+        let watchdog = sd_notify::watchdog_enabled();
+        // Ping at half the watchdog timeout, as recommended by sd_watchdog_enabled(3)
+        let interval = watchdog
+            .map(|w| w / 2)
+            .unwrap_or(Duration::from_secs(10))
+            .max(Duration::from_millis(500));
+
+        tokio::spawn(async move {
+            let mut last_bytes = stats.bytes();
+            let mut last_tick = Instant::now();
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let bytes = stats.bytes();
+                let rate = (bytes - last_bytes) as f64 / last_tick.elapsed().as_secs_f64();
+                last_bytes = bytes;
+                last_tick = Instant::now();
+
+                let current = stats
+                    .current_file()
+                    .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+                    .unwrap_or_else(|| "-".to_string());
+                let status = format!(
+                    "Recording {current}: {}/s, {} total, {} segments",
+                    format_bytes(rate as u64),
+                    format_bytes(bytes),
+                    stats.segments()
+                );
+                notify(&[sd_notify::NotifyState::Status(&status)]);
+
+                if let Some(timeout) = watchdog {
+                    if stats.idle_for() < timeout {
+                        notify(&[sd_notify::NotifyState::Watchdog]);
+                    }
+                }
+            }
+        });
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = stats;
+}
@@ -0,0 +1,36 @@
+//! OpenTelemetry export of the tracing spans (playlist polls, segment
+//! fetches and writes, hooks) over OTLP/gRPC.
+
+use crate::error::StreamError;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Set up the OTLP/gRPC exporter; shut the provider down on exit to flush
+pub fn provider(endpoint: &str) -> Result<SdkTracerProvider, StreamError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("m3u8-dl").build())
+        .build())
+}
+
+/// Layer feeding our spans to `provider`. Only this crate's spans are
+/// exported, independent of the log filter.
+pub fn layer<S>(provider: &SdkTracerProvider) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("stream-utils"))
+        .with_filter(Targets::new().with_target("stream_utils", tracing::Level::DEBUG))
+        .boxed()
+}
@@ -0,0 +1,258 @@
+//! --thumbnails: alongside the recording, grab a keyframe every N seconds
+//! from the stream's I-frame playlist (EXT-X-I-FRAME-STREAM-INF) and save it
+//! as an image with ffmpeg, for a contact sheet of what was recorded.
+
+use crate::cli::ImageFormat;
+use crate::error::StreamError;
+use crate::http_client::{
+    fetch_if_modified_with_retry, fetch_range_with_retry, HttpClient, Validators,
+};
+use crate::playlist;
+use m3u8_rs::{Map, Playlist};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+use url::Url;
+
+/// Two TS packets: enough for the PAT and PMT at the start of a segment,
+/// which an I-frame byte range doesn't include
+const TS_HEADER_BYTES: u64 = 2 * 188;
+
+/// A resource URL and optional (offset, length) byte range
+type ResourceKey = (String, Option<(u64, u64)>);
+
+pub struct ThumbnailConfig {
+    pub playlist_url: Url,
+    pub output_dir: PathBuf,
+    pub every: Duration,
+    pub format: ImageFormat,
+    pub poll_interval: u64,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+    /// The ffmpeg binary that decodes the frames
+    pub ffmpeg: PathBuf,
+}
+
+/// The thumbnail task. It stops when this is dropped, so it ends with the
+/// recording attempt that started it.
+pub struct Thumbnailer {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Thumbnailer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Thumbnailer {
+    pub fn start(client: &HttpClient, config: ThumbnailConfig) -> Result<Self, StreamError> {
+        std::fs::create_dir_all(&config.output_dir)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let task_stop = stop.clone();
+        let client = client.clone();
+        info!(
+            "Saving a thumbnail every {}s to {}",
+            config.every.as_secs(),
+            config.output_dir.display()
+        );
+        tokio::spawn(async move {
+            let mut state = State::default();
+            while !task_stop.load(Ordering::SeqCst) {
+                if let Err(e) = poll(&client, &config, &mut state).await {
+                    warn!("Thumbnail playlist error: {e}");
+                }
+                tokio::time::sleep(Duration::from_secs(config.poll_interval)).await;
+            }
+        });
+        Ok(Self { stop })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// I-frames already considered, by (URI, byte offset). Only entries
+    /// still in the playlist are kept.
+    seen: HashSet<(String, u64)>,
+    /// PAT/PMT or EXT-X-MAP bytes by (URL, range), for resources still in
+    /// the playlist
+    headers: HashMap<ResourceKey, Vec<u8>>,
+    /// Stream time of new I-frames since the last thumbnail
+    since_last: Option<f64>,
+    /// ETag/Last-Modified of the last playlist response
+    validators: Validators,
+}
+
+/// The media initialization bytes an I-frame needs to be decodable on its
+/// own: its EXT-X-MAP if it has one, else the start of the TS resource
+async fn header(
+    client: &HttpClient,
+    config: &ThumbnailConfig,
+    state: &mut State,
+    map: Option<&Map>,
+    url: &Url,
+) -> Result<Vec<u8>, StreamError> {
+    let (header_url, range) = match map {
+        Some(map) => (
+            client.final_url(&config.playlist_url).join(&map.uri)?,
+            map.byte_range
+                .as_ref()
+                .map(|r| (r.offset.unwrap_or(0), r.length)),
+        ),
+        None => (url.clone(), Some((0, TS_HEADER_BYTES))),
+    };
+    let key = (header_url.to_string(), range);
+    if let Some(bytes) = state.headers.get(&key) {
+        return Ok(bytes.clone());
+    }
+    let bytes = fetch_range_with_retry(
+        client,
+        header_url.as_str(),
+        range,
+        config.timeout,
+        config.retries,
+        config.retry_delay_ms,
+    )
+    .await?;
+    state.headers.insert(key, bytes.clone());
+    Ok(bytes)
+}
+
+async fn poll(
+    client: &HttpClient,
+    config: &ThumbnailConfig,
+    state: &mut State,
+) -> Result<(), StreamError> {
+    let Some(data) = fetch_if_modified_with_retry(
+        client,
+        config.playlist_url.as_str(),
+        &mut state.validators,
+        config.timeout,
+        config.retries,
+        config.retry_delay_ms,
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+    let media = match m3u8_rs::parse_playlist(&data) {
+        Ok((_, Playlist::MediaPlaylist(pl))) => pl,
+        _ => return Err(StreamError::parse("Failed to parse I-frame playlist")),
+    };
+    let base_url = client.final_url(&config.playlist_url);
+
+    // A byte range without an offset continues where the previous one on
+    // the same resource ended
+    let mut next_offset: HashMap<&str, u64> = HashMap::new();
+    // EXT-X-MAP applies until the next one, but is only attached to the
+    // segment it precedes
+    let mut map: Option<&Map> = None;
+    let mut listed: HashSet<(String, u64)> = HashSet::new();
+    let mut resources: HashSet<String> = HashSet::new();
+    for (index, segment) in media.segments.iter().enumerate() {
+        if segment.map.is_some() {
+            map = segment.map.as_ref();
+        }
+        if let Some(map) = map {
+            resources.insert(base_url.join(&map.uri)?.to_string());
+        }
+        resources.insert(base_url.join(&segment.uri)?.to_string());
+        let Some(ref range) = segment.byte_range else {
+            continue;
+        };
+        let offset = range
+            .offset
+            .unwrap_or_else(|| next_offset.get(segment.uri.as_str()).copied().unwrap_or(0));
+        next_offset.insert(&segment.uri, offset + range.length);
+        listed.insert((segment.uri.clone(), offset));
+        if !state.seen.insert((segment.uri.clone(), offset)) {
+            continue;
+        }
+
+        // The first I-frame gets a thumbnail, then one per --thumbnails seconds
+        let due = match state.since_last {
+            None => true,
+            Some(secs) => secs >= config.every.as_secs_f64(),
+        };
+        if !due {
+            state.since_last = state.since_last.map(|s| s + segment.duration as f64);
+            continue;
+        }
+        state.since_last = Some(segment.duration as f64);
+
+        let url = base_url.join(&segment.uri)?;
+        // A TS I-frame at the very start of its resource already follows
+        // the PAT/PMT
+        let mut bytes = if map.is_some() || offset > 0 {
+            header(client, config, state, map, &url).await?
+        } else {
+            Vec::new()
+        };
+        bytes.extend(
+            fetch_range_with_retry(
+                client,
+                url.as_str(),
+                Some((offset, range.length)),
+                config.timeout,
+                config.retries,
+                config.retry_delay_ms,
+            )
+            .await?,
+        );
+
+        let time = playlist::program_date_time(&media, index)
+            .map(|t| t.with_timezone(&chrono::Local))
+            .unwrap_or_else(chrono::Local::now);
+        let path = config.output_dir.join(format!(
+            "{}.{}",
+            time.format("%Y_%m_%d-%H_%M_%S"),
+            config.format.extension()
+        ));
+        match extract_frame(&config.ffmpeg, None, &bytes, &path).await {
+            Ok(()) => debug!(file = %path.display(), "Saved thumbnail"),
+            Err(e) => warn!("Thumbnail {} failed: {e}", path.display()),
+        }
+    }
+
+    // Forget what has scrolled out of the live window so week-long
+    // recordings don't accumulate state
+    state.seen.retain(|entry| listed.contains(entry));
+    state.headers.retain(|(url, _), _| resources.contains(url));
+    Ok(())
+}
+
+/// Decode the first video frame of `data` into an image at `path`. ffmpeg
+/// detects the input format unless it's given, as for raw H.264.
+pub async fn extract_frame(
+    ffmpeg: &std::path::Path,
+    format: Option<&str>,
+    data: &[u8],
+    path: &std::path::Path,
+) -> Result<(), StreamError> {
+    let mut command = tokio::process::Command::new(ffmpeg);
+    command.args(["-v", "error", "-y"]);
+    if let Some(format) = format {
+        command.args(["-f", format]);
+    }
+    let mut child = command
+        .args(["-i", "pipe:0", "-frames:v", "1"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // ffmpeg may stop reading once it has a frame; that's not an error
+    let _ = stdin.write_all(data).await;
+    drop(stdin);
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(StreamError::ffmpeg(format!("ffmpeg exited with: {status}")));
+    }
+    Ok(())
+}
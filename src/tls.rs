@@ -0,0 +1,292 @@
+//! The TLS backend for HTTPS and RTSPS: native-tls (OpenSSL or the system
+//! library) by default, or rustls with `--features rustls` for static musl
+//! builds that shouldn't link OpenSSL.
+
+use crate::cli::NetworkArgs;
+use crate::dns::Resolver;
+use crate::error::StreamError;
+use hyper_util::client::legacy::connect::HttpConnector;
+use std::time::Duration;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("enable a TLS backend: the native-tls (default) or rustls feature");
+
+#[cfg(not(feature = "rustls"))]
+pub type HttpsConnector = hyper_tls::HttpsConnector<HttpConnector<Resolver>>;
+#[cfg(feature = "rustls")]
+pub type HttpsConnector = hyper_rustls::HttpsConnector<HttpConnector<Resolver>>;
+
+/// A client certificate to present for mutual TLS
+#[cfg(not(feature = "rustls"))]
+type ClientIdentity = native_tls::Identity;
+
+/// A client certificate to present for mutual TLS
+#[cfg(feature = "rustls")]
+struct ClientIdentity {
+    certs: Vec<rustls::pki_types::CertificateDer<'static>>,
+    key: rustls::pki_types::PrivateKeyDer<'static>,
+}
+
+/// How long the preferred address family gets before the other one is
+/// tried in parallel (Happy Eyeballs, RFC 8305's recommended delay)
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+fn http_connector(net: &NetworkArgs) -> HttpConnector<Resolver> {
+    let mut http = HttpConnector::new_with_resolver(Resolver::new(net));
+    http.enforce_http(false);
+    // Race IPv4 against IPv6 so a host with a broken AAAA record still
+    // connects quickly, and split the connect timeout across the addresses
+    // so one that drops packets can't use up the whole budget
+    http.set_happy_eyeballs_timeout(Some(HAPPY_EYEBALLS_DELAY));
+    http.set_connect_timeout(Some(Duration::from_secs(net.timeout.max(1))));
+    http
+}
+
+/// The HTTPS connector for the HTTP clients. --insecure accepts any server
+/// certificate and host name, --ca-cert adds to the trusted roots,
+/// --client-cert is presented when the server asks for one, and h2 is
+/// offered in ALPN (unless --http1-only) so servers that support it get one
+/// multiplexed connection.
+#[cfg(not(feature = "rustls"))]
+pub fn build_connector(net: &NetworkArgs) -> Result<HttpsConnector, StreamError> {
+    let mut tls = native_tls::TlsConnector::builder();
+    if !net.http1_only {
+        tls.request_alpns(&["h2", "http/1.1"]);
+    }
+    if net.insecure {
+        tls.danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    for cert in ca_certs(net)? {
+        tls.add_root_certificate(cert);
+    }
+    if let Some(identity) = client_identity(net)? {
+        tls.identity(identity);
+    }
+    let tls = tls
+        .build()
+        .map_err(|e| StreamError::config(format!("Failed to build TLS connector: {e}")))?;
+    Ok(hyper_tls::HttpsConnector::from((
+        http_connector(net),
+        tls.into(),
+    )))
+}
+
+/// The HTTPS connector for the HTTP clients. --insecure accepts any server
+/// certificate and host name, --ca-cert adds to the trusted roots,
+/// --client-cert is presented when the server asks for one, and h2 is
+/// offered in ALPN (unless --http1-only) so servers that support it get one
+/// multiplexed connection.
+#[cfg(feature = "rustls")]
+pub fn build_connector(net: &NetworkArgs) -> Result<HttpsConnector, StreamError> {
+    use std::sync::Arc;
+
+    // Pick the provider explicitly: the process default is ambiguous when
+    // another dependency enables a second one
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if net.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(insecure::AcceptAnyCert(provider)))
+    } else {
+        let mut roots =
+            rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        for cert in ca_certs(net)? {
+            roots.add(cert)?;
+        }
+        builder.with_root_certificates(roots)
+    };
+    let config = match client_identity(net)? {
+        Some(identity) => builder.with_client_auth_cert(identity.certs, identity.key)?,
+        None => builder.with_no_client_auth(),
+    };
+    let builder = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(config)
+        .https_or_http();
+    Ok(if !net.http1_only {
+        builder
+            .enable_all_versions()
+            .wrap_connector(http_connector(net))
+    } else {
+        builder.enable_http1().wrap_connector(http_connector(net))
+    })
+}
+
+/// The connector for rtsps:// cameras, with the same certificate checks and
+/// client certificate as HTTPS but without offering h2
+#[cfg(feature = "rtsp")]
+pub fn rtsps_connector(net: &NetworkArgs) -> Result<HttpsConnector, StreamError> {
+    build_connector(&NetworkArgs {
+        http1_only: true,
+        ..net.clone()
+    })
+}
+
+/// The certificates in the --ca-cert file
+#[cfg(not(feature = "rustls"))]
+fn ca_certs(net: &NetworkArgs) -> Result<Vec<native_tls::Certificate>, StreamError> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let Some(path) = &net.ca_cert else {
+        return Ok(Vec::new());
+    };
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        StreamError::config(format!("Can't read CA certificate {}: {e}", path.display()))
+    })?;
+    // native-tls reads one certificate at a time, so split a bundle
+    let certs = text
+        .split_inclusive(END)
+        .filter(|pem| pem.contains(END))
+        .map(|pem| native_tls::Certificate::from_pem(pem.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            StreamError::config(format!("Can't load CA certificate {}: {e}", path.display()))
+        })?;
+    if certs.is_empty() {
+        return Err(StreamError::config(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// The certificates in the --ca-cert file
+#[cfg(feature = "rustls")]
+fn ca_certs(
+    net: &NetworkArgs,
+) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, StreamError> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+
+    let Some(path) = &net.ca_cert else {
+        return Ok(Vec::new());
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            StreamError::config(format!("Can't load CA certificate {}: {e}", path.display()))
+        })?;
+    if certs.is_empty() {
+        return Err(StreamError::config(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// The client certificate from --client-cert: a PEM certificate (chain) with
+/// its --client-key, or a PKCS#12 bundle holding both
+fn client_identity(net: &NetworkArgs) -> Result<Option<ClientIdentity>, StreamError> {
+    let Some(cert_path) = &net.client_cert else {
+        return Ok(None);
+    };
+    load_identity(
+        cert_path,
+        net.client_key.as_deref(),
+        net.client_cert_password.as_deref(),
+    )
+    .map(Some)
+    .map_err(|e| {
+        StreamError::config(format!(
+            "Can't load client certificate {}: {e}",
+            cert_path.display()
+        ))
+    })
+}
+
+#[cfg(not(feature = "rustls"))]
+fn load_identity(
+    cert_path: &std::path::Path,
+    key_path: Option<&std::path::Path>,
+    password: Option<&str>,
+) -> Result<ClientIdentity, StreamError> {
+    let cert = std::fs::read(cert_path)?;
+    let identity = match key_path {
+        Some(key_path) => native_tls::Identity::from_pkcs8(&cert, &std::fs::read(key_path)?)?,
+        None => native_tls::Identity::from_pkcs12(&cert, password.unwrap_or_default())?,
+    };
+    Ok(identity)
+}
+
+#[cfg(feature = "rustls")]
+fn load_identity(
+    cert_path: &std::path::Path,
+    key_path: Option<&std::path::Path>,
+    _password: Option<&str>,
+) -> Result<ClientIdentity, StreamError> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let key_path = key_path.ok_or(
+        "the rustls build reads PEM only; give the key with --client-key \
+         (openssl pkcs12 -nodes converts a PKCS#12 bundle)",
+    )?;
+    let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(StreamError::config("no certificate in the file"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+    Ok(ClientIdentity { certs, key })
+}
+
+#[cfg(feature = "rustls")]
+mod insecure {
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::CryptoProvider;
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{DigitallySignedStruct, SignatureScheme};
+    use std::sync::Arc;
+
+    /// --insecure: trust any certificate for any host, but still check the
+    /// handshake signatures so the connection is at least consistent
+    #[derive(Debug)]
+    pub struct AcceptAnyCert(pub Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCert {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Writing a pushed MPEG-TS byte stream (SRT, UDP, RTP) into rotating files.
+//! Packets are realigned on the sync byte and files rotate at keyframes.
+
+use crate::commands::{self, SharedHooks};
+use crate::output::{OutputFile, OutputOptions};
+use crate::stats::RecordingStats;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// Offset of the first TS packet in `data` that starts a random access point
+/// (adaptation field with random_access_indicator), if any
+fn random_access_offset(data: &[u8]) -> Option<usize> {
+    data.chunks_exact(TS_PACKET_SIZE)
+        .position(|packet| {
+            packet[0] == TS_SYNC_BYTE
+                && packet[3] & 0x20 != 0
+                && packet[4] > 0
+                && packet[5] & 0x40 != 0
+        })
+        .map(|index| index * TS_PACKET_SIZE)
+}
+
+/// Offset of the first sync byte in `data` that's followed by two more, a
+/// packet apart, so a 0x47 inside a payload isn't taken for a packet start.
+/// Err holds where a candidate too close to the end to tell starts, or the
+/// length when there's none.
+fn find_sync(data: &[u8]) -> Result<usize, usize> {
+    let candidates = data.iter().enumerate().filter(|&(_, &b)| b == TS_SYNC_BYTE);
+    'candidates: for (start, _) in candidates {
+        for next in [start + TS_PACKET_SIZE, start + 2 * TS_PACKET_SIZE] {
+            match data.get(next) {
+                None => return Err(start),
+                Some(&b) if b != TS_SYNC_BYTE => continue 'candidates,
+                Some(_) => {}
+            }
+        }
+        return Ok(start);
+    }
+    Err(data.len())
+}
+
+pub struct TsIngest {
+    output: OutputFile,
+    hooks: SharedHooks,
+    stats: Arc<RecordingStats>,
+    pending_commands: Vec<tokio::task::JoinHandle<()>>,
+    /// SIGHUP arrived; rotate at the next keyframe
+    rotate_requested: bool,
+    /// Bytes of a packet split across reads
+    partial: Vec<u8>,
+    /// Whether `partial` starts at a packet boundary
+    synced: bool,
+}
+
+impl TsIngest {
+    pub fn new(
+        output_dir: PathBuf,
+        file_extension: String,
+        segment_secs: u64,
+        output_options: OutputOptions,
+        hooks: SharedHooks,
+        stats: Arc<RecordingStats>,
+    ) -> std::io::Result<Self> {
+        let output = OutputFile::new(
+            file_extension,
+            output_dir,
+            Duration::from_secs(segment_secs),
+            false,
+            output_options,
+        )?;
+        stats.set_current_file(output.current_path());
+        Ok(Self {
+            output,
+            hooks,
+            stats,
+            pending_commands: Vec::new(),
+            rotate_requested: false,
+            partial: Vec::new(),
+            synced: false,
+        })
+    }
+
+    /// Close the current file at the next keyframe instead of waiting for
+    /// --segment-secs
+    pub fn request_rotate(&mut self) {
+        self.rotate_requested = true;
+    }
+
+    /// Append received bytes. Whole packets are written and a trailing
+    /// partial packet is held back. When a packet doesn't start with the sync
+    /// byte, bytes are dropped up to one that starts three packets in a row.
+    pub fn push(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.partial.extend_from_slice(data);
+        let mut packets = Vec::new();
+        let mut pos = 0;
+        loop {
+            if self.synced {
+                while let Some(packet) = self.partial.get(pos..pos + TS_PACKET_SIZE) {
+                    if packet[0] != TS_SYNC_BYTE {
+                        self.synced = false;
+                        break;
+                    }
+                    packets.extend_from_slice(packet);
+                    pos += TS_PACKET_SIZE;
+                }
+                if self.synced {
+                    break;
+                }
+            }
+            match find_sync(&self.partial[pos..]) {
+                Ok(skip) => {
+                    if skip > 0 {
+                        debug!(bytes = skip, "Skipped bytes to resync on a TS packet");
+                    }
+                    pos += skip;
+                    self.synced = true;
+                }
+                Err(keep) => {
+                    pos += keep;
+                    break;
+                }
+            }
+        }
+        self.partial.drain(..pos);
+        if !packets.is_empty() {
+            self.write(&packets)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, packets: &[u8]) -> std::io::Result<()> {
+        // --segment-bytes is a hard limit, so it doesn't wait for a keyframe
+        if let Some(completed) = self.output.make_room(packets.len())? {
+            self.stats.set_current_file(self.output.current_path());
+            self.pending_commands
+                .extend(commands::file_completed(&self.hooks, completed));
+        }
+        // Rotate at the next random access point once the file is due, so
+        // each file starts on a keyframe
+        let due = (self.rotate_requested || self.output.rotation_due()) && !self.output.is_empty();
+        match random_access_offset(packets).filter(|_| due) {
+            Some(offset) => {
+                self.output.write(&packets[..offset], None)?;
+                let completed = self.output.rotate()?;
+                if std::mem::take(&mut self.rotate_requested) {
+                    info!(file = %completed.path.display(), "Rotated on request");
+                }
+                self.stats.set_current_file(self.output.current_path());
+                self.pending_commands
+                    .extend(commands::file_completed(&self.hooks, completed));
+                self.output.write(&packets[offset..], None)?;
+            }
+            None => self.output.write(packets, None)?,
+        }
+        self.stats.add_bytes(packets.len() as u64);
+        Ok(())
+    }
+
+    /// Close the last file, run its hook and wait for pending hooks.
+    /// Returns the total bytes written.
+    pub async fn finish(mut self) -> std::io::Result<u64> {
+        let final_file = self.output.finalize()?;
+        info!(file = %final_file.path.display(), "Flushed current segment");
+        self.pending_commands
+            .extend(commands::file_completed(&self.hooks, final_file));
+        commands::wait_for_commands(&mut self.pending_commands).await;
+        Ok(self.output.total_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` empty TS packets, numbered in their first payload byte
+    fn packets(count: u8) -> Vec<u8> {
+        (0..count)
+            .flat_map(|n| {
+                let mut packet = vec![0; TS_PACKET_SIZE];
+                packet[0] = TS_SYNC_BYTE;
+                packet[4] = n;
+                packet
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sync_needs_three_packets() {
+        // A stray sync byte before the first packet
+        let mut data = vec![1, TS_SYNC_BYTE, 2];
+        data.extend(packets(3));
+        assert_eq!(find_sync(&data), Ok(3));
+        // The third packet hasn't arrived yet
+        assert_eq!(find_sync(&data[..3 + 2 * TS_PACKET_SIZE]), Err(3));
+        assert_eq!(find_sync(&[1, 2, 3]), Err(3));
+        assert_eq!(find_sync(&[]), Err(0));
+    }
+
+    #[tokio::test]
+    async fn push_resyncs_after_garbage() {
+        let dir = std::env::temp_dir().join(format!("ts-ingest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut ingest = TsIngest::new(
+            dir.clone(),
+            "ts".to_string(),
+            3600,
+            OutputOptions::default(),
+            SharedHooks::default(),
+            Arc::new(RecordingStats::new()),
+        )
+        .unwrap();
+
+        let stream = packets(6);
+        let split = 3 * TS_PACKET_SIZE;
+        // Garbage with sync bytes in it, before the stream and in the middle
+        let mut input = vec![TS_SYNC_BYTE, 0, TS_SYNC_BYTE];
+        input.extend(&stream[..split]);
+        input.extend([0, TS_SYNC_BYTE, 1, 2, 3]);
+        input.extend(&stream[split..]);
+        for chunk in input.chunks(50) {
+            ingest.push(chunk).unwrap();
+        }
+        let written = ingest.finish().await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        let data = std::fs::read(&files[0]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(written, stream.len() as u64);
+        assert!(data == stream, "the packets were written as sent");
+    }
+}
@@ -0,0 +1,249 @@
+//! UDP and RTP ingest: receive an MPEG-TS stream on a UDP port, joining the
+//! multicast group when the address is one (IPTV feeds), and write it into
+//! the usual rotating files.
+
+use crate::commands::SharedHooks;
+use crate::error::StreamError;
+use crate::output::OutputOptions;
+use crate::stats::RecordingStats;
+use crate::ts_ingest::TsIngest;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Larger than any UDP payload we'll get (jumbo frames included)
+const MAX_DATAGRAM: usize = 65536;
+
+/// Warn when nothing has arrived for this long, usually a routing problem
+const SILENCE_WARNING: Duration = Duration::from_secs(10);
+
+pub struct UdpConfig {
+    pub url: String,
+    /// Local address of the interface to join the multicast group on
+    pub interface: Option<IpAddr>,
+    pub output_dir: PathBuf,
+    pub file_extension: String,
+    pub segment_secs: u64,
+    pub output_options: OutputOptions,
+    pub hooks: SharedHooks,
+}
+
+/// Whether `url` is a UDP or RTP input
+pub fn is_udp_url(url: &str) -> bool {
+    url.starts_with("udp://") || url.starts_with("rtp://")
+}
+
+/// Address to listen on from `udp://group:port`, `udp://@group:port` (VLC's
+/// spelling) or `udp://:port` for unicast on every interface. Returns the
+/// address and whether the payload is RTP.
+fn parse_udp_url(url: &str) -> Result<(SocketAddr, bool), String> {
+    let (rest, rtp) = match url.strip_prefix("rtp://") {
+        Some(rest) => (rest, true),
+        None => (
+            url.strip_prefix("udp://")
+                .ok_or_else(|| format!("Not a udp:// or rtp:// URL: {url}"))?,
+            false,
+        ),
+    };
+    // Options such as ?pkt_size= are meant for ffmpeg and not needed here
+    let authority = rest.split(['?', '/']).next().unwrap_or_default();
+    let authority = authority.trim_start_matches('@');
+    let (host, port) = authority
+        .rsplit_once(':')
+        .ok_or_else(|| format!("UDP URL needs a port: {url}"))?;
+    let port: u16 = port.parse().map_err(|_| format!("Invalid port in {url}"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let ip = if host.is_empty() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        host.parse()
+            .map_err(|_| format!("UDP URL needs an IP address, not {host}"))?
+    };
+    Ok((SocketAddr::new(ip, port), rtp))
+}
+
+/// Bind to `addr`, joining its multicast group if it is one. The socket is
+/// shared (SO_REUSEADDR) so several recorders can take the same feed.
+fn bind(addr: SocketAddr, interface: Option<IpAddr>) -> std::io::Result<tokio::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    // IPTV bitrates overrun the default receive buffer between reads
+    if let Err(e) = socket.set_recv_buffer_size(4 * 1024 * 1024) {
+        debug!("Can't enlarge the UDP receive buffer: {e}");
+    }
+    socket.set_nonblocking(true)?;
+
+    match addr.ip() {
+        IpAddr::V4(group) if group.is_multicast() => {
+            let interface = match interface {
+                Some(IpAddr::V4(ip)) => ip,
+                Some(IpAddr::V6(_)) => {
+                    return Err(std::io::Error::other(
+                        "--interface must be an IPv4 address for an IPv4 group",
+                    ))
+                }
+                None => Ipv4Addr::UNSPECIFIED,
+            };
+            // Binding the group address keeps other traffic to the port out
+            socket.bind(&addr.into())?;
+            socket.join_multicast_v4(&group, &interface)?;
+            info!("Joined multicast group {group} on {interface}");
+        }
+        IpAddr::V6(group) if group.is_multicast() => {
+            socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port()).into())?;
+            socket.join_multicast_v6(&group, 0)?;
+            info!("Joined multicast group {group}");
+        }
+        _ => socket.bind(&addr.into())?,
+    }
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+/// The MPEG-TS payload of an RTP packet (RFC 3550 header, CSRCs, header
+/// extension and padding removed), with its sequence number
+fn rtp_payload(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+    let mut start = 12 + 4 * usize::from(packet[0] & 0x0f);
+    if packet[0] & 0x10 != 0 {
+        let words = packet.get(start + 2..start + 4)?;
+        start += 4 + 4 * usize::from(u16::from_be_bytes([words[0], words[1]]));
+    }
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(usize::from(*packet.last()?))?;
+    }
+    Some((sequence, packet.get(start..end)?))
+}
+
+pub async fn handle_udp_stream(
+    config: UdpConfig,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    stats: Arc<RecordingStats>,
+) -> Result<u64, StreamError> {
+    let (addr, rtp) = parse_udp_url(&config.url).map_err(StreamError::config)?;
+    let socket = bind(addr, config.interface).map_err(StreamError::network)?;
+    info!("Receiving {} on {addr}", if rtp { "RTP" } else { "UDP" });
+
+    let mut ingest = TsIngest::new(
+        config.output_dir,
+        config.file_extension,
+        config.segment_secs,
+        config.output_options,
+        config.hooks,
+        stats.clone(),
+    )?;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut next_sequence: Option<u16> = None;
+    let mut last_packet = Instant::now();
+    let mut warned = false;
+
+    loop {
+        stats.touch();
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if rotate.swap(false, Ordering::SeqCst) {
+            ingest.request_rotate();
+        }
+
+        // Wake up now and then to check for shutdown while the feed is quiet
+        let len = match tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf))
+            .await
+        {
+            Err(_) => {
+                if !warned && last_packet.elapsed() >= SILENCE_WARNING {
+                    warn!(
+                        "Nothing received on {addr} for {}s; check multicast routing or --interface",
+                        SILENCE_WARNING.as_secs()
+                    );
+                    warned = true;
+                }
+                continue;
+            }
+            Ok(result) => result.map_err(StreamError::network)?,
+        };
+        last_packet = Instant::now();
+        warned = false;
+
+        let payload = if rtp {
+            let Some((sequence, payload)) = rtp_payload(&buf[..len]) else {
+                debug!(bytes = len, "Dropped a datagram that isn't RTP");
+                continue;
+            };
+            if let Some(expected) = next_sequence.filter(|&e| e != sequence) {
+                debug!(lost = sequence.wrapping_sub(expected), "RTP sequence gap");
+            }
+            next_sequence = Some(sequence.wrapping_add(1));
+            payload
+        } else {
+            &buf[..len]
+        };
+        ingest.push(payload)?;
+    }
+
+    Ok(ingest.finish().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_urls() {
+        let addr = |url| parse_udp_url(url).map(|(addr, rtp)| (addr.to_string(), rtp));
+        assert_eq!(
+            addr("udp://@239.0.0.1:1234"),
+            Ok(("239.0.0.1:1234".into(), false))
+        );
+        assert_eq!(
+            addr("udp://:5000?pkt_size=1316"),
+            Ok(("0.0.0.0:5000".into(), false))
+        );
+        assert_eq!(
+            addr("rtp://[ff02::1]:5004"),
+            Ok(("[ff02::1]:5004".into(), true))
+        );
+        assert!(addr("udp://239.0.0.1").is_err());
+        assert!(addr("udp://camera.local:1234").is_err());
+    }
+
+    #[test]
+    fn rtp_header_is_stripped() {
+        let ts = [0x47; 8];
+        // Version 2, one CSRC, a one-word header extension and 3 bytes of
+        // padding
+        let mut packet = vec![0xb1, 33, 0x12, 0x34, 0, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend([0; 4]);
+        packet.extend([0xbe, 0xde, 0, 1, 0, 0, 0, 0]);
+        packet.extend(ts);
+        packet.extend([0, 0, 3]);
+        assert_eq!(rtp_payload(&packet), Some((0x1234, &ts[..])));
+
+        let mut plain = vec![0x80, 33, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0];
+        plain.extend(ts);
+        assert_eq!(rtp_payload(&plain), Some((7, &ts[..])));
+    }
+
+    #[test]
+    fn bad_rtp_packets() {
+        // Too short, or not version 2
+        assert_eq!(rtp_payload(&[0x80; 11]), None);
+        assert_eq!(rtp_payload(&[0x47; 188]), None);
+        // An extension running past the end
+        let mut packet = vec![
+            0x90, 33, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0xbe, 0xde, 0xff, 0xff,
+        ];
+        assert_eq!(rtp_payload(&packet), None);
+        // More padding than packet
+        packet = vec![0xa0, 33, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 200];
+        assert_eq!(rtp_payload(&packet), None);
+    }
+}
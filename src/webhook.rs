@@ -0,0 +1,114 @@
+//! --webhook: POST a line of JSON about each lifecycle event (start,
+//! segment, exit, error) to a URL, for integrations that would otherwise
+//! wrap the shell hooks. A failed POST is retried like a segment fetch, then
+//! given up with a warning; the recording never waits on it, except for the
+//! last event before exiting.
+
+use crate::cli::NetworkArgs;
+use crate::error::StreamError as Error;
+use crate::tls;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::Request;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+pub struct Webhook {
+    url: String,
+    client: Client<tls::HttpsConnector, Full<Bytes>>,
+    /// Limit on each attempt (--request-timeout, else --timeout)
+    timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    /// Events from ffmpeg's file watcher come from a plain thread
+    runtime: tokio::runtime::Handle,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Webhook {
+    /// A webhook for `url`, reached with the TLS settings and retries of
+    /// the network options. Call from within the runtime.
+    pub fn new(url: &str, net: &NetworkArgs) -> Result<Self, Error> {
+        let parsed = url::Url::parse(url)
+            .map_err(|e| Error::config(format!("Bad --webhook URL {url}: {e}")))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(Error::config(format!(
+                "--webhook needs an http:// or https:// URL, not {url}"
+            )));
+        }
+        Ok(Self {
+            url: url.to_string(),
+            client: Client::builder(TokioExecutor::new()).build(tls::build_connector(net)?),
+            timeout: Duration::from_secs(net.request_timeout.unwrap_or(net.timeout).max(1)),
+            retries: net.retries,
+            retry_delay: Duration::from_millis(net.retry_delay_ms),
+            runtime: tokio::runtime::Handle::current(),
+        })
+    }
+
+    /// POST `event` in the background. `fields` (a JSON object) go in the
+    /// payload next to `event` and `time`.
+    pub fn send(
+        self: &Arc<Self>,
+        event: &'static str,
+        fields: serde_json::Value,
+    ) -> tokio::task::JoinHandle<()> {
+        let mut payload = serde_json::json!({
+            "event": event,
+            "time": chrono::Local::now().to_rfc3339(),
+        });
+        if let (Some(payload), serde_json::Value::Object(fields)) =
+            (payload.as_object_mut(), fields)
+        {
+            payload.extend(fields);
+        }
+        let webhook = self.clone();
+        self.runtime.spawn(async move {
+            if let Err(e) = webhook.post(payload.to_string()).await {
+                warn!(event, "Webhook failed: {e}");
+            }
+        })
+    }
+
+    async fn post(&self, body: String) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            let req = Request::post(&self.url)
+                .header("Content-Type", "application/json")
+                .header("User-Agent", "stream-utils/1.0")
+                .body(Full::new(Bytes::from(body.clone())))?;
+            let error = match tokio::time::timeout(self.timeout, self.client.request(req)).await {
+                Ok(Ok(resp)) if resp.status().is_success() => {
+                    let _ = resp.collect().await;
+                    return Ok(());
+                }
+                Ok(Ok(resp)) => format!("HTTP {} from {}", resp.status(), self.url),
+                // hyper's own message is just "client error (Connect)"
+                Ok(Err(e)) => match std::error::Error::source(&e) {
+                    Some(source) => format!("{e}: {source}"),
+                    None => e.to_string(),
+                },
+                Err(_) => format!("{} timed out", self.url),
+            };
+            if attempt >= self.retries {
+                return Err(error.into());
+            }
+            attempt += 1;
+            debug!(
+                "Webhook failed (retrying {attempt}/{}): {error}",
+                self.retries
+            );
+            tokio::time::sleep(self.retry_delay).await;
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! WHEP (WebRTC-HTTP egress) ingest: negotiate a receive-only WebRTC session
+//! with a WHEP endpoint and record its H.264 video into segmented MP4 files
+//! like the RTSP path, with the Opus audio in an Ogg file next to each one.
+
+use crate::commands::{self, SharedHooks};
+use crate::error::StreamError;
+use crate::h264::sps_dimensions;
+use crate::output::{self, CompletedFile};
+use crate::stats::RecordingStats;
+use crate::tls;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use mp4::{AvcConfig, MediaConfig, Mp4Config, Mp4Sample, Mp4Writer, TrackConfig};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use tracing::{debug, info, warn};
+use url::Url;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS};
+use webrtc::api::APIBuilder;
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::io::ogg_writer::OggWriter;
+use webrtc::media::io::sample_builder::SampleBuilder;
+use webrtc::media::io::Writer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp::codecs::h264::H264Packet;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+};
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::{RTCPFeedback, RTCRtpTransceiverInit};
+
+/// H.264 and Opus RTP clock rates
+const VIDEO_CLOCK_RATE: u32 = 90000;
+const OPUS_CLOCK_RATE: u32 = 48000;
+
+/// H.264 profiles offered, most widely supported first
+const H264_PROFILES: [&str; 4] = ["42e01f", "42001f", "4d001f", "640032"];
+
+pub struct WhepConfig {
+    pub url: String,
+    pub bearer_token: Option<String>,
+    /// HTTPS connector with the --insecure and --client-cert settings
+    pub connector: tls::HttpsConnector,
+    pub output_dir: PathBuf,
+    pub segment_secs: u64,
+    pub hooks: SharedHooks,
+}
+
+/// Split an Annex B access unit into NAL units
+fn annex_b_nals(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(s) = start {
+                nals.push(&data[s..i]);
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(s) = start {
+        nals.push(&data[s..]);
+    }
+    // A four-byte start code leaves a zero on the end of the previous unit
+    nals.into_iter()
+        .map(|nal| {
+            let end = nal.iter().rposition(|&b| b != 0).map_or(0, |p| p + 1);
+            &nal[..end]
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+struct Segment {
+    writer: Mp4Writer<BufWriter<File>>,
+    path: PathBuf,
+    audio: Option<OggWriter<File>>,
+    sps: Vec<u8>,
+    first_timestamp: u32,
+}
+
+/// POST or DELETE against the WHEP endpoint; returns status, Location and body
+async fn whep_request(
+    config: &WhepConfig,
+    method: Method,
+    url: &str,
+    sdp: Option<String>,
+) -> Result<(StatusCode, Option<String>, String), StreamError> {
+    let client: Client<_, Full<Bytes>> =
+        Client::builder(TokioExecutor::new()).build(config.connector.clone());
+    let mut req = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("User-Agent", "stream-utils/1.0");
+    if let Some(ref token) = config.bearer_token {
+        req = req.header("Authorization", format!("Bearer {token}"));
+    }
+    let body = match sdp {
+        Some(sdp) => {
+            req = req.header("Content-Type", "application/sdp");
+            Full::new(Bytes::from(sdp))
+        }
+        None => Full::new(Bytes::new()),
+    };
+    let resp = client.request(req.body(body)?).await?;
+    let status = resp.status();
+    let location = resp
+        .headers()
+        .get("location")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let body = resp.collect().await?.to_bytes();
+    Ok((
+        status,
+        location,
+        String::from_utf8_lossy(&body).into_owned(),
+    ))
+}
+
+fn media_engine() -> Result<MediaEngine, webrtc::Error> {
+    let mut engine = MediaEngine::default();
+    let feedback = |typ: &str, parameter: &str| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    };
+    for (i, profile) in H264_PROFILES.iter().enumerate() {
+        engine.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_H264.to_owned(),
+                    clock_rate: VIDEO_CLOCK_RATE,
+                    channels: 0,
+                    sdp_fmtp_line: format!(
+                        "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={profile}"
+                    ),
+                    rtcp_feedback: vec![
+                        feedback("nack", ""),
+                        feedback("nack", "pli"),
+                        feedback("ccm", "fir"),
+                    ],
+                },
+                payload_type: 102 + i as u8,
+                ..Default::default()
+            },
+            RTPCodecType::Video,
+        )?;
+    }
+    engine.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: OPUS_CLOCK_RATE,
+                channels: 2,
+                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                rtcp_feedback: vec![],
+            },
+            payload_type: 111,
+            ..Default::default()
+        },
+        RTPCodecType::Audio,
+    )?;
+    Ok(engine)
+}
+
+pub async fn handle_whep_stream(
+    config: WhepConfig,
+    shutdown: Arc<AtomicBool>,
+    rotate: Arc<AtomicBool>,
+    stats: Arc<RecordingStats>,
+) -> Result<u64, StreamError> {
+    let mut engine = media_engine()?;
+    let registry = register_default_interceptors(Registry::new(), &mut engine)?;
+    let api = APIBuilder::new()
+        .with_media_engine(engine)
+        .with_interceptor_registry(registry)
+        .build();
+    let pc = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+    for kind in [RTPCodecType::Video, RTPCodecType::Audio] {
+        pc.add_transceiver_from_kind(
+            kind,
+            Some(RTCRtpTransceiverInit {
+                direction: RTCRtpTransceiverDirection::Recvonly,
+                send_encodings: vec![],
+            }),
+        )
+        .await?;
+    }
+
+    // Tracks hand their RTP packets to the recording loop below
+    let (packets_tx, mut packets) = mpsc::channel::<(RTPCodecType, Packet)>(1024);
+    let pc_for_tracks = Arc::downgrade(&pc);
+    pc.on_track(Box::new(move |track, _, _| {
+        let packets_tx = packets_tx.clone();
+        let pc = pc_for_tracks.clone();
+        Box::pin(async move {
+            let kind = track.kind();
+            debug!(codec = %track.codec().capability.mime_type, "WHEP track");
+            if kind == RTPCodecType::Video {
+                // Ask for a keyframe now rather than waiting for the next one
+                if let Some(pc) = pc.upgrade() {
+                    let pli = PictureLossIndication {
+                        sender_ssrc: 0,
+                        media_ssrc: track.ssrc(),
+                    };
+                    let _ = pc.write_rtcp(&[Box::new(pli)]).await;
+                }
+            }
+            tokio::spawn(async move {
+                while let Ok((packet, _)) = track.read_rtp().await {
+                    if packets_tx.send((kind, packet)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        })
+    }));
+    let ended = Arc::new(Notify::new());
+    let ended_tx = ended.clone();
+    pc.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+        debug!("WebRTC connection {state}");
+        if matches!(
+            state,
+            RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Closed
+        ) {
+            ended_tx.notify_one();
+        }
+        Box::pin(async {})
+    }));
+
+    // WHEP has no trickle by default: send the offer with every candidate
+    let offer = pc.create_offer(None).await?;
+    let mut gathered = pc.gathering_complete_promise().await;
+    pc.set_local_description(offer).await?;
+    let _ = gathered.recv().await;
+    let offer = pc
+        .local_description()
+        .await
+        .ok_or("No local session description")?;
+
+    let (status, location, answer) =
+        whep_request(&config, Method::POST, &config.url, Some(offer.sdp)).await?;
+    if !status.is_success() {
+        let msg = format!("WHEP endpoint answered HTTP {status}: {}", answer.trim());
+        return Err(StreamError::network(msg));
+    }
+    // The session resource to DELETE when done
+    let resource = location.and_then(|l| Url::parse(&config.url).ok()?.join(&l).ok());
+    pc.set_remote_description(RTCSessionDescription::answer(answer)?)
+        .await?;
+    info!("WHEP session established");
+
+    let result = record(&config, &mut packets, &ended, &shutdown, &rotate, &stats).await;
+
+    if let Some(resource) = resource {
+        if let Err(e) = whep_request(&config, Method::DELETE, resource.as_str(), None).await {
+            debug!("WHEP session teardown failed: {e}");
+        }
+    }
+    pc.close().await?;
+    result
+}
+
+async fn record(
+    config: &WhepConfig,
+    packets: &mut mpsc::Receiver<(RTPCodecType, Packet)>,
+    ended: &Notify,
+    shutdown: &AtomicBool,
+    rotate: &AtomicBool,
+    stats: &RecordingStats,
+) -> Result<u64, StreamError> {
+    let mut video = SampleBuilder::new(128, H264Packet::default(), VIDEO_CLOCK_RATE);
+    let (mut sps, mut pps): (Option<Vec<u8>>, Option<Vec<u8>>) = (None, None);
+    let mut segment: Option<Segment> = None;
+    let mut segment_start = Instant::now();
+    let segment_duration = Duration::from_secs(config.segment_secs);
+    let mut pending_commands: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    let mut total_bytes: u64 = 0;
+
+    let mut close = |seg: Segment| -> Result<(), StreamError> {
+        let Segment {
+            mut writer,
+            path,
+            audio,
+            ..
+        } = seg;
+        writer.write_end()?;
+        if let Some(mut audio) = audio {
+            audio.close()?;
+        }
+        let completed = CompletedFile {
+            path,
+            program_date_time: None,
+            remux_mp4: false,
+            pipe: false,
+            checksums: None,
+        };
+        pending_commands.extend(commands::file_completed(&config.hooks, completed));
+        Ok(())
+    };
+
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let (kind, packet) = tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(packet) => packet,
+                None => break,
+            },
+            _ = ended.notified() => {
+                info!("WebRTC connection ended");
+                break;
+            }
+            // Check for shutdown while the sender is quiet
+            _ = tokio::time::sleep(Duration::from_millis(500)) => continue,
+        };
+
+        if kind == RTPCodecType::Audio {
+            if let Some(audio) = segment.as_mut().and_then(|s| s.audio.as_mut()) {
+                audio.write_rtp(&packet)?;
+                total_bytes += packet.payload.len() as u64;
+                stats.add_bytes(packet.payload.len() as u64);
+            }
+            continue;
+        }
+
+        video.push(packet);
+        while let Some(sample) = video.pop() {
+            let nals = annex_b_nals(&sample.data);
+            let mut is_key = false;
+            for nal in &nals {
+                match nal[0] & 0x1f {
+                    5 => is_key = true,
+                    7 => sps = Some(nal.to_vec()),
+                    8 => pps = Some(nal.to_vec()),
+                    _ => {}
+                }
+            }
+            let (Some(cur_sps), Some(cur_pps)) = (&sps, &pps) else {
+                continue;
+            };
+
+            // Rotate on a keyframe after the duration, after SIGHUP, or when
+            // the stream's parameters change
+            let need_new = match segment {
+                None => is_key,
+                Some(ref seg) => {
+                    is_key
+                        && (segment_start.elapsed() >= segment_duration
+                            || rotate.load(Ordering::SeqCst)
+                            || &seg.sps != cur_sps)
+                }
+            };
+            if need_new {
+                rotate.store(false, Ordering::SeqCst);
+                if let Some(seg) = segment.take() {
+                    close(seg)?;
+                }
+                let Some((width, height)) = sps_dimensions(cur_sps) else {
+                    warn!("Can't read the picture size from the SPS, skipping keyframe");
+                    continue;
+                };
+
+                let path = output::timestamped_path(&config.output_dir, "mp4");
+                debug!(file = %path.display(), width, height, "New segment");
+                let file = BufWriter::new(File::create(&path)?);
+                stats.set_current_file(path.clone());
+                let mp4_config = Mp4Config {
+                    major_brand: str::parse("isom").unwrap(),
+                    minor_version: 512,
+                    compatible_brands: vec![
+                        str::parse("isom").unwrap(),
+                        str::parse("iso2").unwrap(),
+                        str::parse("avc1").unwrap(),
+                        str::parse("mp41").unwrap(),
+                    ],
+                    timescale: VIDEO_CLOCK_RATE,
+                };
+                let mut writer = Mp4Writer::write_start(file, &mp4_config)?;
+                writer.add_track(&TrackConfig {
+                    track_type: mp4::TrackType::Video,
+                    timescale: VIDEO_CLOCK_RATE,
+                    language: "und".to_string(),
+                    media_conf: MediaConfig::AvcConfig(AvcConfig {
+                        width,
+                        height,
+                        seq_param_set: cur_sps.clone(),
+                        pic_param_set: cur_pps.clone(),
+                    }),
+                })?;
+                // The mp4 crate can't write Opus, so audio goes beside it
+                let audio = OggWriter::new(
+                    File::create(path.with_extension("ogg"))?,
+                    OPUS_CLOCK_RATE,
+                    2,
+                )?;
+                segment = Some(Segment {
+                    writer,
+                    path,
+                    audio: Some(audio),
+                    sps: cur_sps.clone(),
+                    first_timestamp: sample.packet_timestamp,
+                });
+                segment_start = Instant::now();
+            }
+
+            let Some(ref mut seg) = segment else {
+                continue;
+            };
+            // Length-prefixed NAL units; parameter sets live in avcC and
+            // access unit delimiters aren't needed
+            let mut avcc = Vec::with_capacity(sample.data.len());
+            for nal in nals.iter().filter(|n| !matches!(n[0] & 0x1f, 7..=9)) {
+                avcc.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                avcc.extend_from_slice(nal);
+            }
+            let mp4_sample = Mp4Sample {
+                start_time: u64::from(sample.packet_timestamp.wrapping_sub(seg.first_timestamp)),
+                duration: (sample.duration.as_secs_f64() * f64::from(VIDEO_CLOCK_RATE)) as u32,
+                rendering_offset: 0,
+                is_sync: is_key,
+                bytes: mp4::Bytes::from(avcc),
+            };
+            seg.writer.write_sample(1, &mp4_sample)?;
+            total_bytes += mp4_sample.bytes.len() as u64;
+            stats.add_bytes(mp4_sample.bytes.len() as u64);
+        }
+    }
+
+    if let Some(seg) = segment.take() {
+        close(seg)?;
+    }
+    commands::wait_for_commands(&mut pending_commands).await;
+    Ok(total_bytes)
+}